@@ -0,0 +1,8 @@
+-- Add migration script here
+
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "expires_at" TIMESTAMPTZ;
+-- Set once the background notifier has emitted an upcoming expiry
+-- event for the current expires_at, so we don't spam every tick.
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "expiry_notified_when" TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS kv_store_expires_at_idx ON tokaysec.kv_store ("expires_at") WHERE "expires_at" IS NOT NULL;
@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- Keys used to be unique across the whole instance. They are now scoped
+-- to the owning project (and environment within that project).
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "project" TEXT REFERENCES tokaysec.projects("id");
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "environment" TEXT NOT NULL DEFAULT 'default';
+
+UPDATE tokaysec.kv_store kv SET project = ra.assigned_to
+FROM tokaysec.resource_assignment ra
+WHERE ra.resource = 'kv_store:' || kv.id AND ra.resource_type = 'scrt' AND ra.assigned_to_type = 'proj' AND kv.project IS NULL;
+
+ALTER TABLE tokaysec.kv_store ALTER COLUMN "project" SET NOT NULL;
+ALTER TABLE tokaysec.kv_store DROP CONSTRAINT IF EXISTS kv_store_key_key;
+ALTER TABLE tokaysec.kv_store ADD CONSTRAINT kv_store_project_environment_key_key UNIQUE ("project", "environment", "key");
@@ -0,0 +1,25 @@
+-- Add migration script here
+
+-- Non-sensitive configuration. Values are stored as plain JSONB
+-- (no DEK involved) but every write creates a new version.
+CREATE TABLE IF NOT EXISTS tokaysec.config_store (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL DEFAULT 'default',
+    "key" TEXT NOT NULL,
+    "current_version" INT NOT NULL DEFAULT 1,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "last_updated" TIMESTAMPTZ NOT NULL,
+    UNIQUE ("project", "environment", "key")
+);
+
+CREATE TABLE IF NOT EXISTS tokaysec.config_store_versions (
+    "config_id" TEXT NOT NULL REFERENCES tokaysec.config_store("id") ON DELETE CASCADE,
+    "version" INT NOT NULL,
+    "value_type" TEXT NOT NULL, -- string, number, boolean, json
+    "value" JSONB NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    PRIMARY KEY ("config_id", "version")
+);
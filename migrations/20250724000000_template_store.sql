@@ -0,0 +1,17 @@
+-- Add migration script here
+
+-- Config documents (toml, json, yaml, dotenv) that can reference
+-- secrets. Only the template is stored, secrets are resolved and
+-- rendered at read time under the caller's permissions.
+CREATE TABLE IF NOT EXISTS tokaysec.template_store (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL DEFAULT 'default',
+    "key" TEXT NOT NULL,
+    "format" TEXT NOT NULL, -- toml, json, yaml, dotenv
+    "template" TEXT NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "last_updated" TIMESTAMPTZ NOT NULL,
+    UNIQUE ("project", "environment", "key")
+);
@@ -0,0 +1,51 @@
+-- Add migration script here
+
+-- Root and intermediate CA per project. The private key is sealed the
+-- same way kv values are (own DEK, wrapped by the KEK provider).
+CREATE TABLE IF NOT EXISTS tokaysec.pki_cas (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "kind" TEXT NOT NULL, -- root, intermediate
+    "parent" TEXT REFERENCES tokaysec.pki_cas("id"),
+    "common_name" TEXT NOT NULL,
+    "key_type" TEXT NOT NULL,
+    "certificate" TEXT NOT NULL, -- PEM
+    "value" BYTEA NOT NULL, -- sealed PKCS#8 private key
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "not_after" TIMESTAMPTZ NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    UNIQUE ("project", "kind")
+);
+
+-- Templates leaf certificates are issued against.
+CREATE TABLE IF NOT EXISTS tokaysec.pki_roles (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "name" TEXT NOT NULL,
+    "allowed_domains" JSONB NOT NULL DEFAULT '[]'::jsonb,
+    "allow_bare_domains" BOOLEAN NOT NULL DEFAULT TRUE,
+    "allow_subdomains" BOOLEAN NOT NULL DEFAULT FALSE,
+    "allow_ip_sans" BOOLEAN NOT NULL DEFAULT FALSE,
+    "key_types" JSONB NOT NULL DEFAULT '["ec-p256"]'::jsonb,
+    "default_ttl_secs" BIGINT NOT NULL,
+    "max_ttl_secs" BIGINT NOT NULL,
+    "last_updated" TIMESTAMPTZ NOT NULL,
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    UNIQUE ("project", "name")
+);
+
+CREATE TABLE IF NOT EXISTS tokaysec.pki_certificates (
+    "serial" TEXT NOT NULL UNIQUE PRIMARY KEY, -- hex
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "issuer" TEXT NOT NULL REFERENCES tokaysec.pki_cas("id"),
+    "role" TEXT NOT NULL REFERENCES tokaysec.pki_roles("id"),
+    "common_name" TEXT NOT NULL,
+    "certificate" TEXT NOT NULL, -- PEM
+    "not_after" TIMESTAMPTZ NOT NULL,
+    "issued_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "issued_by" TEXT NOT NULL REFERENCES tokaysec.people("id")
+);
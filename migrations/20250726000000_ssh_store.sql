@@ -0,0 +1,48 @@
+-- Add migration script here
+
+-- SSH keypairs. Private keys are kept in OpenSSH format and sealed
+-- like any other secret (own DEK, wrapped by the KEK provider).
+CREATE TABLE IF NOT EXISTS tokaysec.ssh_keys (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "key" TEXT NOT NULL,
+    "algorithm" TEXT NOT NULL,
+    "public_key" TEXT NOT NULL, -- OpenSSH authorized_keys format
+    "fingerprint" TEXT NOT NULL, -- SHA256:...
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    UNIQUE ("project", "key")
+);
+
+-- One SSH user CA per project.
+CREATE TABLE IF NOT EXISTS tokaysec.ssh_cas (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL UNIQUE REFERENCES tokaysec.projects("id"),
+    "algorithm" TEXT NOT NULL,
+    "public_key" TEXT NOT NULL,
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id")
+);
+
+CREATE TABLE IF NOT EXISTS tokaysec.ssh_certificates (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "ca" TEXT NOT NULL REFERENCES tokaysec.ssh_cas("id"),
+    "serial" BIGINT NOT NULL,
+    "key_id" TEXT NOT NULL,
+    "principals" JSONB NOT NULL,
+    "certificate" TEXT NOT NULL, -- OpenSSH format
+    "valid_before" TIMESTAMPTZ NOT NULL,
+    "issued_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "issued_by" TEXT NOT NULL REFERENCES tokaysec.people("id")
+);
@@ -0,0 +1,38 @@
+-- Add migration script here
+
+-- Admin connections used to mint short lived PostgreSQL roles. The
+-- connection url holds the admin password so it's sealed like any
+-- other secret.
+CREATE TABLE IF NOT EXISTS tokaysec.dynamic_postgres (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "key" TEXT NOT NULL,
+    "grants" JSONB NOT NULL, -- statements run for each new role, {{name}} is the role
+    "default_ttl_secs" BIGINT NOT NULL,
+    "max_ttl_secs" BIGINT NOT NULL,
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    UNIQUE ("project", "key")
+);
+
+-- Every role handed out. The revoker drops the role once expires_at
+-- has passed and sets revoked_when.
+CREATE TABLE IF NOT EXISTS tokaysec.dynamic_postgres_leases (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "connection" TEXT NOT NULL REFERENCES tokaysec.dynamic_postgres("id"),
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "role_name" TEXT NOT NULL,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "issued_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "issued_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "revoked_when" TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS dynamic_postgres_leases_expires_at
+    ON tokaysec.dynamic_postgres_leases ("expires_at")
+    WHERE "revoked_when" IS NULL;
@@ -0,0 +1,27 @@
+-- Add migration script here
+
+-- Named encryption keys for the transit endpoints. Key material never
+-- leaves the server, callers only ever see ciphertext.
+CREATE TABLE IF NOT EXISTS tokaysec.transit_keys (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "name" TEXT NOT NULL,
+    "latest_version" INTEGER NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "last_updated" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    UNIQUE ("project", "name")
+);
+
+-- Every version is kept so ciphertext from before a rotation can
+-- still be decrypted (and rewrapped to the latest version).
+CREATE TABLE IF NOT EXISTS tokaysec.transit_key_versions (
+    "key_id" TEXT NOT NULL REFERENCES tokaysec.transit_keys("id"),
+    "version" INTEGER NOT NULL,
+    "wrapped" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "tag" BYTEA NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    PRIMARY KEY ("key_id", "version")
+);
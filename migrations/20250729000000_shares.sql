@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- One-time share links. The key for each share is only ever in the
+-- token handed back to whoever created it, never stored here, so a
+-- row on its own can't be decrypted.
+CREATE TABLE IF NOT EXISTS tokaysec.shares (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "source" TEXT, -- <store>:<name> when copied from a stored secret
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "views_remaining" INTEGER NOT NULL,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id")
+);
@@ -0,0 +1,31 @@
+-- Add migration script here
+
+-- Files kept as secrets. The file is split into fixed size chunks
+-- that are each encrypted under the blob's DEK, with the chunk number
+-- and a last-chunk flag bound into the AAD so chunks can't be
+-- reordered, swapped between blobs or cut off the end.
+CREATE TABLE IF NOT EXISTS tokaysec.blob_store (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL DEFAULT 'default',
+    "key" TEXT NOT NULL,
+    "filename" TEXT,
+    "content_type" TEXT NOT NULL,
+    "size" BIGINT NOT NULL,
+    "chunk_size" INTEGER NOT NULL,
+    "chunk_count" INTEGER NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    UNIQUE ("project", "environment", "key")
+);
+
+CREATE TABLE IF NOT EXISTS tokaysec.blob_chunks (
+    "blob_id" TEXT NOT NULL REFERENCES tokaysec.blob_store("id") ON DELETE CASCADE,
+    "chunk" INTEGER NOT NULL,
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    PRIMARY KEY ("blob_id", "chunk")
+);
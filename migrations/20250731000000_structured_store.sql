@@ -0,0 +1,26 @@
+-- Add migration script here
+
+-- JSON object secrets. Every leaf of the object is its own row,
+-- encrypted separately under the secret's DEK, so single fields can
+-- be read (and access to them checked) without decrypting the rest.
+CREATE TABLE IF NOT EXISTS tokaysec.structured_store (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL DEFAULT 'default',
+    "key" TEXT NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "last_updated" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    UNIQUE ("project", "environment", "key")
+);
+
+CREATE TABLE IF NOT EXISTS tokaysec.structured_store_fields (
+    "secret_id" TEXT NOT NULL REFERENCES tokaysec.structured_store("id") ON DELETE CASCADE,
+    "pointer" TEXT NOT NULL, -- JSON pointer (RFC 6901) of the field, e.g. /db/password
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    PRIMARY KEY ("secret_id", "pointer")
+);
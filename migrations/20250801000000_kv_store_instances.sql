@@ -0,0 +1,7 @@
+-- Add migration script here
+
+-- Several kv store instances can be configured, they all share this
+-- table. Existing rows belong to the default instance.
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "store" TEXT NOT NULL DEFAULT 'kv_store';
+ALTER TABLE tokaysec.kv_store DROP CONSTRAINT IF EXISTS kv_store_project_environment_key_key;
+ALTER TABLE tokaysec.kv_store ADD CONSTRAINT kv_store_store_project_environment_key_key UNIQUE ("store", "project", "environment", "key");
@@ -0,0 +1,22 @@
+-- Add migration script here
+
+-- Secrets owned by out of process store plugins. The plugin decides
+-- what `state` holds, TokaySec only ever keeps it encrypted and hands
+-- it back to the plugin when it is needed.
+CREATE TABLE IF NOT EXISTS tokaysec.plugin_store (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "store" TEXT NOT NULL, -- name of the configured plugin instance
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL DEFAULT 'default',
+    "key" TEXT NOT NULL,
+    "metadata" JSONB NOT NULL DEFAULT '{}'::jsonb, -- not encrypted, plugins keep non sensitive details here
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "last_updated" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    UNIQUE ("store", "project", "environment", "key")
+);
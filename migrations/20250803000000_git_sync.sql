@@ -0,0 +1,22 @@
+-- Add migration script here
+
+-- Where a config version came from, e.g. git:<commit sha>. NULL for
+-- values written through the API.
+ALTER TABLE tokaysec.config_store_versions ADD COLUMN IF NOT EXISTS "source" TEXT;
+
+-- A git repository (local path or file:// remote) whose config file
+-- is pulled into a project's config store, and where the project's
+-- config can be exported back to as a commit.
+CREATE TABLE IF NOT EXISTS tokaysec.git_sync (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL DEFAULT 'default',
+    "repository" TEXT NOT NULL,
+    "branch" TEXT NOT NULL DEFAULT 'main',
+    "file" TEXT NOT NULL DEFAULT 'config.toml', -- path inside the repository, .toml, .json or .yaml
+    "last_commit" TEXT, -- last commit pulled or exported
+    "last_synced" TIMESTAMPTZ,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    UNIQUE ("project", "environment")
+);
@@ -0,0 +1,57 @@
+-- Add migration script here
+
+-- Bumped every time a kv secret's value is replaced.
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "version" INT NOT NULL DEFAULT 1;
+
+-- Values a rotation replaced. They stay readable (by version) until
+-- retained_until so whatever still holds them has time to move over,
+-- then the rotation scheduler removes them along with their DEK.
+CREATE TABLE IF NOT EXISTS tokaysec.kv_store_previous (
+    "secret_id" TEXT NOT NULL REFERENCES tokaysec.kv_store("id"),
+    "version" INT NOT NULL,
+    "value" BYTEA NOT NULL,
+    "gcm_tag" BYTEA NOT NULL,
+    "kmac_tag" BYTEA NOT NULL,
+    "nonce" BYTEA NOT NULL,
+    "dek_used" TEXT NOT NULL REFERENCES tokaysec.wrapped_deks("id"),
+    "retired_when" TIMESTAMPTZ NOT NULL,
+    "retained_until" TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY ("secret_id", "version")
+);
+
+-- At most one rotation policy per kv secret.
+CREATE TABLE IF NOT EXISTS tokaysec.rotation_policies (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "secret_id" TEXT NOT NULL UNIQUE REFERENCES tokaysec.kv_store("id") ON DELETE CASCADE,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "interval_secs" BIGINT NOT NULL,
+    -- Rotations only start in [window_start, window_start + window_secs)
+    -- (UTC) of a day. Any time of day when NULL.
+    "window_start" TIME,
+    "window_secs" BIGINT,
+    "grace_secs" BIGINT NOT NULL,
+    "rotator" JSONB NOT NULL, -- {"kind": "random" | "postgres" | "webhook", ...}, nothing sensitive
+    "next_rotation" TIMESTAMPTZ NOT NULL,
+    "last_rotated" TIMESTAMPTZ,
+    "last_status" TEXT, -- succeeded | failed
+    "last_error" TEXT,
+    "failures" INT NOT NULL DEFAULT 0, -- in a row, reset by a success
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id")
+);
+
+CREATE INDEX IF NOT EXISTS rotation_policies_next_rotation ON tokaysec.rotation_policies ("next_rotation");
+
+-- Every rotation that was run, scheduled or asked for.
+CREATE TABLE IF NOT EXISTS tokaysec.rotation_attempts (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "policy" TEXT NOT NULL REFERENCES tokaysec.rotation_policies("id") ON DELETE CASCADE,
+    "secret_id" TEXT NOT NULL,
+    "status" TEXT NOT NULL, -- succeeded | failed
+    "version" INT, -- the version a successful rotation produced
+    "error" TEXT,
+    "started_when" TIMESTAMPTZ NOT NULL,
+    "finished_when" TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS rotation_attempts_secret ON tokaysec.rotation_attempts ("secret_id", "started_when");
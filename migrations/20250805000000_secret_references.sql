@@ -0,0 +1,24 @@
+-- Add migration script here
+
+-- A secret linked into another project or namespace is one more
+-- resource_assignment of the same resource, with linked_from set to the
+-- project that owns it. NULL for the owner's own assignment.
+ALTER TABLE tokaysec.resource_assignment ADD COLUMN IF NOT EXISTS "linked_from" TEXT REFERENCES tokaysec.projects("id");
+
+CREATE UNIQUE INDEX IF NOT EXISTS resource_assignment_links
+    ON tokaysec.resource_assignment ("resource", "assigned_to", "assigned_to_type")
+    WHERE "linked_from" IS NOT NULL;
+
+-- Who did what to which resource, per project. A read through a
+-- reference is recorded in both the owning and the reading project.
+CREATE TABLE IF NOT EXISTS tokaysec.audit_log (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "person" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "action" TEXT NOT NULL,
+    "resource" TEXT NOT NULL,
+    "detail" JSONB NOT NULL DEFAULT '{}',
+    "occurred_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc')
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_project ON tokaysec.audit_log ("project", "id");
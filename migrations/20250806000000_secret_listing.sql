@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- Project listings page through a project's assignments ordered by
+-- resource.
+CREATE INDEX IF NOT EXISTS resource_assignment_listing
+    ON tokaysec.resource_assignment ("assigned_to", "assigned_to_type", "resource");
+
+-- Free form labels on any store's secret, keyed by the same
+-- <store>:<id> resource as its assignments.
+CREATE TABLE IF NOT EXISTS tokaysec.secret_tags (
+    "resource" TEXT NOT NULL,
+    "tag" TEXT NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    PRIMARY KEY ("resource", "tag")
+);
+
+CREATE INDEX IF NOT EXISTS secret_tags_tag ON tokaysec.secret_tags ("tag", "resource");
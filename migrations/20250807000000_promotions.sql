@@ -0,0 +1,32 @@
+-- Add migration script here
+
+-- Environments a promotion only lands in once it has been approved.
+CREATE TABLE IF NOT EXISTS tokaysec.protected_environments (
+    "project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "environment" TEXT NOT NULL,
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    PRIMARY KEY ("project", "environment")
+);
+
+-- Keys copied (or waiting for approval to be copied) from one
+-- environment to another. "keys" holds the source version of each
+-- key when it was requested, an approval only copies those versions.
+CREATE TABLE IF NOT EXISTS tokaysec.promotions (
+    "id" TEXT NOT NULL UNIQUE PRIMARY KEY,
+    "store" TEXT NOT NULL,
+    "source_project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "source_environment" TEXT NOT NULL,
+    "target_project" TEXT NOT NULL REFERENCES tokaysec.projects("id"),
+    "target_environment" TEXT NOT NULL,
+    "keys" JSONB NOT NULL,
+    "conflict" TEXT NOT NULL,
+    "status" TEXT NOT NULL, -- pending, applied, rejected, failed
+    "requested_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    "requested_when" TIMESTAMPTZ NOT NULL,
+    "decided_by" TEXT REFERENCES tokaysec.people("id"),
+    "decided_when" TIMESTAMPTZ,
+    "result" JSONB
+);
+
+CREATE INDEX IF NOT EXISTS promotions_target ON tokaysec.promotions ("target_project", "status");
@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- Limits set for the whole instance (scope 'inst', scope_id ''), a
+-- namespace ('nmsp') or a project ('proj'). NULL leaves a limit to
+-- the level above, see limits.rs.
+CREATE TABLE IF NOT EXISTS tokaysec.limits (
+    "scope" TEXT NOT NULL,
+    "scope_id" TEXT NOT NULL,
+    "max_value_bytes" BIGINT,
+    "max_secrets" BIGINT,
+    "max_versions" INT,
+    "max_request_bytes" BIGINT,
+    "updated_when" TIMESTAMPTZ NOT NULL,
+    "updated_by" TEXT NOT NULL REFERENCES tokaysec.people("id"),
+    PRIMARY KEY ("scope", "scope_id")
+);
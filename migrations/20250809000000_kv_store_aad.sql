@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- Which AAD a kv value was encrypted under, see aad.rs. 0 is the old
+-- name-only AAD, rows still on it are re-encrypted at startup.
+ALTER TABLE tokaysec.kv_store ADD COLUMN IF NOT EXISTS "aad_version" SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE tokaysec.kv_store_previous ADD COLUMN IF NOT EXISTS "aad_version" SMALLINT NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- Projects whose DEKs are never kept unwrapped in memory, every read
+-- of theirs goes to the KMS even with the DEK cache on.
+CREATE TABLE IF NOT EXISTS tokaysec.dek_cache_opt_outs (
+    "project" TEXT NOT NULL UNIQUE PRIMARY KEY REFERENCES tokaysec.projects("id"),
+    "added_when" TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    "added_by" TEXT NOT NULL REFERENCES tokaysec.people("id")
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- A CA can belong to a namespace instead of a project. Projects
+-- without a CA of their own issue from their namespace's.
+ALTER TABLE tokaysec.pki_cas ALTER COLUMN "project" DROP NOT NULL;
+ALTER TABLE tokaysec.pki_cas ADD COLUMN IF NOT EXISTS "namespace" TEXT REFERENCES tokaysec.namespaces("id");
+ALTER TABLE tokaysec.pki_cas DROP CONSTRAINT IF EXISTS pki_cas_one_owner;
+ALTER TABLE tokaysec.pki_cas ADD CONSTRAINT pki_cas_one_owner CHECK (("project" IS NULL) <> ("namespace" IS NULL));
+CREATE UNIQUE INDEX IF NOT EXISTS pki_cas_namespace_kind ON tokaysec.pki_cas ("namespace", "kind") WHERE "namespace" IS NOT NULL;
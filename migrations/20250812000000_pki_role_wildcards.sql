@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- Wildcard names (*.example.com) have to be allowed explicitly.
+ALTER TABLE tokaysec.pki_roles ADD COLUMN IF NOT EXISTS "allow_wildcards" BOOLEAN NOT NULL DEFAULT FALSE;
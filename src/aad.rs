@@ -0,0 +1,120 @@
+use crate::{app::App, stores::StoreAccessError};
+
+/*
+
+The additional authenticated data kv values are encrypted under, both
+for the value itself (Dek::wrap_with_aad) and for wrapping its DEK
+(handed to the KEK provider in place of the secret name).
+
+It used to be the secret's name alone, so a value (or its wrapped DEK)
+copied onto another row with the same name, in another project or
+store, still decrypted. It now names everything that makes the row
+that row:
+
+    tokaysec.aad.v1;instance=<n>:<id>;project=<n>:<id>;store=<n>:<name>;secret=<n>:<id>;version=<n>:<version>
+
+with <n> the byte length of the value after it, so no value can be
+made to read as the next field. Reading a value back rebuilds this from
+the row it was read from and anything that was moved fails to decrypt.
+
+The version is the one the value was written as, so a previous version
+in kv_store_previous stays readable and can't pass for the current
+one. Rows are tagged with the AAD they were written under
+(aad_version), 0 for the name-only one. kv::migrate_aad re-encrypts
+those at startup, after which they are refused.
+
+*/
+
+pub const AAD_VERSION: i16 = 1;
+
+pub struct SecretAad<'a> {
+    pub instance: &'a str,
+    pub project: &'a str,
+    pub store: &'a str,
+    pub secret: &'a str,
+    pub version: i32,
+}
+
+impl ToString for SecretAad<'_> {
+    fn to_string(&self) -> String {
+        let version = self.version.to_string();
+        let mut aad = format!("tokaysec.aad.v{}", AAD_VERSION);
+        for (field, value) in [
+            ("instance", self.instance),
+            ("project", self.project),
+            ("store", self.store),
+            ("secret", self.secret),
+            ("version", version.as_str()),
+        ] {
+            aad.push_str(&format!(";{}={}:{}", field, value.len(), value));
+        }
+        aad
+    }
+}
+
+// Set once when the instance is first initialized (main.rs).
+pub async fn instance_id(app: &App) -> Result<String, StoreAccessError> {
+    let Some((instance_id,)) = sqlx::query_as::<_, (serde_json::Value,)>(
+        r#"SELECT value FROM tokaysec.config WHERE key = 'instance_id'"#,
+    )
+    .fetch_optional(&app.database.inner)
+    .await?
+    else {
+        return Err(StoreAccessError::Internal(
+            "This instance has no instance_id".to_string(),
+        ));
+    };
+    serde_json::from_value(instance_id).map_err(|e| StoreAccessError::Internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aad<'a>(project: &'a str, store: &'a str, version: i32) -> SecretAad<'a> {
+        SecretAad {
+            instance: "inst1",
+            project,
+            store,
+            secret: "42",
+            version,
+        }
+    }
+
+    #[test]
+    fn names_every_field_with_its_length() {
+        assert_eq!(
+            aad("pr1", "kv_store", 3).to_string(),
+            "tokaysec.aad.v1;instance=5:inst1;project=3:pr1;store=8:kv_store;secret=2:42;version=1:3"
+        );
+    }
+
+    #[test]
+    fn empty_values_still_get_a_length() {
+        assert_eq!(
+            aad("", "", 0).to_string(),
+            "tokaysec.aad.v1;instance=5:inst1;project=0:;store=0:;secret=2:42;version=1:0"
+        );
+    }
+
+    // Without the lengths these two would come out the same.
+    #[test]
+    fn separators_in_values_cannot_shift_fields() {
+        let smuggled = aad("pr1;store=2:kv", "", 1).to_string();
+        let plain = aad("pr1", "kv", 1).to_string();
+        assert_ne!(smuggled, plain);
+        assert!(smuggled.contains("project=14:pr1;store=2:kv;store=0:;"));
+    }
+
+    #[test]
+    fn versions_are_distinct() {
+        assert_ne!(
+            aad("pr1", "kv", 1).to_string(),
+            aad("pr1", "kv", 2).to_string()
+        );
+        assert_ne!(
+            aad("pr1", "kv", 1).to_string(),
+            aad("pr1", "kv", 11).to_string()
+        );
+    }
+}
@@ -1,17 +1,19 @@
 use crate::{
     config::{self, Config},
     db::Database,
+    dek_cache::DekCache,
+    events::{self, SecretEvent},
     kek_provider::{KekProvider, fs::FileSystemKEKProvider, tokaykms::TokayKMSKEKProvider},
     models::{Namespace, Permission, Person, PolicyRuleTarget, Project, ResourceAssignment, Role},
     policies::split,
-    stores::{Store, kv::KvStore},
+    stores::{Store, build_stores},
 };
 use chrono::Utc;
 use serde::{Serialize, de::DeserializeOwned};
 use snowflaked::Generator;
 use sqlx::{FromRow, Postgres, Type, postgres::PgRow};
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, RwLock, broadcast};
 
 #[derive(Clone)]
 pub struct App {
@@ -19,6 +21,10 @@ pub struct App {
     pub id_gen: Arc<Mutex<Generator>>,
     pub stores: Arc<RwLock<HashMap<String, Box<dyn Store>>>>,
     pub kek_provider: Arc<Box<dyn KekProvider>>,
+    pub events: broadcast::Sender<SecretEvent>,
+    pub git_sync: Arc<config::GitSyncConfig>,
+    pub rotation: Arc<config::RotationConfig>,
+    pub dek_cache: Arc<DekCache>,
 }
 
 #[derive(Debug)]
@@ -88,6 +94,7 @@ pub enum ResourceTypes {
     Permission,
     Role,
     Secret,
+    Config,
 }
 
 #[derive(Debug)]
@@ -103,6 +110,7 @@ impl ToString for ResourceTypes {
             ResourceTypes::Permission => "perm",
             ResourceTypes::Role => "role",
             ResourceTypes::Secret => "scrt",
+            ResourceTypes::Config => "cnfg",
         })
     }
 }
@@ -119,6 +127,7 @@ impl TryFrom<&str> for ResourceTypes {
             "perm" => Self::Permission,
             "role" => Self::Role,
             "scrt" => Self::Secret,
+            "cnfg" => Self::Config,
             _ => return Err(String::from("Not found.")),
         });
     }
@@ -126,9 +135,10 @@ impl TryFrom<&str> for ResourceTypes {
 
 impl App {
     pub async fn init(database: Arc<Database>, config: Config) -> Self {
-        let mut stores: HashMap<String, Box<dyn Store>> = HashMap::new();
-        let kv_store = KvStore::init().await;
-        stores.insert("kv_store".to_string(), Box::new(kv_store));
+        let stores = match build_stores(config.stores).await {
+            Ok(stores) => stores,
+            Err(e) => panic!("Invalid store configuration: {}", e),
+        };
         let kek_provider: Arc<Box<dyn KekProvider>> = Arc::new(match config.kms {
             config::KMSProviders::Fs => Box::new(FileSystemKEKProvider::init()),
             config::KMSProviders::TokayKMS { base } => Box::new(TokayKMSKEKProvider::init(base)),
@@ -138,6 +148,10 @@ impl App {
             stores: Arc::new(RwLock::new(stores)),
             id_gen: Arc::new(Mutex::new(Generator::new(1))),
             kek_provider,
+            events: events::channel(),
+            git_sync: Arc::new(config.git_sync),
+            rotation: Arc::new(config.rotation),
+            dek_cache: Arc::new(DekCache::new(config.dek_cache)),
         }
     }
     pub async fn gen_id(&self) -> String {
@@ -252,6 +266,48 @@ impl App {
         return Ok(sqlx::query_as::<_, PolicyRuleTarget>(r#"INSERT INTO tokaysec.policy_rule_target(id,target,target_type,action,resource,resource_type) VALUES($1,$2,$3,$4,$5,$6) RETURNING *"#)
             .bind(gen_id).bind(target).bind(target_type.to_string()).bind(action).bind(resource).bind(resource_type.to_string()).fetch_one(&self.database.inner).await.unwrap());
     }
+    // Like create_policy_rule_target but leaves things alone if the exact
+    // same rule is already there, so it is safe to run on every boot.
+    pub async fn ensure_policy_rule_target(
+        &self,
+        target: &str,
+        action: PolicyRuleTargetAction,
+        resource: &str,
+    ) -> std::result::Result<(), String> {
+        let (target_id, target_type) = split(target.to_string());
+        let (resource_id, resource_type) = split(resource.to_string());
+        let action: i32 = action.into();
+        let (exists,) = sqlx::query_as::<_, (bool,)>(
+            r#"SELECT EXISTS(SELECT 1 FROM tokaysec.policy_rule_target WHERE target = $1 AND target_type = $2 AND action = $3 AND resource = $4 AND resource_type = $5)"#,
+        )
+        .bind(&target_id)
+        .bind(target_type.to_string())
+        .bind(action)
+        .bind(&resource_id)
+        .bind(resource_type.to_string())
+        .fetch_one(&self.database.inner)
+        .await
+        .map_err(|e| e.to_string())?;
+        if !exists {
+            self.create_policy_rule_target(target, action.into(), resource)
+                .await?;
+        }
+        Ok(())
+    }
+    pub async fn find_role(
+        &self,
+        name: &str,
+        defined_by: &str,
+    ) -> std::result::Result<Option<Role>, String> {
+        return sqlx::query_as::<_, Role>(
+            r#"SELECT * FROM tokaysec.roles WHERE name = ($1) AND defined_by = ($2)"#,
+        )
+        .bind(name)
+        .bind(defined_by)
+        .fetch_optional(&self.database.inner)
+        .await
+        .map_err(|e| e.to_string());
+    }
     pub async fn create_permission(
         &self,
         name: &str,
@@ -1,8 +1,83 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
+use crate::{
+    app::App,
+    stores::{DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT, Page, StoreAccessError},
+};
+
 #[derive(Serialize, Deserialize, Debug, FromRow)]
-pub struct AuditLog {}
+pub struct AuditLog {
+    pub id: String,
+    pub project: String,
+    pub person: String,
+    pub action: String,
+    pub resource: String,
+    pub detail: serde_json::Value,
+    pub occurred_when: DateTime<Utc>,
+}
 
 #[derive(Serialize, Deserialize)]
-pub struct Audit {}
+pub struct Audit {
+    // `next_cursor` of the previous page, pages go newest first.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub limit: Option<i64>,
+}
+
+pub async fn record(
+    app: &App,
+    project: &str,
+    person: &str,
+    action: &str,
+    resource: &str,
+    detail: serde_json::Value,
+) -> Result<AuditLog, StoreAccessError> {
+    Ok(sqlx::query_as::<_, AuditLog>(
+        r#"INSERT INTO tokaysec.audit_log(id,project,person,action,resource,detail,occurred_when) VALUES($1,$2,$3,$4,$5,$6,$7) RETURNING *"#,
+    )
+    .bind(app.gen_id().await)
+    .bind(&project)
+    .bind(&person)
+    .bind(&action)
+    .bind(&resource)
+    .bind(&detail)
+    .bind(Utc::now())
+    .fetch_one(&app.database.inner)
+    .await?)
+}
+
+pub async fn list(
+    app: &App,
+    project: &str,
+    query: &Audit,
+) -> Result<Page<AuditLog>, StoreAccessError> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit < 1 || limit > MAX_PAGE_LIMIT {
+        return Err(StoreAccessError::Invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_LIMIT
+        )));
+    }
+    // Same keyset pagination as stores::list_rows, backwards.
+    let mut entries = sqlx::query_as::<_, AuditLog>(
+        r#"SELECT * FROM tokaysec.audit_log WHERE project = ($1) AND ($2::TEXT IS NULL OR id < ($2)) ORDER BY id DESC LIMIT ($3)"#,
+    )
+    .bind(&project)
+    .bind(&query.cursor)
+    .bind(limit + 1)
+    .fetch_all(&app.database.inner)
+    .await?;
+    let next_cursor = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(|e| e.id.to_owned())
+    } else {
+        None
+    };
+    Ok(Page {
+        items: entries,
+        next_cursor,
+    })
+}
@@ -0,0 +1,306 @@
+use serde::{Deserialize, Serialize};
+
+/*
+
+Bulk import and export of a project environment's kv secrets, so an
+app can be moved over (or its secrets taken out) in one request.
+
+    # .env
+    export DATABASE_URL=postgres://db/app
+    API_KEY="abc\ndef"     # double quotes take \n, \" and \\ escapes
+    GREETING='hello $USER' # single quotes are taken as they are
+
+JSON and YAML are a flat object of names to values. Numbers and
+booleans are stored as their text, anything nested is rejected since
+there is no obvious way back to the same shape.
+
+An import is all or nothing. Names already in the environment are
+skipped, overwritten or fail the whole import depending on the
+conflict mode, and everything is written in one transaction. Exports
+are always written back as text, a value that isn't UTF-8 fails the
+export instead of coming out mangled.
+
+*/
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkFormat {
+    #[serde(alias = "env")]
+    Dotenv,
+    Json,
+    #[serde(alias = "yml")]
+    Yaml,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictMode {
+    // Keep what is there, import the rest.
+    Skip,
+    // Replace the value, bumping its version.
+    Overwrite,
+    // Import nothing if any name is taken.
+    #[default]
+    Fail,
+}
+
+impl ToString for ConflictMode {
+    fn to_string(&self) -> String {
+        String::from(match self {
+            Self::Skip => "skip",
+            Self::Overwrite => "overwrite",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|e| e.is_ascii_alphabetic() || e == '_')
+        && chars.all(|e| e.is_ascii_alphanumeric() || e == '_' || e == '.' || e == '-')
+}
+
+fn unescape(quoted: &str) -> String {
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some('t') => value.push('\t'),
+            Some(other) => value.push(other),
+            None => value.push('\\'),
+        }
+    }
+    value
+}
+
+fn parse_dotenv(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = vec![];
+    let mut lines = contents.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, rest)) = line.split_once('=') else {
+            return Err(format!("line {}: expected NAME=value", number + 1));
+        };
+        let name = name.trim();
+        let rest = rest.trim_start();
+        let value = if let Some(quote) = rest.chars().next().filter(|e| *e == '"' || *e == '\'') {
+            // Quoted values may run over several lines.
+            let mut quoted = rest[1..].to_string();
+            loop {
+                let close = if quote == '"' {
+                    let mut escaped = false;
+                    quoted.char_indices().find_map(|(i, c)| {
+                        let found = c == '"' && !escaped;
+                        escaped = c == '\\' && !escaped;
+                        found.then_some(i)
+                    })
+                } else {
+                    quoted.find('\'')
+                };
+                if let Some(close) = close {
+                    let trailing = quoted[close + 1..].trim();
+                    if !trailing.is_empty() && !trailing.starts_with('#') {
+                        return Err(format!(
+                            "line {}: unexpected text after the closing quote",
+                            number + 1
+                        ));
+                    }
+                    quoted.truncate(close);
+                    break;
+                }
+                let Some((_, next)) = lines.next() else {
+                    return Err(format!("line {}: unterminated quote", number + 1));
+                };
+                quoted.push('\n');
+                quoted.push_str(next);
+            }
+            if quote == '"' {
+                unescape(&quoted)
+            } else {
+                quoted
+            }
+        } else {
+            // Unquoted, anything after " #" is a comment.
+            match rest.find(" #") {
+                Some(comment) => rest[..comment].trim_end().to_string(),
+                None => rest.trim_end().to_string(),
+            }
+        };
+        entries.push((name.to_string(), value));
+    }
+    Ok(entries)
+}
+
+fn parse_flat(value: serde_json::Value) -> Result<Vec<(String, String)>, String> {
+    let serde_json::Value::Object(keys) = value else {
+        return Err("the top level has to be an object of names to values".to_string());
+    };
+    keys.into_iter()
+        .map(|(name, value)| match value {
+            serde_json::Value::String(e) => Ok((name, e)),
+            serde_json::Value::Number(e) => Ok((name, e.to_string())),
+            serde_json::Value::Bool(e) => Ok((name, e.to_string())),
+            _ => Err(format!("'{}' has to be a string, number or boolean", name)),
+        })
+        .collect()
+}
+
+// Single quotes unless the value can't be written in them, dotenv
+// loaders expand $VARS inside double quotes.
+fn quote(value: &str) -> String {
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            other => quoted.push(other),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl BulkFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Dotenv => "text/plain; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Yaml => "application/yaml",
+        }
+    }
+    // JSON and YAML come back sorted by name, dotenv in file order.
+    // A name given twice in a dotenv file is left in, the import keeps
+    // its last value.
+    pub fn parse(&self, contents: &[u8]) -> Result<Vec<(String, String)>, String> {
+        let entries = match self {
+            Self::Dotenv => {
+                parse_dotenv(std::str::from_utf8(contents).map_err(|e| e.to_string())?)?
+            }
+            Self::Json => parse_flat(serde_json::from_slice(contents).map_err(|e| e.to_string())?)?,
+            Self::Yaml => parse_flat(serde_yaml::from_slice(contents).map_err(|e| e.to_string())?)?,
+        };
+        for (name, _) in &entries {
+            if !valid_name(name) {
+                return Err(format!(
+                    "'{}' isn't a valid name, use letters, digits, _ . and -",
+                    name
+                ));
+            }
+        }
+        Ok(entries)
+    }
+    pub fn render(&self, entries: &[(String, String)]) -> Result<String, String> {
+        match self {
+            Self::Dotenv => Ok(entries
+                .iter()
+                .map(|(name, value)| format!("{}={}\n", name, quote(value)))
+                .collect()),
+            Self::Json | Self::Yaml => {
+                let keys = entries
+                    .iter()
+                    .map(|(name, value)| (name.to_owned(), serde_json::json!(value)))
+                    .collect::<serde_json::Map<String, serde_json::Value>>();
+                match self {
+                    Self::Json => serde_json::to_string_pretty(&keys)
+                        .map(|e| e + "\n")
+                        .map_err(|e| e.to_string()),
+                    _ => serde_yaml::to_string(&keys).map_err(|e| e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_plain_and_quoted_values() {
+        let contents = "# comment\n\nexport DATABASE_URL=postgres://db/app # trailing\nPLAIN = spaced value\nSINGLE='hello $USER'\nDOUBLE=\"a\\nb \\\"q\\\"\"\n";
+        assert_eq!(
+            parse_dotenv(contents).unwrap(),
+            entries(&[
+                ("DATABASE_URL", "postgres://db/app"),
+                ("PLAIN", "spaced value"),
+                ("SINGLE", "hello $USER"),
+                ("DOUBLE", "a\nb \"q\""),
+            ])
+        );
+    }
+
+    #[test]
+    fn quoted_values_run_over_lines() {
+        assert_eq!(
+            parse_dotenv("MULTI=\"line1\nline2\" # done\nNEXT=1\n").unwrap(),
+            entries(&[("MULTI", "line1\nline2"), ("NEXT", "1")])
+        );
+    }
+
+    // Deduplicating and skipping empty values is up to the import.
+    #[test]
+    fn keeps_empty_and_repeated_names() {
+        assert_eq!(
+            parse_dotenv("A=\nB=''\nA=2\n").unwrap(),
+            entries(&[("A", ""), ("B", ""), ("A", "2")])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for (contents, error) in [
+            ("A=1\nNOPE\n", "line 2: expected NAME=value"),
+            ("A=\"abc\n", "line 1: unterminated quote"),
+            (
+                "A='abc' extra\n",
+                "line 1: unexpected text after the closing quote",
+            ),
+        ] {
+            assert_eq!(parse_dotenv(contents).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert!(BulkFormat::Dotenv.parse(b"1A=1\n").is_err());
+        assert!(BulkFormat::Dotenv.parse(b"A B=1\n").is_err());
+    }
+
+    #[test]
+    fn render_round_trips() {
+        let values = entries(&[
+            ("PLAIN", "value"),
+            ("SPACES", "  padded  "),
+            ("QUOTES", "it's \"quoted\""),
+            ("LINES", "one\ntwo\r\nthree"),
+            ("BACKSLASH", "C:\\path\\n"),
+            ("HASH", "a #b"),
+        ]);
+        let rendered = BulkFormat::Dotenv.render(&values).unwrap();
+        assert_eq!(parse_dotenv(&rendered).unwrap(), values);
+    }
+}
@@ -2,9 +2,19 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+fn enabled() -> bool {
+    true
+}
+
+// One `[stores.<name>]` table per store instance. Anything besides
+// `type` and `enabled` is handed to the store type as its settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StoreConfig {
     pub r#type: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub settings: serde_json::Map<String, serde_json::Value>,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KekConfig {
@@ -18,13 +28,111 @@ pub enum KMSProviders {
     Fs,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExpiryConfig {
+    // How far ahead of `expires_at` a secret is reported as expiring soon.
+    pub notice_days: i64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            notice_days: 14,
+            check_interval_secs: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DynamicPostgresConfig {
+    // How often expired leases are looked for and their roles dropped.
+    pub revoke_interval_secs: u64,
+}
+
+impl Default for DynamicPostgresConfig {
+    fn default() -> Self {
+        Self {
+            revoke_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GitSyncConfig {
+    // How often tracked repositories are fetched for new commits.
+    pub check_interval_secs: u64,
+    // Bare mirrors of the tracked repositories are kept here.
+    pub cache_dir: String,
+}
+
+impl Default for GitSyncConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_secs: 60,
+            cache_dir: "git_sync".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RotationConfig {
+    // How often rotation policies are checked for anything due.
+    pub check_interval_secs: u64,
+    // How long after a failed rotation it is tried again.
+    pub retry_after_secs: i64,
+    pub webhook_timeout_secs: u64,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_secs: 60,
+            retry_after_secs: 900,
+            webhook_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DekCacheConfig {
+    // Off unless asked for, every read goes to the KMS.
+    pub enabled: bool,
+    // How long an unwrapped DEK is kept, counted from when it was
+    // unwrapped rather than last used.
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+}
+
+impl Default for DekCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 300,
+            max_entries: 1024,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub kms: KMSProviders,
-    //pub stores: HashMap<String, StoreConfig>,
+    // Merged over the built in stores, see stores::default_store_configs.
+    #[serde(default)]
+    pub stores: HashMap<String, StoreConfig>,
     pub migrations: String,
     pub postgres: String,
     pub allow_kms_colocation: bool,
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+    #[serde(default)]
+    pub dynamic_postgres: DynamicPostgresConfig,
+    #[serde(default)]
+    pub git_sync: GitSyncConfig,
+    #[serde(default)]
+    pub rotation: RotationConfig,
+    #[serde(default)]
+    pub dek_cache: DekCacheConfig,
 }
 
 // Deny / Allow list is a list of
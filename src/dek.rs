@@ -37,7 +37,35 @@ impl Dek {
         nonce: Vec<u8>,
         gcm_tag: Vec<u8>,
     ) -> SecureBuffer {
+        self.try_unwrap_data(data, kmac_tag, name, nonce, gcm_tag)
+            .unwrap()
+    }
+
+    // Same as unwrap_data but hands back tag mismatches and bad
+    // ciphertext instead of panicking, for callers decrypting
+    // data that came from outside (transit).
+    pub fn try_unwrap_data(
+        &self,
+        data: Vec<u8>,
+        kmac_tag: Vec<u8>,
+        name: &str,
+        nonce: Vec<u8>,
+        gcm_tag: Vec<u8>,
+    ) -> Result<SecureBuffer, String> {
         let aad = format!("name={}", &name).into_bytes();
+        self.try_unwrap_with_aad(data, kmac_tag, &aad, nonce, gcm_tag)
+    }
+
+    // try_unwrap_data with the caller's AAD instead of one made from
+    // a name (see aad.rs).
+    pub fn try_unwrap_with_aad(
+        &self,
+        data: Vec<u8>,
+        kmac_tag: Vec<u8>,
+        aad: &[u8],
+        nonce: Vec<u8>,
+        gcm_tag: Vec<u8>,
+    ) -> Result<SecureBuffer, String> {
         let dek = self.__inner.expose();
         let mut aes_key = [0u8; 32];
         let mut kmac_key = [0u8; 32];
@@ -48,7 +76,7 @@ impl Dek {
         // Split end
         // Compute kmac
         let mut mac = Kmac::v256(&kmac_key, &[]);
-        for chunk in &[&data, &aad] {
+        for chunk in &[&data[..], aad] {
             mac.update(chunk);
         }
         // KMAC-256 over ciphertext + AAD
@@ -57,20 +85,20 @@ impl Dek {
         // Compute kmac end
         // Compare start
         if kmac_tag.ct_ne(&computed_kmac_tag).into() {
-            panic!("mismatch: {:?} != {:?}", kmac_tag, computed_kmac_tag);
+            return Err("KMAC tag mismatch".to_string());
         }
         let plaintext = decrypt_aead(
             Cipher::aes_256_gcm(),
             &aes_key,
             Some(&nonce),
-            &aad,
+            aad,
             &data,
             &gcm_tag,
         )
-        .unwrap();
+        .map_err(|e| e.to_string())?;
         let secure_buffer = SecureBuffer::from_slice(&plaintext).unwrap();
         drop(plaintext);
-        return secure_buffer;
+        return Ok(secure_buffer);
     }
 
     // takes raw data (data: SecureBuffer) and returns the
@@ -79,7 +107,13 @@ impl Dek {
     // secure buffer, zeroing out its memory.
     pub fn wrap_data(&self, data: SecureBuffer, name: String) -> DekWrapDataResult {
         let aad = format!("name={}", &name).into_bytes();
-        let aad_hash = sha3::Sha3_256::digest(&aad);
+        self.wrap_with_aad(data, &aad)
+    }
+
+    // wrap_data with the caller's AAD instead of one made from a name
+    // (see aad.rs).
+    pub fn wrap_with_aad(&self, data: SecureBuffer, aad: &[u8]) -> DekWrapDataResult {
+        let aad_hash = sha3::Sha3_256::digest(aad);
         // End AAD generation
         // FIRST DEK splitting
         let dek = self.__inner.expose();
@@ -100,13 +134,13 @@ impl Dek {
             Cipher::aes_256_gcm(),
             &aes_key,
             Some(&nonce),
-            &aad,
+            aad,
             &data.expose(),
             &mut gcm_tag,
         )
         .unwrap();
         let mut mac = Kmac::v256(&kmac_key, &[]);
-        for chunk in &[&ciphertext, &aad] {
+        for chunk in &[&ciphertext[..], aad] {
             mac.update(chunk);
         }
         // KMAC-256 over ciphertext + AAD
@@ -0,0 +1,495 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{app::App, config::DekCacheConfig, secure_buf::SecureBuffer, stores::StoreAccessError};
+
+/*
+
+Unwrapped DEKs kept in memory for a while so reading the same kv secret
+again doesn't go back to the KMS (a round trip to TokayKMS and a TPM
+decrypt) every time.
+
+Entries are keyed by the wrapped DEK's id and remember the AAD it was
+unwrapped under (see aad.rs). A lookup under any other AAD is a miss
+and goes to the KMS, which turns it down, so a DEK id copied onto
+another row is caught the same way with or without the cache.
+
+Each entry is its own SecureBuffer, so it sits in mlocked memory and is
+zeroed when it is dropped: when its TTL is up, when max_entries pushes
+out the least recently used one, when its value is replaced or deleted
+and when the cache is flushed. The TTL counts from when the DEK was
+unwrapped, a hot secret still goes to the KMS once per TTL. Expired
+entries are refused when looked up and swept out in the background
+(spawn_dek_cache_sweeper). If mlock fails (RLIMIT_MEMLOCK) the DEK
+just isn't cached.
+
+The cache is off unless `[dek_cache] enabled = true`. Projects can opt
+out (dek_cache_opt_outs), the admin decides which.
+
+*/
+
+struct CachedDek {
+    dek: SecureBuffer,
+    aad: String,
+    project: String,
+    unwrapped: Instant,
+    last_used: Instant,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct HitsAndMisses {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CachedDek>,
+    totals: HitsAndMisses,
+    projects: HashMap<String, HitsAndMisses>,
+    expired: u64,
+    evicted: u64,
+    invalidated: u64,
+    lock_failures: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DekCacheStats {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    // Dropped because their TTL was up.
+    pub expired: u64,
+    // Dropped to make room.
+    pub evicted: u64,
+    // Dropped because their value was replaced or deleted, or flushed.
+    pub invalidated: u64,
+    // DEKs that weren't cached because mlock failed.
+    pub lock_failures: u64,
+    pub projects: HashMap<String, HitsAndMisses>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProjectDekCacheStatus {
+    pub project: String,
+    // Whether this project's DEKs are cached, false if the cache is off.
+    pub cached: bool,
+    pub opted_out: bool,
+    #[serde(flatten)]
+    pub usage: HitsAndMisses,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProjectDekCacheRequest {
+    pub enabled: bool,
+}
+
+pub struct DekCache {
+    settings: DekCacheConfig,
+    state: Mutex<CacheState>,
+}
+
+impl DekCache {
+    pub fn new(settings: DekCacheConfig) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+    pub fn enabled(&self) -> bool {
+        self.settings.enabled && self.settings.max_entries > 0
+    }
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.settings.ttl_secs)
+    }
+    // A copy of the DEK, in a SecureBuffer of its own, if it was
+    // unwrapped under this AAD within the TTL.
+    fn get(&self, id: &str, aad: &str, project: &str) -> Option<SecureBuffer> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let now = Instant::now();
+        let found = match state.entries.get_mut(id) {
+            Some(entry) if now.duration_since(entry.unwrapped) >= self.ttl() => {
+                state.entries.remove(id);
+                state.expired += 1;
+                None
+            }
+            Some(entry) if entry.aad == aad => {
+                entry.last_used = now;
+                SecureBuffer::from_slice(entry.dek.expose()).ok()
+            }
+            _ => None,
+        };
+        let project_usage = state.projects.entry(project.to_string()).or_default();
+        for usage in [&mut state.totals, project_usage] {
+            match found {
+                Some(_) => usage.hits += 1,
+                None => usage.misses += 1,
+            }
+        }
+        found
+    }
+    fn insert(&self, id: &str, aad: &str, project: &str, dek: &SecureBuffer) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let Ok(copy) = SecureBuffer::from_slice(dek.expose()) else {
+            state.lock_failures += 1;
+            return;
+        };
+        let now = Instant::now();
+        if !state.entries.contains_key(id) && state.entries.len() >= self.settings.max_entries {
+            state.expired += Self::drop_expired(&mut state.entries, now, self.ttl());
+        }
+        while !state.entries.contains_key(id) && state.entries.len() >= self.settings.max_entries {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(id, _)| id.to_owned())
+            else {
+                break;
+            };
+            state.entries.remove(&oldest);
+            state.evicted += 1;
+        }
+        state.entries.insert(
+            id.to_string(),
+            CachedDek {
+                dek: copy,
+                aad: aad.to_string(),
+                project: project.to_string(),
+                unwrapped: now,
+                last_used: now,
+            },
+        );
+    }
+    fn drop_expired(entries: &mut HashMap<String, CachedDek>, now: Instant, ttl: Duration) -> u64 {
+        let before = entries.len();
+        entries.retain(|_, e| now.duration_since(e.unwrapped) < ttl);
+        (before - entries.len()) as u64
+    }
+    // Zeroes whatever has outlived the TTL, returns how many.
+    pub fn sweep(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let expired = Self::drop_expired(&mut state.entries, Instant::now(), self.ttl());
+        state.expired += expired;
+        expired
+    }
+    // For DEKs that were just deleted along with their value.
+    pub fn invalidate(&self, ids: &[String]) {
+        if !self.enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            if state.entries.remove(id).is_some() {
+                state.invalidated += 1;
+            }
+        }
+    }
+    fn invalidate_project(&self, project: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let before = state.entries.len();
+        state.entries.retain(|_, e| e.project != project);
+        let dropped = (before - state.entries.len()) as u64;
+        state.invalidated += dropped;
+        dropped
+    }
+    fn flush(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let dropped = state.entries.len() as u64;
+        state.entries.clear();
+        state.invalidated += dropped;
+        dropped
+    }
+    pub fn stats(&self) -> DekCacheStats {
+        let state = self.state.lock().unwrap();
+        DekCacheStats {
+            enabled: self.enabled(),
+            ttl_secs: self.settings.ttl_secs,
+            max_entries: self.settings.max_entries,
+            entries: state.entries.len(),
+            hits: state.totals.hits,
+            misses: state.totals.misses,
+            expired: state.expired,
+            evicted: state.evicted,
+            invalidated: state.invalidated,
+            lock_failures: state.lock_failures,
+            projects: state.projects.clone(),
+        }
+    }
+}
+
+// The cache as one project sees it, None if it doesn't use it.
+pub struct ProjectDekCache {
+    cache: Arc<DekCache>,
+    project: String,
+}
+
+impl ProjectDekCache {
+    pub fn get(&self, id: &str, aad: &str) -> Option<SecureBuffer> {
+        self.cache.get(id, aad, &self.project)
+    }
+    pub fn insert(&self, id: &str, aad: &str, dek: &SecureBuffer) {
+        self.cache.insert(id, aad, &self.project, dek)
+    }
+}
+
+async fn opted_out(app: &App, project: &str) -> Result<bool, StoreAccessError> {
+    Ok(sqlx::query_as::<_, (String,)>(
+        r#"SELECT project FROM tokaysec.dek_cache_opt_outs WHERE project = ($1)"#,
+    )
+    .bind(&project)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .is_some())
+}
+
+pub async fn for_project(
+    app: &App,
+    project: &str,
+) -> Result<Option<ProjectDekCache>, StoreAccessError> {
+    if !app.dek_cache.enabled() || opted_out(app, project).await? {
+        return Ok(None);
+    }
+    Ok(Some(ProjectDekCache {
+        cache: app.dek_cache.clone(),
+        project: project.to_string(),
+    }))
+}
+
+async fn project_exists(app: &App, project: &str) -> Result<(), StoreAccessError> {
+    sqlx::query_as::<_, (String,)>(r#"SELECT id FROM tokaysec.projects WHERE id = ($1)"#)
+        .bind(&project)
+        .fetch_optional(&app.database.inner)
+        .await?
+        .map(|_| ())
+        .ok_or_else(|| StoreAccessError::NotFound(format!("No project with id {}", project)))
+}
+
+async fn require_admin(app: &App, requester: &str) -> Result<(), StoreAccessError> {
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap_or_default();
+    if admin_id != requester {
+        return Err(StoreAccessError::Forbidden(
+            "Only the instance admin can see or change the DEK cache".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn stats(app: &App, requester: &str) -> Result<DekCacheStats, StoreAccessError> {
+    require_admin(app, requester).await?;
+    Ok(app.dek_cache.stats())
+}
+
+// Drops every cached DEK, returns how many there were.
+pub async fn flush(app: &App, requester: &str) -> Result<u64, StoreAccessError> {
+    require_admin(app, requester).await?;
+    Ok(app.dek_cache.flush())
+}
+
+pub async fn project_status(
+    app: &App,
+    project: &str,
+    requester: &str,
+) -> Result<ProjectDekCacheStatus, StoreAccessError> {
+    require_admin(app, requester).await?;
+    project_exists(app, project).await?;
+    let opted_out = opted_out(app, project).await?;
+    let usage = app
+        .dek_cache
+        .stats()
+        .projects
+        .remove(project)
+        .unwrap_or_default();
+    Ok(ProjectDekCacheStatus {
+        project: project.to_string(),
+        cached: app.dek_cache.enabled() && !opted_out,
+        opted_out,
+        usage,
+    })
+}
+
+// Opting out drops the project's cached DEKs right away.
+pub async fn set_project(
+    app: &App,
+    project: &str,
+    req: ProjectDekCacheRequest,
+    requester: &str,
+) -> Result<ProjectDekCacheStatus, StoreAccessError> {
+    require_admin(app, requester).await?;
+    project_exists(app, project).await?;
+    match req.enabled {
+        true => {
+            sqlx::query(r#"DELETE FROM tokaysec.dek_cache_opt_outs WHERE project = ($1)"#)
+                .bind(&project)
+                .execute(&app.database.inner)
+                .await?;
+        }
+        false => {
+            sqlx::query(
+                r#"INSERT INTO tokaysec.dek_cache_opt_outs(project,added_when,added_by) VALUES($1,$2,$3) ON CONFLICT (project) DO NOTHING"#,
+            )
+            .bind(&project)
+            .bind(Utc::now())
+            .bind(&requester)
+            .execute(&app.database.inner)
+            .await?;
+            app.dek_cache.invalidate_project(project);
+        }
+    }
+    project_status(app, project, requester).await
+}
+
+pub fn spawn_dek_cache_sweeper(app: App) {
+    if !app.dek_cache.enabled() {
+        return;
+    }
+    let ttl_secs = app.dek_cache.settings.ttl_secs;
+    info!(
+        "DEK cache is on, unwrapped DEKs are kept for up to {}s",
+        ttl_secs
+    );
+    tokio::spawn(async move {
+        // Often enough that nothing outlives its TTL by more than a
+        // quarter of it.
+        let mut interval = tokio::time::interval(Duration::from_secs((ttl_secs / 4).max(1)));
+        loop {
+            interval.tick().await;
+            let expired = app.dek_cache.sweep();
+            if expired > 0 {
+                debug!("Dropped {} expired DEKs from the cache", expired);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl_secs: u64, max_entries: usize) -> DekCache {
+        DekCache::new(DekCacheConfig {
+            enabled: true,
+            ttl_secs,
+            max_entries,
+        })
+    }
+
+    fn dek(byte: u8) -> SecureBuffer {
+        SecureBuffer::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn cached(cache: &DekCache, id: &str, aad: &str) -> Option<u8> {
+        cache.get(id, aad, "p1").map(|e| e.expose()[0])
+    }
+
+    #[test]
+    fn returns_what_was_inserted() {
+        let cache = cache(300, 8);
+        assert_eq!(cached(&cache, "a", "aad"), None);
+        cache.insert("a", "aad", "p1", &dek(1));
+        assert_eq!(cached(&cache, "a", "aad"), Some(1));
+        // Replacing keeps one entry.
+        cache.insert("a", "aad", "p1", &dek(2));
+        assert_eq!(cached(&cache, "a", "aad"), Some(2));
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn other_aad_is_a_miss() {
+        let cache = cache(300, 8);
+        cache.insert("a", "row-1", "p1", &dek(1));
+        assert_eq!(cached(&cache, "a", "row-2"), None);
+        // The entry itself stays for its own AAD.
+        assert_eq!(cached(&cache, "a", "row-1"), Some(1));
+    }
+
+    #[test]
+    fn expired_entries_are_refused_and_swept() {
+        let cache = cache(0, 8);
+        cache.insert("a", "aad", "p1", &dek(1));
+        assert_eq!(cached(&cache, "a", "aad"), None);
+        assert_eq!(cache.stats().expired, 1);
+        cache.insert("b", "aad", "p1", &dek(2));
+        cache.insert("c", "aad", "p1", &dek(3));
+        assert_eq!(cache.sweep(), 2);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.expired), (0, 3));
+    }
+
+    #[test]
+    fn sweep_keeps_live_entries() {
+        let cache = cache(300, 8);
+        cache.insert("a", "aad", "p1", &dek(1));
+        assert_eq!(cache.sweep(), 0);
+        assert_eq!(cached(&cache, "a", "aad"), Some(1));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = cache(300, 2);
+        cache.insert("a", "aad", "p1", &dek(1));
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert("b", "aad", "p1", &dek(2));
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(cached(&cache, "a", "aad"), Some(1));
+        cache.insert("c", "aad", "p1", &dek(3));
+        assert_eq!(cached(&cache, "b", "aad"), None);
+        assert_eq!(cached(&cache, "a", "aad"), Some(1));
+        assert_eq!(cached(&cache, "c", "aad"), Some(3));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evicted), (2, 1));
+    }
+
+    #[test]
+    fn invalidates_ids_projects_and_everything() {
+        let cache = cache(300, 8);
+        cache.insert("a", "aad", "p1", &dek(1));
+        cache.insert("b", "aad", "p1", &dek(2));
+        cache.insert("c", "aad", "p2", &dek(3));
+        cache.insert("d", "aad", "p2", &dek(4));
+        cache.invalidate(&["a".to_string(), "missing".to_string()]);
+        assert_eq!(cached(&cache, "a", "aad"), None);
+        assert_eq!(cache.invalidate_project("p2"), 2);
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.flush(), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.invalidated), (0, 4));
+    }
+
+    #[test]
+    fn counts_hits_and_misses_per_project() {
+        let cache = cache(300, 8);
+        cache.insert("a", "aad", "p1", &dek(1));
+        cache.get("a", "aad", "p1");
+        cache.get("a", "aad", "p1");
+        cache.get("a", "other", "p1");
+        cache.get("b", "aad", "p2");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!(stats.projects["p1"].hits, 2);
+        assert_eq!(stats.projects["p1"].misses, 1);
+        assert_eq!(stats.projects["p2"].misses, 1);
+    }
+
+    #[test]
+    fn disabled_without_room() {
+        assert!(cache(300, 8).enabled());
+        assert!(!cache(300, 0).enabled());
+        assert!(!DekCache::new(DekCacheConfig::default()).enabled());
+    }
+}
@@ -0,0 +1,36 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+// Anything interested in what is happening to secrets (audit,
+// notifications, webhooks down the line) subscribes to the
+// broadcast channel on App. Sending never blocks and if nobody
+// is listening the event is simply dropped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SecretEvent {
+    ExpiringSoon {
+        store: String,
+        id: String,
+        name: String,
+        project: String,
+        expires_at: DateTime<Utc>,
+    },
+    Rotated {
+        store: String,
+        id: String,
+        name: String,
+        project: String,
+        version: i32,
+    },
+    RotationFailed {
+        id: String,
+        project: String,
+        error: String,
+    },
+}
+
+pub fn channel() -> broadcast::Sender<SecretEvent> {
+    let (sender, _) = broadcast::channel(1024);
+    sender
+}
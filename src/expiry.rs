@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tracing::warn;
+
+use crate::{app::App, config::ExpiryConfig, events::SecretEvent};
+
+#[derive(sqlx::FromRow, Debug)]
+struct ExpiringSecret {
+    id: String,
+    key: String,
+    project: String,
+    store: String,
+    expires_at: chrono::DateTime<Utc>,
+}
+
+// Periodically looks for secrets that will expire inside the notice
+// window and emits a single ExpiringSoon event per secret. The
+// notified timestamp is what stops us re-announcing on every tick.
+pub fn spawn_expiry_notifier(app: App, config: ExpiryConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+        loop {
+            interval.tick().await;
+            notify_expiring(&app, &config).await;
+        }
+    });
+}
+
+async fn notify_expiring(app: &App, config: &ExpiryConfig) {
+    let now = Utc::now();
+    let horizon = now + chrono::Duration::days(config.notice_days);
+    let expiring = sqlx::query_as::<_, ExpiringSecret>(
+        r#"SELECT id, key, project, store, expires_at FROM tokaysec.kv_store
+        WHERE expires_at IS NOT NULL AND expires_at <= ($1) AND expiry_notified_when IS NULL"#,
+    )
+    .bind(horizon)
+    .fetch_all(&app.database.inner)
+    .await
+    .unwrap();
+    for secret in expiring {
+        warn!(
+            "Secret '{}' ({}) in project {} expires at {}",
+            secret.key, secret.id, secret.project, secret.expires_at
+        );
+        // Err only means there are no subscribers right now.
+        let _ = app.events.send(SecretEvent::ExpiringSoon {
+            store: secret.store,
+            id: secret.id.to_owned(),
+            name: secret.key,
+            project: secret.project,
+            expires_at: secret.expires_at,
+        });
+        sqlx::query(r#"UPDATE tokaysec.kv_store SET expiry_notified_when = ($1) WHERE id = ($2)"#)
+            .bind(now)
+            .bind(&secret.id)
+            .execute(&app.database.inner)
+            .await
+            .unwrap();
+    }
+}
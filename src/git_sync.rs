@@ -0,0 +1,598 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::Utc;
+use git2::{FileMode, Oid, PushOptions, RemoteCallbacks, Repository, Signature, Tree};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    app::App,
+    models::{GitSync, Person},
+    policies::AccessAction,
+    stores::{
+        StoreAccessError,
+        config::{ConfigStore, ConfigStoreStoreData},
+        require_project_action,
+    },
+};
+
+/*
+
+A git sync ties one project environment to one file in a git
+repository. Only local paths and file:// remotes are supported, there
+is no credential handling. The file is a flat table of config keys:
+
+    # config.toml
+    log_level = "debug"
+    replicas = 3
+    [feature_flags]
+    new_ui = true
+
+Every top level key is one entry in the config store, nested tables
+and arrays are stored as json values. Pulling writes a new config
+version (with `git:<sha>` as its source) for every key whose value
+differs from what is current. Exporting writes the current config of
+the environment back to the file and pushes it as a new commit on the
+branch. Keys are never deleted by either direction.
+
+The repository is fetched into a bare mirror under git_sync.cache_dir,
+we never touch a working tree. Pushing to a non-bare repository only
+works when the branch isn't the one checked out there.
+
+*/
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl TryFrom<&str> for SyncFormat {
+    type Error = String;
+
+    // From the file name, there is nothing else to go on.
+    fn try_from(file: &str) -> Result<Self, Self::Error> {
+        return Ok(match Path::new(file).extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::Toml,
+            Some("json") => Self::Json,
+            Some("yaml") | Some("yml") => Self::Yaml,
+            _ => {
+                return Err(format!(
+                    "Can't tell the format of '{}', use .toml, .json or .yaml",
+                    file
+                ));
+            }
+        });
+    }
+}
+
+impl SyncFormat {
+    pub fn parse(
+        &self,
+        contents: &[u8],
+    ) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+        let value: serde_json::Value = match self {
+            Self::Toml => {
+                let document = std::str::from_utf8(contents).map_err(|e| e.to_string())?;
+                toml::from_str(document).map_err(|e| e.to_string())?
+            }
+            Self::Json => serde_json::from_slice(contents).map_err(|e| e.to_string())?,
+            Self::Yaml => serde_yaml::from_slice(contents).map_err(|e| e.to_string())?,
+        };
+        match value {
+            serde_json::Value::Object(keys) => Ok(keys),
+            _ => Err("the top level has to be a table of keys".to_string()),
+        }
+    }
+    pub fn render(
+        &self,
+        keys: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<String, String> {
+        match self {
+            Self::Toml => toml::to_string_pretty(keys).map_err(|e| e.to_string()),
+            Self::Json => serde_json::to_string_pretty(keys)
+                .map(|e| e + "\n")
+                .map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::to_string(keys).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Only things we can reach without credentials.
+pub fn validate_repository(repository: &str) -> Result<(), String> {
+    if let Some((scheme, _)) = repository.split_once("://") {
+        if scheme != "file" {
+            return Err(format!(
+                "Only local paths and file:// repositories are supported, not {}://",
+                scheme
+            ));
+        }
+        return Ok(());
+    }
+    if !Path::new(repository).is_absolute() {
+        return Err("Local repositories have to be given as an absolute path".to_string());
+    }
+    Ok(())
+}
+
+// A relative path inside the repository, without any way out of it.
+pub fn validate_file(file: &str) -> Result<(), String> {
+    if file.is_empty()
+        || file.starts_with('/')
+        || file
+            .split('/')
+            .any(|e| e.is_empty() || e == "." || e == "..")
+    {
+        return Err(format!("'{}' is not a path inside the repository", file));
+    }
+    SyncFormat::try_from(file).map(|_| ())
+}
+
+fn mirror_path(app: &App, sync: &GitSync) -> PathBuf {
+    Path::new(&app.git_sync.cache_dir).join(format!("{}.git", &sync.id))
+}
+
+fn remote_ref(branch: &str) -> String {
+    format!("refs/remotes/origin/{}", branch)
+}
+
+// Opens (or creates) the bare mirror and fetches the branch into
+// refs/remotes/origin/<branch>. Blocking, run it off the runtime.
+fn fetch(path: &Path, repository: &str, branch: &str) -> Result<Repository, git2::Error> {
+    let repo = match Repository::open_bare(path) {
+        Ok(repo) => repo,
+        Err(_) => Repository::init_bare(path)?,
+    };
+    {
+        let mut remote = repo.remote_anonymous(repository)?;
+        let refspec = format!("+refs/heads/{}:{}", branch, remote_ref(branch));
+        remote.fetch(&[refspec.as_str()], None, None)?;
+    }
+    Ok(repo)
+}
+
+// The branch head and the file's contents in it, None if the branch
+// doesn't exist yet. A missing file is Some((sha, None)).
+fn read_file(
+    path: &Path,
+    repository: &str,
+    branch: &str,
+    file: &str,
+) -> Result<Option<(String, Option<Vec<u8>>)>, git2::Error> {
+    let repo = fetch(path, repository, branch)?;
+    let Ok(reference) = repo.find_reference(&remote_ref(branch)) else {
+        return Ok(None);
+    };
+    let commit = reference.peel_to_commit()?;
+    let contents = match commit.tree()?.get_path(Path::new(file)) {
+        Ok(entry) => Some(entry.to_object(&repo)?.peel_to_blob()?.content().to_vec()),
+        Err(_) => None,
+    };
+    Ok(Some((commit.id().to_string(), contents)))
+}
+
+// Rebuilds every tree on the way down to the file, leaving the rest of
+// the repository as it was.
+fn insert_blob(
+    repo: &Repository,
+    tree: Option<&Tree>,
+    components: &[&str],
+    blob: Oid,
+) -> Result<Oid, git2::Error> {
+    let mut builder = repo.treebuilder(tree)?;
+    let (name, rest) = components.split_first().unwrap();
+    if rest.is_empty() {
+        builder.insert(name, blob, FileMode::Blob.into())?;
+    } else {
+        let subtree = match tree.and_then(|e| e.get_name(name)) {
+            Some(entry) => entry.to_object(repo)?.into_tree().ok(),
+            None => None,
+        };
+        let oid = insert_blob(repo, subtree.as_ref(), rest, blob)?;
+        builder.insert(name, oid, FileMode::Tree.into())?;
+    }
+    builder.write()
+}
+
+// Commits the file on top of the fetched branch head and pushes it.
+// Returns the new head, or the current one when nothing changed.
+fn commit_file(
+    path: &Path,
+    repository: &str,
+    branch: &str,
+    file: &str,
+    contents: &[u8],
+    author: &Signature,
+    message: &str,
+) -> Result<(String, bool), git2::Error> {
+    let repo = fetch(path, repository, branch)?;
+    let parent = match repo.find_reference(&remote_ref(branch)) {
+        Ok(reference) => Some(reference.peel_to_commit()?),
+        Err(_) => None,
+    };
+    let parent_tree = match &parent {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let blob = repo.blob(contents)?;
+    let components = file.split('/').collect::<Vec<&str>>();
+    let tree = repo.find_tree(insert_blob(&repo, parent_tree.as_ref(), &components, blob)?)?;
+    if let (Some(parent), Some(parent_tree)) = (&parent, &parent_tree)
+        && parent_tree.id() == tree.id()
+    {
+        return Ok((parent.id().to_string(), false));
+    }
+    let parents = parent.iter().collect::<Vec<_>>();
+    let commit = repo.commit(None, author, author, message, &tree, &parents)?;
+    let local_ref = format!("refs/heads/{}", branch);
+    repo.reference(&local_ref, commit, true, message)?;
+    // Rejections (not a fast forward, checked out branch) only come
+    // back through this callback.
+    let mut rejected = None;
+    {
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.push_update_reference(|_, status| {
+            rejected = status.map(|e| e.to_string());
+            Ok(())
+        });
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let mut remote = repo.remote_anonymous(repository)?;
+        remote.push(
+            &[format!("{}:{}", &local_ref, &local_ref).as_str()],
+            Some(&mut options),
+        )?;
+    }
+    if let Some(rejected) = rejected {
+        return Err(git2::Error::from_str(&format!(
+            "push rejected: {}",
+            rejected
+        )));
+    }
+    repo.reference(&remote_ref(branch), commit, true, message)?;
+    Ok((commit.to_string(), true))
+}
+
+fn git_error(e: git2::Error) -> StoreAccessError {
+    StoreAccessError::Unavailable(format!("git: {}", e.message()))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullResult {
+    pub commit: Option<String>,
+    // Keys that got a new version from this commit.
+    pub updated: Vec<String>,
+    pub unchanged: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportResult {
+    pub commit: String,
+    // False when the file already had the current config.
+    pub created: bool,
+}
+
+async fn mark_synced(app: &App, sync: &GitSync, commit: &str) -> Result<(), StoreAccessError> {
+    sqlx::query(
+        r#"UPDATE tokaysec.git_sync SET last_commit = ($1), last_synced = ($2) WHERE id = ($3)"#,
+    )
+    .bind(&commit)
+    .bind(Utc::now())
+    .bind(&sync.id)
+    .execute(&app.database.inner)
+    .await?;
+    Ok(())
+}
+
+async fn config_store_enabled(app: &App) -> Result<(), StoreAccessError> {
+    if !app.stores.read().await.contains_key("config") {
+        return Err(StoreAccessError::Invalid(
+            "The config store is disabled".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Brings in the branch head if it's a commit we haven't seen. Writes
+// happen as whoever set the sync up, so their permissions still apply.
+pub async fn pull(app: &App, sync: &GitSync) -> Result<PullResult, StoreAccessError> {
+    config_store_enabled(app).await?;
+    let format = SyncFormat::try_from(sync.file.as_str()).map_err(StoreAccessError::Invalid)?;
+    let (path, repository, branch, file) = (
+        mirror_path(app, sync),
+        sync.repository.to_owned(),
+        sync.branch.to_owned(),
+        sync.file.to_owned(),
+    );
+    let read = tokio::task::spawn_blocking(move || read_file(&path, &repository, &branch, &file))
+        .await
+        .unwrap()
+        .map_err(git_error)?;
+    let Some((commit, contents)) = read else {
+        return Err(StoreAccessError::NotFound(format!(
+            "{} has no branch '{}'",
+            &sync.repository, &sync.branch
+        )));
+    };
+    if sync.last_commit.as_ref() == Some(&commit) {
+        return Ok(PullResult {
+            commit: Some(commit),
+            updated: vec![],
+            unchanged: 0,
+        });
+    }
+    let Some(contents) = contents else {
+        return Err(StoreAccessError::NotFound(format!(
+            "'{}' doesn't exist at {}",
+            &sync.file, &commit
+        )));
+    };
+    let keys = format.parse(&contents).map_err(|e| {
+        StoreAccessError::Invalid(format!("'{}' at {}: {}", &sync.file, &commit, e))
+    })?;
+    let config_store = ConfigStore::init().await;
+    let source = format!("git:{}", &commit);
+    let mut updated = vec![];
+    let mut unchanged = 0;
+    for (key, value) in keys {
+        let current = match config_store
+            .find_by_key(app, &sync.project, &sync.environment, &key)
+            .await
+        {
+            Some(existing) => config_store
+                .get_version(app, &existing.id, existing.current_version)
+                .await
+                .map(|e| e.value),
+            None => None,
+        };
+        if current.as_ref() == Some(&value) {
+            unchanged += 1;
+            continue;
+        }
+        config_store
+            .write(
+                app,
+                ConfigStoreStoreData {
+                    name: key.to_owned(),
+                    value,
+                    project: sync.project.to_owned(),
+                    environment: sync.environment.to_owned(),
+                    value_type: None,
+                },
+                &sync.added_by,
+                false,
+                Some(&source),
+            )
+            .await?;
+        updated.push(key);
+    }
+    mark_synced(app, sync, &commit).await?;
+    Ok(PullResult {
+        commit: Some(commit),
+        updated,
+        unchanged,
+    })
+}
+
+#[derive(sqlx::FromRow)]
+struct CurrentConfig {
+    key: String,
+    value: serde_json::Value,
+}
+
+// Writes the environment's current config to the file and pushes it.
+pub async fn export(
+    app: &App,
+    sync: &GitSync,
+    message: Option<String>,
+    requester: &str,
+) -> Result<ExportResult, StoreAccessError> {
+    config_store_enabled(app).await?;
+    require_project_action(app, &sync.project, requester, AccessAction::ReadConfig).await?;
+    let format = SyncFormat::try_from(sync.file.as_str()).map_err(StoreAccessError::Invalid)?;
+    let current = sqlx::query_as::<_, CurrentConfig>(
+        r#"SELECT c.key, v.value FROM tokaysec.config_store c
+        JOIN tokaysec.config_store_versions v ON v.config_id = c.id AND v.version = c.current_version
+        WHERE c.project = ($1) AND c.environment = ($2) ORDER BY c.key"#,
+    )
+    .bind(&sync.project)
+    .bind(&sync.environment)
+    .fetch_all(&app.database.inner)
+    .await?;
+    let keys = current
+        .into_iter()
+        .map(|e| (e.key, e.value))
+        .collect::<serde_json::Map<String, serde_json::Value>>();
+    let contents = format.render(&keys).map_err(|e| {
+        StoreAccessError::Invalid(format!("Can't write the config as {}: {}", &sync.file, e))
+    })?;
+    let person = sqlx::query_as::<_, Person>(r#"SELECT * FROM tokaysec.people WHERE id = ($1)"#)
+        .bind(&requester)
+        .fetch_one(&app.database.inner)
+        .await?;
+    let message = message.unwrap_or(format!(
+        "Export {} config of project {}",
+        &sync.environment, &sync.project
+    ));
+    let (path, repository, branch, file) = (
+        mirror_path(app, sync),
+        sync.repository.to_owned(),
+        sync.branch.to_owned(),
+        sync.file.to_owned(),
+    );
+    let requester = requester.to_string();
+    let (commit, created) = tokio::task::spawn_blocking(move || {
+        let author = Signature::now(&person.name, &format!("{}@tokaysec", &requester))?;
+        commit_file(
+            &path,
+            &repository,
+            &branch,
+            &file,
+            contents.as_bytes(),
+            &author,
+            &message,
+        )
+    })
+    .await
+    .unwrap()
+    .map_err(git_error)?;
+    // What we just pushed is already in the config store, the next
+    // pull shouldn't bring it back in.
+    mark_synced(app, sync, &commit).await?;
+    Ok(ExportResult { commit, created })
+}
+
+// Drops the mirror along with the sync, it's only a cache.
+pub async fn remove_mirror(app: &App, sync: &GitSync) {
+    let path = mirror_path(app, sync);
+    if path.exists()
+        && let Err(e) = tokio::fs::remove_dir_all(&path).await
+    {
+        warn!("Couldn't remove git mirror {}: {}", path.display(), e);
+    }
+}
+
+// Polls every tracked repository and pulls new commits. A repository
+// that can't be reached is tried again on the next tick.
+pub fn spawn_git_sync(app: App) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(app.git_sync.check_interval_secs));
+        loop {
+            interval.tick().await;
+            let syncs = sqlx::query_as::<_, GitSync>(r#"SELECT * FROM tokaysec.git_sync"#)
+                .fetch_all(&app.database.inner)
+                .await
+                .unwrap();
+            for sync in syncs {
+                match pull(&app, &sync).await {
+                    Ok(pulled) if !pulled.updated.is_empty() => info!(
+                        "Pulled {} config keys from {} into project {} ({})",
+                        pulled.updated.len(),
+                        &sync.repository,
+                        &sync.project,
+                        &sync.environment
+                    ),
+                    Ok(_) => {}
+                    Err(e) => warn!(
+                        "Git sync {} for project {} failed: {}",
+                        &sync.id,
+                        &sync.project,
+                        e.to_string()
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn keys() -> serde_json::Map<String, serde_json::Value> {
+        json!({
+            "log_level": "debug",
+            "replicas": 3,
+            "feature_flags": {"new_ui": true},
+            "hosts": ["a", "b"]
+        })
+        .as_object()
+        .unwrap()
+        .to_owned()
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(SyncFormat::try_from("config.toml"), Ok(SyncFormat::Toml));
+        assert_eq!(SyncFormat::try_from("a/b.json"), Ok(SyncFormat::Json));
+        assert_eq!(SyncFormat::try_from("c.yaml"), Ok(SyncFormat::Yaml));
+        assert_eq!(SyncFormat::try_from("c.yml"), Ok(SyncFormat::Yaml));
+        assert!(SyncFormat::try_from("config.ini").is_err());
+        assert!(SyncFormat::try_from("config").is_err());
+    }
+
+    #[test]
+    fn parses_every_format() {
+        let documents: [(SyncFormat, &str); 3] = [
+            (
+                SyncFormat::Toml,
+                "log_level = \"debug\"\nreplicas = 3\nhosts = [\"a\", \"b\"]\n[feature_flags]\nnew_ui = true\n",
+            ),
+            (
+                SyncFormat::Json,
+                r#"{"log_level": "debug", "replicas": 3, "hosts": ["a", "b"], "feature_flags": {"new_ui": true}}"#,
+            ),
+            (
+                SyncFormat::Yaml,
+                "log_level: debug\nreplicas: 3\nhosts: [a, b]\nfeature_flags:\n  new_ui: true\n",
+            ),
+        ];
+        for (format, document) in documents {
+            assert_eq!(
+                format.parse(document.as_bytes()),
+                Ok(keys()),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn top_level_has_to_be_a_table() {
+        assert!(SyncFormat::Json.parse(b"[1, 2]").is_err());
+        assert!(SyncFormat::Yaml.parse(b"just a string").is_err());
+        assert!(SyncFormat::Json.parse(b"{").is_err());
+        assert!(SyncFormat::Toml.parse(b"key = ").is_err());
+        assert!(SyncFormat::Toml.parse(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn render_round_trips() {
+        for format in [SyncFormat::Toml, SyncFormat::Json, SyncFormat::Yaml] {
+            let rendered = format.render(&keys()).unwrap();
+            assert_eq!(
+                format.parse(rendered.as_bytes()),
+                Ok(keys()),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn repositories_have_to_be_local() {
+        assert!(validate_repository("/srv/git/config.git").is_ok());
+        assert!(validate_repository("file:///srv/git/config.git").is_ok());
+        assert!(validate_repository("https://example.com/config.git").is_err());
+        assert!(validate_repository("ssh://git@example.com/config.git").is_err());
+        assert!(validate_repository("git@example.com:config.git").is_err());
+        assert!(validate_repository("relative/config.git").is_err());
+    }
+
+    #[test]
+    fn files_stay_inside_the_repository() {
+        assert!(validate_file("config.toml").is_ok());
+        assert!(validate_file("deploy/prod/config.yaml").is_ok());
+        for file in [
+            "",
+            "/etc/config.toml",
+            "../config.toml",
+            "deploy/../../config.toml",
+            "deploy/./config.toml",
+            "deploy//config.toml",
+            "deploy/",
+        ] {
+            assert!(validate_file(file).is_err(), "{}", file);
+        }
+        // Inside the repository but not a format we read.
+        assert!(validate_file("deploy/config.ini").is_err());
+    }
+}
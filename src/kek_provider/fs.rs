@@ -75,6 +75,17 @@ impl KekProvider for FileSystemKEKProvider {
         tag: [u8; 16],
         secret_name: &'a str,
     ) -> SecureBuffer {
+        self.try_unwrap_dek(dek, nonce, tag, secret_name)
+            .await
+            .unwrap()
+    }
+    async fn try_unwrap_dek<'a>(
+        &self,
+        dek: &'a [u8],
+        nonce: [u8; 12],
+        tag: [u8; 16],
+        secret_name: &'a str,
+    ) -> Result<SecureBuffer, String> {
         let dek_bytes = decrypt_aead(
             Cipher::aes_256_gcm(),
             &self._kek.expose(),
@@ -83,8 +94,8 @@ impl KekProvider for FileSystemKEKProvider {
             &dek,
             &tag,
         )
-        .unwrap();
-        return SecureBuffer::from_slice(&dek_bytes).unwrap();
+        .map_err(|e| e.to_string())?;
+        return Ok(SecureBuffer::from_slice(&dek_bytes).unwrap());
     }
     async fn wrap_dek<'a>(
         &self,
@@ -20,6 +20,18 @@ pub trait KekProvider: Send + Sync {
     ) -> SecureBuffer {
         unimplemented!()
     }
+    // unwrap_dek for callers that want a wrapped DEK that doesn't
+    // belong to secret_name turned down rather than a panic. Providers
+    // that can tell should override this.
+    async fn try_unwrap_dek<'a>(
+        &self,
+        dek: &'a [u8],
+        nonce: [u8; 12],
+        tag: [u8; 16],
+        secret_name: &'a str,
+    ) -> Result<SecureBuffer, String> {
+        Ok(self.unwrap_dek(dek, nonce, tag, secret_name).await)
+    }
     async fn wrap_dek<'a>(
         &self,
         _dek: Dek,
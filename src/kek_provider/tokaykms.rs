@@ -61,6 +61,19 @@ impl KekProvider for TokayKMSKEKProvider {
         tag: [u8; 16],
         secret_name: &'a str,
     ) -> SecureBuffer {
+        self.try_unwrap_dek(dek, nonce, tag, secret_name)
+            .await
+            .unwrap()
+    }
+    // The KMS decrypts with secret_name as AAD, so a DEK that isn't
+    // secret_name's comes back as an error status.
+    async fn try_unwrap_dek<'a>(
+        &self,
+        dek: &'a [u8],
+        nonce: [u8; 12],
+        tag: [u8; 16],
+        secret_name: &'a str,
+    ) -> Result<SecureBuffer, String> {
         #[derive(Serialize, Deserialize, Debug)]
         struct UnwrapDekResponse {
             unwrapped_dek: Vec<u8>,
@@ -80,12 +93,23 @@ impl KekProvider for TokayKMSKEKProvider {
             )
             .header("Content-Type", "application/json")
             .build()
-            .unwrap();
+            .map_err(|e| e.to_string())?;
 
-        let res: reqwest::Response = self._http.execute(req).await.unwrap();
-        let unwrapped_dek_response: UnwrapDekResponse = res.json().await.unwrap();
-        let buf = SecureBuffer::from_slice(&unwrapped_dek_response.unwrapped_dek).unwrap();
-        drop(unwrapped_dek_response);
+        let res: reqwest::Response = self
+            ._http
+            .execute(req)
+            .await
+            .map_err(|e| format!("KMS unwrap failed: {}", e))?;
+        if !res.status().is_success() {
+            return Err(format!("KMS refused to unwrap: {}", res.status()));
+        }
+        let mut unwrapped_dek_response: UnwrapDekResponse = res
+            .json()
+            .await
+            .map_err(|e| format!("Unexpected KMS unwrap response: {}", e))?;
+        let buf = SecureBuffer::from_slice(&unwrapped_dek_response.unwrapped_dek)
+            .map_err(|e| e.to_string());
+        unwrapped_dek_response.unwrapped_dek.zeroize();
         return buf;
     }
     async fn wrap_dek<'a>(
@@ -0,0 +1,426 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{App, ResourceTypes},
+    models::LimitsRow,
+    policies::AccessAction,
+    stores::{StoreAccessError, require_project_action},
+};
+
+/*
+
+Limits on what a project can put into the kv stores, set for the whole
+instance, for a namespace or for a single project.
+
+    max_value_bytes    largest single value
+    max_secrets        kv secrets in a project, across every kv
+                       instance and environment
+    max_versions       versions kept per secret, counting the current
+                       one. Older retained versions (rotation grace
+                       periods, migrated history) are dropped as new
+                       ones come in rather than the write being refused
+    max_request_bytes  request bodies, enforced before the handler runs
+                       (see routes::limits::enforce_request_size)
+
+The most specific level that sets a limit wins, so a namespace limit is
+what each of its projects gets unless the project has its own. Limits
+left unset everywhere don't apply, except the request size which falls
+back to axum's usual 2MB. A store instance's own max_value_bytes (see
+KvStoreSettings) applies on top.
+
+Only the instance admin sets limits. A value or request over a limit
+is a 413, running out of secrets a 429.
+
+*/
+
+// What axum allows without any configuration.
+pub const DEFAULT_MAX_REQUEST_BYTES: i64 = 2 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    #[serde(default)]
+    pub max_value_bytes: Option<i64>,
+    #[serde(default)]
+    pub max_secrets: Option<i64>,
+    #[serde(default)]
+    pub max_versions: Option<i32>,
+    #[serde(default)]
+    pub max_request_bytes: Option<i64>,
+}
+
+impl Limits {
+    // Ours where set, the level above's otherwise.
+    fn or(self, above: Limits) -> Limits {
+        Limits {
+            max_value_bytes: self.max_value_bytes.or(above.max_value_bytes),
+            max_secrets: self.max_secrets.or(above.max_secrets),
+            max_versions: self.max_versions.or(above.max_versions),
+            max_request_bytes: self.max_request_bytes.or(above.max_request_bytes),
+        }
+    }
+    fn validate(&self) -> Result<(), StoreAccessError> {
+        let set = [
+            ("max_value_bytes", self.max_value_bytes),
+            ("max_secrets", self.max_secrets),
+            ("max_versions", self.max_versions.map(i64::from)),
+            ("max_request_bytes", self.max_request_bytes),
+        ];
+        for (name, value) in set {
+            if let Some(value) = value
+                && value < 1
+            {
+                return Err(StoreAccessError::Invalid(format!(
+                    "{} has to be at least 1",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+    pub fn request_bytes(&self) -> i64 {
+        self.max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_BYTES)
+    }
+}
+
+impl From<LimitsRow> for Limits {
+    fn from(value: LimitsRow) -> Self {
+        Self {
+            max_value_bytes: value.max_value_bytes,
+            max_secrets: value.max_secrets,
+            max_versions: value.max_versions,
+            max_request_bytes: value.max_request_bytes,
+        }
+    }
+}
+
+pub enum LimitScope {
+    Instance,
+    Namespace(String),
+    Project(String),
+}
+
+impl LimitScope {
+    fn key(&self) -> (String, &str) {
+        match self {
+            LimitScope::Instance => (ResourceTypes::Instance.to_string(), ""),
+            LimitScope::Namespace(id) => (ResourceTypes::Namespace.to_string(), id),
+            LimitScope::Project(id) => (ResourceTypes::Project.to_string(), id),
+        }
+    }
+    async fn exists(&self, app: &App) -> Result<(), StoreAccessError> {
+        let (table, kind, id) = match self {
+            LimitScope::Instance => return Ok(()),
+            LimitScope::Namespace(id) => ("namespaces", "namespace", id),
+            LimitScope::Project(id) => ("projects", "project", id),
+        };
+        sqlx::query_as::<_, (String,)>(&format!(
+            r#"SELECT id FROM tokaysec.{} WHERE id = ($1)"#,
+            table
+        ))
+        .bind(&id)
+        .fetch_optional(&app.database.inner)
+        .await?
+        .map(|_| ())
+        .ok_or_else(|| StoreAccessError::NotFound(format!("No {} with id {}", kind, id)))
+    }
+}
+
+// Every level that applies to a project, and what comes out of them.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ProjectLimits {
+    pub project: String,
+    pub namespace: String,
+    pub effective: Limits,
+    pub instance_limits: Limits,
+    pub namespace_limits: Limits,
+    pub project_limits: Limits,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Usage {
+    pub secrets: i64,
+    // Current and retained previous versions.
+    pub versions: i64,
+    // The most versions any one secret has.
+    pub most_versions: i64,
+    // Encrypted, of every version.
+    pub stored_bytes: i64,
+    pub largest_value_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProjectUsage {
+    pub project: String,
+    pub usage: Usage,
+    pub limits: ProjectLimits,
+}
+
+async fn require_admin(app: &App, requester: &str) -> Result<(), StoreAccessError> {
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .map_err(StoreAccessError::Internal)?;
+    if admin_id != requester {
+        return Err(StoreAccessError::Forbidden(
+            "Only the instance admin can see or change limits".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// What is set at that level alone, nothing set is all None.
+pub async fn get(
+    app: &App,
+    scope: &LimitScope,
+    requester: &str,
+) -> Result<Limits, StoreAccessError> {
+    require_admin(app, requester).await?;
+    scope.exists(app).await?;
+    let (scope, scope_id) = scope.key();
+    Ok(sqlx::query_as::<_, LimitsRow>(
+        r#"SELECT * FROM tokaysec.limits WHERE scope = ($1) AND scope_id = ($2)"#,
+    )
+    .bind(&scope)
+    .bind(&scope_id)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .map(Limits::from)
+    .unwrap_or_default())
+}
+
+// Replaces everything set at that level, None hands a limit back to
+// the level above.
+pub async fn set(
+    app: &App,
+    scope: &LimitScope,
+    limits: Limits,
+    requester: &str,
+) -> Result<LimitsRow, StoreAccessError> {
+    require_admin(app, requester).await?;
+    limits.validate()?;
+    scope.exists(app).await?;
+    let (scope, scope_id) = scope.key();
+    Ok(sqlx::query_as::<_, LimitsRow>(
+        r#"INSERT INTO tokaysec.limits(scope,scope_id,max_value_bytes,max_secrets,max_versions,max_request_bytes,updated_when,updated_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8)
+        ON CONFLICT (scope, scope_id) DO UPDATE SET max_value_bytes = ($3), max_secrets = ($4), max_versions = ($5), max_request_bytes = ($6), updated_when = ($7), updated_by = ($8) RETURNING *"#,
+    )
+    .bind(&scope)
+    .bind(&scope_id)
+    .bind(limits.max_value_bytes)
+    .bind(limits.max_secrets)
+    .bind(limits.max_versions)
+    .bind(limits.max_request_bytes)
+    .bind(Utc::now())
+    .bind(&requester)
+    .fetch_one(&app.database.inner)
+    .await?)
+}
+
+pub async fn clear(app: &App, scope: &LimitScope, requester: &str) -> Result<(), StoreAccessError> {
+    require_admin(app, requester).await?;
+    let (scope, scope_id) = scope.key();
+    sqlx::query(r#"DELETE FROM tokaysec.limits WHERE scope = ($1) AND scope_id = ($2)"#)
+        .bind(&scope)
+        .bind(&scope_id)
+        .execute(&app.database.inner)
+        .await?;
+    Ok(())
+}
+
+// No permission check, this is what the stores go by.
+pub async fn for_project(app: &App, project: &str) -> Result<ProjectLimits, StoreAccessError> {
+    let Some((namespace,)) = sqlx::query_as::<_, (String,)>(
+        r#"SELECT namespace FROM tokaysec.projects WHERE id = ($1)"#,
+    )
+    .bind(&project)
+    .fetch_optional(&app.database.inner)
+    .await?
+    else {
+        return Err(StoreAccessError::NotFound(format!(
+            "No project with id {}",
+            project
+        )));
+    };
+    let rows = sqlx::query_as::<_, LimitsRow>(
+        r#"SELECT * FROM tokaysec.limits WHERE (scope = ($1) AND scope_id = '') OR (scope = ($2) AND scope_id = ($3)) OR (scope = ($4) AND scope_id = ($5))"#,
+    )
+    .bind(ResourceTypes::Instance.to_string())
+    .bind(ResourceTypes::Namespace.to_string())
+    .bind(&namespace)
+    .bind(ResourceTypes::Project.to_string())
+    .bind(&project)
+    .fetch_all(&app.database.inner)
+    .await?;
+    let mut limits = ProjectLimits {
+        project: project.to_string(),
+        namespace,
+        ..Default::default()
+    };
+    for row in rows {
+        match ResourceTypes::try_from(row.scope.as_str()) {
+            Ok(ResourceTypes::Instance) => limits.instance_limits = row.into(),
+            Ok(ResourceTypes::Namespace) => limits.namespace_limits = row.into(),
+            Ok(ResourceTypes::Project) => limits.project_limits = row.into(),
+            _ => {}
+        }
+    }
+    limits.effective = limits
+        .project_limits
+        .or(limits.namespace_limits)
+        .or(limits.instance_limits);
+    Ok(limits)
+}
+
+pub async fn effective(app: &App, project: &str) -> Result<Limits, StoreAccessError> {
+    Ok(for_project(app, project).await?.effective)
+}
+
+// For requests that aren't about a project (or a namespace), only the
+// instance limit applies.
+pub async fn for_namespace(app: &App, namespace: Option<&str>) -> Result<Limits, StoreAccessError> {
+    let rows = sqlx::query_as::<_, LimitsRow>(
+        r#"SELECT * FROM tokaysec.limits WHERE (scope = ($1) AND scope_id = '') OR (scope = ($2) AND scope_id = ($3))"#,
+    )
+    .bind(ResourceTypes::Instance.to_string())
+    .bind(ResourceTypes::Namespace.to_string())
+    .bind(&namespace)
+    .fetch_all(&app.database.inner)
+    .await?;
+    let (mut instance, mut namespace) = (Limits::default(), Limits::default());
+    for row in rows {
+        if row.scope == ResourceTypes::Instance.to_string() {
+            instance = row.into();
+        } else {
+            namespace = row.into();
+        }
+    }
+    Ok(namespace.or(instance))
+}
+
+// The largest request any project could be allowed, bodies are read
+// up to this before we know which project they are for.
+pub async fn request_ceiling(app: &App) -> Result<i64, StoreAccessError> {
+    let instance = for_namespace(app, None).await?.request_bytes();
+    let (largest,) = sqlx::query_as::<_, (Option<i64>,)>(
+        r#"SELECT MAX(max_request_bytes) FROM tokaysec.limits"#,
+    )
+    .fetch_one(&app.database.inner)
+    .await?;
+    Ok(instance.max(largest.unwrap_or(0)))
+}
+
+pub fn check_value_size(
+    limits: &Limits,
+    project: &str,
+    size: usize,
+) -> Result<(), StoreAccessError> {
+    if let Some(max_value_bytes) = limits.max_value_bytes
+        && size as i64 > max_value_bytes
+    {
+        return Err(StoreAccessError::TooLarge(format!(
+            "Values in project {} can't be larger than {} bytes, this one is {}",
+            project, max_value_bytes, size
+        )));
+    }
+    Ok(())
+}
+
+// Call inside the transaction adding the secrets. The project row
+// stays locked until it commits so concurrent adds can't both squeeze
+// under the limit.
+pub async fn check_secret_count(
+    conn: &mut sqlx::PgConnection,
+    limits: &Limits,
+    project: &str,
+    adding: usize,
+) -> Result<(), StoreAccessError> {
+    let Some(max_secrets) = limits.max_secrets else {
+        return Ok(());
+    };
+    if adding == 0 {
+        return Ok(());
+    }
+    sqlx::query(r#"SELECT id FROM tokaysec.projects WHERE id = ($1) FOR UPDATE"#)
+        .bind(&project)
+        .execute(&mut *conn)
+        .await?;
+    let (secrets,) = sqlx::query_as::<_, (i64,)>(
+        r#"SELECT COUNT(*) FROM tokaysec.kv_store WHERE project = ($1)"#,
+    )
+    .bind(&project)
+    .fetch_one(&mut *conn)
+    .await?;
+    if secrets + adding as i64 > max_secrets {
+        return Err(StoreAccessError::OverQuota(format!(
+            "Project {} is limited to {} secrets and has {}, adding {} would go over",
+            project, max_secrets, secrets, adding
+        )));
+    }
+    Ok(())
+}
+
+// Drops the oldest retained versions of a secret (and their DEKs) past
+// max_versions.
+pub async fn prune_versions(
+    conn: &mut sqlx::PgConnection,
+    limits: &Limits,
+    secret_id: &str,
+) -> Result<u64, StoreAccessError> {
+    let Some(max_versions) = limits.max_versions else {
+        return Ok(0);
+    };
+    // The current version is one of them.
+    let keep = (max_versions - 1) as i64;
+    let pruned = sqlx::query_as::<_, (String,)>(
+        r#"DELETE FROM tokaysec.kv_store_previous WHERE secret_id = ($1) AND version NOT IN (SELECT version FROM tokaysec.kv_store_previous WHERE secret_id = ($1) ORDER BY version DESC LIMIT ($2)) RETURNING dek_used"#,
+    )
+    .bind(&secret_id)
+    .bind(keep)
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|(e,)| e)
+    .collect::<Vec<String>>();
+    sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ANY($1)"#)
+        .bind(&pruned)
+        .execute(&mut *conn)
+        .await?;
+    Ok(pruned.len() as u64)
+}
+
+pub async fn usage(
+    app: &App,
+    project: &str,
+    requester: &str,
+) -> Result<ProjectUsage, StoreAccessError> {
+    // Before the permission check so an unknown project is a 404.
+    let limits = for_project(app, project).await?;
+    require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+    let (secrets, current_bytes, largest_value_bytes) = sqlx::query_as::<_, (i64, i64, i32)>(
+        r#"SELECT COUNT(*), COALESCE(SUM(octet_length(value)), 0)::BIGINT, COALESCE(MAX(octet_length(value)), 0) FROM tokaysec.kv_store WHERE project = ($1)"#,
+    )
+    .bind(&project)
+    .fetch_one(&app.database.inner)
+    .await?;
+    let (previous, previous_bytes, most_previous) = sqlx::query_as::<_, (i64, i64, i64)>(
+        r#"SELECT COUNT(*), COALESCE(SUM(octet_length(p.value)), 0)::BIGINT, COALESCE(MAX(c.versions), 0) FROM tokaysec.kv_store_previous p
+        JOIN tokaysec.kv_store k ON k.id = p.secret_id
+        JOIN (SELECT secret_id, COUNT(*) AS versions FROM tokaysec.kv_store_previous GROUP BY secret_id) c ON c.secret_id = p.secret_id
+        WHERE k.project = ($1)"#,
+    )
+    .bind(&project)
+    .fetch_one(&app.database.inner)
+    .await?;
+    Ok(ProjectUsage {
+        project: project.to_string(),
+        usage: Usage {
+            secrets,
+            versions: secrets + previous,
+            most_versions: if secrets == 0 { 0 } else { most_previous + 1 },
+            stored_bytes: current_bytes + previous_bytes,
+            largest_value_bytes: largest_value_bytes as i64,
+        },
+        limits,
+    })
+}
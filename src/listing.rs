@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    app::{App, ResourceTypes},
+    models::Project,
+    policies::AccessAction,
+    references,
+    stores::{
+        DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT, Page, RetrievedSecretData, StoreAccessError,
+        require_project_action,
+    },
+};
+
+/*
+
+A project's secrets across every store, for /v1/projects/<id>/secrets.
+
+The project's resource_assignments are walked in resource order, the
+cursor being the last `<store>:<id>` handed out. Store, creator and tag
+filters are part of that query. Names and update times live in each
+store's own table, so a batch of assignments is looked up with one
+get_many per store and the name and updated_since filters apply to
+what comes back. Batches carry on until the page is full, so a
+narrow search may read a few batches for one page but never one
+secret at a time.
+
+*/
+
+// Assignments read per round trip.
+const BATCH_SIZE: i64 = 500;
+
+#[derive(Deserialize, Default)]
+pub struct SecretSearch {
+    // `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub limit: Option<i64>,
+    // Names starting with this.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // Names containing this, ignoring case.
+    #[serde(default)]
+    pub search: Option<String>,
+    #[serde(default)]
+    pub store: Option<String>,
+    // Comma separated, a secret needs all of them.
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub updated_since: Option<DateTime<Utc>>,
+}
+
+impl SecretSearch {
+    fn tags(&self) -> Vec<String> {
+        self.tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty())
+            .collect()
+    }
+    fn matches(&self, data: &RetrievedSecretData) -> bool {
+        if let Some(prefix) = &self.prefix
+            && !data.name.starts_with(prefix.as_str())
+        {
+            return false;
+        }
+        if let Some(search) = &self.search
+            && !data.name.to_lowercase().contains(&search.to_lowercase())
+        {
+            return false;
+        }
+        if let Some(since) = self.updated_since
+            && !data.updated_when.is_some_and(|e| e >= since)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Serialize)]
+pub struct ListedSecret {
+    #[serde(skip)]
+    resource: String,
+    pub id: String,
+    pub name: String,
+    pub store_used: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    pub tags: Vec<String>,
+    pub created_by: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_when: Option<DateTime<Utc>>,
+    // Set for references, see references.rs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub linked_from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+}
+
+#[derive(FromRow)]
+struct ListedAssignment {
+    resource: String,
+    linked_from: Option<String>,
+    created_by: String,
+    tags: Vec<String>,
+}
+
+async fn assignments(
+    app: &App,
+    project: &Project,
+    search: &SecretSearch,
+    tags: &[String],
+    after: &str,
+) -> Result<Vec<ListedAssignment>, StoreAccessError> {
+    // A reference's creator is whoever added the secret in the owning
+    // project. DISTINCT ON keeps one row for a secret linked both
+    // directly and through the namespace.
+    Ok(sqlx::query_as::<_, ListedAssignment>(
+        r#"SELECT * FROM (
+            SELECT DISTINCT ON (a.resource) a.resource, a.linked_from,
+            CASE WHEN a.linked_from IS NULL THEN a.assigned_by ELSE COALESCE((SELECT o.assigned_by FROM tokaysec.resource_assignment o
+                WHERE o.resource = a.resource AND o.assigned_to = a.linked_from AND o.assigned_to_type = 'proj' AND o.linked_from IS NULL LIMIT 1), a.assigned_by) END AS created_by,
+            ARRAY(SELECT t.tag FROM tokaysec.secret_tags t WHERE t.resource = a.resource ORDER BY t.tag) AS tags
+            FROM tokaysec.resource_assignment a
+            WHERE ((a.assigned_to = ($1) AND a.assigned_to_type = 'proj') OR (a.assigned_to = ($2) AND a.assigned_to_type = 'nmsp' AND a.linked_from IS NOT NULL))
+            AND a.resource_type IN ('scrt', 'cnfg') AND a.resource > ($3)
+            AND ($4::TEXT IS NULL OR split_part(a.resource, ':', 1) = ($4))
+            AND (cardinality($5::TEXT[]) = 0 OR a.resource IN (SELECT t.resource FROM tokaysec.secret_tags t WHERE t.tag = ANY($5) GROUP BY t.resource HAVING count(*) = cardinality($5::TEXT[])))
+            ORDER BY a.resource, a.linked_from NULLS FIRST
+        ) listed WHERE ($6::TEXT IS NULL OR created_by = ($6)) ORDER BY resource LIMIT ($7)"#,
+    )
+    .bind(&project.id)
+    .bind(&project.namespace)
+    .bind(&after)
+    .bind(&search.store)
+    .bind(tags)
+    .bind(&search.created_by)
+    .bind(BATCH_SIZE)
+    .fetch_all(&app.database.inner)
+    .await?)
+}
+
+pub async fn list_secrets(
+    app: &App,
+    project: &str,
+    search: &SecretSearch,
+    requester: &str,
+) -> Result<Page<ListedSecret>, StoreAccessError> {
+    let limit = search.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit < 1 || limit > MAX_PAGE_LIMIT {
+        return Err(StoreAccessError::Invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_LIMIT
+        )));
+    }
+    let project =
+        sqlx::query_as::<_, Project>(r#"SELECT * FROM tokaysec.projects WHERE id = ($1)"#)
+            .bind(&project)
+            .fetch_optional(&app.database.inner)
+            .await?
+            .ok_or(StoreAccessError::NotFound(format!(
+                "No project {}",
+                project
+            )))?;
+    // Config values only come back to whoever could read them from the
+    // config store itself.
+    let read_config = require_project_action(app, &project.id, requester, AccessAction::ReadConfig)
+        .await
+        .is_ok();
+    let tags = search.tags();
+    let stores = app.stores.read().await;
+    let mut after = search.cursor.to_owned().unwrap_or_default();
+    let mut items: Vec<ListedSecret> = vec![];
+    // One past the limit tells us whether there is another page.
+    while items.len() as i64 <= limit {
+        let batch = assignments(app, &project, search, &tags, &after).await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = last.resource.to_owned();
+        let mut ids: HashMap<&str, Vec<String>> = HashMap::new();
+        for assignment in &batch {
+            if let Some((store, id)) = assignment.resource.split_once(':') {
+                ids.entry(store).or_default().push(id.to_string());
+            }
+        }
+        let mut found: HashMap<String, RetrievedSecretData> = HashMap::new();
+        for (store, ids) in ids {
+            // The store may have been disabled since the secret was added.
+            let Some(secret_store) = stores.get(store) else {
+                continue;
+            };
+            for data in secret_store.get_many(app, &ids).await? {
+                found.insert(format!("{}:{}", store, data.id), data);
+            }
+        }
+        let exhausted = (batch.len() as i64) < BATCH_SIZE;
+        for assignment in batch {
+            let Some(data) = found.remove(&assignment.resource) else {
+                continue;
+            };
+            if !search.matches(&data) {
+                continue;
+            }
+            let store_used = assignment
+                .resource
+                .split_once(':')
+                .map(|e| e.0.to_string())
+                .unwrap_or_default();
+            items.push(ListedSecret {
+                id: data.id,
+                name: data.name,
+                store_used,
+                environment: data.environment,
+                tags: assignment.tags,
+                created_by: assignment.created_by,
+                updated_when: data.updated_when,
+                // Config is not sensitive so it comes back with its
+                // value, except through a reference which is read
+                // through /references so the owner sees it in its
+                // audit log.
+                value: data
+                    .value
+                    .filter(|_| read_config && assignment.linked_from.is_none()),
+                linked_from: assignment.linked_from,
+                resource: assignment.resource,
+            });
+            if items.len() as i64 > limit {
+                break;
+            }
+        }
+        if exhausted {
+            break;
+        }
+    }
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|e| e.resource.to_owned())
+    } else {
+        None
+    };
+    Ok(Page { items, next_cursor })
+}
+
+#[derive(Deserialize)]
+pub struct SetTags {
+    pub store: String,
+    pub id: String,
+    pub tags: Vec<String>,
+}
+
+// Replaces the secret's tags, only in the project owning it.
+pub async fn set_tags(
+    app: &App,
+    project: &str,
+    request: &SetTags,
+    requester: &str,
+) -> Result<Vec<String>, StoreAccessError> {
+    let resource = format!("{}:{}", request.store, request.id);
+    let owned = references::owner(app, &resource).await?;
+    if owned.assigned_to != project {
+        return Err(StoreAccessError::NotFound(format!(
+            "Project {} has no secret {}",
+            project, resource
+        )));
+    }
+    let action = if owned.resource_type == ResourceTypes::Config.to_string() {
+        AccessAction::UpdateConfig
+    } else {
+        AccessAction::UpdateSecret
+    };
+    require_project_action(app, project, requester, action).await?;
+    let mut tags: Vec<String> = request
+        .tags
+        .iter()
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+    if tags.iter().any(|e| e.contains(',')) {
+        return Err(StoreAccessError::Invalid(
+            "Tags can't contain commas".to_string(),
+        ));
+    }
+    tags.sort();
+    tags.dedup();
+    let mut tx = app.database.inner.begin().await?;
+    sqlx::query(r#"DELETE FROM tokaysec.secret_tags WHERE resource = ($1)"#)
+        .bind(&resource)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"INSERT INTO tokaysec.secret_tags(resource,tag,added_by) SELECT ($1), UNNEST($2::TEXT[]), ($3)"#,
+    )
+    .bind(&resource)
+    .bind(&tags)
+    .bind(&requester)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(tags)
+}
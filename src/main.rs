@@ -1,14 +1,28 @@
+mod aad;
 mod app;
 mod audit;
+mod bulk;
 mod config;
 mod db;
 mod dek;
+mod dek_cache;
+mod events;
+mod expiry;
+mod git_sync;
 mod kek_provider;
+mod limits;
+mod listing;
+mod migrate;
 mod models;
 mod policies;
+mod promotion;
+mod references;
+mod rotation;
 mod routes;
 mod secure_buf;
+mod share;
 mod stores;
+mod transit;
 
 use aes_gcm::{
     Aes256Gcm, Nonce,
@@ -57,6 +71,25 @@ use crate::{
     },
 };
 
+// Granted to the default role on first boot and backfilled on every
+// boot after that.
+const DEFAULT_ROLE_ACTIONS: [AccessAction; 14] = [
+    AccessAction::ReadSecret,
+    AccessAction::CreateConfig,
+    AccessAction::UpdateConfig,
+    AccessAction::DeleteConfig,
+    AccessAction::ReadConfig,
+    AccessAction::ManagePki,
+    AccessAction::IssueCertificate,
+    AccessAction::ManageSshCa,
+    AccessAction::SignSshCertificate,
+    AccessAction::ManageTransitKey,
+    AccessAction::TransitEncrypt,
+    AccessAction::TransitDecrypt,
+    AccessAction::ShareSecret,
+    AccessAction::ApprovePromotion,
+];
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -134,6 +167,8 @@ HOST IF RUNNING TOKAY-KMS. YOU'VE BEEN WARNED!.\x1B[0m
             .await
             .unwrap(),
     );
+    let expiry_config = config.expiry.clone();
+    let dynamic_postgres_config = config.dynamic_postgres.clone();
     let app = App::init(db, config).await;
     if !app
         .get_config_value::<bool>("intially_initialized")
@@ -251,6 +286,15 @@ HOST IF RUNNING TOKAY-KMS. YOU'VE BEEN WARNED!.\x1B[0m
         )
         .await
         .unwrap();
+        app.create_policy_rule_target(
+            &target,
+            app::PolicyRuleTargetAction::Allow,
+            &format!("perm:{}", AccessAction::ReadSecret.to_string()),
+        )
+        .await
+        .unwrap();
+        // config, pki, ssh, transit and sharing related permissions are
+        // granted by the backfill below.
         // Now that everything has intialized we will set this true.
         app.set_config_value("intially_initialized", true)
             .await
@@ -261,6 +305,51 @@ HOST IF RUNNING TOKAY-KMS. YOU'VE BEEN WARNED!.\x1B[0m
         info!("Initial initialization is complete!");
     }
 
+    // The default role only got what existed when the instance was first
+    // set up. Grant it anything added since, missing rules only.
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    if let Some(default_role) = app.find_role("default", &admin_id).await.unwrap() {
+        let target = format!("role:{}", &default_role.id);
+        for action in DEFAULT_ROLE_ACTIONS {
+            app.ensure_policy_rule_target(
+                &target,
+                app::PolicyRuleTargetAction::Allow,
+                &format!("perm:{}", action.to_string()),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    // kv values written before their AAD named the row they are in
+    // (see aad.rs).
+    let kek_provider = app.kek_provider.to_owned();
+    let migrated = stores::kv::migrate_aad(&app, (*kek_provider).as_ref())
+        .await
+        .unwrap();
+    if migrated > 0 {
+        info!("Re-encrypted {} kv values under the new AAD", migrated);
+    }
+
+    // `tokaysec import ...` runs the importer (see migrate.rs) and
+    // exits instead of serving.
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.get(1).is_some_and(|e| e == "import") {
+        if let Err(e) = migrate::run_cli(&app, &args[2..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    expiry::spawn_expiry_notifier(app.clone(), expiry_config);
+    stores::dynamic_postgres::spawn_lease_revoker(app.clone(), dynamic_postgres_config);
+    git_sync::spawn_git_sync(app.clone());
+    rotation::spawn_rotation_scheduler(app.clone());
+    dek_cache::spawn_dek_cache_sweeper(app.clone());
     let routes = generate_routers(app).await;
     let addr = SocketAddr::from(([0, 0, 0, 0], 2323));
     info!("Starting on: {addr:?}");
@@ -0,0 +1,1258 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{self, App, EasyResource, ResourceTypes},
+    models::{Namespace, Project},
+    stores::kv::{DEFAULT_ENVIRONMENT, KV_STORE},
+};
+
+/*
+
+Offline importer for moving off other secret managers, run instead of
+the server:
+
+    tokaysec import <vault|1password|bitwarden> <file> [--dry-run]
+        [--namespace <name>] [--environment <env>] [--store <kv instance>]
+
+Everything lands in a kv store as text, one secret per value.
+
+    Vault KV v2     <namespace>/<project>/<rest of the path> in the
+                    dump, each key of the path's data becomes the
+                    secret <rest of the path>/<key>. The dump is an
+                    object of paths (relative to the mount) to either
+                    the data itself, `vault kv get -format=json`'s
+                    .data ({data, metadata}) or {metadata, versions}
+                    with `vault kv metadata get`'s .data and every
+                    version's data by number. Version numbers, times
+                    and older versions are kept, custom_metadata turns
+                    into key=value tags. Deleted and destroyed versions
+                    are left out.
+    1Password       the export.data of a 1pux export. Account is the
+                    namespace, vault the project and every field of an
+                    item is <item title>/<field>. Password history
+                    becomes older versions of <title>/password, item
+                    tags are kept.
+    Bitwarden       an unencrypted JSON export. The namespace is
+                    "bitwarden" unless --namespace says otherwise, the
+                    project is the item's collection or folder
+                    ("unfiled" if it has neither). Logins, cards,
+                    identities, notes and custom fields are
+                    <item name>/<field>, password history as above.
+
+--namespace puts everything in that namespace for any format.
+Namespaces and projects are looked up by name and created when they
+don't exist, the importer (the admin account) is allowed into the new
+projects. Secrets whose name is already taken are left alone so an
+import can be run again after fixing whatever was skipped. Each secret
+is its own transaction.
+
+The report lists what was (or with --dry-run would be) created and
+every item skipped along with why.
+
+*/
+
+// Namespace for Bitwarden exports, they don't name the account.
+const BITWARDEN_NAMESPACE: &str = "bitwarden";
+// Project for Bitwarden items in no folder or collection.
+const UNFILED_PROJECT: &str = "unfiled";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MigrationSource {
+    Vault,
+    OnePassword,
+    Bitwarden,
+}
+
+impl ToString for MigrationSource {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Vault => "vault".to_string(),
+            Self::OnePassword => "1password".to_string(),
+            Self::Bitwarden => "bitwarden".to_string(),
+        }
+    }
+}
+
+impl TryFrom<&str> for MigrationSource {
+    type Error = String;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "vault" => Ok(Self::Vault),
+            "1password" | "onepassword" => Ok(Self::OnePassword),
+            "bitwarden" => Ok(Self::Bitwarden),
+            other => Err(format!(
+                "Unknown source '{}', expected vault, 1password or bitwarden",
+                other
+            )),
+        }
+    }
+}
+
+pub struct MigrationOptions {
+    pub source: MigrationSource,
+    pub dry_run: bool,
+    pub namespace: Option<String>,
+    pub environment: String,
+    pub store: String,
+}
+
+pub struct MigratedVersion {
+    pub version: i32,
+    pub value: String,
+    pub when: Option<DateTime<Utc>>,
+}
+
+pub struct MigratedSecret {
+    pub namespace: String,
+    pub project: String,
+    pub name: String,
+    pub created: Option<DateTime<Utc>>,
+    pub tags: Vec<String>,
+    // Oldest first, the last one is the current value.
+    pub versions: Vec<MigratedVersion>,
+}
+
+#[derive(Serialize)]
+pub struct MigratedItem {
+    pub namespace: String,
+    pub project: String,
+    pub name: String,
+    pub versions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SkippedItem {
+    pub item: String,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct MigrationReport {
+    pub source: String,
+    pub dry_run: bool,
+    pub namespaces_created: Vec<String>,
+    pub projects_created: Vec<String>,
+    pub imported: Vec<MigratedItem>,
+    pub skipped: Vec<SkippedItem>,
+}
+
+impl MigrationReport {
+    fn skip(&mut self, item: impl Into<String>, reason: impl Into<String>) {
+        self.skipped.push(SkippedItem {
+            item: item.into(),
+            reason: reason.into(),
+        });
+    }
+}
+
+// Values are stored as text, anything nested as its JSON.
+fn text(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(e) => Some(e.to_owned()),
+        other => Some(other.to_string()),
+    }
+}
+
+// Vault leaves deletion_time as "" when there is none.
+fn timestamp(value: Option<&str>) -> Option<DateTime<Utc>> {
+    value
+        .filter(|e| !e.is_empty())
+        .and_then(|e| DateTime::parse_from_rfc3339(e).ok())
+        .map(|e| e.with_timezone(&Utc))
+}
+
+fn unix_timestamp(value: Option<i64>) -> Option<DateTime<Utc>> {
+    value.and_then(|e| DateTime::from_timestamp(e, 0))
+}
+
+// Tags can't hold commas, see listing.rs.
+fn clean_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .into_iter()
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty() && !e.contains(','))
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Previous passwords (oldest first) in front of the current one.
+fn with_history(
+    mut history: Vec<(String, Option<DateTime<Utc>>)>,
+    current: String,
+    updated: Option<DateTime<Utc>>,
+) -> Vec<MigratedVersion> {
+    history.sort_by_key(|e| e.1);
+    history.dedup_by(|a, b| a.0 == b.0);
+    history.retain(|e| e.0 != current);
+    history
+        .into_iter()
+        .chain([(current, updated)])
+        .enumerate()
+        .map(|(i, (value, when))| MigratedVersion {
+            version: i as i32 + 1,
+            value,
+            when,
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Default)]
+struct VaultVersionMetadata {
+    #[serde(default)]
+    created_time: Option<String>,
+    #[serde(default)]
+    deletion_time: Option<String>,
+    #[serde(default)]
+    destroyed: bool,
+}
+
+#[derive(Deserialize, Default)]
+struct VaultMetadata {
+    #[serde(default)]
+    created_time: Option<String>,
+    #[serde(default)]
+    custom_metadata: Option<HashMap<String, String>>,
+    // `vault kv metadata get`
+    #[serde(default)]
+    current_version: Option<i32>,
+    #[serde(default)]
+    versions: HashMap<String, VaultVersionMetadata>,
+    // `vault kv get`, describing the version it returned.
+    #[serde(default)]
+    version: Option<i32>,
+    #[serde(default)]
+    deletion_time: Option<String>,
+    #[serde(default)]
+    destroyed: bool,
+}
+
+fn vault_data(value: &serde_json::Value) -> Option<&serde_json::Map<String, serde_json::Value>> {
+    match value.get("data") {
+        Some(serde_json::Value::Object(data)) => Some(data),
+        _ => value.as_object(),
+    }
+}
+
+fn parse_vault(
+    contents: &[u8],
+    namespace: Option<&str>,
+    report: &mut MigrationReport,
+) -> Result<Vec<MigratedSecret>, String> {
+    let dump: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(contents).map_err(|e| e.to_string())?;
+    let mut secrets = vec![];
+    for (path, entry) in dump {
+        let mut segments = path.split('/').filter(|e| !e.is_empty());
+        let (Some(namespace), Some(project)) = (
+            namespace
+                .map(|e| e.to_string())
+                .or(segments.next().map(|e| e.to_string())),
+            segments.next(),
+        ) else {
+            report.skip(&path, "paths need to be <namespace>/<project>/...");
+            continue;
+        };
+        let rest = segments.collect::<Vec<&str>>().join("/");
+        let Some(object) = entry.as_object() else {
+            report.skip(&path, "not an object");
+            continue;
+        };
+        let metadata: VaultMetadata = object
+            .get("metadata")
+            .and_then(|e| serde_json::from_value(e.clone()).ok())
+            .unwrap_or_default();
+        // Every version kept, oldest first.
+        let mut versions: Vec<(
+            i32,
+            Option<DateTime<Utc>>,
+            &serde_json::Map<String, serde_json::Value>,
+        )> = vec![];
+        if let Some(serde_json::Value::Object(by_number)) = object.get("versions") {
+            for (number, data) in by_number {
+                let Ok(number) = number.parse::<i32>() else {
+                    report.skip(format!("{} v{}", path, number), "not a version number");
+                    continue;
+                };
+                let version_metadata = metadata.versions.get(&number.to_string());
+                if version_metadata
+                    .is_some_and(|e| e.destroyed || timestamp(e.deletion_time.as_deref()).is_some())
+                {
+                    report.skip(format!("{} v{}", path, number), "deleted in Vault");
+                    continue;
+                }
+                let Some(data) = vault_data(data) else {
+                    report.skip(format!("{} v{}", path, number), "not an object");
+                    continue;
+                };
+                let when = version_metadata.and_then(|e| timestamp(e.created_time.as_deref()));
+                versions.push((number, when, data));
+            }
+            versions.sort_by_key(|e| e.0);
+            if let Some(current) = metadata.current_version
+                && versions.last().is_none_or(|e| e.0 != current)
+            {
+                report.skip(
+                    &path,
+                    format!("current version {} is deleted or missing", current),
+                );
+                continue;
+            }
+        } else if object.contains_key("metadata") && object.contains_key("data") {
+            if metadata.destroyed || timestamp(metadata.deletion_time.as_deref()).is_some() {
+                report.skip(&path, "deleted in Vault");
+                continue;
+            }
+            let Some(data) = vault_data(&entry) else {
+                report.skip(&path, "data isn't an object");
+                continue;
+            };
+            versions.push((
+                metadata.version.unwrap_or(1),
+                timestamp(metadata.created_time.as_deref()),
+                data,
+            ));
+        } else {
+            versions.push((1, None, object));
+        }
+        let Some((_, _, current)) = versions.last() else {
+            report.skip(&path, "no versions left to import");
+            continue;
+        };
+        // The first version is when the path was created, version
+        // metadata may be missing though.
+        let created = timestamp(metadata.created_time.as_deref()).or(versions[0].1);
+        let tags = clean_tags(
+            metadata
+                .custom_metadata
+                .iter()
+                .flatten()
+                .map(|(key, value)| format!("{}={}", key, value)),
+        );
+        for key in current.keys() {
+            let name = if rest.is_empty() {
+                key.to_owned()
+            } else {
+                format!("{}/{}", rest, key)
+            };
+            // Only the versions that changed this key.
+            let mut history: Vec<MigratedVersion> = vec![];
+            for (number, when, data) in &versions {
+                let Some(value) = data.get(key).and_then(text) else {
+                    continue;
+                };
+                if history.last().is_some_and(|e| e.value == value) {
+                    continue;
+                }
+                history.push(MigratedVersion {
+                    version: *number,
+                    value,
+                    when: *when,
+                });
+            }
+            if history.is_empty() || current.get(key).and_then(text).is_none() {
+                report.skip(format!("{}/{}", path, key), "null value");
+                continue;
+            }
+            // Skipping unchanged versions may have dropped the latest
+            // number, the current value keeps it.
+            let last = history.len() - 1;
+            history[last].version = versions.last().unwrap().0;
+            history[last].when = history[last].when.or(versions.last().unwrap().1);
+            secrets.push(MigratedSecret {
+                namespace: namespace.to_owned(),
+                project: project.to_string(),
+                name,
+                created,
+                tags: tags.clone(),
+                versions: history,
+            });
+        }
+    }
+    Ok(secrets)
+}
+
+#[derive(Deserialize)]
+struct OnePuxExport {
+    #[serde(default)]
+    accounts: Vec<OnePuxAccount>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct OnePuxAttrs {
+    #[serde(default)]
+    account_name: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OnePuxAccount {
+    #[serde(default)]
+    attrs: OnePuxAttrs,
+    #[serde(default)]
+    vaults: Vec<OnePuxVault>,
+}
+
+#[derive(Deserialize)]
+struct OnePuxVault {
+    #[serde(default)]
+    attrs: OnePuxAttrs,
+    #[serde(default)]
+    items: Vec<OnePuxItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OnePuxItem {
+    #[serde(default)]
+    created_at: Option<i64>,
+    #[serde(default)]
+    updated_at: Option<i64>,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    trashed: bool,
+    #[serde(default)]
+    overview: OnePuxOverview,
+    #[serde(default)]
+    details: OnePuxDetails,
+}
+
+#[derive(Deserialize, Default)]
+struct OnePuxOverview {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct OnePuxDetails {
+    #[serde(default)]
+    login_fields: Vec<OnePuxLoginField>,
+    #[serde(default)]
+    notes_plain: Option<String>,
+    #[serde(default)]
+    sections: Vec<OnePuxSection>,
+    #[serde(default)]
+    password_history: Vec<OnePuxPasswordHistory>,
+    // Password items keep theirs here instead of in a field.
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OnePuxLoginField {
+    #[serde(default)]
+    value: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    designation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OnePuxSection {
+    #[serde(default)]
+    fields: Vec<OnePuxField>,
+}
+
+#[derive(Deserialize)]
+struct OnePuxField {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    id: String,
+    // One entry named after the field type, e.g. {"concealed": "..."}.
+    #[serde(default)]
+    value: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct OnePuxPasswordHistory {
+    value: String,
+    #[serde(default)]
+    time: Option<i64>,
+}
+
+// Ok(None) for fields left empty, Err for types we can't store.
+fn one_pux_value(value: &HashMap<String, serde_json::Value>) -> Result<Option<String>, String> {
+    let Some((kind, inner)) = value.iter().next() else {
+        return Ok(None);
+    };
+    match (kind.as_str(), inner) {
+        ("file", _) => Err("attachments aren't in the export".to_string()),
+        ("email", serde_json::Value::Object(email)) => {
+            Ok(email.get("email_address").and_then(text))
+        }
+        (kind, serde_json::Value::Object(_) | serde_json::Value::Array(_)) => {
+            Err(format!("{} fields aren't supported", kind))
+        }
+        (_, inner) => Ok(text(inner)),
+    }
+}
+
+fn parse_one_password(
+    contents: &[u8],
+    namespace: Option<&str>,
+    report: &mut MigrationReport,
+) -> Result<Vec<MigratedSecret>, String> {
+    let export: OnePuxExport = serde_json::from_slice(contents).map_err(|e| e.to_string())?;
+    let mut secrets = vec![];
+    for account in export.accounts {
+        let account_name = namespace
+            .map(|e| e.to_string())
+            .or(account.attrs.account_name)
+            .or(account.attrs.name)
+            .unwrap_or("1password".to_string());
+        for vault in account.vaults {
+            let vault_name = vault.attrs.name.unwrap_or("Personal".to_string());
+            for item in vault.items {
+                let title = item.overview.title.trim().to_string();
+                let item_path = format!("{}/{}/{}", account_name, vault_name, title);
+                if item.trashed || item.state.as_deref() == Some("archived") {
+                    report.skip(&item_path, "archived or in the trash");
+                    continue;
+                }
+                if title.is_empty() {
+                    report.skip(&item_path, "item has no title");
+                    continue;
+                }
+                let created = unix_timestamp(item.created_at);
+                let updated = unix_timestamp(item.updated_at);
+                let mut fields: Vec<(String, String)> = vec![];
+                for field in &item.details.login_fields {
+                    let label = field
+                        .designation
+                        .to_owned()
+                        .filter(|e| !e.is_empty())
+                        .unwrap_or(field.name.to_owned());
+                    if !label.is_empty() && !field.value.is_empty() {
+                        fields.push((label, field.value.to_owned()));
+                    }
+                }
+                if let Some(password) = item.details.password.to_owned().filter(|e| !e.is_empty()) {
+                    fields.push(("password".to_string(), password));
+                }
+                for field in item.details.sections.iter().flat_map(|e| &e.fields) {
+                    let label = if field.title.is_empty() {
+                        &field.id
+                    } else {
+                        &field.title
+                    };
+                    match one_pux_value(&field.value) {
+                        Ok(Some(value)) if !value.is_empty() && !label.is_empty() => {
+                            fields.push((label.to_owned(), value))
+                        }
+                        Ok(_) => {}
+                        Err(reason) => report.skip(format!("{}/{}", item_path, label), reason),
+                    }
+                }
+                if let Some(notes) = item
+                    .details
+                    .notes_plain
+                    .to_owned()
+                    .filter(|e| !e.is_empty())
+                {
+                    fields.push(("notes".to_string(), notes));
+                }
+                if fields.is_empty() {
+                    report.skip(&item_path, "nothing to import");
+                    continue;
+                }
+                let tags = clean_tags(item.overview.tags.to_owned());
+                for (label, value) in fields {
+                    let versions = if label == "password" {
+                        let history = item
+                            .details
+                            .password_history
+                            .iter()
+                            .map(|e| (e.value.to_owned(), unix_timestamp(e.time)))
+                            .collect();
+                        with_history(history, value, updated)
+                    } else {
+                        with_history(vec![], value, updated)
+                    };
+                    secrets.push(MigratedSecret {
+                        namespace: account_name.to_owned(),
+                        project: vault_name.to_owned(),
+                        name: format!("{}/{}", title, label),
+                        created,
+                        tags: tags.clone(),
+                        versions,
+                    });
+                }
+            }
+        }
+    }
+    Ok(secrets)
+}
+
+#[derive(Deserialize)]
+struct BitwardenExport {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    folders: Vec<BitwardenFolder>,
+    #[serde(default)]
+    collections: Vec<BitwardenFolder>,
+    #[serde(default)]
+    items: Vec<BitwardenItem>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenFolder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitwardenItem {
+    #[serde(default)]
+    folder_id: Option<String>,
+    #[serde(default)]
+    collection_ids: Option<Vec<String>>,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    fields: Option<Vec<BitwardenField>>,
+    #[serde(default)]
+    login: Option<BitwardenLogin>,
+    #[serde(default)]
+    card: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    identity: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    password_history: Option<Vec<BitwardenPasswordHistory>>,
+    #[serde(default)]
+    creation_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    revision_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    deleted_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenField {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    value: Option<String>,
+    // 0 text, 1 hidden, 2 boolean, 3 linked to a login/card field.
+    #[serde(rename = "type", default)]
+    field_type: i32,
+}
+
+#[derive(Deserialize)]
+struct BitwardenLogin {
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    totp: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitwardenPasswordHistory {
+    #[serde(default)]
+    last_used_date: Option<DateTime<Utc>>,
+    password: String,
+}
+
+fn parse_bitwarden(
+    contents: &[u8],
+    namespace: Option<&str>,
+    report: &mut MigrationReport,
+) -> Result<Vec<MigratedSecret>, String> {
+    let export: BitwardenExport = serde_json::from_slice(contents).map_err(|e| e.to_string())?;
+    if export.encrypted {
+        return Err(
+            "Encrypted Bitwarden exports can't be read, export as unencrypted JSON".to_string(),
+        );
+    }
+    let namespace = namespace.unwrap_or(BITWARDEN_NAMESPACE);
+    let folders: HashMap<String, String> = export
+        .folders
+        .into_iter()
+        .chain(export.collections)
+        .map(|e| (e.id, e.name))
+        .collect();
+    let mut secrets = vec![];
+    for item in export.items {
+        // Organization items are in collections, personal ones in folders.
+        let project = item
+            .collection_ids
+            .iter()
+            .flatten()
+            .chain(item.folder_id.iter())
+            .find_map(|e| folders.get(e))
+            .map(|e| e.to_owned())
+            .unwrap_or(UNFILED_PROJECT.to_string());
+        let title = item.name.trim().to_string();
+        let item_path = format!("{}/{}/{}", namespace, project, title);
+        if item.deleted_date.is_some() {
+            report.skip(&item_path, "in the trash");
+            continue;
+        }
+        if title.is_empty() {
+            report.skip(&item_path, "item has no name");
+            continue;
+        }
+        let mut fields: Vec<(String, String)> = vec![];
+        if let Some(login) = &item.login {
+            for (label, value) in [
+                ("username", &login.username),
+                ("password", &login.password),
+                ("totp", &login.totp),
+            ] {
+                if let Some(value) = value.to_owned().filter(|e| !e.is_empty()) {
+                    fields.push((label.to_string(), value));
+                }
+            }
+        }
+        for section in [&item.card, &item.identity].into_iter().flatten() {
+            for (label, value) in section {
+                if let Some(value) = text(value).filter(|e| !e.is_empty()) {
+                    fields.push((label.to_owned(), value));
+                }
+            }
+        }
+        for field in item.fields.iter().flatten() {
+            let label = field.name.to_owned().unwrap_or_default();
+            if field.field_type == 3 {
+                report.skip(
+                    format!("{}/{}", item_path, label),
+                    "linked fields have no value of their own",
+                );
+                continue;
+            }
+            if let Some(value) = field.value.to_owned().filter(|e| !e.is_empty())
+                && !label.is_empty()
+            {
+                fields.push((label, value));
+            }
+        }
+        if let Some(notes) = item.notes.to_owned().filter(|e| !e.is_empty()) {
+            fields.push(("notes".to_string(), notes));
+        }
+        if fields.is_empty() {
+            report.skip(&item_path, "nothing to import");
+            continue;
+        }
+        for (label, value) in fields {
+            let history = if label == "password" {
+                item.password_history
+                    .iter()
+                    .flatten()
+                    .map(|e| (e.password.to_owned(), e.last_used_date))
+                    .collect()
+            } else {
+                vec![]
+            };
+            secrets.push(MigratedSecret {
+                namespace: namespace.to_string(),
+                project: project.to_owned(),
+                name: format!("{}/{}", title, label),
+                created: item.creation_date,
+                tags: vec![],
+                versions: with_history(history, value, item.revision_date),
+            });
+        }
+    }
+    Ok(secrets)
+}
+
+async fn find_namespace(app: &App, name: &str) -> Result<Option<Namespace>, sqlx::Error> {
+    sqlx::query_as::<_, Namespace>(
+        r#"SELECT * FROM tokaysec.namespaces WHERE name = ($1) ORDER BY added_when LIMIT 1"#,
+    )
+    .bind(&name)
+    .fetch_optional(&app.database.inner)
+    .await
+}
+
+async fn find_project(
+    app: &App,
+    namespace: &str,
+    name: &str,
+) -> Result<Option<Project>, sqlx::Error> {
+    sqlx::query_as::<_, Project>(
+        r#"SELECT * FROM tokaysec.projects WHERE namespace = ($1) AND name = ($2) ORDER BY added_when LIMIT 1"#,
+    )
+    .bind(&namespace)
+    .bind(&name)
+    .fetch_optional(&app.database.inner)
+    .await
+}
+
+pub async fn run(
+    app: &App,
+    options: &MigrationOptions,
+    contents: &[u8],
+    requester: &str,
+) -> Result<MigrationReport, String> {
+    let mut report = MigrationReport {
+        source: options.source.to_string(),
+        dry_run: options.dry_run,
+        namespaces_created: vec![],
+        projects_created: vec![],
+        imported: vec![],
+        skipped: vec![],
+    };
+    let namespace = options.namespace.as_deref();
+    let secrets = match options.source {
+        MigrationSource::Vault => parse_vault(contents, namespace, &mut report)?,
+        MigrationSource::OnePassword => parse_one_password(contents, namespace, &mut report)?,
+        MigrationSource::Bitwarden => parse_bitwarden(contents, namespace, &mut report)?,
+    };
+    let stores = app.stores.read().await;
+    let kv = stores
+        .get(&options.store)
+        .and_then(|e| e.as_kv())
+        .ok_or(format!("No kv store named '{}' is enabled", options.store))?;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let mut namespaces: HashMap<String, Option<String>> = HashMap::new();
+    let mut projects: HashMap<(String, String), Option<String>> = HashMap::new();
+    let mut seen: HashSet<(String, String, String)> = HashSet::new();
+    for secret in secrets {
+        let item = format!("{}/{}/{}", secret.namespace, secret.project, secret.name);
+        if !seen.insert((
+            secret.namespace.to_owned(),
+            secret.project.to_owned(),
+            secret.name.to_owned(),
+        )) {
+            report.skip(item, "another item in the export has the same name");
+            continue;
+        }
+        // None for ones a dry run would create.
+        if !namespaces.contains_key(&secret.namespace) {
+            let id = match find_namespace(app, &secret.namespace)
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                Some(namespace) => Some(namespace.id),
+                None => {
+                    report.namespaces_created.push(secret.namespace.to_owned());
+                    match options.dry_run {
+                        true => None,
+                        false => Some(app.create_namespace(&secret.namespace, requester).await?.id),
+                    }
+                }
+            };
+            namespaces.insert(secret.namespace.to_owned(), id);
+        }
+        let namespace_id = namespaces[&secret.namespace].to_owned();
+        let project_key = (secret.namespace.to_owned(), secret.project.to_owned());
+        if !projects.contains_key(&project_key) {
+            let existing = match &namespace_id {
+                Some(namespace_id) => find_project(app, namespace_id, &secret.project)
+                    .await
+                    .map_err(|e| e.to_string())?,
+                None => None,
+            };
+            let id = match (existing, &namespace_id) {
+                (Some(project), _) => Some(project.id),
+                (None, Some(namespace_id)) if !options.dry_run => {
+                    let project = app
+                        .create_project(kek_provider, &secret.project, namespace_id)
+                        .await?;
+                    app.create_resource_assignment(
+                        EasyResource(ResourceTypes::Namespace, namespace_id),
+                        EasyResource(ResourceTypes::Project, &project.id),
+                        requester,
+                    )
+                    .await?;
+                    app.create_policy_rule_target(
+                        &format!("proj:{}", &project.id),
+                        app::PolicyRuleTargetAction::Allow,
+                        &format!("prsn:{}", requester),
+                    )
+                    .await?;
+                    report
+                        .projects_created
+                        .push(format!("{}/{}", secret.namespace, secret.project));
+                    Some(project.id)
+                }
+                _ => {
+                    report
+                        .projects_created
+                        .push(format!("{}/{}", secret.namespace, secret.project));
+                    None
+                }
+            };
+            projects.insert(project_key.to_owned(), id);
+        }
+        let project_id = projects[&project_key].to_owned();
+        let imported = MigratedItem {
+            namespace: secret.namespace.to_owned(),
+            project: secret.project.to_owned(),
+            name: secret.name.to_owned(),
+            versions: secret.versions.len(),
+            id: None,
+        };
+        let Some(project_id) = project_id else {
+            report.imported.push(imported);
+            continue;
+        };
+        if options.dry_run {
+            if kv
+                .find_by_key(app, &project_id, &options.environment, &secret.name)
+                .await
+                .is_some()
+            {
+                report.skip(item, "already exists");
+            } else {
+                report.imported.push(imported);
+            }
+            continue;
+        }
+        match kv
+            .import_migrated(
+                app,
+                kek_provider,
+                &project_id,
+                &options.environment,
+                &secret,
+                requester,
+            )
+            .await
+        {
+            Ok(Some(id)) => report.imported.push(MigratedItem {
+                id: Some(id),
+                ..imported
+            }),
+            Ok(None) => report.skip(item, "already exists"),
+            Err(e) => report.skip(item, e.to_string()),
+        }
+    }
+    Ok(report)
+}
+
+// `tokaysec import ...`, see the top of this file. Prints the report
+// as JSON.
+pub async fn run_cli(app: &App, args: &[String]) -> Result<(), String> {
+    let usage = "usage: tokaysec import <vault|1password|bitwarden> <file> [--dry-run] [--namespace <name>] [--environment <env>] [--store <kv instance>]";
+    let mut positional = vec![];
+    let mut options = MigrationOptions {
+        source: MigrationSource::Vault,
+        dry_run: false,
+        namespace: None,
+        environment: DEFAULT_ENVIRONMENT.to_string(),
+        store: KV_STORE.to_string(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--namespace" => options.namespace = Some(args.next().ok_or(usage)?.to_owned()),
+            "--environment" => options.environment = args.next().ok_or(usage)?.to_owned(),
+            "--store" => options.store = args.next().ok_or(usage)?.to_owned(),
+            other if other.starts_with("--") => return Err(usage.to_string()),
+            other => positional.push(other),
+        }
+    }
+    let [source, file] = positional[..] else {
+        return Err(usage.to_string());
+    };
+    options.source = MigrationSource::try_from(source)?;
+    let contents = std::fs::read(file).map_err(|e| format!("{}: {}", file, e))?;
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    let report = run(app, &options, &contents, &admin_id).await?;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn report() -> MigrationReport {
+        MigrationReport {
+            source: String::new(),
+            dry_run: true,
+            namespaces_created: vec![],
+            projects_created: vec![],
+            imported: vec![],
+            skipped: vec![],
+        }
+    }
+
+    fn find<'a>(secrets: &'a [MigratedSecret], name: &str) -> &'a MigratedSecret {
+        secrets.iter().find(|e| e.name == name).unwrap()
+    }
+
+    fn versions(secret: &MigratedSecret) -> Vec<(i32, &str)> {
+        secret
+            .versions
+            .iter()
+            .map(|e| (e.version, e.value.as_str()))
+            .collect()
+    }
+
+    fn reason<'a>(report: &'a MigrationReport, item: &str) -> &'a str {
+        &report
+            .skipped
+            .iter()
+            .find(|e| e.item == item)
+            .unwrap()
+            .reason
+    }
+
+    fn parse(
+        parser: fn(
+            &[u8],
+            Option<&str>,
+            &mut MigrationReport,
+        ) -> Result<Vec<MigratedSecret>, String>,
+        fixture: serde_json::Value,
+        namespace: Option<&str>,
+    ) -> (Vec<MigratedSecret>, MigrationReport) {
+        let mut report = report();
+        let secrets = parser(
+            &serde_json::to_vec(&fixture).unwrap(),
+            namespace,
+            &mut report,
+        )
+        .unwrap();
+        (secrets, report)
+    }
+
+    #[test]
+    fn vault_paths_and_history() {
+        let (secrets, report) = parse(
+            parse_vault,
+            json!({
+                "team/api/db": {
+                    "metadata": {
+                        "created_time": "2024-01-01T00:00:00Z",
+                        "current_version": 3,
+                        "custom_metadata": {"owner": "ops"},
+                        "versions": {
+                            "1": {"created_time": "2024-01-01T00:00:00Z"},
+                            "2": {"created_time": "2024-02-01T00:00:00Z", "destroyed": true},
+                            "3": {"created_time": "2024-03-01T00:00:00Z"}
+                        }
+                    },
+                    "versions": {
+                        "1": {"password": "one", "user": "admin"},
+                        "2": {"password": "two", "user": "admin"},
+                        "3": {"password": "three", "user": "admin"}
+                    }
+                },
+                "team/api/plain": {"token": "abc"},
+                "team/api/gone": {
+                    "data": {"key": "value"},
+                    "metadata": {"version": 2, "deletion_time": "2024-04-01T00:00:00Z"}
+                },
+                "toplevel": {"key": "value"}
+            }),
+            None,
+        );
+        assert_eq!(secrets.len(), 3);
+        let password = find(&secrets, "db/password");
+        assert_eq!(
+            (password.namespace.as_str(), password.project.as_str()),
+            ("team", "api")
+        );
+        assert_eq!(password.tags, ["owner=ops"]);
+        assert_eq!(versions(password), [(1, "one"), (3, "three")]);
+        assert_eq!(
+            password.versions[1].when,
+            timestamp(Some("2024-03-01T00:00:00Z"))
+        );
+        // Unchanged since version 1, still numbered as the current one.
+        assert_eq!(versions(find(&secrets, "db/user")), [(3, "admin")]);
+        assert_eq!(versions(find(&secrets, "plain/token")), [(1, "abc")]);
+        assert_eq!(reason(&report, "team/api/db v2"), "deleted in Vault");
+        assert_eq!(reason(&report, "team/api/gone"), "deleted in Vault");
+        assert_eq!(
+            reason(&report, "toplevel"),
+            "paths need to be <namespace>/<project>/..."
+        );
+    }
+
+    // --namespace stands in for the first segment, the dump keeps its layout.
+    #[test]
+    fn vault_namespace_override() {
+        let (secrets, report) = parse(
+            parse_vault,
+            json!({"team/api/db": {"password": "one"}, "team": {"key": "value"}}),
+            Some("imported"),
+        );
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].namespace, "imported");
+        assert_eq!(secrets[0].project, "api");
+        assert_eq!(secrets[0].name, "db/password");
+        assert_eq!(report.skipped.len(), 1);
+    }
+
+    #[test]
+    fn vault_missing_current_version() {
+        let (secrets, report) = parse(
+            parse_vault,
+            json!({
+                "team/api/db": {
+                    "metadata": {
+                        "current_version": 2,
+                        "versions": {"2": {"deletion_time": "2024-04-01T00:00:00Z"}}
+                    },
+                    "versions": {"1": {"password": "one"}, "2": {"password": "two"}}
+                }
+            }),
+            None,
+        );
+        assert!(secrets.is_empty());
+        assert_eq!(
+            reason(&report, "team/api/db"),
+            "current version 2 is deleted or missing"
+        );
+    }
+
+    #[test]
+    fn one_password_items() {
+        let (secrets, report) = parse(
+            parse_one_password,
+            json!({"accounts": [{
+                "attrs": {"accountName": "Acme"},
+                "vaults": [{
+                    "attrs": {"name": "Shared"},
+                    "items": [
+                        {
+                            "createdAt": 1700000000,
+                            "updatedAt": 1700100000,
+                            "overview": {"title": "Database", "tags": ["prod", "a,b", "prod"]},
+                            "details": {
+                                "loginFields": [
+                                    {"value": "admin", "name": "username", "designation": "username"},
+                                    {"value": "new", "name": "password", "designation": "password"}
+                                ],
+                                "notesPlain": "rotate monthly",
+                                "sections": [{"fields": [
+                                    {"title": "port", "id": "p", "value": {"string": "5432"}},
+                                    {"title": "cert", "id": "c", "value": {"file": {"name": "ca.pem"}}}
+                                ]}],
+                                "passwordHistory": [
+                                    {"value": "new", "time": 1695000000},
+                                    {"value": "old", "time": 1690000000}
+                                ]
+                            }
+                        },
+                        {"trashed": true, "overview": {"title": "Old"}, "details": {"password": "x"}},
+                        {"overview": {"title": "Empty"}, "details": {}}
+                    ]
+                }]
+            }]}),
+            None,
+        );
+        assert_eq!(secrets.len(), 4);
+        let password = find(&secrets, "Database/password");
+        assert_eq!(
+            (password.namespace.as_str(), password.project.as_str()),
+            ("Acme", "Shared")
+        );
+        assert_eq!(password.tags, ["prod"]);
+        assert_eq!(password.created, unix_timestamp(Some(1700000000)));
+        // The current password shows up in the history as well.
+        assert_eq!(versions(password), [(1, "old"), (2, "new")]);
+        assert_eq!(password.versions[1].when, unix_timestamp(Some(1700100000)));
+        assert_eq!(
+            versions(find(&secrets, "Database/username")),
+            [(1, "admin")]
+        );
+        assert_eq!(versions(find(&secrets, "Database/port")), [(1, "5432")]);
+        assert_eq!(
+            versions(find(&secrets, "Database/notes")),
+            [(1, "rotate monthly")]
+        );
+        assert_eq!(
+            reason(&report, "Acme/Shared/Database/cert"),
+            "attachments aren't in the export"
+        );
+        assert_eq!(
+            reason(&report, "Acme/Shared/Old"),
+            "archived or in the trash"
+        );
+        assert_eq!(reason(&report, "Acme/Shared/Empty"), "nothing to import");
+    }
+
+    #[test]
+    fn bitwarden_items() {
+        let (secrets, report) = parse(
+            parse_bitwarden,
+            json!({
+                "encrypted": false,
+                "folders": [{"id": "f1", "name": "Work"}],
+                "items": [
+                    {
+                        "folderId": "f1",
+                        "name": "Mail",
+                        "login": {"username": "me", "password": "p3"},
+                        "passwordHistory": [
+                            {"lastUsedDate": "2024-02-01T00:00:00Z", "password": "p2"},
+                            {"lastUsedDate": "2024-01-01T00:00:00Z", "password": "p1"}
+                        ],
+                        "fields": [
+                            {"name": "pin", "value": "1234", "type": 1},
+                            {"name": "link", "value": null, "type": 3}
+                        ],
+                        "revisionDate": "2024-03-01T00:00:00Z"
+                    },
+                    {"name": "Loose", "notes": "hello"},
+                    {"name": "Bin", "notes": "x", "deletedDate": "2024-01-01T00:00:00Z"}
+                ]
+            }),
+            None,
+        );
+        assert_eq!(secrets.len(), 4);
+        let password = find(&secrets, "Mail/password");
+        assert_eq!(
+            (password.namespace.as_str(), password.project.as_str()),
+            (BITWARDEN_NAMESPACE, "Work")
+        );
+        assert_eq!(versions(password), [(1, "p1"), (2, "p2"), (3, "p3")]);
+        assert_eq!(
+            password.versions[2].when,
+            timestamp(Some("2024-03-01T00:00:00Z"))
+        );
+        assert_eq!(versions(find(&secrets, "Mail/username")), [(1, "me")]);
+        assert_eq!(versions(find(&secrets, "Mail/pin")), [(1, "1234")]);
+        assert_eq!(find(&secrets, "Loose/notes").project, UNFILED_PROJECT);
+        assert_eq!(
+            reason(&report, "bitwarden/Work/Mail/link"),
+            "linked fields have no value of their own"
+        );
+        assert_eq!(reason(&report, "bitwarden/unfiled/Bin"), "in the trash");
+    }
+
+    #[test]
+    fn bitwarden_refuses_encrypted_exports() {
+        let mut report = report();
+        let result = parse_bitwarden(
+            &serde_json::to_vec(&json!({"encrypted": true, "items": []})).unwrap(),
+            None,
+            &mut report,
+        );
+        assert!(result.is_err());
+    }
+}
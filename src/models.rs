@@ -11,6 +11,8 @@ pub struct ResourceAssignment {
     pub assigned_to_type: String,
     pub assigned_when: DateTime<Utc>,
     pub assigned_by: String,
+    // The owning project when this links a secret in as a reference.
+    pub linked_from: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug)]
@@ -26,6 +28,8 @@ pub struct WrappedDek {
 pub struct KVStoredValue {
     pub id: String,
     pub key: String,
+    pub project: String,
+    pub environment: String,
     pub value: Vec<u8>,
     pub gcm_tag: Vec<u8>,
     pub kmac_tag: Vec<u8>,
@@ -34,6 +38,295 @@ pub struct KVStoredValue {
     pub added_when: DateTime<Utc>,
     pub added_by: String,
     pub last_updated: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub expiry_notified_when: Option<DateTime<Utc>>,
+    pub store: String,
+    pub version: i32,
+    pub aad_version: i16,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct KVPreviousValue {
+    pub secret_id: String,
+    pub version: i32,
+    pub value: Vec<u8>,
+    pub gcm_tag: Vec<u8>,
+    pub kmac_tag: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub dek_used: String,
+    pub retired_when: DateTime<Utc>,
+    pub retained_until: DateTime<Utc>,
+    pub aad_version: i16,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct ConfigStoredValue {
+    pub id: String,
+    pub project: String,
+    pub environment: String,
+    pub key: String,
+    pub current_version: i32,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct ConfigValueVersion {
+    pub config_id: String,
+    pub version: i32,
+    pub value_type: String,
+    pub value: serde_json::Value,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct TemplateStoredValue {
+    pub id: String,
+    pub project: String,
+    pub environment: String,
+    pub key: String,
+    pub format: String,
+    pub template: String,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+    pub last_updated: DateTime<Utc>,
+}
+
+// Columns every sealed (DEK encrypted) value is stored with. Meant to
+// be flattened into a store's row, see stores::sealed.
+#[derive(Serialize, Deserialize, FromRow, Debug, Default)]
+pub struct SealedValue {
+    pub value: Vec<u8>,
+    pub gcm_tag: Vec<u8>,
+    pub kmac_tag: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub dek_used: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct PkiCa {
+    pub id: String,
+    // One of project or namespace is set.
+    pub project: Option<String>,
+    pub namespace: Option<String>,
+    pub kind: String,
+    pub parent: Option<String>,
+    pub common_name: String,
+    pub key_type: String,
+    pub certificate: String,
+    #[sqlx(flatten)]
+    #[serde(skip)]
+    pub private_key: SealedValue,
+    pub not_after: DateTime<Utc>,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct PkiRole {
+    pub id: String,
+    pub project: String,
+    pub name: String,
+    pub allowed_domains: sqlx::types::Json<Vec<String>>,
+    pub allow_bare_domains: bool,
+    pub allow_subdomains: bool,
+    pub allow_ip_sans: bool,
+    pub key_types: sqlx::types::Json<Vec<String>>,
+    pub default_ttl_secs: i64,
+    pub max_ttl_secs: i64,
+    pub last_updated: DateTime<Utc>,
+    pub added_by: String,
+    pub allow_wildcards: bool,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct PkiCertificate {
+    pub serial: String,
+    pub project: String,
+    pub issuer: String,
+    pub role: String,
+    pub common_name: String,
+    pub certificate: String,
+    pub not_after: DateTime<Utc>,
+    pub issued_when: DateTime<Utc>,
+    pub issued_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct SshKey {
+    pub id: String,
+    pub project: String,
+    pub key: String,
+    pub algorithm: String,
+    pub public_key: String,
+    pub fingerprint: String,
+    #[sqlx(flatten)]
+    #[serde(skip)]
+    pub private_key: SealedValue,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct SshCa {
+    pub id: String,
+    pub project: String,
+    pub algorithm: String,
+    pub public_key: String,
+    #[sqlx(flatten)]
+    #[serde(skip)]
+    pub private_key: SealedValue,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct SshCertificate {
+    pub id: String,
+    pub project: String,
+    pub ca: String,
+    pub serial: i64,
+    pub key_id: String,
+    pub principals: sqlx::types::Json<Vec<String>>,
+    pub certificate: String,
+    pub valid_before: DateTime<Utc>,
+    pub issued_when: DateTime<Utc>,
+    pub issued_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct DynamicPostgresConnection {
+    pub id: String,
+    pub project: String,
+    pub key: String,
+    pub grants: sqlx::types::Json<Vec<String>>,
+    pub default_ttl_secs: i64,
+    pub max_ttl_secs: i64,
+    #[sqlx(flatten)]
+    #[serde(skip)]
+    pub connection_url: SealedValue,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct DynamicPostgresLease {
+    pub id: String,
+    pub connection: String,
+    pub project: String,
+    pub role_name: String,
+    pub expires_at: DateTime<Utc>,
+    pub issued_when: DateTime<Utc>,
+    pub issued_by: String,
+    pub revoked_when: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct TransitKey {
+    pub id: String,
+    pub project: String,
+    pub name: String,
+    pub latest_version: i32,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct TransitKeyVersion {
+    pub key_id: String,
+    pub version: i32,
+    pub wrapped: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub tag: Vec<u8>,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct Share {
+    pub id: String,
+    pub project: String,
+    pub source: Option<String>,
+    #[serde(skip)]
+    pub value: Vec<u8>,
+    #[serde(skip)]
+    pub gcm_tag: Vec<u8>,
+    #[serde(skip)]
+    pub kmac_tag: Vec<u8>,
+    #[serde(skip)]
+    pub nonce: Vec<u8>,
+    pub views_remaining: i32,
+    pub expires_at: DateTime<Utc>,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct BlobStoredValue {
+    pub id: String,
+    pub project: String,
+    pub environment: String,
+    pub key: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    pub chunk_size: i32,
+    pub chunk_count: i32,
+    pub dek_used: String,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct BlobChunk {
+    pub blob_id: String,
+    pub chunk: i32,
+    pub value: Vec<u8>,
+    pub gcm_tag: Vec<u8>,
+    pub kmac_tag: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct StructuredStoredValue {
+    pub id: String,
+    pub project: String,
+    pub environment: String,
+    pub key: String,
+    pub dek_used: String,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+    pub last_updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct StructuredField {
+    pub secret_id: String,
+    pub pointer: String,
+    pub value: Vec<u8>,
+    pub gcm_tag: Vec<u8>,
+    pub kmac_tag: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct PluginStoredValue {
+    pub id: String,
+    pub store: String,
+    pub project: String,
+    pub environment: String,
+    pub key: String,
+    pub metadata: sqlx::types::Json<serde_json::Value>,
+    #[sqlx(flatten)]
+    #[serde(skip)]
+    pub state: SealedValue,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+    pub last_updated: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug)]
@@ -113,3 +406,86 @@ are isolated and scoped within so projects, and roles, and permissions,
 cannot be shared between them unless specified globally in the instance
 level policy.
 */
+
+#[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
+pub struct GitSync {
+    pub id: String,
+    pub project: String,
+    pub environment: String,
+    pub repository: String,
+    pub branch: String,
+    pub file: String,
+    pub last_commit: Option<String>,
+    pub last_synced: Option<DateTime<Utc>>,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct RotationPolicy {
+    pub id: String,
+    pub secret_id: String,
+    pub project: String,
+    pub interval_secs: i64,
+    pub window_start: Option<chrono::NaiveTime>,
+    pub window_secs: Option<i64>,
+    pub grace_secs: i64,
+    pub rotator: sqlx::types::Json<crate::rotation::Rotator>,
+    pub next_rotation: DateTime<Utc>,
+    pub last_rotated: Option<DateTime<Utc>>,
+    pub last_status: Option<String>,
+    pub last_error: Option<String>,
+    pub failures: i32,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct RotationAttempt {
+    pub id: String,
+    pub policy: String,
+    pub secret_id: String,
+    pub status: String,
+    pub version: Option<i32>,
+    pub error: Option<String>,
+    pub started_when: DateTime<Utc>,
+    pub finished_when: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct ProtectedEnvironment {
+    pub project: String,
+    pub environment: String,
+    pub added_when: DateTime<Utc>,
+    pub added_by: String,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct Promotion {
+    pub id: String,
+    pub store: String,
+    pub source_project: String,
+    pub source_environment: String,
+    pub target_project: String,
+    pub target_environment: String,
+    pub keys: sqlx::types::Json<std::collections::BTreeMap<String, i32>>,
+    pub conflict: String,
+    pub status: String,
+    pub requested_by: String,
+    pub requested_when: DateTime<Utc>,
+    pub decided_by: Option<String>,
+    pub decided_when: Option<DateTime<Utc>>,
+    pub result: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct LimitsRow {
+    pub scope: String,
+    pub scope_id: String,
+    pub max_value_bytes: Option<i64>,
+    pub max_secrets: Option<i64>,
+    pub max_versions: Option<i32>,
+    pub max_request_bytes: Option<i64>,
+    pub updated_when: DateTime<Utc>,
+    pub updated_by: String,
+}
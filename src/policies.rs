@@ -14,6 +14,20 @@ pub enum AccessAction {
     CreateSecret,
     DeleteSecret,
     UpdateSecret,
+    ReadSecret,
+    CreateConfig,
+    DeleteConfig,
+    UpdateConfig,
+    ReadConfig,
+    ManagePki,
+    IssueCertificate,
+    ManageSshCa,
+    SignSshCertificate,
+    ManageTransitKey,
+    TransitEncrypt,
+    TransitDecrypt,
+    ShareSecret,
+    ApprovePromotion,
     CreateProject,
     DeleteProject,
     UpdateProject,
@@ -30,6 +44,20 @@ impl ToString for AccessAction {
             AccessAction::CreateSecret => "create:secret",
             AccessAction::DeleteSecret => "delete:secret",
             AccessAction::UpdateSecret => "update:secret",
+            AccessAction::ReadSecret => "read:secret",
+            AccessAction::CreateConfig => "create:config",
+            AccessAction::DeleteConfig => "delete:config",
+            AccessAction::UpdateConfig => "update:config",
+            AccessAction::ReadConfig => "read:config",
+            AccessAction::ManagePki => "manage:pki",
+            AccessAction::IssueCertificate => "issue:certificate",
+            AccessAction::ManageSshCa => "manage:ssh_ca",
+            AccessAction::SignSshCertificate => "sign:ssh_certificate",
+            AccessAction::ManageTransitKey => "manage:transit_key",
+            AccessAction::TransitEncrypt => "transit:encrypt",
+            AccessAction::TransitDecrypt => "transit:decrypt",
+            AccessAction::ShareSecret => "share:secret",
+            AccessAction::ApprovePromotion => "approve:promotion",
             AccessAction::CreateProject => "create:project",
             AccessAction::DeleteProject => "delete:project",
             AccessAction::UpdateProject => "update:project",
@@ -50,6 +78,20 @@ impl TryFrom<String> for AccessAction {
             "create:secret" => Self::CreateSecret,
             "delete:secret" => Self::DeleteSecret,
             "update:secret" => Self::UpdateSecret,
+            "read:secret" => Self::ReadSecret,
+            "create:config" => Self::CreateConfig,
+            "delete:config" => Self::DeleteConfig,
+            "update:config" => Self::UpdateConfig,
+            "read:config" => Self::ReadConfig,
+            "manage:pki" => Self::ManagePki,
+            "issue:certificate" => Self::IssueCertificate,
+            "manage:ssh_ca" => Self::ManageSshCa,
+            "sign:ssh_certificate" => Self::SignSshCertificate,
+            "manage:transit_key" => Self::ManageTransitKey,
+            "transit:encrypt" => Self::TransitEncrypt,
+            "transit:decrypt" => Self::TransitDecrypt,
+            "share:secret" => Self::ShareSecret,
+            "approve:promotion" => Self::ApprovePromotion,
             "create:project" => Self::CreateProject,
             "delete:project" => Self::DeleteProject,
             "update:project" => Self::UpdateProject,
@@ -68,6 +110,20 @@ impl Into<String> for AccessAction {
             AccessAction::CreateSecret => "create:secret",
             AccessAction::DeleteSecret => "delete:secret",
             AccessAction::UpdateSecret => "update:secret",
+            AccessAction::ReadSecret => "read:secret",
+            AccessAction::CreateConfig => "create:config",
+            AccessAction::DeleteConfig => "delete:config",
+            AccessAction::UpdateConfig => "update:config",
+            AccessAction::ReadConfig => "read:config",
+            AccessAction::ManagePki => "manage:pki",
+            AccessAction::IssueCertificate => "issue:certificate",
+            AccessAction::ManageSshCa => "manage:ssh_ca",
+            AccessAction::SignSshCertificate => "sign:ssh_certificate",
+            AccessAction::ManageTransitKey => "manage:transit_key",
+            AccessAction::TransitEncrypt => "transit:encrypt",
+            AccessAction::TransitDecrypt => "transit:decrypt",
+            AccessAction::ShareSecret => "share:secret",
+            AccessAction::ApprovePromotion => "approve:promotion",
             AccessAction::CreateProject => "create:project",
             AccessAction::DeleteProject => "delete:project",
             AccessAction::UpdateProject => "update:project",
@@ -0,0 +1,771 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::Utc;
+use ring::{hmac, rand::SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    app::App,
+    audit,
+    bulk::ConflictMode,
+    kek_provider::KekProvider,
+    models::{ConfigValueVersion, Promotion, ProtectedEnvironment},
+    policies::AccessAction,
+    secure_buf::SecureBuffer,
+    stores::{
+        StoreAccessError,
+        config::{ConfigStoreStoreData, ConfigValueType},
+        kv::{DEFAULT_ENVIRONMENT, KV_STORE},
+        require_project_action,
+    },
+};
+
+/*
+
+Comparing two environments (of the same or different projects) and
+copying keys from one to the other, for kv instances and config.
+
+A diff reports the keys only the source has (missing), only the target
+has (extra) and the ones in both with a different value. Values are
+compared by fingerprint, an HMAC-SHA256 under a key made for that one
+diff, so the response can be compared within itself but says nothing
+about a value on its own or next to another diff's fingerprints.
+Diffing needs read on both sides.
+
+A promotion copies the listed keys from the source environment to the
+target, with the same conflict modes as a bulk import (bulk.rs). Into
+a protected environment, or when asked for, it waits as pending until
+it is approved (or rejected) in the target project. The source version
+of every key is taken when the promotion is requested and an approval
+only copies those versions, if any of them has changed since the
+promotion fails instead of copying something nobody looked at.
+
+Promotions are applied as whoever approved them (or requested them if
+there was nothing to approve), who needs read on the source and create
+(plus update when overwriting) on the target. Approving also needs
+approve:promotion on the target, which nothing else implies, and can't
+be done by whoever requested the promotion. Each step goes into both
+projects' audit logs.
+
+*/
+
+pub const REQUESTED: &str = "promotion:requested";
+pub const APPLIED: &str = "promotion:applied";
+pub const REJECTED: &str = "promotion:rejected";
+pub const FAILED: &str = "promotion:failed";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PromotionStatus {
+    Pending,
+    // Claimed by an approval that is being applied.
+    Approved,
+    Applied,
+    Rejected,
+    Failed,
+}
+
+impl ToString for PromotionStatus {
+    fn to_string(&self) -> String {
+        String::from(match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Applied => "applied",
+            Self::Rejected => "rejected",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+fn default_store() -> String {
+    KV_STORE.to_string()
+}
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct EnvironmentRef {
+    pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+#[derive(Deserialize)]
+pub struct DiffRequest {
+    pub source: EnvironmentRef,
+    pub target: EnvironmentRef,
+    #[serde(default = "default_store")]
+    pub store: String,
+}
+
+#[derive(Serialize)]
+pub struct Fingerprints {
+    pub source: BTreeMap<String, String>,
+    pub target: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct EnvironmentDiff {
+    pub store: String,
+    pub source: EnvironmentRef,
+    pub target: EnvironmentRef,
+    // Only in the source.
+    pub missing: Vec<String>,
+    // Only in the target.
+    pub extra: Vec<String>,
+    pub differing: Vec<String>,
+    pub matching: Vec<String>,
+    pub fingerprints: Fingerprints,
+}
+
+#[derive(Deserialize)]
+pub struct PromoteRequest {
+    pub source: EnvironmentRef,
+    pub target: EnvironmentRef,
+    #[serde(default = "default_store")]
+    pub store: String,
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub conflict: ConflictMode,
+    // Wait for an approval even if the target isn't protected.
+    #[serde(default)]
+    pub require_approval: bool,
+}
+
+#[derive(Serialize, Default)]
+pub struct Promoted {
+    pub created: Vec<String>,
+    pub overwritten: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+enum Value {
+    Secret(SecureBuffer),
+    Config(ConfigValueVersion),
+}
+
+struct Current {
+    version: i32,
+    value: Value,
+}
+
+enum StoreKind {
+    Kv,
+    Config,
+}
+
+impl StoreKind {
+    fn read(&self) -> AccessAction {
+        match self {
+            Self::Kv => AccessAction::ReadSecret,
+            Self::Config => AccessAction::ReadConfig,
+        }
+    }
+    fn create(&self) -> AccessAction {
+        match self {
+            Self::Kv => AccessAction::CreateSecret,
+            Self::Config => AccessAction::CreateConfig,
+        }
+    }
+    fn update(&self) -> AccessAction {
+        match self {
+            Self::Kv => AccessAction::UpdateSecret,
+            Self::Config => AccessAction::UpdateConfig,
+        }
+    }
+}
+
+async fn store_kind(app: &App, store: &str) -> Result<StoreKind, StoreAccessError> {
+    let stores = app.stores.read().await;
+    let Some(secret_store) = stores.get(store) else {
+        return Err(StoreAccessError::NotFound(format!(
+            "No store named '{}' is enabled",
+            store
+        )));
+    };
+    if secret_store.as_kv().is_some() {
+        Ok(StoreKind::Kv)
+    } else if secret_store.as_config().is_some() {
+        Ok(StoreKind::Config)
+    } else {
+        Err(StoreAccessError::Invalid(format!(
+            "Only kv and config stores can be compared or promoted, not {}",
+            store
+        )))
+    }
+}
+
+// Every current value of the environment, needs read on its project.
+async fn current_values(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    store: &str,
+    environment: &EnvironmentRef,
+    requester: &str,
+) -> Result<BTreeMap<String, Current>, StoreAccessError> {
+    let kind = store_kind(app, store).await?;
+    require_project_action(app, &environment.project, requester, kind.read()).await?;
+    let stores = app.stores.read().await;
+    let secret_store = stores.get(store).unwrap();
+    if let Some(kv) = secret_store.as_kv() {
+        return Ok(kv
+            .current_values(
+                app,
+                kek_provider,
+                &environment.project,
+                &environment.environment,
+            )
+            .await?
+            .into_iter()
+            .map(|(key, version, value)| {
+                (
+                    key,
+                    Current {
+                        version,
+                        value: Value::Secret(value),
+                    },
+                )
+            })
+            .collect());
+    }
+    let config = secret_store.as_config().unwrap();
+    Ok(config
+        .current_values(app, &environment.project, &environment.environment)
+        .await?
+        .into_iter()
+        .map(|(key, version)| {
+            (
+                key,
+                Current {
+                    version: version.version,
+                    value: Value::Config(version),
+                },
+            )
+        })
+        .collect())
+}
+
+fn fingerprint(key: &hmac::Key, value: &Value) -> String {
+    let tag = match value {
+        Value::Secret(e) => hmac::sign(key, e.expose()),
+        // The type is part of the value, "1" and 1 differ.
+        Value::Config(e) => hmac::sign(key, format!("{}:{}", e.value_type, e.value).as_bytes()),
+    };
+    tag.as_ref().iter().map(|e| format!("{:02x}", e)).collect()
+}
+
+pub async fn diff(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    request: DiffRequest,
+    requester: &str,
+) -> Result<EnvironmentDiff, StoreAccessError> {
+    let source = current_values(
+        app,
+        kek_provider,
+        &request.store,
+        &request.source,
+        requester,
+    )
+    .await?;
+    let target = current_values(
+        app,
+        kek_provider,
+        &request.store,
+        &request.target,
+        requester,
+    )
+    .await?;
+    let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+        .map_err(|_| StoreAccessError::Internal("Couldn't generate a key".to_string()))?;
+    let fingerprints = Fingerprints {
+        source: source
+            .iter()
+            .map(|(name, e)| (name.to_owned(), fingerprint(&key, &e.value)))
+            .collect(),
+        target: target
+            .iter()
+            .map(|(name, e)| (name.to_owned(), fingerprint(&key, &e.value)))
+            .collect(),
+    };
+    let mut diff = EnvironmentDiff {
+        store: request.store,
+        source: request.source,
+        target: request.target,
+        missing: vec![],
+        extra: vec![],
+        differing: vec![],
+        matching: vec![],
+        fingerprints,
+    };
+    for (name, source_fingerprint) in &diff.fingerprints.source {
+        match diff.fingerprints.target.get(name) {
+            None => diff.missing.push(name.to_owned()),
+            Some(e) if e == source_fingerprint => diff.matching.push(name.to_owned()),
+            Some(_) => diff.differing.push(name.to_owned()),
+        }
+    }
+    diff.extra = diff
+        .fingerprints
+        .target
+        .keys()
+        .filter(|e| !diff.fingerprints.source.contains_key(*e))
+        .cloned()
+        .collect();
+    Ok(diff)
+}
+
+async fn require_target_actions(
+    app: &App,
+    kind: &StoreKind,
+    project: &str,
+    conflict: ConflictMode,
+    person: &str,
+) -> Result<(), StoreAccessError> {
+    require_project_action(app, project, person, kind.create()).await?;
+    if conflict == ConflictMode::Overwrite {
+        require_project_action(app, project, person, kind.update()).await?;
+    }
+    Ok(())
+}
+
+async fn record_both(
+    app: &App,
+    promotion: &Promotion,
+    person: &str,
+    action: &str,
+    detail: serde_json::Value,
+) -> Result<(), StoreAccessError> {
+    let resource = format!("promotion:{}", promotion.id);
+    audit::record(
+        app,
+        &promotion.target_project,
+        person,
+        action,
+        &resource,
+        detail.clone(),
+    )
+    .await?;
+    if promotion.source_project != promotion.target_project {
+        audit::record(
+            app,
+            &promotion.source_project,
+            person,
+            action,
+            &resource,
+            detail,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn is_protected(app: &App, environment: &EnvironmentRef) -> Result<bool, StoreAccessError> {
+    Ok(sqlx::query_as::<_, ProtectedEnvironment>(
+        r#"SELECT * FROM tokaysec.protected_environments WHERE project = ($1) AND environment = ($2)"#,
+    )
+    .bind(&environment.project)
+    .bind(&environment.environment)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .is_some())
+}
+
+pub async fn promote(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    request: PromoteRequest,
+    requester: &str,
+) -> Result<Promotion, StoreAccessError> {
+    if request.source == request.target {
+        return Err(StoreAccessError::Invalid(
+            "Source and target are the same environment".to_string(),
+        ));
+    }
+    let keys = request.keys.iter().cloned().collect::<BTreeSet<String>>();
+    if keys.is_empty() {
+        return Err(StoreAccessError::Invalid("Nothing to promote".to_string()));
+    }
+    let kind = store_kind(app, &request.store).await?;
+    let source = current_values(
+        app,
+        kek_provider,
+        &request.store,
+        &request.source,
+        requester,
+    )
+    .await?;
+    let absent = keys
+        .iter()
+        .filter(|e| !source.contains_key(*e))
+        .map(|e| e.as_str())
+        .collect::<Vec<&str>>();
+    if !absent.is_empty() {
+        return Err(StoreAccessError::NotFound(format!(
+            "Not in project {} ({}): {}",
+            request.source.project,
+            request.source.environment,
+            absent.join(", ")
+        )));
+    }
+    require_target_actions(
+        app,
+        &kind,
+        &request.target.project,
+        request.conflict,
+        requester,
+    )
+    .await?;
+    let versions = keys
+        .into_iter()
+        .map(|e| {
+            let version = source[&e].version;
+            (e, version)
+        })
+        .collect::<BTreeMap<String, i32>>();
+    let promotion = sqlx::query_as::<_, Promotion>(
+        r#"INSERT INTO tokaysec.promotions(id,store,source_project,source_environment,target_project,target_environment,keys,conflict,status,requested_by,requested_when) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11) RETURNING *"#,
+    )
+    .bind(app.gen_id().await)
+    .bind(&request.store)
+    .bind(&request.source.project)
+    .bind(&request.source.environment)
+    .bind(&request.target.project)
+    .bind(&request.target.environment)
+    .bind(sqlx::types::Json(&versions))
+    .bind(request.conflict.to_string())
+    .bind(PromotionStatus::Pending.to_string())
+    .bind(&requester)
+    .bind(Utc::now())
+    .fetch_one(&app.database.inner)
+    .await?;
+    record_both(
+        app,
+        &promotion,
+        requester,
+        REQUESTED,
+        json!({ "keys": &versions }),
+    )
+    .await?;
+    if request.require_approval || is_protected(app, &request.target).await? {
+        return Ok(promotion);
+    }
+    apply(app, kek_provider, promotion, requester).await
+}
+
+async fn find(app: &App, project: &str, id: &str) -> Result<Promotion, StoreAccessError> {
+    sqlx::query_as::<_, Promotion>(
+        r#"SELECT * FROM tokaysec.promotions WHERE id = ($1) AND target_project = ($2)"#,
+    )
+    .bind(&id)
+    .bind(&project)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .ok_or(StoreAccessError::NotFound(format!(
+        "Project {} has no promotion {}",
+        project, id
+    )))
+}
+
+// Moves a pending promotion on, only one caller gets it.
+async fn decide(
+    app: &App,
+    promotion: &Promotion,
+    status: PromotionStatus,
+    person: &str,
+    result: Option<serde_json::Value>,
+) -> Result<Promotion, StoreAccessError> {
+    sqlx::query_as::<_, Promotion>(
+        r#"UPDATE tokaysec.promotions SET status = ($1), decided_by = ($2), decided_when = ($3), result = ($4) WHERE id = ($5) AND status = ($6) RETURNING *"#,
+    )
+    .bind(status.to_string())
+    .bind(&person)
+    .bind(Utc::now())
+    .bind(&result)
+    .bind(&promotion.id)
+    .bind(PromotionStatus::Pending.to_string())
+    .fetch_optional(&app.database.inner)
+    .await?
+    .ok_or(StoreAccessError::Conflict(format!(
+        "Promotion {} isn't pending anymore",
+        promotion.id
+    )))
+}
+
+async fn copy(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    promotion: &Promotion,
+    conflict: ConflictMode,
+    person: &str,
+) -> Result<Promoted, StoreAccessError> {
+    let source = EnvironmentRef {
+        project: promotion.source_project.to_owned(),
+        environment: promotion.source_environment.to_owned(),
+    };
+    let mut source = current_values(app, kek_provider, &promotion.store, &source, person).await?;
+    let mut values = vec![];
+    for (key, version) in promotion.keys.iter() {
+        match source.remove(key) {
+            Some(current) if current.version == *version => {
+                values.push((key.to_owned(), current.value))
+            }
+            Some(current) => {
+                return Err(StoreAccessError::Conflict(format!(
+                    "'{}' is at version {} in the source, the promotion was for version {}",
+                    key, current.version, version
+                )));
+            }
+            None => {
+                return Err(StoreAccessError::NotFound(format!(
+                    "'{}' is gone from the source",
+                    key
+                )));
+            }
+        }
+    }
+    let stores = app.stores.read().await;
+    let secret_store = stores
+        .get(&promotion.store)
+        .ok_or(StoreAccessError::NotFound(format!(
+            "No store named '{}' is enabled",
+            promotion.store
+        )))?;
+    if let Some(kv) = secret_store.as_kv() {
+        let values = values
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Value::Secret(e) => Some((key, e)),
+                Value::Config(_) => None,
+            })
+            .collect();
+        let imported = kv
+            .import_values(
+                app,
+                kek_provider,
+                &promotion.target_project,
+                &promotion.target_environment,
+                values,
+                conflict,
+                person,
+            )
+            .await?;
+        return Ok(Promoted {
+            created: imported.created,
+            overwritten: imported.overwritten,
+            skipped: imported.skipped,
+        });
+    }
+    let config = secret_store.as_config().unwrap();
+    // Config writes are one key at a time, so conflicts are all
+    // checked before anything is written.
+    let existing = config
+        .current_values(
+            app,
+            &promotion.target_project,
+            &promotion.target_environment,
+        )
+        .await?
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|e| promotion.keys.contains_key(e))
+        .collect::<Vec<String>>();
+    if !existing.is_empty() && conflict == ConflictMode::Fail {
+        return Err(StoreAccessError::Conflict(format!(
+            "Already in project {} ({}): {}",
+            promotion.target_project,
+            promotion.target_environment,
+            existing.join(", ")
+        )));
+    }
+    let mut promoted = Promoted::default();
+    let source = format!("promotion:{}", promotion.id);
+    for (key, value) in values {
+        let Value::Config(value) = value else {
+            continue;
+        };
+        let exists = existing.contains(&key);
+        if exists && conflict == ConflictMode::Skip {
+            promoted.skipped.push(key);
+            continue;
+        }
+        config
+            .write(
+                app,
+                ConfigStoreStoreData {
+                    name: key.to_owned(),
+                    value: value.value,
+                    project: promotion.target_project.to_owned(),
+                    environment: promotion.target_environment.to_owned(),
+                    value_type: serde_json::from_value::<ConfigValueType>(json!(value.value_type))
+                        .ok(),
+                },
+                person,
+                false,
+                Some(&source),
+            )
+            .await?;
+        match exists {
+            true => promoted.overwritten.push(key),
+            false => promoted.created.push(key),
+        }
+    }
+    Ok(promoted)
+}
+
+async fn apply(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    promotion: Promotion,
+    person: &str,
+) -> Result<Promotion, StoreAccessError> {
+    let conflict = serde_json::from_value::<ConflictMode>(json!(promotion.conflict))
+        .map_err(|e| StoreAccessError::Internal(e.to_string()))?;
+    let claimed = decide(app, &promotion, PromotionStatus::Approved, person, None).await?;
+    let (status, action, result) = match copy(app, kek_provider, &claimed, conflict, person).await {
+        Ok(promoted) => (PromotionStatus::Applied, APPLIED, json!(promoted)),
+        Err(e) => (
+            PromotionStatus::Failed,
+            FAILED,
+            json!({ "error": e.to_string() }),
+        ),
+    };
+    let promotion = sqlx::query_as::<_, Promotion>(
+        r#"UPDATE tokaysec.promotions SET status = ($1), result = ($2) WHERE id = ($3) RETURNING *"#,
+    )
+    .bind(status.to_string())
+    .bind(&result)
+    .bind(&claimed.id)
+    .fetch_one(&app.database.inner)
+    .await?;
+    record_both(app, &promotion, person, action, result).await?;
+    Ok(promotion)
+}
+
+pub async fn approve(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    project: &str,
+    id: &str,
+    approver: &str,
+) -> Result<Promotion, StoreAccessError> {
+    let promotion = find(app, project, id).await?;
+    if promotion.requested_by == approver {
+        return Err(StoreAccessError::Forbidden(
+            "A promotion has to be approved by someone other than who requested it".to_string(),
+        ));
+    }
+    let kind = store_kind(app, &promotion.store).await?;
+    let conflict = serde_json::from_value::<ConflictMode>(json!(promotion.conflict))
+        .map_err(|e| StoreAccessError::Internal(e.to_string()))?;
+    require_project_action(app, project, approver, AccessAction::ApprovePromotion).await?;
+    require_project_action(app, &promotion.source_project, approver, kind.read()).await?;
+    require_target_actions(app, &kind, project, conflict, approver).await?;
+    apply(app, kek_provider, promotion, approver).await
+}
+
+pub async fn reject(
+    app: &App,
+    project: &str,
+    id: &str,
+    reason: Option<String>,
+    person: &str,
+) -> Result<Promotion, StoreAccessError> {
+    let promotion = find(app, project, id).await?;
+    // Whoever asked can always take it back.
+    if promotion.requested_by != person {
+        let kind = store_kind(app, &promotion.store).await?;
+        require_project_action(app, project, person, kind.update()).await?;
+    }
+    let result = json!({ "reason": reason });
+    let promotion = decide(
+        app,
+        &promotion,
+        PromotionStatus::Rejected,
+        person,
+        Some(result.clone()),
+    )
+    .await?;
+    record_both(app, &promotion, person, REJECTED, result).await?;
+    Ok(promotion)
+}
+
+// Promotions into or out of the project, newest first.
+pub async fn list(
+    app: &App,
+    project: &str,
+    status: Option<PromotionStatus>,
+) -> Result<Vec<Promotion>, StoreAccessError> {
+    Ok(sqlx::query_as::<_, Promotion>(
+        r#"SELECT * FROM tokaysec.promotions WHERE (target_project = ($1) OR source_project = ($1)) AND ($2::TEXT IS NULL OR status = ($2)) ORDER BY requested_when DESC"#,
+    )
+    .bind(&project)
+    .bind(status.map(|e| e.to_string()))
+    .fetch_all(&app.database.inner)
+    .await?)
+}
+
+pub async fn protect(
+    app: &App,
+    environment: &EnvironmentRef,
+    person: &str,
+) -> Result<ProtectedEnvironment, StoreAccessError> {
+    require_project_action(
+        app,
+        &environment.project,
+        person,
+        AccessAction::UpdateSecret,
+    )
+    .await?;
+    Ok(sqlx::query_as::<_, ProtectedEnvironment>(
+        r#"INSERT INTO tokaysec.protected_environments(project,environment,added_when,added_by) VALUES($1,$2,$3,$4) RETURNING *"#,
+    )
+    .bind(&environment.project)
+    .bind(&environment.environment)
+    .bind(Utc::now())
+    .bind(&person)
+    .fetch_one(&app.database.inner)
+    .await?)
+}
+
+pub async fn unprotect(
+    app: &App,
+    environment: &EnvironmentRef,
+    person: &str,
+) -> Result<(), StoreAccessError> {
+    require_project_action(
+        app,
+        &environment.project,
+        person,
+        AccessAction::UpdateSecret,
+    )
+    .await?;
+    let removed = sqlx::query(
+        r#"DELETE FROM tokaysec.protected_environments WHERE project = ($1) AND environment = ($2)"#,
+    )
+    .bind(&environment.project)
+    .bind(&environment.environment)
+    .execute(&app.database.inner)
+    .await?;
+    if removed.rows_affected() == 0 {
+        return Err(StoreAccessError::NotFound(format!(
+            "Environment {} of project {} isn't protected",
+            environment.environment, environment.project
+        )));
+    }
+    Ok(())
+}
+
+pub async fn protected_environments(
+    app: &App,
+    project: &str,
+) -> Result<Vec<ProtectedEnvironment>, StoreAccessError> {
+    Ok(sqlx::query_as::<_, ProtectedEnvironment>(
+        r#"SELECT * FROM tokaysec.protected_environments WHERE project = ($1) ORDER BY environment"#,
+    )
+    .bind(&project)
+    .fetch_all(&app.database.inner)
+    .await?)
+}
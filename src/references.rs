@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use serde_json::json;
+
+use crate::{
+    app::{App, ResourceTypes},
+    audit,
+    kek_provider::KekProvider,
+    models::{Namespace, Project, ResourceAssignment},
+    policies::AccessAction,
+    stores::{StoreAccessError, require_project_action},
+};
+
+/*
+
+A secret can be linked into other projects (or every project in a
+namespace) as a read-only reference instead of being copied around.
+The link is another resource_assignment of the same `<store>:<id>`
+resource, to the consuming proj:/nmsp:, with linked_from set to the
+owning project.
+
+Reading through a reference resolves to the owner's secret and goes
+through the store's normal retrieve for the owning project. The reader
+needs the read permission on both projects, so the owner's policy
+(including a deny) still applies. Every read is written to the audit
+log of both projects. Nothing can be written through a reference,
+updates and deletes only take the owning project.
+
+Deleting the secret removes every assignment of the resource, links
+included.
+
+*/
+
+pub const LINK: &str = "reference:link";
+pub const UNLINK: &str = "reference:unlink";
+pub const READ: &str = "reference:read";
+
+fn read_action(resource_type: &str) -> AccessAction {
+    if resource_type == ResourceTypes::Config.to_string() {
+        AccessAction::ReadConfig
+    } else {
+        AccessAction::ReadSecret
+    }
+}
+
+// The owning project's own assignment of the resource.
+pub async fn owner(app: &App, resource: &str) -> Result<ResourceAssignment, StoreAccessError> {
+    sqlx::query_as::<_, ResourceAssignment>(
+        r#"SELECT * FROM tokaysec.resource_assignment WHERE resource = ($1) AND assigned_to_type = 'proj' AND resource_type IN ('scrt', 'cnfg') AND linked_from IS NULL"#,
+    )
+    .bind(&resource)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .ok_or(StoreAccessError::NotFound(format!(
+        "No secret {}",
+        resource
+    )))
+}
+
+// `proj:<id>` or `nmsp:<id>`, checked to exist.
+async fn link_target(app: &App, target: &str) -> Result<(String, ResourceTypes), StoreAccessError> {
+    let invalid = || {
+        StoreAccessError::Invalid(format!(
+            "Secrets can be linked to proj:<id> or nmsp:<id>, not '{}'",
+            target
+        ))
+    };
+    let Some((kind, id)) = target.split_once(':') else {
+        return Err(invalid());
+    };
+    match ResourceTypes::try_from(kind) {
+        Ok(ResourceTypes::Project) => {
+            sqlx::query_as::<_, Project>(r#"SELECT * FROM tokaysec.projects WHERE id = ($1)"#)
+                .bind(&id)
+                .fetch_optional(&app.database.inner)
+                .await?
+                .ok_or(StoreAccessError::NotFound(format!("No project {}", id)))?;
+            Ok((id.to_string(), ResourceTypes::Project))
+        }
+        Ok(ResourceTypes::Namespace) => {
+            sqlx::query_as::<_, Namespace>(r#"SELECT * FROM tokaysec.namespaces WHERE id = ($1)"#)
+                .bind(&id)
+                .fetch_optional(&app.database.inner)
+                .await?
+                .ok_or(StoreAccessError::NotFound(format!("No namespace {}", id)))?;
+            Ok((id.to_string(), ResourceTypes::Namespace))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Linking is the owner's call, it takes share:secret on the owning
+// project.
+pub async fn link(
+    app: &App,
+    store: &str,
+    id: &str,
+    project: &str,
+    target: &str,
+    requester: &str,
+) -> Result<ResourceAssignment, StoreAccessError> {
+    let resource = format!("{}:{}", store, id);
+    let owned = owner(app, &resource).await?;
+    if owned.assigned_to != project {
+        return Err(StoreAccessError::NotFound(format!(
+            "Project {} has no secret {}",
+            project, resource
+        )));
+    }
+    require_project_action(app, project, requester, AccessAction::ShareSecret).await?;
+    let (assigned_to, assigned_to_type) = link_target(app, target).await?;
+    if assigned_to == project {
+        return Err(StoreAccessError::Invalid(
+            "A secret can't be linked into its own project".to_string(),
+        ));
+    }
+    let link = sqlx::query_as::<_, ResourceAssignment>(
+        r#"INSERT INTO tokaysec.resource_assignment(assigned_to,assigned_to_type,resource,resource_type,assigned_by,assigned_when,linked_from) VALUES($1,$2,$3,$4,$5,$6,$7) RETURNING *"#,
+    )
+    .bind(&assigned_to)
+    .bind(assigned_to_type.to_string())
+    .bind(&resource)
+    .bind(&owned.resource_type)
+    .bind(&requester)
+    .bind(Utc::now())
+    .bind(&project)
+    .fetch_one(&app.database.inner)
+    .await?;
+    audit::record(
+        app,
+        project,
+        requester,
+        LINK,
+        &resource,
+        json!({ "linked_to": target }),
+    )
+    .await?;
+    if let ResourceTypes::Project = assigned_to_type {
+        audit::record(
+            app,
+            &assigned_to,
+            requester,
+            LINK,
+            &resource,
+            json!({ "linked_from": project }),
+        )
+        .await?;
+    }
+    Ok(link)
+}
+
+pub async fn unlink(
+    app: &App,
+    store: &str,
+    id: &str,
+    project: &str,
+    target: &str,
+    requester: &str,
+) -> Result<(), StoreAccessError> {
+    let resource = format!("{}:{}", store, id);
+    require_project_action(app, project, requester, AccessAction::ShareSecret).await?;
+    let (assigned_to, assigned_to_type) = link_target(app, target).await?;
+    let removed = sqlx::query(
+        r#"DELETE FROM tokaysec.resource_assignment WHERE resource = ($1) AND assigned_to = ($2) AND assigned_to_type = ($3) AND linked_from = ($4)"#,
+    )
+    .bind(&resource)
+    .bind(&assigned_to)
+    .bind(assigned_to_type.to_string())
+    .bind(&project)
+    .execute(&app.database.inner)
+    .await?;
+    if removed.rows_affected() == 0 {
+        return Err(StoreAccessError::NotFound(format!(
+            "{} isn't linked to {}",
+            resource, target
+        )));
+    }
+    audit::record(
+        app,
+        project,
+        requester,
+        UNLINK,
+        &resource,
+        json!({ "linked_to": target }),
+    )
+    .await?;
+    if let ResourceTypes::Project = assigned_to_type {
+        audit::record(
+            app,
+            &assigned_to,
+            requester,
+            UNLINK,
+            &resource,
+            json!({ "linked_from": project }),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// Everything linked into the project, directly or through its namespace.
+pub async fn linked_into(
+    app: &App,
+    project: &str,
+    resource: Option<&str>,
+) -> Result<Vec<ResourceAssignment>, StoreAccessError> {
+    let project =
+        sqlx::query_as::<_, Project>(r#"SELECT * FROM tokaysec.projects WHERE id = ($1)"#)
+            .bind(&project)
+            .fetch_optional(&app.database.inner)
+            .await?
+            .ok_or(StoreAccessError::NotFound(format!(
+                "No project {}",
+                project
+            )))?;
+    Ok(sqlx::query_as::<_, ResourceAssignment>(
+        r#"SELECT * FROM tokaysec.resource_assignment WHERE linked_from IS NOT NULL
+        AND ((assigned_to = ($1) AND assigned_to_type = 'proj') OR (assigned_to = ($2) AND assigned_to_type = 'nmsp'))
+        AND ($3::TEXT IS NULL OR resource = ($3)) ORDER BY assigned_when"#,
+    )
+    .bind(&project.id)
+    .bind(&project.namespace)
+    .bind(&resource)
+    .fetch_all(&app.database.inner)
+    .await?)
+}
+
+// Reads the owner's secret for `project`. `query` is whatever else the
+// store's retrieve takes (version, pointer, ...), the owner, name and
+// environment come from the link.
+pub async fn read(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    project: &str,
+    store: &str,
+    id: &str,
+    mut query: HashMap<String, String>,
+    requester: &str,
+) -> Result<serde_json::Value, StoreAccessError> {
+    let resource = format!("{}:{}", store, id);
+    let Some(link) = linked_into(app, project, Some(&resource)).await?.pop() else {
+        return Err(StoreAccessError::NotFound(format!(
+            "{} isn't linked into project {}",
+            resource, project
+        )));
+    };
+    let source = link.linked_from.unwrap();
+    require_project_action(app, project, requester, read_action(&link.resource_type)).await?;
+    require_project_action(app, &source, requester, read_action(&link.resource_type)).await?;
+    let stores = app.stores.read().await;
+    let Some(secret_store) = stores.get(store) else {
+        return Err(StoreAccessError::Unavailable(format!(
+            "Store {} is not enabled",
+            store
+        )));
+    };
+    let secret = secret_store.get(app, id).await?;
+    query.insert("project".to_string(), source.to_owned());
+    query.insert("name".to_string(), secret.name);
+    query.remove("key");
+    match secret.environment {
+        Some(environment) => query.insert("environment".to_string(), environment),
+        None => query.remove("environment"),
+    };
+    let retrieved = secret_store
+        .retrieve(app, kek_provider, query, requester)
+        .await?;
+    audit::record(
+        app,
+        &source,
+        requester,
+        READ,
+        &resource,
+        json!({ "read_from": project }),
+    )
+    .await?;
+    audit::record(
+        app,
+        project,
+        requester,
+        READ,
+        &resource,
+        json!({ "linked_from": source }),
+    )
+    .await?;
+    Ok(retrieved)
+}
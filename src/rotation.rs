@@ -0,0 +1,553 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::Connection;
+use tracing::{info, warn};
+use zeroize::Zeroize;
+
+use crate::{
+    app::App,
+    events::SecretEvent,
+    models::{KVStoredValue, RotationAttempt, RotationPolicy},
+    secure_buf::SecureBuffer,
+    stores::{
+        StoreAccessError,
+        dynamic_postgres::{DynamicPostgresStore, ROLE_PASSWORD, admin_connection},
+        generate::GeneratePolicy,
+        kv::{KvStore, KvStoreSettings, purge_previous},
+    },
+};
+
+/*
+
+A rotation policy puts one kv secret on a schedule. Every
+interval_secs the rotator comes up with a new value, which is stored
+as the secret's next version. The version it replaced stays readable
+(?version=<n>) for grace_secs so whatever is still using it has time
+to move over.
+
+Rotators:
+
+    {"kind": "random", "generate": {"format": "password", "length": 40}}
+    {"kind": "postgres", "connection": "<dynamic_postgres connection>", "role": "app"}
+    {"kind": "webhook", "url": "https://rotate.internal/api-key"}
+
+random only replaces the value we hold. postgres sets a new password on
+an existing role through one of the project's dynamic_postgres
+connections. webhook POSTs the secret's id, project, environment, name
+and current version to the url and expects {"value": "<new value>"}
+back, whatever is behind it rotates the credential where it lives.
+
+Every run, scheduled or not, is recorded in rotation_attempts and the
+outcome of the last one is kept on the policy. A failed rotation is
+tried again after rotation.retry_after_secs.
+
+*/
+
+fn default_generate() -> GeneratePolicy {
+    GeneratePolicy::Password {
+        length: 32,
+        classes: Default::default(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Rotator {
+    Random {
+        #[serde(default = "default_generate")]
+        generate: GeneratePolicy,
+    },
+    Postgres {
+        // Name of a dynamic_postgres connection in the same project.
+        connection: String,
+        role: String,
+    },
+    Webhook {
+        url: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct WebhookResponse {
+    value: String,
+}
+
+pub enum RotationStatus {
+    Succeeded,
+    Failed,
+}
+
+impl ToString for RotationStatus {
+    fn to_string(&self) -> String {
+        match self {
+            RotationStatus::Succeeded => "succeeded".to_string(),
+            RotationStatus::Failed => "failed".to_string(),
+        }
+    }
+}
+
+// The role name ends up in ALTER ROLE which can't take bind
+// parameters, so only plain identifiers are accepted.
+fn valid_role(role: &str) -> bool {
+    !role.is_empty()
+        && role.len() <= 63
+        && role.chars().all(|e| e.is_ascii_alphanumeric() || e == '_')
+}
+
+impl Rotator {
+    pub async fn validate(&self, app: &App, project: &str) -> Result<(), StoreAccessError> {
+        match self {
+            // Catches out of range lengths and the like now rather
+            // than on the first rotation.
+            Rotator::Random { generate } => generate.generate().map(|_| ()),
+            Rotator::Postgres { connection, role } => {
+                if !valid_role(role) {
+                    return Err(StoreAccessError::Invalid(format!(
+                        "'{}' is not a role name we can rotate, use letters, digits and _",
+                        role
+                    )));
+                }
+                if DynamicPostgresStore::init()
+                    .await
+                    .find_by_key(app, project, connection)
+                    .await
+                    .is_none()
+                {
+                    return Err(StoreAccessError::NotFound(format!(
+                        "No postgres connection named '{}' in project {}",
+                        connection, project
+                    )));
+                }
+                Ok(())
+            }
+            Rotator::Webhook { url } => match reqwest::Url::parse(url) {
+                Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+                Ok(parsed) => Err(StoreAccessError::Invalid(format!(
+                    "Webhooks have to be http or https, not {}",
+                    parsed.scheme()
+                ))),
+                Err(e) => Err(StoreAccessError::Invalid(format!(
+                    "'{}' is not a url: {}",
+                    url, e
+                ))),
+            },
+        }
+    }
+
+    async fn new_value(&self, app: &App, secret: &KVStoredValue) -> Result<SecureBuffer, String> {
+        match self {
+            Rotator::Random { generate } => generate.generate().map_err(|e| e.to_string()),
+            Rotator::Postgres { connection, role } => {
+                let Some(connection) = DynamicPostgresStore::init()
+                    .await
+                    .find_by_key(app, &secret.project, connection)
+                    .await
+                else {
+                    return Err(format!("Postgres connection '{}' is gone", connection));
+                };
+                let password = ROLE_PASSWORD.generate().map_err(|e| e.to_string())?;
+                let mut conn = admin_connection(app, (*app.kek_provider).as_ref(), &connection.id)
+                    .await
+                    .map_err(|e| format!("Can't connect: {}", e))?;
+                // The role is changed before the new password is stored,
+                // if storing it fails the rotation is reported as failed
+                // and the next one sets a password we do keep.
+                sqlx::query(&format!(
+                    "ALTER ROLE \"{}\" WITH PASSWORD '{}'",
+                    role,
+                    std::str::from_utf8(password.expose()).unwrap()
+                ))
+                .execute(&mut conn)
+                .await
+                .map_err(|e| format!("Couldn't set the password of {}: {}", role, e))?;
+                conn.close().await.map_err(|e| e.to_string())?;
+                Ok(password)
+            }
+            Rotator::Webhook { url } => {
+                call_webhook(
+                    url,
+                    Duration::from_secs(app.rotation.webhook_timeout_secs),
+                    secret,
+                )
+                .await
+            }
+        }
+    }
+}
+
+async fn call_webhook(
+    url: &str,
+    timeout: Duration,
+    secret: &KVStoredValue,
+) -> Result<SecureBuffer, String> {
+    let response = reqwest::Client::new()
+        .post(url)
+        .timeout(timeout)
+        .json(&json!({
+            "secret": secret.id,
+            "store": secret.store,
+            "project": secret.project,
+            "environment": secret.environment,
+            "name": secret.key,
+            "version": secret.version,
+        }))
+        .send()
+        .await
+        .map_err(|e| format!("Webhook request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Webhook answered {}", response.status()));
+    }
+    let mut body = response
+        .json::<WebhookResponse>()
+        .await
+        .map_err(|e| format!("Webhook response isn't {{\"value\": ...}}: {}", e))?;
+    if body.value.is_empty() {
+        return Err("Webhook returned an empty value".to_string());
+    }
+    let value = SecureBuffer::from_slice(body.value.as_bytes()).unwrap();
+    body.value.zeroize();
+    Ok(value)
+}
+
+// `at` plus `secs`, None when that's past what chrono can represent.
+fn secs_after(at: DateTime<Utc>, secs: i64) -> Option<DateTime<Utc>> {
+    chrono::Duration::try_seconds(secs).and_then(|e| at.checked_add_signed(e))
+}
+
+// Whether `at` falls in the policy's daily window, windows running
+// past midnight wrap around.
+pub fn in_window(policy: &RotationPolicy, at: DateTime<Utc>) -> bool {
+    let (Some(start), Some(length)) = (policy.window_start, policy.window_secs) else {
+        return true;
+    };
+    let since_start = (at.time().num_seconds_from_midnight() as i64
+        - start.num_seconds_from_midnight() as i64)
+        .rem_euclid(86400);
+    since_start < length
+}
+
+// Runs the policy's rotator and stores the result as the secret's next
+// version. Errors are what gets recorded on the attempt.
+async fn rotate_secret(app: &App, policy: &RotationPolicy) -> Result<(KVStoredValue, i32), String> {
+    let secret =
+        sqlx::query_as::<_, KVStoredValue>(r#"SELECT * FROM tokaysec.kv_store WHERE id = ($1)"#)
+            .bind(&policy.secret_id)
+            .fetch_one(&app.database.inner)
+            .await
+            .map_err(|e| e.to_string())?;
+    if !app.stores.read().await.contains_key(&secret.store) {
+        return Err(format!("Store {} is not enabled", &secret.store));
+    }
+    let value = policy.rotator.new_value(app, &secret).await?;
+    let retain_until = match policy.grace_secs {
+        0 => None,
+        grace_secs => Some(
+            secs_after(Utc::now(), grace_secs)
+                .ok_or_else(|| format!("grace_secs {} is out of range", grace_secs))?,
+        ),
+    };
+    // The instance's own size limit doesn't apply, rotators pick the
+    // size of what they produce.
+    let version = KvStore::configured(&secret.store, KvStoreSettings::default())
+        .replace_value(
+            app,
+            (*app.kek_provider).as_ref(),
+            &secret,
+            value,
+            retain_until,
+            None,
+            &policy.added_by,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((secret, version))
+}
+
+// Rotates now, whether or not it's due, and records how it went.
+pub async fn rotate(app: &App, policy: &RotationPolicy) -> RotationAttempt {
+    let started_when = Utc::now();
+    let rotated = rotate_secret(app, policy).await;
+    let finished_when = Utc::now();
+    let (status, version, error, next_rotation) = match &rotated {
+        Ok((_, version)) => (
+            RotationStatus::Succeeded,
+            Some(*version),
+            None,
+            // Policies from before intervals were capped can be too far
+            // out to represent, those just never come due again.
+            secs_after(finished_when, policy.interval_secs).unwrap_or(DateTime::<Utc>::MAX_UTC),
+        ),
+        Err(e) => (
+            RotationStatus::Failed,
+            None,
+            Some(e.to_owned()),
+            secs_after(finished_when, app.rotation.retry_after_secs)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        ),
+    };
+    let attempt = sqlx::query_as::<_, RotationAttempt>(
+        r#"INSERT INTO tokaysec.rotation_attempts(id,policy,secret_id,status,version,error,started_when,finished_when) VALUES($1,$2,$3,$4,$5,$6,$7,$8) RETURNING *"#,
+    )
+    .bind(app.gen_id().await)
+    .bind(&policy.id)
+    .bind(&policy.secret_id)
+    .bind(status.to_string())
+    .bind(version)
+    .bind(&error)
+    .bind(started_when)
+    .bind(finished_when)
+    .fetch_one(&app.database.inner)
+    .await
+    .unwrap();
+    match &error {
+        None => sqlx::query(
+            r#"UPDATE tokaysec.rotation_policies SET next_rotation = ($1), last_rotated = ($2), last_status = ($3), last_error = NULL, failures = 0 WHERE id = ($4)"#,
+        )
+        .bind(next_rotation)
+        .bind(finished_when),
+        Some(e) => sqlx::query(
+            r#"UPDATE tokaysec.rotation_policies SET next_rotation = ($1), last_error = ($2), last_status = ($3), failures = failures + 1 WHERE id = ($4)"#,
+        )
+        .bind(next_rotation)
+        .bind(e),
+    }
+    .bind(status.to_string())
+    .bind(&policy.id)
+    .execute(&app.database.inner)
+    .await
+    .unwrap();
+    // Err only means there are no subscribers right now.
+    let _ = app.events.send(match rotated {
+        Ok((secret, version)) => SecretEvent::Rotated {
+            store: secret.store,
+            id: secret.id,
+            name: secret.key,
+            project: secret.project,
+            version,
+        },
+        Err(error) => SecretEvent::RotationFailed {
+            id: policy.secret_id.to_owned(),
+            project: policy.project.to_owned(),
+            error,
+        },
+    });
+    attempt
+}
+
+// Rotates whatever is due (and inside its window) and drops retained
+// versions whose grace period is over.
+pub fn spawn_rotation_scheduler(app: App) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(app.rotation.check_interval_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_previous(&app).await {
+                warn!("Failed to purge retained secret versions: {}", e);
+            }
+            let now = Utc::now();
+            let due = sqlx::query_as::<_, RotationPolicy>(
+                r#"SELECT * FROM tokaysec.rotation_policies WHERE next_rotation <= ($1) ORDER BY next_rotation"#,
+            )
+            .bind(now)
+            .fetch_all(&app.database.inner)
+            .await
+            .unwrap();
+            for policy in due.iter().filter(|e| in_window(e, now)) {
+                let attempt = rotate(&app, policy).await;
+                match attempt.error {
+                    None => info!(
+                        "Rotated secret {} in project {} to version {}",
+                        &policy.secret_id,
+                        &policy.project,
+                        attempt.version.unwrap_or_default()
+                    ),
+                    Some(e) => warn!(
+                        "Rotating secret {} in project {} failed: {}",
+                        &policy.secret_id, &policy.project, e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, Router, http::StatusCode, routing::post};
+    use chrono::{NaiveTime, TimeZone};
+
+    use super::*;
+
+    fn policy(window: Option<(u32, i64)>) -> RotationPolicy {
+        let now = Utc::now();
+        RotationPolicy {
+            id: "p1".to_string(),
+            secret_id: "s1".to_string(),
+            project: "pr1".to_string(),
+            interval_secs: 3600,
+            window_start: window.map(|(hour, _)| NaiveTime::from_hms_opt(hour, 0, 0).unwrap()),
+            window_secs: window.map(|(_, secs)| secs),
+            grace_secs: 0,
+            rotator: sqlx::types::Json(Rotator::Random {
+                generate: default_generate(),
+            }),
+            next_rotation: now,
+            last_rotated: None,
+            last_status: None,
+            last_error: None,
+            failures: 0,
+            added_when: now,
+            added_by: "admin".to_string(),
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 8, 1, hour, minute, 0).unwrap()
+    }
+
+    fn secret() -> KVStoredValue {
+        let now = Utc::now();
+        KVStoredValue {
+            id: "s1".to_string(),
+            key: "API_KEY".to_string(),
+            project: "pr1".to_string(),
+            environment: "production".to_string(),
+            value: vec![],
+            gcm_tag: vec![],
+            kmac_tag: vec![],
+            nonce: vec![],
+            dek_used: "d1".to_string(),
+            added_when: now,
+            added_by: "admin".to_string(),
+            last_updated: now,
+            expires_at: None,
+            expiry_notified_when: None,
+            store: "kv".to_string(),
+            version: 3,
+            aad_version: 1,
+        }
+    }
+
+    // Serves `router` on a free local port and hands back its url.
+    async fn mock(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}/rotate", addr)
+    }
+
+    #[test]
+    fn no_window_is_always_open() {
+        assert!(in_window(&policy(None), at(0, 0)));
+        assert!(in_window(&policy(None), at(13, 37)));
+    }
+
+    #[test]
+    fn window_covers_start_to_length() {
+        let policy = policy(Some((2, 7200)));
+        assert!(!in_window(&policy, at(1, 59)));
+        assert!(in_window(&policy, at(2, 0)));
+        assert!(in_window(&policy, at(3, 59)));
+        assert!(!in_window(&policy, at(4, 0)));
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let policy = policy(Some((23, 7200)));
+        assert!(in_window(&policy, at(23, 30)));
+        assert!(in_window(&policy, at(0, 59)));
+        assert!(!in_window(&policy, at(1, 0)));
+        assert!(!in_window(&policy, at(22, 59)));
+    }
+
+    #[test]
+    fn secs_after_rejects_what_chrono_cant_hold() {
+        assert_eq!(secs_after(at(2, 0), 3600), Some(at(3, 0)));
+        assert_eq!(secs_after(at(2, 0), i64::MAX), None);
+        assert_eq!(secs_after(DateTime::<Utc>::MAX_UTC, 1), None);
+    }
+
+    #[tokio::test]
+    async fn webhook_sends_the_secret_and_takes_the_value() {
+        let url = mock(Router::new().route(
+            "/rotate",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                assert_eq!(
+                    body,
+                    json!({
+                        "secret": "s1",
+                        "store": "kv",
+                        "project": "pr1",
+                        "environment": "production",
+                        "name": "API_KEY",
+                        "version": 3,
+                    })
+                );
+                Json(json!({ "value": "rotated" }))
+            }),
+        ))
+        .await;
+        let value = call_webhook(&url, Duration::from_secs(5), &secret())
+            .await
+            .unwrap();
+        assert_eq!(value.expose(), b"rotated");
+    }
+
+    #[tokio::test]
+    async fn webhook_errors_fail_the_rotation() {
+        let url = mock(Router::new().route(
+            "/rotate",
+            post(|| async { (StatusCode::BAD_GATEWAY, "upstream down") }),
+        ))
+        .await;
+        let error = call_webhook(&url, Duration::from_secs(5), &secret())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error, "Webhook answered 502 Bad Gateway");
+    }
+
+    #[tokio::test]
+    async fn webhook_has_to_return_a_value() {
+        let url =
+            mock(Router::new().route("/rotate", post(|| async { Json(json!({ "value": "" })) })))
+                .await;
+        let error = call_webhook(&url, Duration::from_secs(5), &secret())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error, "Webhook returned an empty value");
+
+        let url = mock(Router::new().route(
+            "/rotate",
+            post(|| async { Json(json!({ "password": "rotated" })) }),
+        ))
+        .await;
+        let error = call_webhook(&url, Duration::from_secs(5), &secret())
+            .await
+            .err()
+            .unwrap();
+        assert!(error.starts_with("Webhook response isn't"));
+    }
+
+    #[tokio::test]
+    async fn webhook_times_out() {
+        let url = mock(Router::new().route(
+            "/rotate",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Json(json!({ "value": "late" }))
+            }),
+        ))
+        .await;
+        let error = call_webhook(&url, Duration::from_millis(200), &secret())
+            .await
+            .err()
+            .unwrap();
+        assert!(error.starts_with("Webhook request failed"));
+    }
+}
@@ -0,0 +1,210 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Multipart, Query, State, multipart::Field},
+    http::{HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use reqwest::StatusCode;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    routes::stores::access_error_status,
+    stores::{
+        StoreAccessError,
+        blob::{BLOB_STORE, BlobStore, BlobWriter},
+        kv::DEFAULT_ENVIRONMENT,
+    },
+};
+
+fn error_response(e: StoreAccessError) -> Response {
+    (
+        access_error_status(&e),
+        json!({ "error": e.to_string() }).to_string(),
+    )
+        .into_response()
+}
+
+fn invalid(message: &str) -> StoreAccessError {
+    StoreAccessError::Invalid(message.to_string())
+}
+
+// The routes talk to BlobStore directly, they still have to go away
+// when the store is disabled in Config.toml.
+async fn blob_store_enabled(app: &App) -> Result<(), StoreAccessError> {
+    if !app.stores.read().await.contains_key(BLOB_STORE) {
+        return Err(StoreAccessError::NotFound(
+            "The blob store is not enabled".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Only used in a quoted header value, keep it to something that can't
+// break out of the quotes.
+fn safe_filename(filename: &str) -> String {
+    filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect()
+}
+
+// The non file fields are short identifiers. Cap them so a huge one
+// can't be buffered into memory before the file even starts.
+const MAX_TEXT_FIELD_SIZE: usize = 4 * 1024;
+
+async fn read_text_field(field: &mut Field<'_>) -> Result<String, StoreAccessError> {
+    let mut buf = Vec::new();
+    while let Some(bytes) = field.chunk().await.map_err(|e| invalid(&e.to_string()))? {
+        if buf.len() + bytes.len() > MAX_TEXT_FIELD_SIZE {
+            return Err(StoreAccessError::TooLarge(format!(
+                "Form fields are limited to {} bytes",
+                MAX_TEXT_FIELD_SIZE
+            )));
+        }
+        buf.extend_from_slice(&bytes);
+    }
+    String::from_utf8(buf).map_err(|_| invalid("Form fields have to be valid UTF-8"))
+}
+
+// multipart/form-data with `project`, `name` and optionally
+// `environment` fields, followed by a `file` field. The file is
+// encrypted as it streams in so it has to come last.
+pub async fn upload(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    mut multipart: Multipart,
+) -> Response {
+    if let Err(e) = blob_store_enabled(&app).await {
+        return error_response(e);
+    }
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    let blob_store = BlobStore::init().await;
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut writer: Option<BlobWriter> = None;
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return error_response(invalid(&e.to_string())),
+        };
+        let field_name = field.name().unwrap_or_default().to_string();
+        if field_name != "file" {
+            match read_text_field(&mut field).await {
+                Ok(text) => fields.insert(field_name, text),
+                Err(e) => return error_response(e),
+            };
+            continue;
+        }
+        if writer.is_some() {
+            return error_response(invalid("Only one file can be uploaded at a time"));
+        }
+        let (Some(project), Some(name)) = (fields.get("project"), fields.get("name")) else {
+            return error_response(invalid("project and name have to be sent before the file"));
+        };
+        let environment = fields
+            .get("environment")
+            .map(|e| e.as_str())
+            .unwrap_or(DEFAULT_ENVIRONMENT);
+        let mut blob_writer = match blob_store
+            .begin_upload(
+                &app,
+                kek_provider,
+                project,
+                environment,
+                name,
+                field.file_name().map(|e| e.to_string()),
+                field
+                    .content_type()
+                    .unwrap_or("application/octet-stream")
+                    .to_string(),
+                &admin_id,
+            )
+            .await
+        {
+            Ok(blob_writer) => blob_writer,
+            Err(e) => return error_response(e),
+        };
+        loop {
+            match field.chunk().await {
+                Ok(Some(bytes)) => {
+                    if let Err(e) = blob_writer.write(&bytes).await {
+                        return error_response(e);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => return error_response(invalid(&e.to_string())),
+            }
+        }
+        writer = Some(blob_writer);
+    }
+    let Some(writer) = writer else {
+        return error_response(invalid("No file field in the upload"));
+    };
+    let blob = writer.finish(&app).await;
+    (
+        StatusCode::OK,
+        json!({
+            "id": blob.id,
+            "name": blob.key,
+            "environment": blob.environment,
+            "filename": blob.filename,
+            "content_type": blob.content_type,
+            "size": blob.size,
+        })
+        .to_string(),
+    )
+        .into_response()
+}
+
+// ?project=<id>&name=<key>[&environment=<env>]
+pub async fn download(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    if let Err(e) = blob_store_enabled(&app).await {
+        return error_response(e);
+    }
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    let (Some(project), Some(name)) = (query.get("project"), query.get("name")) else {
+        return error_response(invalid("project and name are required"));
+    };
+    let environment = query
+        .get("environment")
+        .map(|e| e.as_str())
+        .unwrap_or(DEFAULT_ENVIRONMENT);
+    let blob_store = BlobStore::init().await;
+    let (blob, reader) = match blob_store
+        .open_download(&app, kek_provider, project, environment, name, &admin_id)
+        .await
+    {
+        Ok(opened) => opened,
+        Err(e) => return error_response(e),
+    };
+    let content_type = HeaderValue::from_str(&blob.content_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    let filename = safe_filename(blob.filename.as_deref().unwrap_or(&blob.key));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, blob.size)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(Body::from_stream(reader.into_stream()))
+        .unwrap()
+}
@@ -0,0 +1,135 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    bulk::{BulkFormat, ConflictMode},
+    routes::stores::access_error_status,
+    stores::{
+        StoreAccessError,
+        kv::{DEFAULT_ENVIRONMENT, KV_STORE},
+    },
+};
+
+fn default_store() -> String {
+    KV_STORE.to_string()
+}
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub format: BulkFormat,
+    #[serde(default)]
+    pub conflict: ConflictMode,
+    #[serde(default = "default_store")]
+    pub store: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: BulkFormat,
+    #[serde(default = "default_store")]
+    pub store: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+fn error_response(e: StoreAccessError) -> Response {
+    (
+        access_error_status(&e),
+        json!({ "error": e.to_string() }).to_string(),
+    )
+        .into_response()
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+fn not_kv(store: &str) -> StoreAccessError {
+    StoreAccessError::NotFound(format!("No kv store named '{}' is enabled", store))
+}
+
+// The file is the request body as is,
+// ?format=dotenv|json|yaml[&conflict=skip|overwrite|fail][&environment=<env>][&store=<kv instance>]
+pub async fn import(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(query): Query<ImportQuery>,
+    contents: Bytes,
+) -> Response {
+    let admin_id = admin_id(&app).await;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let entries = match query.format.parse(&contents) {
+        Ok(entries) => entries,
+        Err(e) => return error_response(StoreAccessError::Invalid(e)),
+    };
+    let stores = app.stores.read().await;
+    let Some(kv) = stores.get(&query.store).and_then(|e| e.as_kv()) else {
+        return error_response(not_kv(&query.store));
+    };
+    match kv
+        .import(
+            &app,
+            kek_provider,
+            &project,
+            &query.environment,
+            entries,
+            query.conflict,
+            &admin_id,
+        )
+        .await
+    {
+        Ok(imported) => (StatusCode::OK, json!(imported).to_string()).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+// ?format=dotenv|json|yaml[&environment=<env>][&store=<kv instance>]
+pub async fn export(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let admin_id = admin_id(&app).await;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let stores = app.stores.read().await;
+    let Some(kv) = stores.get(&query.store).and_then(|e| e.as_kv()) else {
+        return error_response(not_kv(&query.store));
+    };
+    let entries = match kv
+        .export(&app, kek_provider, &project, &query.environment, &admin_id)
+        .await
+    {
+        Ok(entries) => entries,
+        Err(e) => return error_response(e),
+    };
+    match query.format.render(&entries) {
+        Ok(rendered) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, query.format.content_type())],
+            rendered,
+        )
+            .into_response(),
+        Err(e) => error_response(StoreAccessError::Internal(e)),
+    }
+}
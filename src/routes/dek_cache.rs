@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    response::IntoResponse,
+};
+use reqwest::StatusCode;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    dek_cache::{self, ProjectDekCacheRequest},
+    routes::stores::access_error_status,
+    stores::StoreAccessError,
+};
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+// Hit/miss counters and what is in the cache right now.
+pub async fn dek_cache_stats(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let stats = dek_cache::stats(&app, &admin_id).await?;
+            Ok(json!(stats))
+        }
+        .await,
+    )
+}
+
+pub async fn flush_dek_cache(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let flushed = dek_cache::flush(&app, &admin_id).await?;
+            Ok(json!({ "flushed": flushed }))
+        }
+        .await,
+    )
+}
+
+pub async fn project_dek_cache(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let status = dek_cache::project_status(&app, &project, &admin_id).await?;
+            Ok(json!(status))
+        }
+        .await,
+    )
+}
+
+pub async fn set_project_dek_cache(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(req): Json<ProjectDekCacheRequest>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let status = dek_cache::set_project(&app, &project, req, &admin_id).await?;
+            Ok(json!(status))
+        }
+        .await,
+    )
+}
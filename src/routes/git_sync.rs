@@ -0,0 +1,216 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, Query, State},
+    response::IntoResponse,
+};
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    git_sync::{self, validate_file, validate_repository},
+    models::GitSync,
+    policies::AccessAction,
+    routes::stores::access_error_status,
+    stores::{StoreAccessError, kv::DEFAULT_ENVIRONMENT, require_project_action},
+};
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+fn default_branch() -> String {
+    "main".to_string()
+}
+fn default_file() -> String {
+    "config.toml".to_string()
+}
+
+#[derive(Deserialize)]
+pub struct ConfigureGitSync {
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    // Absolute local path or file:// URL.
+    pub repository: String,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    #[serde(default = "default_file")]
+    pub file: String,
+}
+
+#[derive(Deserialize)]
+pub struct GitSyncTarget {
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportRequest {
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn find_sync(
+    app: &App,
+    project: &str,
+    environment: &str,
+) -> Result<GitSync, StoreAccessError> {
+    sqlx::query_as::<_, GitSync>(
+        r#"SELECT * FROM tokaysec.git_sync WHERE project = ($1) AND environment = ($2)"#,
+    )
+    .bind(&project)
+    .bind(&environment)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .ok_or(StoreAccessError::NotFound(format!(
+        "Project {} has no git sync for {}",
+        project, environment
+    )))
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+pub async fn list_git_syncs(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadConfig).await?;
+            let syncs = sqlx::query_as::<_, GitSync>(
+                r#"SELECT * FROM tokaysec.git_sync WHERE project = ($1) ORDER BY environment"#,
+            )
+            .bind(&project)
+            .fetch_all(&app.database.inner)
+            .await?;
+            Ok(serde_json::to_value(&syncs).unwrap())
+        }
+        .await,
+    )
+}
+
+// One sync per environment, configuring it again points it somewhere
+// else and starts over from that branch's head.
+pub async fn configure_git_sync(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(req): Json<ConfigureGitSync>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            // Pulls create and update config as whoever set this up.
+            require_project_action(&app, &project, &admin_id, AccessAction::CreateConfig).await?;
+            require_project_action(&app, &project, &admin_id, AccessAction::UpdateConfig).await?;
+            validate_repository(&req.repository).map_err(StoreAccessError::Invalid)?;
+            validate_file(&req.file).map_err(StoreAccessError::Invalid)?;
+            if !git2::Reference::is_valid_name(&format!("refs/heads/{}", &req.branch)) {
+                return Err(StoreAccessError::Invalid(format!(
+                    "'{}' is not a valid branch name",
+                    &req.branch
+                )));
+            }
+            if let Ok(existing) = find_sync(&app, &project, &req.environment).await {
+                git_sync::remove_mirror(&app, &existing).await;
+            }
+            let sync = sqlx::query_as::<_, GitSync>(
+                r#"INSERT INTO tokaysec.git_sync(id,project,environment,repository,branch,file,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8)
+                ON CONFLICT (project, environment) DO UPDATE SET repository = EXCLUDED.repository, branch = EXCLUDED.branch, file = EXCLUDED.file,
+                last_commit = NULL, last_synced = NULL, added_when = EXCLUDED.added_when, added_by = EXCLUDED.added_by RETURNING *"#,
+            )
+            .bind(app.gen_id().await)
+            .bind(&project)
+            .bind(&req.environment)
+            .bind(&req.repository)
+            .bind(&req.branch)
+            .bind(&req.file)
+            .bind(Utc::now())
+            .bind(&admin_id)
+            .fetch_one(&app.database.inner)
+            .await?;
+            Ok(serde_json::to_value(&sync).unwrap())
+        }
+        .await,
+    )
+}
+
+// ?environment=<env>
+pub async fn remove_git_sync(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(target): Query<GitSyncTarget>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::UpdateConfig).await?;
+            let sync = find_sync(&app, &project, &target.environment).await?;
+            sqlx::query(r#"DELETE FROM tokaysec.git_sync WHERE id = ($1)"#)
+                .bind(&sync.id)
+                .execute(&app.database.inner)
+                .await?;
+            git_sync::remove_mirror(&app, &sync).await;
+            Ok(json!({ "id": sync.id, "deleted": true }))
+        }
+        .await,
+    )
+}
+
+// Pulls right away instead of waiting for the next poll.
+pub async fn pull_git_sync(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(target): Json<GitSyncTarget>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::UpdateConfig).await?;
+            let sync = find_sync(&app, &project, &target.environment).await?;
+            let pulled = git_sync::pull(&app, &sync).await?;
+            Ok(serde_json::to_value(&pulled).unwrap())
+        }
+        .await,
+    )
+}
+
+pub async fn export_git_sync(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(req): Json<ExportRequest>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let sync = find_sync(&app, &project, &req.environment).await?;
+            let exported = git_sync::export(&app, &sync, req.message, &admin_id).await?;
+            Ok(serde_json::to_value(&exported).unwrap())
+        }
+        .await,
+    )
+}
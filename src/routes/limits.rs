@@ -0,0 +1,251 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{
+    Json,
+    body::{Body, to_bytes},
+    extract::{ConnectInfo, Path, Request, State},
+    http::header::CONTENT_LENGTH,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    limits::{self, LimitScope, Limits},
+    routes::stores::access_error_status,
+    stores::StoreAccessError,
+};
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+async fn get_limits(app: App, scope: LimitScope) -> (StatusCode, String) {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let limits = limits::get(&app, &scope, &admin_id).await?;
+            Ok(json!(limits))
+        }
+        .await,
+    )
+}
+
+async fn set_limits(app: App, scope: LimitScope, req: Limits) -> (StatusCode, String) {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let limits = limits::set(&app, &scope, req, &admin_id).await?;
+            Ok(json!(limits))
+        }
+        .await,
+    )
+}
+
+async fn clear_limits(app: App, scope: LimitScope) -> (StatusCode, String) {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            limits::clear(&app, &scope, &admin_id).await?;
+            Ok(json!({}))
+        }
+        .await,
+    )
+}
+
+pub async fn instance_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    get_limits(app, LimitScope::Instance).await
+}
+
+pub async fn set_instance_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<Limits>,
+) -> impl IntoResponse {
+    set_limits(app, LimitScope::Instance, req).await
+}
+
+pub async fn clear_instance_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    clear_limits(app, LimitScope::Instance).await
+}
+
+pub async fn namespace_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(namespace): Path<String>,
+) -> impl IntoResponse {
+    get_limits(app, LimitScope::Namespace(namespace)).await
+}
+
+pub async fn set_namespace_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(namespace): Path<String>,
+    Json(req): Json<Limits>,
+) -> impl IntoResponse {
+    set_limits(app, LimitScope::Namespace(namespace), req).await
+}
+
+pub async fn clear_namespace_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(namespace): Path<String>,
+) -> impl IntoResponse {
+    clear_limits(app, LimitScope::Namespace(namespace)).await
+}
+
+pub async fn project_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    get_limits(app, LimitScope::Project(project)).await
+}
+
+pub async fn set_project_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(req): Json<Limits>,
+) -> impl IntoResponse {
+    set_limits(app, LimitScope::Project(project), req).await
+}
+
+pub async fn clear_project_limits(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    clear_limits(app, LimitScope::Project(project)).await
+}
+
+// Consumption next to the limits that apply to it.
+pub async fn project_usage(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let usage = limits::usage(&app, &project, &admin_id).await?;
+            Ok(json!(usage))
+        }
+        .await,
+    )
+}
+
+#[derive(Deserialize)]
+struct ProjectField {
+    project: Option<String>,
+}
+
+// Where a request says it is going: /projects/{project}/..,
+// /namespaces/{namespace}/.., ?project= or a JSON body's "project".
+fn request_scope(path: &str, query: Option<&str>, body: &[u8]) -> LimitScope {
+    let path = path.strip_prefix("/v1").unwrap_or(path);
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("projects"), Some(project)) if !project.is_empty() => {
+            return LimitScope::Project(project.to_string());
+        }
+        (Some("namespaces"), Some(namespace)) if !namespace.is_empty() => {
+            return LimitScope::Namespace(namespace.to_string());
+        }
+        _ => {}
+    }
+    if let Some(project) = query
+        .and_then(|q| serde_urlencoded::from_str::<HashMap<String, String>>(q).ok())
+        .and_then(|mut q| q.remove("project"))
+    {
+        return LimitScope::Project(project);
+    }
+    match serde_json::from_slice::<ProjectField>(body) {
+        Ok(ProjectField {
+            project: Some(project),
+        }) => LimitScope::Project(project),
+        _ => LimitScope::Instance,
+    }
+}
+
+async fn request_limit(app: &App, scope: &LimitScope) -> Result<i64, StoreAccessError> {
+    let limits = match scope {
+        LimitScope::Project(project) => match limits::effective(app, project).await {
+            Ok(limits) => limits,
+            // Unknown projects are for the handler to turn down, until
+            // then they get what the instance allows.
+            Err(StoreAccessError::NotFound(_)) => limits::for_namespace(app, None).await?,
+            Err(e) => return Err(e),
+        },
+        LimitScope::Namespace(namespace) => limits::for_namespace(app, Some(namespace)).await?,
+        LimitScope::Instance => limits::for_namespace(app, None).await?,
+    };
+    Ok(limits.request_bytes())
+}
+
+fn too_large(max_request_bytes: i64) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        json!({
+            "error": format!("Request bodies can't be larger than {} bytes", max_request_bytes)
+        })
+        .to_string(),
+    )
+        .into_response()
+}
+
+// Reads the body up to the largest limit anyone has, then holds it to
+// the limit of the project (or namespace) it is for. Routes behind
+// this have axum's own body limit turned off.
+pub async fn enforce_request_size(
+    State(app): State<App>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ceiling = match limits::request_ceiling(&app).await {
+        Ok(ceiling) => ceiling,
+        Err(e) => return respond(Err(e)).into_response(),
+    };
+    let (parts, body) = request.into_parts();
+    let announced = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|e| e.to_str().ok())
+        .and_then(|e| e.parse::<i64>().ok());
+    if announced.is_some_and(|e| e > ceiling) {
+        return too_large(ceiling);
+    }
+    let Ok(body) = to_bytes(body, ceiling as usize).await else {
+        return too_large(ceiling);
+    };
+    let scope = request_scope(parts.uri.path(), parts.uri.query(), &body);
+    let max_request_bytes = match request_limit(&app, &scope).await {
+        Ok(max_request_bytes) => max_request_bytes,
+        Err(e) => return respond(Err(e)).into_response(),
+    };
+    if body.len() as i64 > max_request_bytes {
+        return too_large(max_request_bytes);
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
@@ -1,6 +1,8 @@
 use axum::{
     Router,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
 };
 use axum_client_ip::ClientIpSource;
 use reqwest::Method;
@@ -8,8 +10,31 @@ use reqwest::Method;
 use crate::{
     app::App,
     routes::{
-        projects::{list_namespace_projects, list_namespaces, load_secrets},
-        stores::{retrieve, store, ui_reqs},
+        blobs::{download, upload},
+        bulk::{export, import},
+        dek_cache::{dek_cache_stats, flush_dek_cache, project_dek_cache, set_project_dek_cache},
+        git_sync::{
+            configure_git_sync, export_git_sync, list_git_syncs, pull_git_sync, remove_git_sync,
+        },
+        limits::{
+            clear_instance_limits, clear_namespace_limits, clear_project_limits,
+            enforce_request_size, instance_limits, namespace_limits, project_limits,
+            project_usage, set_instance_limits, set_namespace_limits, set_project_limits,
+        },
+        projects::{
+            expiring_secrets, list_namespace_projects, list_namespaces, load_secrets, tag_secret,
+        },
+        promotion::{
+            approve_promotion, diff_environments, list_promotions, list_protected_environments,
+            promote, protect_environment, reject_promotion, unprotect_environment,
+        },
+        references::{audit_log, link_reference, list_references, read_reference, unlink_reference},
+        rotation::{
+            configure_rotation, list_rotations, remove_rotation, rotate_now, rotation_attempts,
+        },
+        share::{create_share, open_share},
+        stores::{list, list_stores, remove, retrieve, store, ui_reqs, update},
+        transit::{create_key, datakey, decrypt, encrypt, rewrap, rotate_key},
     },
 };
 use tower_http::{
@@ -17,27 +42,128 @@ use tower_http::{
     trace::TraceLayer,
 };
 
+pub mod blobs;
+pub mod bulk;
+pub mod dek_cache;
+pub mod git_sync;
+pub mod limits;
 pub mod projects;
+pub mod promotion;
+pub mod references;
+pub mod rotation;
+pub mod share;
 pub mod stores;
+pub mod transit;
 
 pub async fn generate_routers(app: App) -> Router {
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_origin(tower_http::cors::Any)
         .allow_headers(tower_http::cors::Any);
 
     let stores = Router::new()
         .route("/{store}", post(store))
         .route("/{store}", get(retrieve))
+        .route("/{store}", put(update))
+        .route("/{store}", delete(remove))
+        .route("/{store}/list", get(list))
         .route("/{store}/uireqs", get(ui_reqs));
-    let projects = Router::new().route("/secrets", get(load_secrets));
+    let projects = Router::new()
+        .route("/secrets", get(load_secrets))
+        .route("/secrets/expiring", get(expiring_secrets))
+        .route("/secrets/tags", put(tag_secret))
+        .route("/secrets/import", post(import))
+        .route("/secrets/export", get(export))
+        .route(
+            "/git_sync",
+            get(list_git_syncs)
+                .post(configure_git_sync)
+                .delete(remove_git_sync),
+        )
+        .route("/git_sync/pull", post(pull_git_sync))
+        .route("/git_sync/export", post(export_git_sync))
+        .route(
+            "/rotation",
+            get(list_rotations)
+                .post(configure_rotation)
+                .delete(remove_rotation),
+        )
+        .route("/rotation/rotate", post(rotate_now))
+        .route("/rotation/attempts", get(rotation_attempts))
+        .route("/references", get(list_references))
+        .route("/references/{store}/{id}", get(read_reference))
+        .route("/audit", get(audit_log))
+        .route(
+            "/limits",
+            get(project_limits)
+                .put(set_project_limits)
+                .delete(clear_project_limits),
+        )
+        .route("/usage", get(project_usage))
+        .route(
+            "/dek-cache",
+            get(project_dek_cache).put(set_project_dek_cache),
+        )
+        .route("/promotions", get(list_promotions))
+        .route("/promotions/{id}/approve", post(approve_promotion))
+        .route("/promotions/{id}/reject", post(reject_promotion))
+        .route("/environments/protected", get(list_protected_environments))
+        .route(
+            "/environments/{environment}/protection",
+            put(protect_environment).delete(unprotect_environment),
+        );
     let namespaces = Router::new()
         .route("/", get(list_namespaces))
-        .route("/{namespace}/projects", get(list_namespace_projects));
+        .route("/{namespace}/projects", get(list_namespace_projects))
+        .route(
+            "/{namespace}/limits",
+            get(namespace_limits)
+                .put(set_namespace_limits)
+                .delete(clear_namespace_limits),
+        );
+    let transit = Router::new()
+        .route("/{key}", post(create_key))
+        .route("/{key}/rotate", post(rotate_key))
+        .route("/{key}/encrypt", post(encrypt))
+        .route("/{key}/decrypt", post(decrypt))
+        .route("/{key}/rewrap", post(rewrap))
+        .route("/{key}/datakey", post(datakey));
+    let references = Router::new().route("/", post(link_reference).delete(unlink_reference));
+    let promotions = Router::new()
+        .route("/", post(promote))
+        .route("/diff", post(diff_environments));
+    let shares = Router::new()
+        .route("/", post(create_share))
+        .route("/open", post(open_share));
+    // Multipart uploads go past axum's default 2MB body limit, the
+    // blob store enforces its own maximum as the file streams in.
+    let blobs = Router::new()
+        .route("/", post(upload).get(download))
+        .layer(DefaultBodyLimit::disable());
+    // Request sizes are up to limits.rs for everything but blobs, which
+    // are nested after the layers so they don't go through them.
     let v1 = Router::new()
+        .route("/stores", get(list_stores))
+        .route(
+            "/limits",
+            get(instance_limits)
+                .put(set_instance_limits)
+                .delete(clear_instance_limits),
+        )
+        .route("/dek-cache", get(dek_cache_stats).delete(flush_dek_cache))
         .nest("/store", stores)
+        .nest("/transit", transit)
+        .nest("/shares", shares)
+        .nest("/references", references)
+        .nest("/promotions", promotions)
         .nest("/projects/{project}", projects)
-        .nest("/namespaces", namespaces);
+        .nest("/namespaces", namespaces)
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            enforce_request_size,
+        ))
+        .layer(DefaultBodyLimit::disable())
+        .nest("/blobs", blobs);
     let global_router = Router::new()
         .nest("/v1", v1)
         .layer(TraceLayer::new_for_http())
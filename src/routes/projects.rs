@@ -11,8 +11,11 @@ use sqlx::Value;
 
 use crate::{
     app::App,
-    models::{Namespace, Project, ResourceAssignment},
-    stores::RetrievedSecretData,
+    listing::{self, SecretSearch, SetTags},
+    models::{KVStoredValue, Namespace, Project},
+    policies::AccessAction,
+    routes::stores::access_error_status,
+    stores::{StoreAccessError, require_project_action},
 };
 /*
 justin@Mac tokaysecapp % curl http://localhost:2323/v1/namespaces
@@ -20,6 +23,22 @@ justin@Mac tokaysecapp % curl http://localhost:2323/v1/namespaces
 justin@Mac tokaysecapp % curl http://localhost:2323/v1/namespaces/7352140924266221570/projects
 [{"id":"7352141003882500096","name":"default_projcet","kek_id":"7352140924433993728","namespace":"7352140924266221570","added_when":"2025-07-19T01:03:31.500473Z"},{"id":"7352141083272286208","name":"top_secret_project","kek_id":"7352141004062855168","namespace":"7352140924266221570","added_when":"2025-07-19T01:03:50.428022Z"}]%     
 */
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
 pub async fn list_namespaces(
     State(app): State<App>,
     ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
@@ -45,34 +64,88 @@ pub async fn list_namespace_projects(
     (StatusCode::OK, serde_json::to_string(&projects).unwrap())
 }
 
+// ?cursor=&limit=&prefix=&search=&store=&tags=a,b&created_by=&updated_since=<rfc3339>
 pub async fn load_secrets(
     State(app): State<App>,
     ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
     Path(project): Path<String>,
+    Query(search): Query<SecretSearch>,
 ) -> impl IntoResponse {
-    let mut secrets: Vec<serde_json::Value> = vec![];
-    let assignees = sqlx::query_as::<_, ResourceAssignment>(
-        r#"SELECT * FROM tokaysec.resource_assignment WHERE assigned_to = ($1) AND assigned_to_type = 'proj' AND resource_type = 'scrt'"#
-    ).bind(&project).fetch_all(&app.database.inner).await.unwrap();
-    let stores = app.stores.clone();
-    let stores_read = stores.read().await;
-    for assignee in assignees {
-        let split = assignee.resource.split(":").collect::<Vec<&str>>();
-        let (store, id) = if let Some(store) = split.get(0)
-            && let Some(id) = split.get(1)
-        {
-            (*store, *id)
-        } else {
-            continue;
-        };
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let page = listing::list_secrets(&app, &project, &search, &admin_id).await?;
+            Ok(serde_json::to_value(&page).unwrap())
+        }
+        .await,
+    )
+}
 
-        let _store = stores_read.get(store).unwrap().to_owned();
-        let stored_data = _store.get(&app, &id).await;
-        secrets.push(json!({
-            "id": stored_data.id,
-            "name": stored_data.name,
-            "store_used": store
-        }));
-    }
-    (StatusCode::OK, serde_json::to_string(&secrets).unwrap())
+pub async fn tag_secret(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(request): Json<SetTags>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let tags = listing::set_tags(&app, &project, &request, &admin_id).await?;
+            Ok(json!({ "tags": tags }))
+        }
+        .await,
+    )
+}
+
+// How far ahead ?days= can look.
+const MAX_EXPIRING_DAYS: i64 = 3650;
+
+pub async fn expiring_secrets(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let days = match query.get("days") {
+                None => 30,
+                Some(days) => days
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|e| (0..=MAX_EXPIRING_DAYS).contains(e))
+                    .ok_or(StoreAccessError::Invalid(format!(
+                        "days must be between 0 and {}",
+                        MAX_EXPIRING_DAYS
+                    )))?,
+            };
+            let now = chrono::Utc::now();
+            let expiring = sqlx::query_as::<_, KVStoredValue>(
+                r#"SELECT * FROM tokaysec.kv_store WHERE project = ($1) AND expires_at IS NOT NULL AND expires_at <= ($2)
+                ORDER BY expires_at ASC"#,
+            )
+            .bind(&project)
+            .bind(now + chrono::Duration::days(days))
+            .fetch_all(&app.database.inner)
+            .await?;
+            let report = expiring
+                .into_iter()
+                .map(|e| {
+                    json!({
+                        "id": e.id,
+                        "name": e.key,
+                        "environment": e.environment,
+                        "store_used": e.store,
+                        "expires_at": e.expires_at,
+                        "expired": e.expires_at.is_some_and(|at| at <= now),
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>();
+            Ok(json!(report))
+        }
+        .await,
+    )
 }
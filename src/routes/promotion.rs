@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, Query, State},
+    response::IntoResponse,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    policies::AccessAction,
+    promotion::{self, DiffRequest, EnvironmentRef, PromoteRequest, PromotionStatus},
+    routes::stores::access_error_status,
+    stores::{StoreAccessError, require_project_action},
+};
+
+#[derive(Deserialize)]
+pub struct PromotionSearch {
+    #[serde(default)]
+    pub status: Option<PromotionStatus>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct RejectRequest {
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+pub async fn diff_environments(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<DiffRequest>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let diff = promotion::diff(&app, kek_provider, req, &admin_id).await?;
+            Ok(json!(diff))
+        }
+        .await,
+    )
+}
+
+pub async fn promote(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<PromoteRequest>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let promotion = promotion::promote(&app, kek_provider, req, &admin_id).await?;
+            Ok(json!(promotion))
+        }
+        .await,
+    )
+}
+
+// ?status=pending|approved|applied|rejected|failed
+pub async fn list_promotions(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(search): Query<PromotionSearch>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let promotions = promotion::list(&app, &project, search.status).await?;
+            Ok(json!(promotions))
+        }
+        .await,
+    )
+}
+
+// Routes all act as the admin account for now, so this refuses
+// promotions requested through the API until callers are told apart.
+pub async fn approve_promotion(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path((project, id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let promotion =
+                promotion::approve(&app, kek_provider, &project, &id, &admin_id).await?;
+            Ok(json!(promotion))
+        }
+        .await,
+    )
+}
+
+pub async fn reject_promotion(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path((project, id)): Path<(String, String)>,
+    req: Option<Json<RejectRequest>>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    let Json(req) = req.unwrap_or_default();
+    respond(
+        async {
+            let promotion = promotion::reject(&app, &project, &id, req.reason, &admin_id).await?;
+            Ok(json!(promotion))
+        }
+        .await,
+    )
+}
+
+pub async fn list_protected_environments(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let protected = promotion::protected_environments(&app, &project).await?;
+            Ok(json!(protected))
+        }
+        .await,
+    )
+}
+
+pub async fn protect_environment(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path((project, environment)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let protected = promotion::protect(
+                &app,
+                &EnvironmentRef {
+                    project,
+                    environment,
+                },
+                &admin_id,
+            )
+            .await?;
+            Ok(json!(protected))
+        }
+        .await,
+    )
+}
+
+pub async fn unprotect_environment(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path((project, environment)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            promotion::unprotect(
+                &app,
+                &EnvironmentRef {
+                    project,
+                    environment,
+                },
+                &admin_id,
+            )
+            .await?;
+            Ok(json!({}))
+        }
+        .await,
+    )
+}
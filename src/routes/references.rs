@@ -0,0 +1,139 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, Query, State},
+    response::IntoResponse,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    audit::{self, Audit},
+    policies::AccessAction,
+    references,
+    routes::stores::access_error_status,
+    stores::{StoreAccessError, require_project_action},
+};
+
+// The owning project's secret and where it goes, link_to being
+// proj:<id> or nmsp:<id>.
+#[derive(Deserialize)]
+pub struct ReferenceRequest {
+    pub store: String,
+    pub id: String,
+    pub project: String,
+    pub link_to: String,
+}
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+pub async fn link_reference(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<ReferenceRequest>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            let link = references::link(
+                &app,
+                &req.store,
+                &req.id,
+                &req.project,
+                &req.link_to,
+                &admin_id,
+            )
+            .await?;
+            Ok(serde_json::to_value(&link).unwrap())
+        }
+        .await,
+    )
+}
+
+// ?store=<store>&id=<id>&project=<owner>&link_to=<proj:|nmsp:>
+pub async fn unlink_reference(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Query(req): Query<ReferenceRequest>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            references::unlink(
+                &app,
+                &req.store,
+                &req.id,
+                &req.project,
+                &req.link_to,
+                &admin_id,
+            )
+            .await?;
+            Ok(json!({ "deleted": true }))
+        }
+        .await,
+    )
+}
+
+pub async fn list_references(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let links = references::linked_into(&app, &project, None).await?;
+            Ok(serde_json::to_value(&links).unwrap())
+        }
+        .await,
+    )
+}
+
+// Anything else in the query goes to the owning store's retrieve.
+pub async fn read_reference(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path((project, store, id)): Path<(String, String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(references::read(&app, kek_provider, &project, &store, &id, query, &admin_id).await)
+}
+
+// ?cursor=<next_cursor>&limit=<n>, newest first.
+pub async fn audit_log(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(query): Query<Audit>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let page = audit::list(&app, &project, &query).await?;
+            Ok(serde_json::to_value(&page).unwrap())
+        }
+        .await,
+    )
+}
@@ -0,0 +1,313 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, Query, State},
+    response::IntoResponse,
+};
+use chrono::{DateTime, NaiveTime, Utc};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    models::{KVStoredValue, RotationAttempt, RotationPolicy},
+    policies::AccessAction,
+    rotation::{self, Rotator},
+    routes::stores::access_error_status,
+    stores::{
+        StoreAccessError,
+        kv::{DEFAULT_ENVIRONMENT, KV_STORE, KvStore, KvStoreSettings},
+        require_project_action,
+    },
+};
+
+// Anything shorter is more churn than rotation.
+const MIN_INTERVAL_SECS: i64 = 60;
+const MAX_INTERVAL_SECS: i64 = 10 * 366 * 86400;
+const MAX_GRACE_SECS: i64 = 366 * 86400;
+const MAX_ATTEMPTS_LISTED: i64 = 100;
+
+fn default_store() -> String {
+    KV_STORE.to_string()
+}
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+fn default_grace() -> i64 {
+    3600
+}
+fn default_attempts_limit() -> i64 {
+    20
+}
+
+// Which kv secret, in whichever kv instance holds it.
+#[derive(Deserialize)]
+pub struct RotationTarget {
+    #[serde(default = "default_store")]
+    pub store: String,
+    #[serde(alias = "key")]
+    pub name: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigureRotation {
+    #[serde(flatten)]
+    pub target: RotationTarget,
+    pub interval_secs: i64,
+    // e.g. "02:00:00" with 7200 to only rotate between 2 and 4 (UTC).
+    #[serde(default)]
+    pub window_start: Option<NaiveTime>,
+    #[serde(default)]
+    pub window_secs: Option<i64>,
+    // How long the replaced value stays readable, 0 drops it right away.
+    #[serde(default = "default_grace")]
+    pub grace_secs: i64,
+    pub rotator: Rotator,
+    // Defaults to one interval from now.
+    #[serde(default)]
+    pub first_rotation: Option<DateTime<Utc>>,
+}
+
+// Not flattening RotationTarget, flattened query strings lose the
+// numbers.
+#[derive(Deserialize)]
+pub struct AttemptsQuery {
+    #[serde(default = "default_store")]
+    pub store: String,
+    #[serde(alias = "key")]
+    pub name: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default = "default_attempts_limit")]
+    pub limit: i64,
+}
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn admin_id(app: &App) -> String {
+    app.get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap()
+}
+
+async fn find_secret(
+    app: &App,
+    project: &str,
+    target: &RotationTarget,
+) -> Result<KVStoredValue, StoreAccessError> {
+    match app.stores.read().await.get(&target.store) {
+        Some(store) if store.store_type() == "kv" => {}
+        _ => {
+            return Err(StoreAccessError::NotFound(format!(
+                "No kv store named '{}' is enabled",
+                &target.store
+            )));
+        }
+    }
+    KvStore::configured(&target.store, KvStoreSettings::default())
+        .find_by_key(app, project, &target.environment, &target.name)
+        .await
+        .ok_or(StoreAccessError::NotFound(format!(
+            "No secret named '{}' in project {} ({})",
+            &target.name, project, &target.environment
+        )))
+}
+
+async fn find_policy(
+    app: &App,
+    project: &str,
+    target: &RotationTarget,
+) -> Result<RotationPolicy, StoreAccessError> {
+    let secret = find_secret(app, project, target).await?;
+    sqlx::query_as::<_, RotationPolicy>(
+        r#"SELECT * FROM tokaysec.rotation_policies WHERE secret_id = ($1)"#,
+    )
+    .bind(&secret.id)
+    .fetch_optional(&app.database.inner)
+    .await?
+    .ok_or(StoreAccessError::NotFound(format!(
+        "Secret '{}' has no rotation policy",
+        &target.name
+    )))
+}
+
+pub async fn list_rotations(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            let policies = sqlx::query_as::<_, RotationPolicy>(
+                r#"SELECT * FROM tokaysec.rotation_policies WHERE project = ($1) ORDER BY next_rotation"#,
+            )
+            .bind(&project)
+            .fetch_all(&app.database.inner)
+            .await?;
+            Ok(serde_json::to_value(&policies).unwrap())
+        }
+        .await,
+    )
+}
+
+// Configuring a secret that already has a policy replaces it.
+pub async fn configure_rotation(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(req): Json<ConfigureRotation>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            // Scheduled rotations run as whoever set this up.
+            require_project_action(&app, &project, &admin_id, AccessAction::UpdateSecret).await?;
+            if !(MIN_INTERVAL_SECS..=MAX_INTERVAL_SECS).contains(&req.interval_secs) {
+                return Err(StoreAccessError::Invalid(format!(
+                    "interval_secs has to be between {} and {}",
+                    MIN_INTERVAL_SECS, MAX_INTERVAL_SECS
+                )));
+            }
+            if !(0..=MAX_GRACE_SECS).contains(&req.grace_secs) {
+                return Err(StoreAccessError::Invalid(format!(
+                    "grace_secs has to be between 0 and {}",
+                    MAX_GRACE_SECS
+                )));
+            }
+            match (req.window_start, req.window_secs) {
+                (None, None) => {}
+                (Some(_), Some(window_secs)) if window_secs > 0 && window_secs <= 86400 => {}
+                _ => {
+                    return Err(StoreAccessError::Invalid(
+                        "A window needs both window_start and window_secs (1 to 86400)"
+                            .to_string(),
+                    ));
+                }
+            }
+            req.rotator.validate(&app, &project).await?;
+            let secret = find_secret(&app, &project, &req.target).await?;
+            let now = Utc::now();
+            let policy = sqlx::query_as::<_, RotationPolicy>(
+                r#"INSERT INTO tokaysec.rotation_policies(id,secret_id,project,interval_secs,window_start,window_secs,grace_secs,rotator,next_rotation,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)
+                ON CONFLICT (secret_id) DO UPDATE SET interval_secs = EXCLUDED.interval_secs, window_start = EXCLUDED.window_start, window_secs = EXCLUDED.window_secs,
+                grace_secs = EXCLUDED.grace_secs, rotator = EXCLUDED.rotator, next_rotation = EXCLUDED.next_rotation, failures = 0, added_when = EXCLUDED.added_when, added_by = EXCLUDED.added_by RETURNING *"#,
+            )
+            .bind(app.gen_id().await)
+            .bind(&secret.id)
+            .bind(&project)
+            .bind(req.interval_secs)
+            .bind(req.window_start)
+            .bind(req.window_secs)
+            .bind(req.grace_secs)
+            .bind(sqlx::types::Json(&req.rotator))
+            .bind(
+                req.first_rotation
+                    .unwrap_or(now + chrono::Duration::seconds(req.interval_secs)),
+            )
+            .bind(now)
+            .bind(&admin_id)
+            .fetch_one(&app.database.inner)
+            .await?;
+            Ok(serde_json::to_value(&policy).unwrap())
+        }
+        .await,
+    )
+}
+
+// ?name=<secret>[&environment=<env>][&store=<kv instance>]
+pub async fn remove_rotation(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(target): Query<RotationTarget>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::UpdateSecret).await?;
+            let policy = find_policy(&app, &project, &target).await?;
+            // Attempts go with it.
+            sqlx::query(r#"DELETE FROM tokaysec.rotation_policies WHERE id = ($1)"#)
+                .bind(&policy.id)
+                .execute(&app.database.inner)
+                .await?;
+            Ok(json!({ "id": policy.id, "deleted": true }))
+        }
+        .await,
+    )
+}
+
+// Rotates right away, the schedule carries on from now.
+pub async fn rotate_now(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Json(target): Json<RotationTarget>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::UpdateSecret).await?;
+            let policy = find_policy(&app, &project, &target).await?;
+            let attempt = rotation::rotate(&app, &policy).await;
+            match &attempt.error {
+                None => Ok(serde_json::to_value(&attempt).unwrap()),
+                Some(e) => Err(StoreAccessError::Unavailable(format!(
+                    "Rotation failed: {}",
+                    e
+                ))),
+            }
+        }
+        .await,
+    )
+}
+
+// Newest first, ?name=<secret>[&environment=<env>][&store=<kv instance>][&limit=<n>]
+pub async fn rotation_attempts(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(project): Path<String>,
+    Query(query): Query<AttemptsQuery>,
+) -> impl IntoResponse {
+    let admin_id = admin_id(&app).await;
+    respond(
+        async {
+            require_project_action(&app, &project, &admin_id, AccessAction::ReadSecret).await?;
+            if query.limit < 1 || query.limit > MAX_ATTEMPTS_LISTED {
+                return Err(StoreAccessError::Invalid(format!(
+                    "limit must be between 1 and {}",
+                    MAX_ATTEMPTS_LISTED
+                )));
+            }
+            let target = RotationTarget {
+                store: query.store,
+                name: query.name,
+                environment: query.environment,
+            };
+            let secret = find_secret(&app, &project, &target).await?;
+            let attempts = sqlx::query_as::<_, RotationAttempt>(
+                r#"SELECT * FROM tokaysec.rotation_attempts WHERE secret_id = ($1) ORDER BY started_when DESC LIMIT ($2)"#,
+            )
+            .bind(&secret.id)
+            .bind(query.limit)
+            .fetch_all(&app.database.inner)
+            .await?;
+            Ok(serde_json::to_value(&attempts).unwrap())
+        }
+        .await,
+    )
+}
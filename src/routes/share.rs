@@ -0,0 +1,143 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    response::IntoResponse,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    policies::AccessAction,
+    routes::stores::access_error_status,
+    share::{self, DEFAULT_SHARE_TTL_SECS},
+    stores::{StoreAccessError, require_project_action},
+};
+
+fn default_max_views() -> i32 {
+    1
+}
+fn default_ttl() -> i64 {
+    DEFAULT_SHARE_TTL_SECS
+}
+
+// Either `value` for something ad-hoc, or `store` + `query` (the same
+// query string the store's GET takes) to share a copy of a stored secret.
+#[derive(Deserialize)]
+pub struct CreateShareRequest {
+    pub project: String,
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
+    #[serde(default)]
+    pub store: Option<String>,
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    #[serde(default = "default_max_views")]
+    pub max_views: i32,
+    #[serde(default = "default_ttl")]
+    pub ttl_secs: i64,
+}
+
+#[derive(Deserialize)]
+pub struct OpenShareRequest {
+    pub token: String,
+}
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+pub async fn create_share(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<CreateShareRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    respond(
+        async {
+            require_project_action(&app, &req.project, &admin_id, AccessAction::ShareSecret)
+                .await?;
+            let (source, value) = match (req.value, req.store) {
+                (Some(value), None) => (None, value),
+                (None, Some(store)) => {
+                    let mut query = req.query;
+                    query.insert("project".to_string(), req.project.to_owned());
+                    let stores = app.stores.read().await;
+                    let Some(secret_store) = stores.get(&store) else {
+                        return Err(StoreAccessError::NotFound(format!(
+                            "No store named '{}'",
+                            store
+                        )));
+                    };
+                    let mut retrieved = secret_store
+                        .retrieve(&app, kek_provider, query, &admin_id)
+                        .await?;
+                    let source = retrieved["id"]
+                        .as_str()
+                        .map(|id| format!("{}:{}", store, id));
+                    let value = match retrieved.get_mut("value") {
+                        Some(value) => value.take(),
+                        None => retrieved,
+                    };
+                    (source, value)
+                }
+                _ => {
+                    return Err(StoreAccessError::Invalid(
+                        "Pass either a value or a store to share from".to_string(),
+                    ));
+                }
+            };
+            let (created, token) = share::create_share(
+                &app,
+                &req.project,
+                source,
+                &value,
+                req.max_views,
+                req.ttl_secs,
+                &admin_id,
+            )
+            .await?;
+            Ok(json!({
+                "id": created.id,
+                "token": token,
+                "views_remaining": created.views_remaining,
+                "expires_at": created.expires_at,
+            }))
+        }
+        .await,
+    )
+}
+
+// Deliberately unauthenticated, the token is the credential. It goes
+// in the body rather than the path so it doesn't end up in access logs.
+pub async fn open_share(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<OpenShareRequest>,
+) -> impl IntoResponse {
+    respond(
+        async {
+            let (value, opened) = share::open_share(&app, &req.token).await?;
+            Ok(json!({
+                "value": value,
+                "views_remaining": opened.views_remaining,
+                "expires_at": opened.expires_at,
+            }))
+        }
+        .await,
+    )
+}
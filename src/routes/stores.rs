@@ -8,7 +8,53 @@ use axum::{
 use reqwest::StatusCode;
 use serde_json::json;
 
-use crate::app::App;
+use crate::{
+    app::App,
+    stores::{ListRequest, SecretRef, StoreAccessError},
+};
+
+pub fn access_error_status(e: &StoreAccessError) -> StatusCode {
+    match e {
+        StoreAccessError::NotFound(_) => StatusCode::NOT_FOUND,
+        StoreAccessError::Expired(_) => StatusCode::GONE,
+        StoreAccessError::Conflict(_) => StatusCode::CONFLICT,
+        StoreAccessError::Forbidden(_) => StatusCode::FORBIDDEN,
+        StoreAccessError::Invalid(_) => StatusCode::BAD_REQUEST,
+        StoreAccessError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        StoreAccessError::OverQuota(_) => StatusCode::TOO_MANY_REQUESTS,
+        StoreAccessError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        StoreAccessError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn unknown_store(store: &str) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        json!({ "error": format!("No store named '{}' is enabled", store) }).to_string(),
+    )
+}
+
+// Every enabled store instance, so clients can offer them without
+// knowing the configuration.
+pub async fn list_stores(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    let stores = app.stores.clone();
+    let stores_read = stores.read().await;
+    let mut listed = stores_read
+        .iter()
+        .map(|(name, store)| {
+            json!({
+                "name": name,
+                "type": store.store_type(),
+                "ui_reqs": store.ui_reqs(),
+            })
+        })
+        .collect::<Vec<serde_json::Value>>();
+    listed.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    (StatusCode::OK, serde_json::to_string(&listed).unwrap())
+}
 
 pub async fn ui_reqs(
     State(app): State<App>,
@@ -17,7 +63,9 @@ pub async fn ui_reqs(
 ) -> impl IntoResponse {
     let stores = app.stores.clone();
     let stores_read = stores.read().await;
-    let store = stores_read.get(&store).unwrap().to_owned();
+    let Some(store) = stores_read.get(&store) else {
+        return unknown_store(&store);
+    };
     (
         StatusCode::OK,
         serde_json::to_string(&store.ui_reqs()).unwrap(),
@@ -32,11 +80,25 @@ pub async fn retrieve(
 ) -> impl IntoResponse {
     let stores = app.stores.clone();
     let stores_read = stores.read().await;
-    let kv_store = stores_read.get(&store).unwrap().to_owned();
+    let Some(kv_store) = stores_read.get(&store) else {
+        return unknown_store(&store);
+    };
     let kek_provider = app.kek_provider.to_owned();
     let kek_provider = (*kek_provider).as_ref();
-    kv_store.retrieve(&app, query, kek_provider).await;
-    (StatusCode::OK, json!({}).to_string())
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    match kv_store
+        .retrieve(&app, kek_provider, query, admin_id.as_str())
+        .await
+    {
+        Ok(retrieved) => (StatusCode::OK, retrieved.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
 }
 
 pub async fn store(
@@ -47,7 +109,9 @@ pub async fn store(
 ) -> impl IntoResponse {
     let stores = app.stores.clone();
     let stores_read = stores.read().await;
-    let kv_store = stores_read.get(&store).unwrap().to_owned();
+    let Some(kv_store) = stores_read.get(&store) else {
+        return unknown_store(&store);
+    };
     let kek_provider = app.kek_provider.to_owned();
     let kek_provider = (*kek_provider).as_ref();
     let admin_id = app
@@ -55,16 +119,98 @@ pub async fn store(
         .await
         .unwrap();
     let admin_user: &str = admin_id.as_str();
-    println!("{:?}", store_req);
-    kv_store
-        .store(
-            &app,
-            serde_json::from_value::<String>(store_req["project"].to_owned()).unwrap(),
-            kek_provider,
-            store_req,
-            admin_user,
-        )
-        .await;
+    match kv_store
+        .store(&app, kek_provider, store_req, admin_user)
+        .await
+    {
+        Ok(stored) => (StatusCode::OK, stored.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
 
-    (StatusCode::OK, json!({}).to_string())
+pub async fn update(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(store): Path<String>,
+    Json(update_req): Json<serde_json::Value>,
+) -> impl IntoResponse {
+    let stores = app.stores.clone();
+    let stores_read = stores.read().await;
+    let Some(secret_store) = stores_read.get(&store) else {
+        return unknown_store(&store);
+    };
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    match secret_store
+        .update(&app, kek_provider, update_req, admin_id.as_str())
+        .await
+    {
+        Ok(updated) => (StatusCode::OK, updated.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+// ?project=<id>&name=<name>[&environment=<env>]
+pub async fn remove(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(store): Path<String>,
+    Query(target): Query<SecretRef>,
+) -> impl IntoResponse {
+    let stores = app.stores.clone();
+    let stores_read = stores.read().await;
+    let Some(secret_store) = stores_read.get(&store) else {
+        return unknown_store(&store);
+    };
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    match secret_store
+        .delete(&app, kek_provider, target, admin_id.as_str())
+        .await
+    {
+        Ok(()) => (StatusCode::NO_CONTENT, String::new()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+// ?project=<id>[&environment=<env>][&cursor=<next_cursor>][&limit=<n>]
+pub async fn list(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(store): Path<String>,
+    Query(request): Query<ListRequest>,
+) -> impl IntoResponse {
+    let stores = app.stores.clone();
+    let stores_read = stores.read().await;
+    let Some(secret_store) = stores_read.get(&store) else {
+        return unknown_store(&store);
+    };
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    match secret_store.list(&app, request, admin_id.as_str()).await {
+        Ok(page) => (StatusCode::OK, serde_json::to_string(&page).unwrap()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
 }
@@ -0,0 +1,244 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, State},
+    response::IntoResponse,
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    app::App,
+    models::TransitKey,
+    policies::AccessAction,
+    routes::stores::access_error_status,
+    secure_buf::SecureBuffer,
+    stores::{StoreAccessError, require_project_action},
+    transit,
+};
+
+#[derive(Deserialize)]
+pub struct TransitKeyRequest {
+    pub project: String,
+}
+
+#[derive(Deserialize)]
+pub struct TransitEncryptRequest {
+    pub project: String,
+    // base64
+    pub plaintext: String,
+}
+
+#[derive(Deserialize)]
+pub struct TransitCiphertextRequest {
+    pub project: String,
+    pub ciphertext: String,
+}
+
+fn default_include_plaintext() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct TransitDataKeyRequest {
+    pub project: String,
+    // false only returns the wrapped key, for handing to something
+    // that will decrypt it later.
+    #[serde(default = "default_include_plaintext")]
+    pub include_plaintext: bool,
+}
+
+fn respond(result: Result<serde_json::Value, StoreAccessError>) -> (StatusCode, String) {
+    match result {
+        Ok(value) => (StatusCode::OK, value.to_string()),
+        Err(e) => (
+            access_error_status(&e),
+            json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+async fn authorize(
+    app: &App,
+    project: &str,
+    key: &str,
+    action: AccessAction,
+) -> Result<(TransitKey, String), StoreAccessError> {
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    require_project_action(app, project, &admin_id, action).await?;
+    let Some(transit_key) = transit::find_key(app, project, key).await else {
+        return Err(StoreAccessError::NotFound(format!(
+            "No transit key named '{}' in project {}",
+            key, project
+        )));
+    };
+    Ok((transit_key, admin_id))
+}
+
+pub async fn create_key(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(key): Path<String>,
+    Json(req): Json<TransitKeyRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .unwrap();
+    respond(
+        async {
+            require_project_action(
+                &app,
+                &req.project,
+                &admin_id,
+                AccessAction::ManageTransitKey,
+            )
+            .await?;
+            let created =
+                transit::create_key(&app, kek_provider, &req.project, &key, &admin_id).await?;
+            Ok(serde_json::to_value(&created).unwrap())
+        }
+        .await,
+    )
+}
+
+pub async fn rotate_key(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(key): Path<String>,
+    Json(req): Json<TransitKeyRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let (transit_key, admin_id) =
+                authorize(&app, &req.project, &key, AccessAction::ManageTransitKey).await?;
+            let rotated = transit::rotate_key(&app, kek_provider, &transit_key, &admin_id).await;
+            Ok(serde_json::to_value(&rotated).unwrap())
+        }
+        .await,
+    )
+}
+
+pub async fn encrypt(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(key): Path<String>,
+    Json(req): Json<TransitEncryptRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let (transit_key, _) =
+                authorize(&app, &req.project, &key, AccessAction::TransitEncrypt).await?;
+            let plaintext = BASE64_STANDARD
+                .decode(&req.plaintext)
+                .map_err(|_| StoreAccessError::Invalid("plaintext must be base64".to_string()))?;
+            // A zero sized SecureBuffer can't be allocated.
+            if plaintext.is_empty() {
+                return Err(StoreAccessError::Invalid(
+                    "plaintext can't be empty".to_string(),
+                ));
+            }
+            let plaintext = SecureBuffer::from_slice(&plaintext).unwrap();
+            let (version, ciphertext) =
+                transit::encrypt(&app, kek_provider, &transit_key, plaintext).await?;
+            Ok(json!({
+                "ciphertext": ciphertext,
+                "key_version": version,
+            }))
+        }
+        .await,
+    )
+}
+
+pub async fn decrypt(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(key): Path<String>,
+    Json(req): Json<TransitCiphertextRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let (transit_key, _) =
+                authorize(&app, &req.project, &key, AccessAction::TransitDecrypt).await?;
+            let plaintext =
+                transit::decrypt(&app, kek_provider, &transit_key, &req.ciphertext).await?;
+            Ok(json!({ "plaintext": BASE64_STANDARD.encode(plaintext.expose()) }))
+        }
+        .await,
+    )
+}
+
+// Decrypts with whatever version the ciphertext was made with and
+// encrypts again under the latest one. The plaintext never leaves
+// the server.
+pub async fn rewrap(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(key): Path<String>,
+    Json(req): Json<TransitCiphertextRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let (transit_key, _) =
+                authorize(&app, &req.project, &key, AccessAction::TransitEncrypt).await?;
+            let plaintext =
+                transit::decrypt(&app, kek_provider, &transit_key, &req.ciphertext).await?;
+            let (version, ciphertext) =
+                transit::encrypt(&app, kek_provider, &transit_key, plaintext).await?;
+            Ok(json!({
+                "ciphertext": ciphertext,
+                "key_version": version,
+            }))
+        }
+        .await,
+    )
+}
+
+// A fresh random key for the caller to encrypt with locally, plus the
+// same key encrypted under the transit key to store next to the data.
+pub async fn datakey(
+    State(app): State<App>,
+    ConnectInfo(_client_addr): ConnectInfo<SocketAddr>,
+    Path(key): Path<String>,
+    Json(req): Json<TransitDataKeyRequest>,
+) -> impl IntoResponse {
+    let kek_provider = app.kek_provider.to_owned();
+    let kek_provider = (*kek_provider).as_ref();
+    respond(
+        async {
+            let (transit_key, _) =
+                authorize(&app, &req.project, &key, AccessAction::TransitEncrypt).await?;
+            let data_key = transit::generate_data_key();
+            let encoded = req
+                .include_plaintext
+                .then(|| BASE64_STANDARD.encode(data_key.expose()));
+            let (version, ciphertext) =
+                transit::encrypt(&app, kek_provider, &transit_key, data_key).await?;
+            let mut response = json!({
+                "ciphertext": ciphertext,
+                "key_version": version,
+            });
+            if let Some(encoded) = encoded {
+                response["plaintext"] = json!(encoded);
+            }
+            Ok(response)
+        }
+        .await,
+    )
+}
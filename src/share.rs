@@ -0,0 +1,154 @@
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::Utc;
+
+use crate::{
+    app::App, dek::Dek, models::Share, secure_buf::SecureBuffer, stores::StoreAccessError,
+};
+
+pub const DEFAULT_SHARE_TTL_SECS: i64 = 86400;
+pub const MAX_SHARE_TTL_SECS: i64 = 7 * 86400;
+pub const MAX_SHARE_VIEWS: i32 = 100;
+
+fn share_name(share_id: &str) -> String {
+    format!("share:{}", share_id)
+}
+
+fn gone() -> StoreAccessError {
+    StoreAccessError::NotFound("This share doesn't exist or has already been read".to_string())
+}
+
+// Encrypts the value under a brand new key and returns the share plus
+// the token (<share id>.<key>) for the recipient. We only keep the
+// ciphertext, once the token is handed out it can't be recovered.
+pub async fn create_share(
+    app: &App,
+    project: &str,
+    source: Option<String>,
+    value: &serde_json::Value,
+    max_views: i32,
+    ttl_secs: i64,
+    creator: &str,
+) -> Result<(Share, String), StoreAccessError> {
+    if max_views < 1 || max_views > MAX_SHARE_VIEWS {
+        return Err(StoreAccessError::Invalid(format!(
+            "max_views must be between 1 and {}",
+            MAX_SHARE_VIEWS
+        )));
+    }
+    if ttl_secs < 1 || ttl_secs > MAX_SHARE_TTL_SECS {
+        return Err(StoreAccessError::Invalid(format!(
+            "ttl_secs must be between 1 and {}",
+            MAX_SHARE_TTL_SECS
+        )));
+    }
+    // Expired shares can't be opened anyway, this just stops them
+    // piling up.
+    sqlx::query(r#"DELETE FROM tokaysec.shares WHERE expires_at <= ($1)"#)
+        .bind(Utc::now())
+        .execute(&app.database.inner)
+        .await
+        .unwrap();
+    let id = app.gen_id().await;
+    let key = Dek::init();
+    let plaintext = SecureBuffer::from_slice(&serde_json::to_vec(value).unwrap()).unwrap();
+    let encrypted = key.wrap_data(plaintext, share_name(&id));
+    let token = format!(
+        "{}.{}",
+        &id,
+        BASE64_URL_SAFE_NO_PAD.encode(key.__inner.expose())
+    );
+    drop(key);
+    let share = sqlx::query_as::<_, Share>(
+        r#"INSERT INTO tokaysec.shares(id,project,source,value,gcm_tag,kmac_tag,nonce,views_remaining,expires_at,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11) RETURNING *"#,
+    )
+    .bind(&id)
+    .bind(&project)
+    .bind(&source)
+    .bind(&encrypted.data)
+    .bind(&encrypted.gcm_tag)
+    .bind(&encrypted.kmac_tag)
+    .bind(&encrypted.nonce)
+    .bind(max_views)
+    .bind(Utc::now() + chrono::Duration::seconds(ttl_secs))
+    .bind(Utc::now())
+    .bind(&creator)
+    .fetch_one(&app.database.inner)
+    .await
+    .unwrap();
+    Ok((share, token))
+}
+
+// Anyone holding the token can open the share, there is no account
+// check. Each successful open uses up a view and the row is deleted
+// once the last one is gone.
+pub async fn open_share(
+    app: &App,
+    token: &str,
+) -> Result<(serde_json::Value, Share), StoreAccessError> {
+    let Some((id, key)) = token.split_once('.') else {
+        return Err(StoreAccessError::Invalid(
+            "Malformed share token".to_string(),
+        ));
+    };
+    let key = BASE64_URL_SAFE_NO_PAD
+        .decode(key)
+        .map_err(|_| StoreAccessError::Invalid("Malformed share token".to_string()))?;
+    // Anything but a full AES-256 key would blow up once it gets used.
+    if key.len() != 32 {
+        return Err(StoreAccessError::Invalid(
+            "Malformed share token".to_string(),
+        ));
+    }
+    let key: Dek = SecureBuffer::from_slice(&key).unwrap().into();
+    let Some(share) =
+        sqlx::query_as::<_, Share>(r#"SELECT * FROM tokaysec.shares WHERE id = ($1)"#)
+            .bind(&id)
+            .fetch_optional(&app.database.inner)
+            .await
+            .unwrap()
+    else {
+        return Err(gone());
+    };
+    if share.expires_at <= Utc::now() {
+        delete_share(app, &share.id).await;
+        return Err(StoreAccessError::Expired(format!(
+            "This share expired at {}",
+            share.expires_at.to_rfc3339()
+        )));
+    }
+    // A wrong key doesn't use up a view.
+    let plaintext = key
+        .try_unwrap_data(
+            share.value.to_owned(),
+            share.kmac_tag.to_owned(),
+            &share_name(&share.id),
+            share.nonce.to_owned(),
+            share.gcm_tag.to_owned(),
+        )
+        .map_err(|_| gone())?;
+    drop(key);
+    // Conditional decrement so two readers racing for the last view
+    // can't both get it.
+    let Some(share) = sqlx::query_as::<_, Share>(
+        r#"UPDATE tokaysec.shares SET views_remaining = views_remaining - 1 WHERE id = ($1) AND views_remaining > 0 RETURNING *"#,
+    )
+    .bind(&share.id)
+    .fetch_optional(&app.database.inner)
+    .await
+    .unwrap() else {
+        return Err(gone());
+    };
+    if share.views_remaining <= 0 {
+        delete_share(app, &share.id).await;
+    }
+    let value = serde_json::from_slice(plaintext.expose()).unwrap();
+    Ok((value, share))
+}
+
+pub async fn delete_share(app: &App, id: &str) {
+    sqlx::query(r#"DELETE FROM tokaysec.shares WHERE id = ($1)"#)
+        .bind(&id)
+        .execute(&app.database.inner)
+        .await
+        .unwrap();
+}
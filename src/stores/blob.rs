@@ -0,0 +1,513 @@
+use axum::body::Bytes;
+use chrono::Utc;
+use futures_util::Stream;
+use sqlx::{Postgres, Transaction};
+
+use crate::{
+    app::{App, EasyResource, ResourceTypes},
+    dek::Dek,
+    kek_provider::KekProvider,
+    models::{BlobChunk, BlobStoredValue, WrappedDek},
+    policies::AccessAction,
+    secure_buf::SecureBuffer,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, get_rows, kv::DEFAULT_ENVIRONMENT, list_rows,
+        remove_resource_assignments, require_project_action,
+    },
+};
+
+pub const BLOB_STORE: &str = "blob";
+// Plaintext bytes per encrypted chunk. Upload and download hold at most
+// about one chunk of the file in memory at a time.
+pub const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_BLOB_SIZE: usize = 256 * 1024 * 1024;
+
+fn dek_name(blob_id: &str) -> String {
+    format!("{}:{}", BLOB_STORE, blob_id)
+}
+
+// AAD for each chunk. The position and whether it's the last chunk are
+// part of it so dropping, reordering or truncating chunks fails to
+// decrypt instead of silently giving back a different file.
+fn chunk_name(blob_id: &str, chunk: i32, last: bool) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        BLOB_STORE,
+        blob_id,
+        chunk,
+        if last { "last" } else { "more" }
+    )
+}
+
+// Takes the file as it comes off the wire and writes it out one
+// encrypted chunk at a time. Everything happens in one transaction so
+// an upload that fails or is dropped part way leaves nothing behind.
+pub struct BlobWriter {
+    tx: Transaction<'static, Postgres>,
+    blob: BlobStoredValue,
+    dek: Dek,
+    buffer: Vec<u8>,
+    chunk: i32,
+    size: usize,
+}
+
+impl BlobWriter {
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), StoreAccessError> {
+        self.size += data.len();
+        if self.size > MAX_BLOB_SIZE {
+            return Err(StoreAccessError::Invalid(format!(
+                "Files can be at most {} bytes",
+                MAX_BLOB_SIZE
+            )));
+        }
+        self.buffer.extend_from_slice(data);
+        // Strictly greater, a full chunk is only known not to be the
+        // last one once there is at least one byte after it.
+        while self.buffer.len() > BLOB_CHUNK_SIZE {
+            let rest = self.buffer.split_off(BLOB_CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            self.write_chunk(&chunk, false).await;
+        }
+        Ok(())
+    }
+
+    async fn write_chunk(&mut self, plaintext: &[u8], last: bool) {
+        let encrypted = self.dek.wrap_data(
+            SecureBuffer::from_slice(plaintext).unwrap(),
+            chunk_name(&self.blob.id, self.chunk, last),
+        );
+        sqlx::query(
+            r#"INSERT INTO tokaysec.blob_chunks(blob_id,chunk,value,gcm_tag,kmac_tag,nonce) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&self.blob.id)
+        .bind(self.chunk)
+        .bind(&encrypted.data)
+        .bind(&encrypted.gcm_tag)
+        .bind(&encrypted.kmac_tag)
+        .bind(&encrypted.nonce)
+        .execute(&mut *self.tx)
+        .await
+        .unwrap();
+        self.chunk += 1;
+    }
+
+    pub async fn finish(mut self, app: &App) -> BlobStoredValue {
+        // An empty file has no chunks at all.
+        if !self.buffer.is_empty() {
+            let last = std::mem::take(&mut self.buffer);
+            self.write_chunk(&last, true).await;
+        }
+        let blob = sqlx::query_as::<_, BlobStoredValue>(
+            r#"UPDATE tokaysec.blob_store SET size = ($1), chunk_count = ($2) WHERE id = ($3) RETURNING *"#,
+        )
+        .bind(self.size as i64)
+        .bind(self.chunk)
+        .bind(&self.blob.id)
+        .fetch_one(&mut *self.tx)
+        .await
+        .unwrap();
+        self.tx.commit().await.unwrap();
+        app.create_resource_assignment(
+            EasyResource(ResourceTypes::Project, &blob.project),
+            EasyResource(
+                ResourceTypes::Secret,
+                &format!("{}:{}", BLOB_STORE, &blob.id),
+            ),
+            &blob.added_by,
+        )
+        .await
+        .unwrap();
+        blob
+    }
+}
+
+// Decrypts one chunk per poll, for streaming a download straight into
+// the response body.
+pub struct BlobReader {
+    app: App,
+    blob_id: String,
+    chunk_count: i32,
+    dek: Dek,
+    chunk: i32,
+}
+
+impl BlobReader {
+    pub async fn next_chunk(&mut self) -> Option<Result<Bytes, String>> {
+        if self.chunk >= self.chunk_count {
+            return None;
+        }
+        let stored = sqlx::query_as::<_, BlobChunk>(
+            r#"SELECT * FROM tokaysec.blob_chunks WHERE blob_id = ($1) AND chunk = ($2)"#,
+        )
+        .bind(&self.blob_id)
+        .bind(self.chunk)
+        .fetch_optional(&self.app.database.inner)
+        .await
+        .unwrap();
+        let Some(stored) = stored else {
+            return Some(Err(format!("Chunk {} is missing", self.chunk)));
+        };
+        let last = self.chunk == self.chunk_count - 1;
+        let plaintext = self.dek.try_unwrap_data(
+            stored.value,
+            stored.kmac_tag,
+            &chunk_name(&self.blob_id, self.chunk, last),
+            stored.nonce,
+            stored.gcm_tag,
+        );
+        self.chunk += 1;
+        Some(plaintext.map(|e| Bytes::copy_from_slice(e.expose())))
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+        futures_util::stream::unfold(self, |mut reader| async move {
+            let next = reader.next_chunk().await?;
+            Some((next.map_err(std::io::Error::other), reader))
+        })
+    }
+}
+
+pub struct BlobStore {}
+
+impl BlobStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "blob_store",
+            name_column: "key",
+            has_environment: true,
+            updated_column: "added_when",
+            store: None,
+        }
+    }
+    pub async fn init() -> Self {
+        Self {}
+    }
+    pub async fn find_by_key(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Option<BlobStoredValue> {
+        sqlx::query_as::<_, BlobStoredValue>(
+            r#"SELECT * FROM tokaysec.blob_store WHERE project = ($1) AND environment = ($2) AND key = ($3)"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    pub async fn begin_upload(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        key: &str,
+        filename: Option<String>,
+        content_type: String,
+        creator: &str,
+    ) -> Result<BlobWriter, StoreAccessError> {
+        require_project_action(app, project, creator, AccessAction::CreateSecret).await?;
+        if self
+            .find_by_key(app, project, environment, key)
+            .await
+            .is_some()
+        {
+            return Err(StoreAccessError::Conflict(format!(
+                "A file named '{}' already exists in this project ({})",
+                key, environment
+            )));
+        }
+        let id = app.gen_id().await;
+        let dek = Dek::init();
+        // wrap_dek consumes the DEK and we still need it for the chunks.
+        let for_wrapping: Dek = SecureBuffer::from_slice(dek.__inner.expose())
+            .unwrap()
+            .into();
+        let (wrapped_dek, nonce, tag) = kek_provider
+            .wrap_dek(for_wrapping, &dek_name(&id))
+            .await
+            .unwrap();
+        let dek_id = app.gen_id().await;
+        let now = Utc::now();
+        let mut tx = app.database.inner.begin().await.unwrap();
+        sqlx::query(
+            r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&dek_id)
+        .bind(&wrapped_dek)
+        .bind(nonce)
+        .bind(tag)
+        .bind(now)
+        .bind(&creator)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        let blob = sqlx::query_as::<_, BlobStoredValue>(
+            r#"INSERT INTO tokaysec.blob_store(id,project,environment,key,filename,content_type,size,chunk_size,chunk_count,dek_used,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,0,$7,0,$8,$9,$10) RETURNING *"#,
+        )
+        .bind(&id)
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .bind(&filename)
+        .bind(&content_type)
+        .bind(BLOB_CHUNK_SIZE as i32)
+        .bind(&dek_id)
+        .bind(now)
+        .bind(&creator)
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        Ok(BlobWriter {
+            tx,
+            blob,
+            dek,
+            buffer: Vec::with_capacity(BLOB_CHUNK_SIZE),
+            chunk: 0,
+            size: 0,
+        })
+    }
+    pub async fn open_download(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        key: &str,
+        requester: &str,
+    ) -> Result<(BlobStoredValue, BlobReader), StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+        let Some(blob) = self.find_by_key(app, project, environment, key).await else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No file named '{}' in project {} ({})",
+                key, project, environment
+            )));
+        };
+        let dek_data =
+            sqlx::query_as::<_, WrappedDek>(r#"SELECT * FROM tokaysec.wrapped_deks WHERE id = $1"#)
+                .bind(&blob.dek_used)
+                .fetch_one(&app.database.inner)
+                .await
+                .unwrap();
+        let dek: Dek = kek_provider
+            .unwrap_dek(
+                &dek_data.wrapped,
+                dek_data.nonce.try_into().unwrap(),
+                dek_data.tag.try_into().unwrap(),
+                &dek_name(&blob.id),
+            )
+            .await
+            .into();
+        let reader = BlobReader {
+            app: app.clone(),
+            blob_id: blob.id.to_owned(),
+            chunk_count: blob.chunk_count,
+            dek,
+            chunk: 0,
+        };
+        Ok((blob, reader))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BlobRetrieveRequest {
+    pub project: String,
+    #[serde(alias = "name")]
+    pub key: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BlobMetadata {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+}
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+#[async_trait::async_trait]
+impl TypedStore for BlobStore {
+    type StoreRequest = serde_json::Value;
+    type StoreResponse = serde_json::Value;
+    type RetrieveRequest = BlobRetrieveRequest;
+    type RetrieveResponse = BlobMetadata;
+    type UpdateRequest = serde_json::Value;
+
+    fn store_type(&self) -> &str {
+        "blob"
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: true,
+            description: false,
+            secret_type: true,
+        }
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let blob = sqlx::query_as::<_, BlobStoredValue>(
+            r#"SELECT * FROM tokaysec.blob_store WHERE id = $1"#,
+        )
+        .bind(&id)
+        .fetch_one(&app.database.inner)
+        .await?;
+        return Ok(RetrievedSecretData {
+            id: blob.id,
+            name: blob.key,
+            environment: Some(blob.environment),
+            updated_when: Some(blob.added_when),
+            value: None,
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    // Metadata only, the contents are streamed from GET /v1/blobs.
+    async fn retrieve(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        request: BlobRetrieveRequest,
+        requester: &str,
+    ) -> Result<BlobMetadata, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        let Some(blob) = self
+            .find_by_key(app, &request.project, &request.environment, &request.key)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No file named '{}' in project {} ({})",
+                request.key, request.project, request.environment
+            )));
+        };
+        return Ok(BlobMetadata {
+            id: blob.id,
+            name: blob.key,
+            environment: blob.environment,
+            filename: blob.filename,
+            content_type: blob.content_type,
+            size: blob.size,
+        });
+    }
+
+    async fn store(
+        &self,
+        _app: &App,
+        _kek_provider: &dyn KekProvider,
+        _data: serde_json::Value,
+        _creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        Err(StoreAccessError::Invalid(
+            "Files are uploaded as multipart/form-data to POST /v1/blobs".to_string(),
+        ))
+    }
+
+    async fn delete(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, &target.project, requester, AccessAction::DeleteSecret).await?;
+        let Some(blob) = self
+            .find_by_key(app, &target.project, &target.environment, &target.name)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No file named '{}' in project {} ({})",
+                target.name, target.project, target.environment
+            )));
+        };
+        let mut tx = app.database.inner.begin().await?;
+        // Chunks go with it (ON DELETE CASCADE).
+        sqlx::query(r#"DELETE FROM tokaysec.blob_store WHERE id = ($1)"#)
+            .bind(&blob.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&blob.dek_used)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        remove_resource_assignments(app, &format!("{}:{}", BLOB_STORE, &blob.id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dek::DekWrapDataResult;
+
+    fn seal(dek: &Dek, name: String) -> DekWrapDataResult {
+        dek.wrap_data(SecureBuffer::from_slice(b"chunk").unwrap(), name)
+    }
+
+    fn open(dek: &Dek, sealed: DekWrapDataResult, name: &str) -> Result<Vec<u8>, String> {
+        dek.try_unwrap_data(
+            sealed.data,
+            sealed.kmac_tag,
+            name,
+            sealed.nonce,
+            sealed.gcm_tag,
+        )
+        .map(|e| e.expose().to_vec())
+    }
+
+    #[test]
+    fn chunk_names() {
+        assert_eq!(chunk_name("b1", 0, false), "blob:b1:0:more");
+        assert_eq!(chunk_name("b1", 4, true), "blob:b1:4:last");
+    }
+
+    #[test]
+    fn chunk_round_trips() {
+        let dek = Dek::init();
+        let sealed = seal(&dek, chunk_name("b1", 2, true));
+        assert_eq!(
+            open(&dek, sealed, &chunk_name("b1", 2, true)).unwrap(),
+            b"chunk"
+        );
+    }
+
+    // Each of these is a chunk read back somewhere other than where it
+    // was written.
+    #[test]
+    fn misplaced_chunks_fail_to_decrypt() {
+        let dek = Dek::init();
+        for wrong in [
+            chunk_name("b1", 1, false),
+            chunk_name("b1", 2, false),
+            chunk_name("b2", 2, true),
+            "blob:b1:2".to_string(),
+        ] {
+            let sealed = seal(&dek, chunk_name("b1", 2, true));
+            assert!(open(&dek, sealed, &wrong).is_err(), "{} decrypted", wrong);
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    app::{App, EasyResource, ResourceTypes},
+    kek_provider::KekProvider,
+    models::{ConfigStoredValue, ConfigValueVersion},
+    policies::AccessAction,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, get_rows, kv::DEFAULT_ENVIRONMENT, list_rows,
+        remove_resource_assignments, require_project_action,
+    },
+};
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigValueType {
+    String,
+    Number,
+    Boolean,
+    Json,
+}
+
+impl ConfigValueType {
+    // Anything that isn't a plain scalar is treated as json.
+    pub fn infer(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::String(_) => Self::String,
+            serde_json::Value::Number(_) => Self::Number,
+            serde_json::Value::Bool(_) => Self::Boolean,
+            _ => Self::Json,
+        }
+    }
+    pub fn accepts(&self, value: &serde_json::Value) -> bool {
+        match self {
+            Self::Json => true,
+            other => *other == Self::infer(value),
+        }
+    }
+}
+
+impl ToString for ConfigValueType {
+    fn to_string(&self) -> String {
+        String::from(match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Boolean => "boolean",
+            Self::Json => "json",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfigStoreStoreData {
+    pub name: String,
+    pub value: serde_json::Value,
+    pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default)]
+    pub value_type: Option<ConfigValueType>,
+}
+
+pub struct ConfigStore {}
+
+impl ConfigStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "config_store",
+            name_column: "key",
+            has_environment: true,
+            updated_column: "last_updated",
+            store: None,
+        }
+    }
+    pub async fn init() -> Self {
+        Self {}
+    }
+    pub async fn find_by_key(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Option<ConfigStoredValue> {
+        sqlx::query_as::<_, ConfigStoredValue>(
+            r#"SELECT * FROM tokaysec.config_store WHERE project = ($1) AND environment = ($2) AND key = ($3)"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    // Every key in the environment with its current version. Callers
+    // check permissions.
+    pub async fn current_values(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+    ) -> Result<Vec<(String, ConfigValueVersion)>, StoreAccessError> {
+        #[derive(FromRow)]
+        struct CurrentValue {
+            key: String,
+            #[sqlx(flatten)]
+            version: ConfigValueVersion,
+        }
+        Ok(sqlx::query_as::<_, CurrentValue>(
+            r#"SELECT c.key, v.* FROM tokaysec.config_store c JOIN tokaysec.config_store_versions v ON v.config_id = c.id AND v.version = c.current_version WHERE c.project = ($1) AND c.environment = ($2) ORDER BY c.key"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .fetch_all(&app.database.inner)
+        .await?
+        .into_iter()
+        .map(|e| (e.key, e.version))
+        .collect())
+    }
+    pub async fn get_version(
+        &self,
+        app: &App,
+        config_id: &str,
+        version: i32,
+    ) -> Option<ConfigValueVersion> {
+        sqlx::query_as::<_, ConfigValueVersion>(
+            r#"SELECT * FROM tokaysec.config_store_versions WHERE config_id = ($1) AND version = ($2)"#,
+        )
+        .bind(&config_id)
+        .bind(version)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfigRetrieveRequest {
+    pub project: String,
+    #[serde(alias = "name")]
+    pub key: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    // Older versions are kept around and can be asked for directly.
+    #[serde(default)]
+    pub version: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfigRetrieved {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub version: i32,
+    pub value_type: String,
+    pub value: serde_json::Value,
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfigStored {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub version: i32,
+}
+
+impl ConfigStore {
+    // Writing to an existing key is an update and gets a new version.
+    // Updates (must_exist) refuse to create the key. `source` is kept
+    // with the version, e.g. the commit a git sync pulled it from.
+    pub async fn write(
+        &self,
+        app: &App,
+        data: ConfigStoreStoreData,
+        creator: &str,
+        must_exist: bool,
+        source: Option<&str>,
+    ) -> Result<ConfigStored, StoreAccessError> {
+        let value_type = match data.value_type {
+            Some(value_type) if !value_type.accepts(&data.value) => {
+                return Err(StoreAccessError::Invalid(format!(
+                    "Value of '{}' is not a {}",
+                    data.name,
+                    value_type.to_string()
+                )));
+            }
+            Some(value_type) => value_type,
+            None => ConfigValueType::infer(&data.value),
+        };
+        let now = Utc::now();
+        let existing = self
+            .find_by_key(app, &data.project, &data.environment, &data.name)
+            .await;
+        // Writing to an existing key is an update and gets a new version,
+        // anything else is a brand new config entry.
+        let required = match (&existing, must_exist) {
+            (None, true) => {
+                return Err(StoreAccessError::NotFound(format!(
+                    "No config named '{}' in project {} ({})",
+                    data.name, data.project, data.environment
+                )));
+            }
+            (Some(_), _) => AccessAction::UpdateConfig,
+            (None, false) => AccessAction::CreateConfig,
+        };
+        require_project_action(app, &data.project, creator, required).await?;
+        let mut tx = app.database.inner.begin().await?;
+        let (config_id, version) = match existing {
+            Some(existing) => {
+                let version = existing.current_version + 1;
+                sqlx::query(
+                    r#"UPDATE tokaysec.config_store SET current_version = ($1), last_updated = ($2) WHERE id = ($3)"#,
+                )
+                .bind(version)
+                .bind(now)
+                .bind(&existing.id)
+                .execute(&mut *tx)
+                .await?;
+                (existing.id, version)
+            }
+            None => {
+                let id = app.gen_id().await;
+                sqlx::query(
+                    r#"INSERT INTO tokaysec.config_store(id,project,environment,key,current_version,added_when,added_by,last_updated) VALUES($1,$2,$3,$4,1,$5,$6,$5)"#,
+                )
+                .bind(&id)
+                .bind(&data.project)
+                .bind(&data.environment)
+                .bind(&data.name)
+                .bind(now)
+                .bind(&creator)
+                .execute(&mut *tx)
+                .await?;
+                (id, 1)
+            }
+        };
+        sqlx::query(
+            r#"INSERT INTO tokaysec.config_store_versions(config_id,version,value_type,value,added_when,added_by,source) VALUES($1,$2,$3,$4,$5,$6,$7)"#,
+        )
+        .bind(&config_id)
+        .bind(version)
+        .bind(value_type.to_string())
+        .bind(&data.value)
+        .bind(now)
+        .bind(&creator)
+        .bind(source)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        if version == 1 {
+            app.create_resource_assignment(
+                EasyResource(ResourceTypes::Project, &data.project),
+                EasyResource(ResourceTypes::Config, &format!("config:{}", &config_id)),
+                creator,
+            )
+            .await
+            .map_err(StoreAccessError::Internal)?;
+        }
+        return Ok(ConfigStored {
+            id: config_id,
+            name: data.name,
+            environment: data.environment,
+            version,
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl TypedStore for ConfigStore {
+    type StoreRequest = ConfigStoreStoreData;
+    type StoreResponse = ConfigStored;
+    type RetrieveRequest = ConfigRetrieveRequest;
+    type RetrieveResponse = ConfigRetrieved;
+    type UpdateRequest = ConfigStoreStoreData;
+
+    fn store_type(&self) -> &str {
+        "config"
+    }
+    fn as_config(&self) -> Option<&ConfigStore> {
+        Some(self)
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: true,
+            description: false,
+            secret_type: false,
+        }
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let config_data = sqlx::query_as::<_, ConfigStoredValue>(
+            r#"SELECT * FROM tokaysec.config_store WHERE id = $1"#,
+        )
+        .bind(&id)
+        .fetch_one(&app.database.inner)
+        .await?;
+        let current = self
+            .get_version(app, &config_data.id, config_data.current_version)
+            .await;
+        return Ok(RetrievedSecretData {
+            id: config_data.id,
+            name: config_data.key,
+            environment: Some(config_data.environment),
+            updated_when: Some(config_data.last_updated),
+            value: current.map(|e| e.value),
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        let mut found = get_rows(app, self.list_table(), ids).await?;
+        // Current values in one go, same as get.
+        let current = sqlx::query_as::<_, ConfigValueVersion>(
+            r#"SELECT v.* FROM tokaysec.config_store_versions v JOIN tokaysec.config_store c ON c.id = v.config_id AND c.current_version = v.version WHERE c.id = ANY($1)"#,
+        )
+        .bind(ids)
+        .fetch_all(&app.database.inner)
+        .await?;
+        let mut current: HashMap<String, serde_json::Value> = current
+            .into_iter()
+            .map(|e| (e.config_id, e.value))
+            .collect();
+        for data in found.iter_mut() {
+            data.value = current.remove(&data.id);
+        }
+        Ok(found)
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadConfig).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    async fn retrieve(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        request: ConfigRetrieveRequest,
+        requester: &str,
+    ) -> Result<ConfigRetrieved, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadConfig).await?;
+        let Some(config_data) = self
+            .find_by_key(app, &request.project, &request.environment, &request.key)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No config named '{}' in project {} ({})",
+                request.key, request.project, request.environment
+            )));
+        };
+        let version = request.version.unwrap_or(config_data.current_version);
+        let Some(value) = self.get_version(app, &config_data.id, version).await else {
+            return Err(StoreAccessError::NotFound(format!(
+                "Config '{}' has no version {}",
+                request.key, version
+            )));
+        };
+        return Ok(ConfigRetrieved {
+            id: config_data.id,
+            name: config_data.key,
+            environment: config_data.environment,
+            version: value.version,
+            value_type: value.value_type,
+            value: value.value,
+            source: value.source,
+        });
+    }
+
+    async fn store(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        data: ConfigStoreStoreData,
+        creator: &str,
+    ) -> Result<ConfigStored, StoreAccessError> {
+        self.write(app, data, creator, false, None).await
+    }
+
+    async fn update(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        data: ConfigStoreStoreData,
+        requester: &str,
+    ) -> Result<ConfigStored, StoreAccessError> {
+        self.write(app, data, requester, true, None).await
+    }
+
+    async fn delete(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, &target.project, requester, AccessAction::DeleteConfig).await?;
+        let Some(existing) = self
+            .find_by_key(app, &target.project, &target.environment, &target.name)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No config named '{}' in project {} ({})",
+                target.name, target.project, target.environment
+            )));
+        };
+        // Versions go with it (ON DELETE CASCADE).
+        sqlx::query(r#"DELETE FROM tokaysec.config_store WHERE id = ($1)"#)
+            .bind(&existing.id)
+            .execute(&app.database.inner)
+            .await?;
+        remove_resource_assignments(app, &format!("config:{}", &existing.id)).await
+    }
+}
@@ -0,0 +1,394 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::{secure_buf::SecureBuffer, stores::StoreAccessError, stores::pki::KeyType};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+pub const MIN_TOKEN_BYTES: usize = 16;
+pub const MAX_TOKEN_BYTES: usize = 1024;
+pub const MIN_PASSPHRASE_WORDS: usize = 4;
+pub const MAX_PASSPHRASE_WORDS: usize = 64;
+
+const LOWERCASE: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &[u8] = b"0123456789";
+const SYMBOLS: &[u8] = b"!#$%&()*+,-./:;<=>?@[]^_{|}~";
+const HEX: &[u8] = b"0123456789abcdef";
+
+fn default_password_length() -> usize {
+    32
+}
+
+fn default_token_bytes() -> usize {
+    32
+}
+
+fn default_passphrase_words() -> usize {
+    6
+}
+
+fn default_separator() -> String {
+    "-".to_string()
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CharacterClasses {
+    #[serde(default = "enabled")]
+    pub lowercase: bool,
+    #[serde(default = "enabled")]
+    pub uppercase: bool,
+    #[serde(default = "enabled")]
+    pub digits: bool,
+    #[serde(default = "enabled")]
+    pub symbols: bool,
+}
+
+impl Default for CharacterClasses {
+    fn default() -> Self {
+        CharacterClasses {
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+        }
+    }
+}
+
+// Sent in place of a value to have the server come up with one, e.g.
+// {"format": "password", "length": 40, "classes": {"symbols": false}}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum GeneratePolicy {
+    Password {
+        #[serde(default = "default_password_length")]
+        length: usize,
+        #[serde(default)]
+        classes: CharacterClasses,
+    },
+    Hex {
+        #[serde(default = "default_token_bytes")]
+        bytes: usize,
+    },
+    Base64url {
+        #[serde(default = "default_token_bytes")]
+        bytes: usize,
+    },
+    Uuid,
+    Passphrase {
+        #[serde(default = "default_passphrase_words")]
+        words: usize,
+        #[serde(default = "default_separator")]
+        separator: String,
+    },
+    Key {
+        key_type: KeyType,
+    },
+}
+
+fn invalid(message: String) -> StoreAccessError {
+    StoreAccessError::Invalid(message)
+}
+
+fn check_range(what: &str, value: usize, min: usize, max: usize) -> Result<(), StoreAccessError> {
+    if value < min || value > max {
+        return Err(invalid(format!(
+            "{} must be between {} and {}",
+            what, min, max
+        )));
+    }
+    Ok(())
+}
+
+// Uniform in [0, upper), rejecting the tail of the u32 range that
+// would otherwise favour the low values.
+fn random_below(upper: usize) -> usize {
+    let upper = upper as u32;
+    let zone = u32::MAX - (u32::MAX % upper);
+    loop {
+        let candidate = OsRng.next_u32();
+        if candidate < zone {
+            return (candidate % upper) as usize;
+        }
+    }
+}
+
+fn random_bytes(count: usize) -> SecureBuffer {
+    let mut buffer = SecureBuffer::new(count).unwrap();
+    OsRng.fill_bytes(buffer.expose_mut());
+    buffer
+}
+
+impl GeneratePolicy {
+    // The value is written straight into locked memory, nothing about it
+    // is kept around once the buffer is dropped.
+    pub fn generate(&self) -> Result<SecureBuffer, StoreAccessError> {
+        match self {
+            GeneratePolicy::Password { length, classes } => password(*length, classes),
+            GeneratePolicy::Hex { bytes } => {
+                check_range("bytes", *bytes, MIN_TOKEN_BYTES, MAX_TOKEN_BYTES)?;
+                let raw = random_bytes(*bytes);
+                let mut buffer = SecureBuffer::new(bytes * 2).unwrap();
+                let out = buffer.expose_mut();
+                for (i, byte) in raw.expose().iter().enumerate() {
+                    out[i * 2] = HEX[(byte >> 4) as usize];
+                    out[i * 2 + 1] = HEX[(byte & 0x0f) as usize];
+                }
+                Ok(buffer)
+            }
+            GeneratePolicy::Base64url { bytes } => {
+                check_range("bytes", *bytes, MIN_TOKEN_BYTES, MAX_TOKEN_BYTES)?;
+                let raw = random_bytes(*bytes);
+                let mut buffer =
+                    SecureBuffer::new(base64::encoded_len(*bytes, false).unwrap()).unwrap();
+                BASE64_URL_SAFE_NO_PAD
+                    .encode_slice(raw.expose(), buffer.expose_mut())
+                    .unwrap();
+                Ok(buffer)
+            }
+            GeneratePolicy::Uuid => {
+                let mut raw = random_bytes(16);
+                let raw_bytes = raw.expose_mut();
+                // Version 4, RFC 4122 variant.
+                raw_bytes[6] = (raw_bytes[6] & 0x0f) | 0x40;
+                raw_bytes[8] = (raw_bytes[8] & 0x3f) | 0x80;
+                let mut buffer = SecureBuffer::new(36).unwrap();
+                let out = buffer.expose_mut();
+                let mut pos = 0;
+                for (i, byte) in raw.expose().iter().enumerate() {
+                    if i == 4 || i == 6 || i == 8 || i == 10 {
+                        out[pos] = b'-';
+                        pos += 1;
+                    }
+                    out[pos] = HEX[(byte >> 4) as usize];
+                    out[pos + 1] = HEX[(byte & 0x0f) as usize];
+                    pos += 2;
+                }
+                Ok(buffer)
+            }
+            GeneratePolicy::Passphrase { words, separator } => passphrase(*words, separator),
+            GeneratePolicy::Key { key_type } => {
+                let key = key_type.generate();
+                let mut pem = key.private_key_to_pem_pkcs8().unwrap();
+                let buffer = SecureBuffer::from_slice(&pem).unwrap();
+                pem.zeroize();
+                Ok(buffer)
+            }
+        }
+    }
+}
+
+fn password(length: usize, classes: &CharacterClasses) -> Result<SecureBuffer, StoreAccessError> {
+    check_range("length", length, MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH)?;
+    let sets: Vec<&[u8]> = [
+        (classes.lowercase, LOWERCASE),
+        (classes.uppercase, UPPERCASE),
+        (classes.digits, DIGITS),
+        (classes.symbols, SYMBOLS),
+    ]
+    .into_iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, set)| set)
+    .collect();
+    if sets.is_empty() {
+        return Err(invalid(
+            "At least one character class has to be enabled".to_string(),
+        ));
+    }
+    let alphabet: Vec<u8> = sets.concat();
+    let mut buffer = SecureBuffer::new(length).unwrap();
+    let out = buffer.expose_mut();
+    // One character from every enabled class so the result passes
+    // whatever complexity rule asked for those classes, the rest from
+    // all of them. Shuffled afterwards so the guaranteed ones don't
+    // always sit at the front.
+    for (i, set) in sets.iter().enumerate() {
+        out[i] = set[random_below(set.len())];
+    }
+    for slot in out.iter_mut().skip(sets.len()) {
+        *slot = alphabet[random_below(alphabet.len())];
+    }
+    for i in (1..length).rev() {
+        out.swap(i, random_below(i + 1));
+    }
+    Ok(buffer)
+}
+
+// Words from the EFF large list, ~12.9 bits each.
+fn passphrase(words: usize, separator: &str) -> Result<SecureBuffer, StoreAccessError> {
+    check_range("words", words, MIN_PASSPHRASE_WORDS, MAX_PASSPHRASE_WORDS)?;
+    if separator.len() > 8 {
+        return Err(invalid(
+            "separator can't be longer than 8 bytes".to_string(),
+        ));
+    }
+    let list = eff_wordlist::large::LIST;
+    let mut picks: Vec<usize> = (0..words).map(|_| random_below(list.len())).collect();
+    let length =
+        picks.iter().map(|i| list[*i].1.len()).sum::<usize>() + separator.len() * (words - 1);
+    let mut buffer = SecureBuffer::new(length).unwrap();
+    let out = buffer.expose_mut();
+    let mut pos = 0;
+    for (n, i) in picks.iter().enumerate() {
+        if n > 0 {
+            out[pos..pos + separator.len()].copy_from_slice(separator.as_bytes());
+            pos += separator.len();
+        }
+        let word = list[*i].1.as_bytes();
+        out[pos..pos + word.len()].copy_from_slice(word);
+        pos += word.len();
+    }
+    picks.zeroize();
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(policy: GeneratePolicy) -> String {
+        String::from_utf8(policy.generate().unwrap().expose().to_vec()).unwrap()
+    }
+
+    fn classes(lowercase: bool, uppercase: bool, digits: bool, symbols: bool) -> CharacterClasses {
+        CharacterClasses {
+            lowercase,
+            uppercase,
+            digits,
+            symbols,
+        }
+    }
+
+    #[test]
+    fn passwords_have_every_enabled_class() {
+        // Eight characters with four classes leaves little room, run it
+        // a few times so a missing guarantee would show.
+        for _ in 0..200 {
+            let value = text(GeneratePolicy::Password {
+                length: 8,
+                classes: CharacterClasses::default(),
+            });
+            assert_eq!(value.len(), 8);
+            for set in [LOWERCASE, UPPERCASE, DIGITS, SYMBOLS] {
+                assert!(value.bytes().any(|b| set.contains(&b)), "{}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn passwords_leave_out_disabled_classes() {
+        let value = text(GeneratePolicy::Password {
+            length: 256,
+            classes: classes(true, false, true, false),
+        });
+        assert_eq!(value.len(), 256);
+        assert!(
+            value
+                .bytes()
+                .all(|b| LOWERCASE.contains(&b) || DIGITS.contains(&b))
+        );
+    }
+
+    #[test]
+    fn passwords_need_a_class() {
+        let result = GeneratePolicy::Password {
+            length: 32,
+            classes: classes(false, false, false, false),
+        }
+        .generate();
+        assert!(matches!(result, Err(StoreAccessError::Invalid(_))));
+    }
+
+    #[test]
+    fn uuids_are_version_4() {
+        let value = text(GeneratePolicy::Uuid);
+        let groups: Vec<&str> = value.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|g| g.len()).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12]
+        );
+        assert!(value.bytes().all(|b| b == b'-' || HEX.contains(&b)));
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+    }
+
+    #[test]
+    fn token_lengths() {
+        let hex = text(GeneratePolicy::Hex { bytes: 20 });
+        assert_eq!(hex.len(), 40);
+        assert!(hex.bytes().all(|b| HEX.contains(&b)));
+        let encoded = text(GeneratePolicy::Base64url { bytes: 20 });
+        assert_eq!(encoded.len(), 27);
+        assert_eq!(BASE64_URL_SAFE_NO_PAD.decode(&encoded).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn passphrases_use_the_separator() {
+        let value = text(GeneratePolicy::Passphrase {
+            words: 5,
+            separator: "+".to_string(),
+        });
+        let words: Vec<&str> = value.split('+').collect();
+        assert_eq!(words.len(), 5);
+        assert!(
+            words
+                .iter()
+                .all(|w| eff_wordlist::large::LIST.iter().any(|(_, e)| e == w))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        for policy in [
+            GeneratePolicy::Password {
+                length: MIN_PASSWORD_LENGTH - 1,
+                classes: CharacterClasses::default(),
+            },
+            GeneratePolicy::Password {
+                length: MAX_PASSWORD_LENGTH + 1,
+                classes: CharacterClasses::default(),
+            },
+            GeneratePolicy::Hex {
+                bytes: MIN_TOKEN_BYTES - 1,
+            },
+            GeneratePolicy::Base64url {
+                bytes: MAX_TOKEN_BYTES + 1,
+            },
+            GeneratePolicy::Passphrase {
+                words: MIN_PASSPHRASE_WORDS - 1,
+                separator: default_separator(),
+            },
+            GeneratePolicy::Passphrase {
+                words: MAX_PASSPHRASE_WORDS + 1,
+                separator: default_separator(),
+            },
+            GeneratePolicy::Passphrase {
+                words: 6,
+                separator: "123456789".to_string(),
+            },
+        ] {
+            assert!(matches!(
+                policy.generate(),
+                Err(StoreAccessError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn random_below_stays_in_range_and_covers_it() {
+        assert_eq!(random_below(1), 0);
+        let mut seen = [false; 7];
+        for _ in 0..1000 {
+            let value = random_below(7);
+            assert!(value < 7);
+            seen[value] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+    }
+}
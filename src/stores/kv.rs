@@ -1,23 +1,53 @@
 use std::{collections::HashMap, io::empty};
 
 use aes_gcm::aead::{OsRng, rand_core::RngCore};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use tracing::warn;
 
 use crate::{
-    app::{App, EasyResource},
+    aad::{self, AAD_VERSION, SecretAad},
+    app::{App, ResourceTypes},
+    bulk::ConflictMode,
     dek::Dek,
+    dek_cache::{self, ProjectDekCache},
     kek_provider::KekProvider,
-    models::{KVStoredValue, WrappedDek},
+    limits::{self, Limits},
+    migrate::MigratedSecret,
+    models::{KVPreviousValue, KVStoredValue, WrappedDek},
+    policies::AccessAction,
     secure_buf::SecureBuffer,
-    stores::{RetrievedSecretData, Store, StoreUiRequirements, kv},
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, generate::GeneratePolicy, get_rows, list_rows,
+        remove_resource_assignments, require_project_action,
+    },
 };
 
+// Name of the kv instance that exists without any configuration.
+pub const KV_STORE: &str = "kv_store";
+
+// Environment secrets land in when the caller doesn't pick one.
+pub const DEFAULT_ENVIRONMENT: &str = "default";
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct KvStoreStoreData {
     pub name: String,
+    #[serde(default)]
     pub value: Vec<u8>,
+    // Have the server generate the value instead of sending one.
+    #[serde(default)]
+    pub generate: Option<GeneratePolicy>,
     pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,58 +66,1045 @@ pub struct KvStoreReturnDek {
     pub data: Vec<u8>,
 }
 
-pub struct KvStore {}
+// Per instance settings from `[stores.<name>]` in Config.toml.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct KvStoreSettings {
+    // Largest value (in bytes) this instance accepts.
+    #[serde(default)]
+    pub max_value_bytes: Option<usize>,
+    // Applied to secrets stored without their own expires_at.
+    #[serde(default)]
+    pub default_ttl_secs: Option<i64>,
+}
+
+pub struct KvStore {
+    // Instances share the kv_store table, rows and resource names are
+    // kept apart by this.
+    pub name: String,
+    pub settings: KvStoreSettings,
+}
 
 impl KvStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "kv_store",
+            name_column: "key",
+            has_environment: true,
+            updated_column: "last_updated",
+            store: Some(&self.name),
+        }
+    }
     pub async fn init() -> Self {
-        Self {}
+        Self::configured(KV_STORE, KvStoreSettings::default())
+    }
+    pub fn configured(name: &str, settings: KvStoreSettings) -> Self {
+        Self {
+            name: name.to_string(),
+            settings,
+        }
+    }
+    // Keys are only unique within a project + environment, so that
+    // is the only way to look one up by name.
+    pub async fn find_by_key(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Option<KVStoredValue> {
+        sqlx::query_as::<_, KVStoredValue>(
+            r#"SELECT * FROM tokaysec.kv_store WHERE project = ($1) AND environment = ($2) AND key = ($3) AND store = ($4)"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .bind(&self.name)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
     }
     pub async fn store_secret(
         &self,
         app: &App,
+        id: &str,
         key: &str,
         store_result: &KvStoreReturn,
         project: &str,
+        environment: &str,
         creator: &str,
-    ) -> Result<(), String>
+        expires_at: Option<DateTime<Utc>>,
+        limits: &Limits,
+    ) -> Result<KVStoredValue, StoreAccessError>
     where
         Self: Sized,
     {
         let added_when = Utc::now();
-        let id = app.gen_id().await;
         let dek_id = app.gen_id().await;
+        let mut tx = app.database.inner.begin().await?;
+        limits::check_secret_count(&mut *tx, limits, project, 1).await?;
         let wrapped_dek = sqlx::query_as::<_, WrappedDek>(
             r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6) RETURNING *"#,
         )
         .bind(&dek_id).bind(&store_result.dek.data).bind(store_result.dek.nonce).bind(store_result.dek.tag)
         .bind(added_when).bind(&creator)
-        .fetch_one(&app.database.inner)
-        .await
-        .unwrap();
+        .fetch_one(&mut *tx)
+        .await?;
         let stored_value = sqlx::query_as::<_, KVStoredValue>(
-            r#"INSERT INTO tokaysec.kv_store(id,key,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by,last_updated) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$8) RETURNING *"#,
+            r#"INSERT INTO tokaysec.kv_store(id,key,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by,last_updated,expires_at,project,environment,store,aad_version) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$8,$10,$11,$12,$13,$14) RETURNING *"#,
         )
         .bind(&id).bind(&key).bind(&store_result.data).bind(&store_result.gcm_tag).bind(&store_result.kmac_tag)
-        .bind(&store_result.nonce).bind(&wrapped_dek.id).bind(added_when).bind(&creator)
-        .fetch_one(&app.database.inner)
-        .await
-        .unwrap();
-        app.create_resource_assignment(
-            EasyResource(crate::app::ResourceTypes::Project, &project),
-            EasyResource(
-                crate::app::ResourceTypes::Secret,
-                &format!("kv_store:{}", &stored_value.id),
-            ),
-            creator,
+        .bind(&store_result.nonce).bind(&wrapped_dek.id).bind(added_when).bind(&creator).bind(expires_at)
+        .bind(&project).bind(&environment).bind(&self.name).bind(AAD_VERSION)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"INSERT INTO tokaysec.resource_assignment(assigned_to,assigned_to_type,resource,resource_type,assigned_by,assigned_when) VALUES($1,$2,$3,$4,$5,$6)"#,
         )
-        .await
-        .unwrap();
-        return Ok(());
+        .bind(&project)
+        .bind(ResourceTypes::Project.to_string())
+        .bind(format!("{}:{}", &self.name, &stored_value.id))
+        .bind(ResourceTypes::Secret.to_string())
+        .bind(&creator)
+        .bind(added_when)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Ok(stored_value);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KvRetrieveRequest {
+    pub project: String,
+    #[serde(alias = "name")]
+    pub key: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    // Expired secrets stay around (so they can be rotated or inspected)
+    // but reading them has to be asked for explicitly.
+    #[serde(default)]
+    pub allow_expired: bool,
+    // A previous version, only readable while a rotation's grace
+    // period keeps it around.
+    #[serde(default)]
+    pub version: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KvRetrieved {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub version: i32,
+    // Same shape the value was stored with.
+    pub value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KvStored {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub version: i32,
+    pub generated: bool,
+}
+
+// Replaces the value and/or the expiry of an existing secret.
+#[derive(Serialize, Deserialize)]
+pub struct KvStoreUpdateData {
+    pub project: String,
+    pub name: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default)]
+    pub value: Vec<u8>,
+    #[serde(default)]
+    pub generate: Option<GeneratePolicy>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Either the value sent or one generated from the policy, never both.
+fn value_buffer(
+    value: &[u8],
+    generate: &Option<GeneratePolicy>,
+) -> Result<SecureBuffer, StoreAccessError> {
+    match generate {
+        Some(policy) => {
+            if !value.is_empty() {
+                return Err(StoreAccessError::Invalid(
+                    "Send either a value or a generate policy, not both".to_string(),
+                ));
+            }
+            policy.generate()
+        }
+        None if value.is_empty() => Err(StoreAccessError::Invalid(
+            "A value or a generate policy is required".to_string(),
+        )),
+        None => Ok(SecureBuffer::from_slice(value).unwrap()),
+    }
+}
+
+// A new expiry also re-arms the expiry notification (see expiry.rs).
+async fn set_expiry(
+    conn: &mut sqlx::PgConnection,
+    id: &str,
+    expires_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<(), StoreAccessError> {
+    sqlx::query(
+        r#"UPDATE tokaysec.kv_store SET expires_at = ($1), expiry_notified_when = NULL, last_updated = ($2) WHERE id = ($3)"#,
+    )
+    .bind(expires_at)
+    .bind(now)
+    .bind(&id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+impl KvStore {
+    // The instance's own maximum and then the project's limit.
+    fn check_size(
+        &self,
+        limits: &Limits,
+        project: &str,
+        value: &SecureBuffer,
+    ) -> Result<(), StoreAccessError> {
+        if let Some(max_value_bytes) = self.settings.max_value_bytes
+            && value.expose().len() > max_value_bytes
+        {
+            return Err(StoreAccessError::TooLarge(format!(
+                "Values in {} can't be larger than {} bytes",
+                self.name, max_value_bytes
+            )));
+        }
+        limits::check_value_size(limits, project, value.expose().len())
+    }
+    // What a value of this secret at this version is bound to, see
+    // aad.rs.
+    fn aad(&self, instance: &str, project: &str, secret: &str, version: i32) -> String {
+        SecretAad {
+            instance,
+            project,
+            store: &self.name,
+            secret,
+            version,
+        }
+        .to_string()
+    }
+    async fn encrypt(
+        &self,
+        kek_provider: &dyn KekProvider,
+        aad: &str,
+        value: SecureBuffer,
+    ) -> KvStoreReturn {
+        let dek = Dek::init();
+        let encrypted = dek.wrap_with_aad(value, aad.as_bytes());
+        let (wrapped_dek, nonce, tag) = kek_provider.wrap_dek(dek, aad).await.unwrap();
+        KvStoreReturn {
+            dek: KvStoreReturnDek {
+                nonce,
+                tag,
+                data: wrapped_dek,
+            },
+            gcm_tag: encrypted.gcm_tag,
+            kmac_tag: encrypted.kmac_tag,
+            nonce: encrypted.nonce,
+            data: encrypted.data,
+        }
+    }
+    // Anything that doesn't decrypt under the AAD of the row it was
+    // read from has been moved there and is turned down.
+    // Through the DEK cache when the project uses it (see dek_cache.rs).
+    async fn decrypt(
+        &self,
+        kek_provider: &dyn KekProvider,
+        cache: Option<&ProjectDekCache>,
+        dek: WrappedDek,
+        name: &str,
+        aad: &str,
+        aad_version: i16,
+        value: Vec<u8>,
+        kmac_tag: Vec<u8>,
+        nonce: Vec<u8>,
+        gcm_tag: Vec<u8>,
+    ) -> Result<SecureBuffer, StoreAccessError> {
+        if aad_version != AAD_VERSION {
+            return Err(StoreAccessError::Internal(format!(
+                "'{}' is still encrypted under an old AAD, restart to have it re-encrypted",
+                name
+            )));
+        }
+        let mismatch = |_| {
+            StoreAccessError::Internal(format!(
+                "'{}' doesn't match the row it is stored in and won't be decrypted",
+                name
+            ))
+        };
+        let unwrapped_dek: Dek = match cache.and_then(|e| e.get(&dek.id, aad)) {
+            Some(cached) => cached,
+            None => {
+                let unwrapped = kek_provider
+                    .try_unwrap_dek(
+                        &dek.wrapped,
+                        dek.nonce.try_into().unwrap(),
+                        dek.tag.try_into().unwrap(),
+                        aad,
+                    )
+                    .await
+                    .map_err(mismatch)?;
+                if let Some(cache) = cache {
+                    cache.insert(&dek.id, aad, &unwrapped);
+                }
+                unwrapped
+            }
+        }
+        .into();
+        unwrapped_dek
+            .try_unwrap_with_aad(value, kmac_tag, aad.as_bytes(), nonce, gcm_tag)
+            .map_err(mismatch)
+    }
+    fn not_found(&self, project: &str, environment: &str, key: &str) -> StoreAccessError {
+        StoreAccessError::NotFound(format!(
+            "No secret named '{}' in project {} ({})",
+            key, project, environment
+        ))
+    }
+    // Swaps in a new value under a fresh DEK and bumps the version.
+    // With retain_until the replaced value stays readable by version
+    // until then, otherwise it goes right away along with its DEK.
+    // A new expires_at is set in the same transaction. Returns the new
+    // version.
+    pub async fn replace_value(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        existing: &KVStoredValue,
+        value: SecureBuffer,
+        retain_until: Option<DateTime<Utc>>,
+        expires_at: Option<DateTime<Utc>>,
+        replaced_by: &str,
+    ) -> Result<i32, StoreAccessError> {
+        let limits = limits::effective(app, &existing.project).await?;
+        self.check_size(&limits, &existing.project, &value)?;
+        let now = Utc::now();
+        let instance = aad::instance_id(app).await?;
+        let mut tx = app.database.inner.begin().await?;
+        // Locked before encrypting as the version goes into the AAD, a
+        // concurrent replace waits and then retires our value instead
+        // of racing us for the version.
+        let current = sqlx::query_as::<_, KVStoredValue>(
+            r#"SELECT * FROM tokaysec.kv_store WHERE id = ($1) FOR UPDATE"#,
+        )
+        .bind(&existing.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        let aad = self.aad(
+            &instance,
+            &current.project,
+            &current.id,
+            current.version + 1,
+        );
+        let encrypted = self.encrypt(kek_provider, &aad, value).await;
+        let dek_id = app.gen_id().await;
+        sqlx::query(
+            r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&dek_id)
+        .bind(&encrypted.dek.data)
+        .bind(encrypted.dek.nonce)
+        .bind(encrypted.dek.tag)
+        .bind(now)
+        .bind(&replaced_by)
+        .execute(&mut *tx)
+        .await?;
+        if let Some(retain_until) = retain_until {
+            sqlx::query(
+                r#"INSERT INTO tokaysec.kv_store_previous(secret_id,version,value,gcm_tag,kmac_tag,nonce,dek_used,retired_when,retained_until,aad_version) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10)"#,
+            )
+            .bind(&current.id)
+            .bind(current.version)
+            .bind(&current.value)
+            .bind(&current.gcm_tag)
+            .bind(&current.kmac_tag)
+            .bind(&current.nonce)
+            .bind(&current.dek_used)
+            .bind(now)
+            .bind(retain_until)
+            .bind(current.aad_version)
+            .execute(&mut *tx)
+            .await?;
+            limits::prune_versions(&mut *tx, &limits, &current.id).await?;
+        }
+        sqlx::query(
+            r#"UPDATE tokaysec.kv_store SET value = ($1), gcm_tag = ($2), kmac_tag = ($3), nonce = ($4), dek_used = ($5), last_updated = ($6), version = ($7), aad_version = ($9) WHERE id = ($8)"#,
+        )
+        .bind(&encrypted.data)
+        .bind(&encrypted.gcm_tag)
+        .bind(&encrypted.kmac_tag)
+        .bind(&encrypted.nonce)
+        .bind(&dek_id)
+        .bind(now)
+        .bind(current.version + 1)
+        .bind(&current.id)
+        .bind(AAD_VERSION)
+        .execute(&mut *tx)
+        .await?;
+        if let Some(expires_at) = expires_at {
+            set_expiry(&mut *tx, &current.id, expires_at, now).await?;
+        }
+        if retain_until.is_none() {
+            sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+                .bind(&current.dek_used)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        if retain_until.is_none() {
+            app.dek_cache.invalidate(&[current.dek_used]);
+        }
+        Ok(current.version + 1)
+    }
+    async fn find_previous(
+        &self,
+        app: &App,
+        secret_id: &str,
+        version: i32,
+    ) -> Result<Option<KVPreviousValue>, StoreAccessError> {
+        Ok(sqlx::query_as::<_, KVPreviousValue>(
+            r#"SELECT * FROM tokaysec.kv_store_previous WHERE secret_id = ($1) AND version = ($2) AND retained_until > ($3)"#,
+        )
+        .bind(&secret_id)
+        .bind(version)
+        .bind(Utc::now())
+        .fetch_optional(&app.database.inner)
+        .await?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct KvImported {
+    pub created: Vec<String>,
+    pub overwritten: Vec<String>,
+    pub skipped: Vec<String>,
+    // Left out because they had no value, e.g. `FOO=` in a dotenv file.
+    #[serde(default)]
+    pub empty: Vec<String>,
+}
+
+// Bulk import and export, see bulk.rs.
+impl KvStore {
+    pub async fn import(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        entries: Vec<(String, String)>,
+        conflict: ConflictMode,
+        requester: &str,
+    ) -> Result<KvImported, StoreAccessError> {
+        if entries.is_empty() {
+            return Err(StoreAccessError::Invalid("Nothing to import".to_string()));
+        }
+        // Only the last value given for a name counts (see
+        // import_values), an empty one there leaves the name out.
+        let last = entries
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.to_owned(), i))
+            .collect::<HashMap<String, usize>>();
+        let mut values = Vec::with_capacity(entries.len());
+        let mut empty = vec![];
+        for (i, (name, value)) in entries.into_iter().enumerate() {
+            if last[&name] != i {
+                continue;
+            }
+            if value.is_empty() {
+                empty.push(name);
+                continue;
+            }
+            values.push((name, SecureBuffer::from_slice(value.as_bytes()).unwrap()));
+        }
+        let mut imported = self
+            .import_values(
+                app,
+                kek_provider,
+                project,
+                environment,
+                values,
+                conflict,
+                requester,
+            )
+            .await?;
+        imported.empty = empty;
+        Ok(imported)
+    }
+    // import for values that don't have to be text, e.g. promotions
+    // (see promotion.rs).
+    pub async fn import_values(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        values: Vec<(String, SecureBuffer)>,
+        conflict: ConflictMode,
+        requester: &str,
+    ) -> Result<KvImported, StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::CreateSecret).await?;
+        // A name given twice keeps its last value, the same as sourcing
+        // a dotenv file.
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut deduped: Vec<(String, SecureBuffer)> = Vec::with_capacity(values.len());
+        for (name, value) in values {
+            match positions.get(&name) {
+                Some(&i) => deduped[i].1 = value,
+                None => {
+                    positions.insert(name.to_owned(), deduped.len());
+                    deduped.push((name, value));
+                }
+            }
+        }
+        let values = deduped;
+        let limits = limits::effective(app, project).await?;
+        for (_, value) in &values {
+            self.check_size(&limits, project, value)?;
+        }
+        let names = values
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect::<Vec<String>>();
+        let now = Utc::now();
+        let expires_at = self
+            .settings
+            .default_ttl_secs
+            .map(|ttl| now + chrono::Duration::seconds(ttl));
+        let mut tx = app.database.inner.begin().await?;
+        // Locked so nothing changes under us between deciding and
+        // writing.
+        let existing = sqlx::query_as::<_, KVStoredValue>(
+            r#"SELECT * FROM tokaysec.kv_store WHERE project = ($1) AND environment = ($2) AND store = ($3) AND key = ANY($4) FOR UPDATE"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&self.name)
+        .bind(&names)
+        .fetch_all(&mut *tx)
+        .await?;
+        if !existing.is_empty() {
+            match conflict {
+                ConflictMode::Fail => {
+                    return Err(StoreAccessError::Conflict(format!(
+                        "Already in project {} ({}): {}",
+                        project,
+                        environment,
+                        existing
+                            .iter()
+                            .map(|e| e.key.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    )));
+                }
+                ConflictMode::Overwrite => {
+                    require_project_action(app, project, requester, AccessAction::UpdateSecret)
+                        .await?;
+                }
+                ConflictMode::Skip => {}
+            }
+        }
+        limits::check_secret_count(&mut *tx, &limits, project, names.len() - existing.len())
+            .await?;
+        let mut existing = existing
+            .into_iter()
+            .map(|e| (e.key.to_owned(), e))
+            .collect::<HashMap<String, KVStoredValue>>();
+        let instance = aad::instance_id(app).await?;
+        let mut imported = KvImported::default();
+        let mut replaced_deks = vec![];
+        for (name, value) in values {
+            let current = existing.remove(&name);
+            if current.is_some() && conflict == ConflictMode::Skip {
+                imported.skipped.push(name);
+                continue;
+            }
+            let (id, version) = match &current {
+                Some(current) => (current.id.to_owned(), current.version + 1),
+                None => (app.gen_id().await, 1),
+            };
+            let aad = self.aad(&instance, project, &id, version);
+            let encrypted = self.encrypt(kek_provider, &aad, value).await;
+            let dek_id = app.gen_id().await;
+            sqlx::query(
+                r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+            )
+            .bind(&dek_id)
+            .bind(&encrypted.dek.data)
+            .bind(encrypted.dek.nonce)
+            .bind(encrypted.dek.tag)
+            .bind(now)
+            .bind(&requester)
+            .execute(&mut *tx)
+            .await?;
+            match current {
+                // Same as replace_value without keeping the old value.
+                Some(current) => {
+                    sqlx::query(
+                        r#"UPDATE tokaysec.kv_store SET value = ($1), gcm_tag = ($2), kmac_tag = ($3), nonce = ($4), dek_used = ($5), last_updated = ($6), version = ($7), aad_version = ($9) WHERE id = ($8)"#,
+                    )
+                    .bind(&encrypted.data)
+                    .bind(&encrypted.gcm_tag)
+                    .bind(&encrypted.kmac_tag)
+                    .bind(&encrypted.nonce)
+                    .bind(&dek_id)
+                    .bind(now)
+                    .bind(version)
+                    .bind(&current.id)
+                    .bind(AAD_VERSION)
+                    .execute(&mut *tx)
+                    .await?;
+                    sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+                        .bind(&current.dek_used)
+                        .execute(&mut *tx)
+                        .await?;
+                    replaced_deks.push(current.dek_used);
+                    imported.overwritten.push(name);
+                }
+                None => {
+                    sqlx::query(
+                        r#"INSERT INTO tokaysec.kv_store(id,key,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by,last_updated,expires_at,project,environment,store,aad_version) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$8,$10,$11,$12,$13,$14)"#,
+                    )
+                    .bind(&id)
+                    .bind(&name)
+                    .bind(&encrypted.data)
+                    .bind(&encrypted.gcm_tag)
+                    .bind(&encrypted.kmac_tag)
+                    .bind(&encrypted.nonce)
+                    .bind(&dek_id)
+                    .bind(now)
+                    .bind(&requester)
+                    .bind(expires_at)
+                    .bind(&project)
+                    .bind(&environment)
+                    .bind(&self.name)
+                    .bind(AAD_VERSION)
+                    .execute(&mut *tx)
+                    .await?;
+                    sqlx::query(
+                        r#"INSERT INTO tokaysec.resource_assignment(assigned_to,assigned_to_type,resource,resource_type,assigned_by,assigned_when) VALUES($1,$2,$3,$4,$5,$6)"#,
+                    )
+                    .bind(&project)
+                    .bind(ResourceTypes::Project.to_string())
+                    .bind(format!("{}:{}", &self.name, &id))
+                    .bind(ResourceTypes::Secret.to_string())
+                    .bind(&requester)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                    imported.created.push(name);
+                }
+            }
+        }
+        tx.commit().await?;
+        app.dek_cache.invalidate(&replaced_deks);
+        Ok(imported)
+    }
+    // Current values by name, expired secrets are left out like they
+    // are for retrieve.
+    pub async fn export(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        requester: &str,
+    ) -> Result<Vec<(String, String)>, StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+        let mut entries = vec![];
+        for (key, _, value) in self
+            .current_values(app, kek_provider, project, environment)
+            .await?
+        {
+            let Ok(value) = String::from_utf8(value.expose().to_vec()) else {
+                return Err(StoreAccessError::Invalid(format!(
+                    "'{}' isn't text and can't be exported",
+                    key
+                )));
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+    // Every unexpired value in the environment by name, with its
+    // version. Callers check permissions.
+    pub async fn current_values(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+    ) -> Result<Vec<(String, i32, SecureBuffer)>, StoreAccessError> {
+        let instance = aad::instance_id(app).await?;
+        let rows = sqlx::query_as::<_, KVStoredValue>(
+            r#"SELECT * FROM tokaysec.kv_store WHERE project = ($1) AND environment = ($2) AND store = ($3) AND (expires_at IS NULL OR expires_at > ($4)) ORDER BY key"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&self.name)
+        .bind(Utc::now())
+        .fetch_all(&app.database.inner)
+        .await?;
+        let dek_ids = rows
+            .iter()
+            .map(|e| e.dek_used.to_owned())
+            .collect::<Vec<String>>();
+        let mut deks = sqlx::query_as::<_, WrappedDek>(
+            r#"SELECT * FROM tokaysec.wrapped_deks WHERE id = ANY($1)"#,
+        )
+        .bind(&dek_ids)
+        .fetch_all(&app.database.inner)
+        .await?
+        .into_iter()
+        .map(|e| (e.id.to_owned(), e))
+        .collect::<HashMap<String, WrappedDek>>();
+        let cache = dek_cache::for_project(app, project).await?;
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in rows {
+            let Some(dek) = deks.remove(&row.dek_used) else {
+                return Err(StoreAccessError::Internal(format!(
+                    "The DEK of '{}' is missing",
+                    row.key
+                )));
+            };
+            let aad = self.aad(&instance, &row.project, &row.id, row.version);
+            let value = self
+                .decrypt(
+                    kek_provider,
+                    cache.as_ref(),
+                    dek,
+                    &row.key,
+                    &aad,
+                    row.aad_version,
+                    row.value,
+                    row.kmac_tag,
+                    row.nonce,
+                    row.gcm_tag,
+                )
+                .await?;
+            entries.push((row.key, row.version, value));
+        }
+        Ok(entries)
+    }
+}
+
+// Far enough out that purge_previous never gets to it.
+fn kept_for_good() -> DateTime<Utc> {
+    "9999-12-31T00:00:00Z".parse().unwrap()
+}
+
+// Secrets brought over from another secret manager, see migrate.rs.
+impl KvStore {
+    // Adds the secret with its history in one transaction. The last
+    // version is the current value, keeping its version number and
+    // times, the rest go to kv_store_previous for good (until someone
+    // deletes the secret). Ok(None) if the name is already taken.
+    pub async fn import_migrated(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        secret: &MigratedSecret,
+        requester: &str,
+    ) -> Result<Option<String>, StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::CreateSecret).await?;
+        let Some(current) = secret.versions.last() else {
+            return Err(StoreAccessError::Invalid(format!(
+                "'{}' has no value",
+                secret.name
+            )));
+        };
+        let now = Utc::now();
+        let limits = limits::effective(app, project).await?;
+        let instance = aad::instance_id(app).await?;
+        let id = app.gen_id().await;
+        let mut versions = Vec::with_capacity(secret.versions.len());
+        for version in &secret.versions {
+            let value = SecureBuffer::from_slice(version.value.as_bytes()).unwrap();
+            self.check_size(&limits, project, &value)?;
+            let aad = self.aad(&instance, project, &id, version.version);
+            let encrypted = self.encrypt(kek_provider, &aad, value).await;
+            versions.push((version, app.gen_id().await, encrypted));
+        }
+        let mut tx = app.database.inner.begin().await?;
+        let existing = sqlx::query_as::<_, KVStoredValue>(
+            r#"SELECT * FROM tokaysec.kv_store WHERE project = ($1) AND environment = ($2) AND store = ($3) AND key = ($4)"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&self.name)
+        .bind(&secret.name)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if existing.is_some() {
+            return Ok(None);
+        }
+        limits::check_secret_count(&mut *tx, &limits, project, 1).await?;
+        for (_, dek_id, encrypted) in &versions {
+            sqlx::query(
+                r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+            )
+            .bind(&dek_id)
+            .bind(&encrypted.dek.data)
+            .bind(encrypted.dek.nonce)
+            .bind(encrypted.dek.tag)
+            .bind(now)
+            .bind(&requester)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let (_, dek_id, encrypted) = versions.last().unwrap();
+        let added_when = secret.created.unwrap_or(now);
+        sqlx::query(
+            r#"INSERT INTO tokaysec.kv_store(id,key,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by,last_updated,project,environment,store,version,aad_version) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15)"#,
+        )
+        .bind(&id)
+        .bind(&secret.name)
+        .bind(&encrypted.data)
+        .bind(&encrypted.gcm_tag)
+        .bind(&encrypted.kmac_tag)
+        .bind(&encrypted.nonce)
+        .bind(&dek_id)
+        .bind(added_when)
+        .bind(&requester)
+        .bind(current.when.unwrap_or(added_when))
+        .bind(&project)
+        .bind(&environment)
+        .bind(&self.name)
+        .bind(current.version)
+        .bind(AAD_VERSION)
+        .execute(&mut *tx)
+        .await?;
+        // A version was retired when the one after it was written.
+        for (index, (version, dek_id, encrypted)) in versions.iter().enumerate().rev().skip(1) {
+            let retired_when = secret.versions[index + 1].when.unwrap_or(now);
+            sqlx::query(
+                r#"INSERT INTO tokaysec.kv_store_previous(secret_id,version,value,gcm_tag,kmac_tag,nonce,dek_used,retired_when,retained_until,aad_version) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10)"#,
+            )
+            .bind(&id)
+            .bind(version.version)
+            .bind(&encrypted.data)
+            .bind(&encrypted.gcm_tag)
+            .bind(&encrypted.kmac_tag)
+            .bind(&encrypted.nonce)
+            .bind(&dek_id)
+            .bind(retired_when)
+            .bind(kept_for_good())
+            .bind(AAD_VERSION)
+            .execute(&mut *tx)
+            .await?;
+        }
+        limits::prune_versions(&mut *tx, &limits, &id).await?;
+        let resource = format!("{}:{}", &self.name, &id);
+        sqlx::query(
+            r#"INSERT INTO tokaysec.resource_assignment(assigned_to,assigned_to_type,resource,resource_type,assigned_by,assigned_when) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&project)
+        .bind(ResourceTypes::Project.to_string())
+        .bind(&resource)
+        .bind(ResourceTypes::Secret.to_string())
+        .bind(&requester)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"INSERT INTO tokaysec.secret_tags(resource,tag,added_by) SELECT ($1), UNNEST($2::TEXT[]), ($3) ON CONFLICT DO NOTHING"#,
+        )
+        .bind(&resource)
+        .bind(&secret.tags)
+        .bind(&requester)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(id))
+    }
+}
+
+// A current or previous value still under the name-only AAD.
+#[derive(FromRow)]
+struct LegacyValue {
+    secret_id: String,
+    key: String,
+    project: String,
+    store: String,
+    version: i32,
+    value: Vec<u8>,
+    gcm_tag: Vec<u8>,
+    kmac_tag: Vec<u8>,
+    nonce: Vec<u8>,
+    dek_used: String,
+    previous: bool,
+}
+
+// Re-encrypts everything still under the name-only AAD (aad_version 0)
+// under a fresh DEK and the AAD from aad.rs. Runs at startup before
+// anything is served. A value that doesn't decrypt under its old AAD
+// either is left as it is, and refused when read.
+pub async fn migrate_aad(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+) -> Result<u64, StoreAccessError> {
+    let legacy = sqlx::query_as::<_, LegacyValue>(
+        r#"SELECT id AS secret_id, key, project, store, version, value, gcm_tag, kmac_tag, nonce, dek_used, FALSE AS previous FROM tokaysec.kv_store WHERE aad_version = 0
+        UNION ALL
+        SELECT p.secret_id, k.key, k.project, k.store, p.version, p.value, p.gcm_tag, p.kmac_tag, p.nonce, p.dek_used, TRUE AS previous FROM tokaysec.kv_store_previous p JOIN tokaysec.kv_store k ON k.id = p.secret_id WHERE p.aad_version = 0"#,
+    )
+    .fetch_all(&app.database.inner)
+    .await?;
+    if legacy.is_empty() {
+        return Ok(0);
+    }
+    let instance = aad::instance_id(app).await?;
+    let mut migrated = 0;
+    for row in legacy {
+        let Some(dek) = sqlx::query_as::<_, WrappedDek>(
+            r#"SELECT * FROM tokaysec.wrapped_deks WHERE id = ($1)"#,
+        )
+        .bind(&row.dek_used)
+        .fetch_optional(&app.database.inner)
+        .await?
+        else {
+            warn!(
+                "Version {} of kv secret {} has no DEK, not re-encrypting it",
+                row.version, row.secret_id
+            );
+            continue;
+        };
+        let unwrapped_dek: Dek = match kek_provider
+            .try_unwrap_dek(
+                &dek.wrapped,
+                dek.nonce.try_into().unwrap(),
+                dek.tag.try_into().unwrap(),
+                &row.key,
+            )
+            .await
+        {
+            Ok(unwrapped_dek) => unwrapped_dek.into(),
+            Err(e) => {
+                warn!(
+                    "The DEK of version {} of kv secret {} doesn't unwrap, not re-encrypting it: {}",
+                    row.version, row.secret_id, e
+                );
+                continue;
+            }
+        };
+        let value = match unwrapped_dek.try_unwrap_data(
+            row.value,
+            row.kmac_tag,
+            &row.key,
+            row.nonce,
+            row.gcm_tag,
+        ) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!(
+                    "Version {} of kv secret {} doesn't decrypt, not re-encrypting it: {}",
+                    row.version, row.secret_id, e
+                );
+                continue;
+            }
+        };
+        // Stands in for the instance the row belongs to, which may not
+        // be configured anymore.
+        let store = KvStore::configured(&row.store, KvStoreSettings::default());
+        let aad = store.aad(&instance, &row.project, &row.secret_id, row.version);
+        let encrypted = store.encrypt(kek_provider, &aad, value).await;
+        let dek_id = app.gen_id().await;
+        let mut tx = app.database.inner.begin().await?;
+        sqlx::query(
+            r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&dek_id)
+        .bind(&encrypted.dek.data)
+        .bind(encrypted.dek.nonce)
+        .bind(encrypted.dek.tag)
+        .bind(Utc::now())
+        .bind(&dek.added_by)
+        .execute(&mut *tx)
+        .await?;
+        let table = match row.previous {
+            true => "kv_store_previous",
+            false => "kv_store",
+        };
+        let id_column = match row.previous {
+            true => "secret_id",
+            false => "id",
+        };
+        let updated = sqlx::query(&format!(
+            r#"UPDATE tokaysec.{} SET value = ($1), gcm_tag = ($2), kmac_tag = ($3), nonce = ($4), dek_used = ($5), aad_version = ($6) WHERE {} = ($7) AND version = ($8) AND dek_used = ($9) AND aad_version = 0"#,
+            table, id_column
+        ))
+        .bind(&encrypted.data)
+        .bind(&encrypted.gcm_tag)
+        .bind(&encrypted.kmac_tag)
+        .bind(&encrypted.nonce)
+        .bind(&dek_id)
+        .bind(AAD_VERSION)
+        .bind(&row.secret_id)
+        .bind(row.version)
+        .bind(&row.dek_used)
+        .execute(&mut *tx)
+        .await?;
+        // Changed since we read it, it was written under the new AAD.
+        if updated.rows_affected() == 0 {
+            tx.rollback().await?;
+            continue;
+        }
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&row.dek_used)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        migrated += 1;
     }
+    Ok(migrated)
+}
+
+// Retained values whose grace period is over, and their DEKs.
+pub async fn purge_previous(app: &App) -> Result<u64, sqlx::Error> {
+    let mut tx = app.database.inner.begin().await?;
+    let purged = sqlx::query_as::<_, (String,)>(
+        r#"DELETE FROM tokaysec.kv_store_previous WHERE retained_until <= ($1) RETURNING dek_used"#,
+    )
+    .bind(Utc::now())
+    .fetch_all(&mut *tx)
+    .await?;
+    let purged = purged.into_iter().map(|(e,)| e).collect::<Vec<String>>();
+    for dek_used in &purged {
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&dek_used)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    app.dek_cache.invalidate(&purged);
+    Ok(purged.len() as u64)
 }
 
 #[async_trait::async_trait]
-impl Store for KvStore {
+impl TypedStore for KvStore {
+    type StoreRequest = KvStoreStoreData;
+    type StoreResponse = KvStored;
+    type RetrieveRequest = KvRetrieveRequest;
+    type RetrieveResponse = KvRetrieved;
+    type UpdateRequest = KvStoreUpdateData;
+
+    fn store_type(&self) -> &str {
+        "kv"
+    }
     fn ui_reqs(&self) -> StoreUiRequirements {
         StoreUiRequirements {
             name: true,
@@ -95,100 +1112,251 @@ impl Store for KvStore {
             secret_type: true,
         }
     }
-    async fn get(&self, app: &App, id: &str) -> RetrievedSecretData
-    where
-        Self: Sized,
-    {
+    fn as_kv(&self) -> Option<&KvStore> {
+        Some(self)
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
         let kv_data =
             sqlx::query_as::<_, KVStoredValue>(r#"SELECT * FROM tokaysec.kv_store WHERE id = $1"#)
                 .bind(&id)
                 .fetch_one(&app.database.inner)
-                .await
-                .unwrap();
-        return RetrievedSecretData {
+                .await?;
+        return Ok(RetrievedSecretData {
             id: kv_data.id,
             name: kv_data.key,
-        };
+            environment: Some(kv_data.environment),
+            updated_when: Some(kv_data.last_updated),
+            value: None,
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        list_rows(app, self.list_table(), &request).await
     }
     async fn retrieve(
         &self,
         app: &App,
-        data: HashMap<String, String>,
         kek_provider: &dyn KekProvider,
-    ) -> ()
-    where
-        Self: Sized,
-    {
-        let id = data.get("id").unwrap();
-        let kv_data =
-            sqlx::query_as::<_, KVStoredValue>(r#"SELECT * FROM tokaysec.kv_store WHERE id = $1"#)
-                .bind(&id)
-                .fetch_one(&app.database.inner)
-                .await
-                .unwrap();
+        request: KvRetrieveRequest,
+        requester: &str,
+    ) -> Result<KvRetrieved, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        let Some(kv_data) = self
+            .find_by_key(app, &request.project, &request.environment, &request.key)
+            .await
+        else {
+            return Err(self.not_found(&request.project, &request.environment, &request.key));
+        };
+        if let Some(expires_at) = kv_data.expires_at
+            && expires_at <= Utc::now()
+            && !request.allow_expired
+        {
+            return Err(StoreAccessError::Expired(format!(
+                "Secret '{}' expired at {}. Pass allow_expired=true to read it anyway.",
+                kv_data.key,
+                expires_at.to_rfc3339()
+            )));
+        }
+        let (version, value, kmac_tag, nonce, gcm_tag, dek_used, aad_version) = match request
+            .version
+        {
+            Some(version) if version != kv_data.version => {
+                let Some(previous) = self.find_previous(app, &kv_data.id, version).await? else {
+                    return Err(StoreAccessError::NotFound(format!(
+                        "Version {} of '{}' is not retained, the current version is {}",
+                        version, kv_data.key, kv_data.version
+                    )));
+                };
+                (
+                    previous.version,
+                    previous.value,
+                    previous.kmac_tag,
+                    previous.nonce,
+                    previous.gcm_tag,
+                    previous.dek_used,
+                    previous.aad_version,
+                )
+            }
+            _ => (
+                kv_data.version,
+                kv_data.value,
+                kv_data.kmac_tag,
+                kv_data.nonce,
+                kv_data.gcm_tag,
+                kv_data.dek_used,
+                kv_data.aad_version,
+            ),
+        };
         let dek_data =
             sqlx::query_as::<_, WrappedDek>(r#"SELECT * FROM tokaysec.wrapped_deks WHERE id = $1"#)
-                .bind(&kv_data.dek_used)
+                .bind(&dek_used)
                 .fetch_one(&app.database.inner)
-                .await
-                .unwrap();
-
-        let unwrapped_dek: Dek = kek_provider
-            .unwrap_dek(
-                &dek_data.wrapped,
-                dek_data.nonce.try_into().unwrap(),
-                dek_data.tag.try_into().unwrap(),
+                .await?;
+        let instance = aad::instance_id(app).await?;
+        let aad = self.aad(&instance, &kv_data.project, &kv_data.id, version);
+        let cache = dek_cache::for_project(app, &kv_data.project).await?;
+        let raw_data = self
+            .decrypt(
+                kek_provider,
+                cache.as_ref(),
+                dek_data,
                 &kv_data.key,
+                &aad,
+                aad_version,
+                value,
+                kmac_tag,
+                nonce,
+                gcm_tag,
             )
-            .await
-            .into();
-        let raw_data = unwrapped_dek.unwrap_data(
-            kv_data.value,
-            kv_data.kmac_tag,
-            &kv_data.key,
-            kv_data.nonce,
-            kv_data.gcm_tag,
-        );
-        println!("{:?}", String::from_utf8(raw_data.expose().to_vec()));
-        return ();
+            .await?;
+        return Ok(KvRetrieved {
+            id: kv_data.id,
+            name: kv_data.key,
+            environment: kv_data.environment,
+            version,
+            value: raw_data.expose().to_vec(),
+        });
     }
-    
+
     async fn store(
         &self,
         app: &App,
-        project: String,
         kek_provider: &dyn KekProvider,
-        data: serde_json::Value,
+        data: KvStoreStoreData,
         creator: &str,
-    ) -> KvStoreReturn
-    where
-        Self: Sized,
-    {
-        let dek = Dek::init();
-        let data: KvStoreStoreData = serde_json::from_value(data).unwrap();
-        let sec_data = SecureBuffer::from_slice(&data.value).unwrap();
-        drop(data.value);
-        let encrypted = dek.wrap_data(sec_data, data.name.to_owned());
-        let (wrapped_dek, nonce, tag) = kek_provider
-            .wrap_dek(dek, &data.name.to_owned())
+    ) -> Result<KvStored, StoreAccessError> {
+        require_project_action(app, &data.project, creator, AccessAction::CreateSecret).await?;
+        if self
+            .find_by_key(app, &data.project, &data.environment, &data.name)
             .await
-            .unwrap();
+            .is_some()
+        {
+            return Err(StoreAccessError::Conflict(format!(
+                "A secret named '{}' already exists in this project ({})",
+                data.name, data.environment
+            )));
+        }
+        let sec_data = value_buffer(&data.value, &data.generate)?;
+        drop(data.value);
+        let limits = limits::effective(app, &data.project).await?;
+        self.check_size(&limits, &data.project, &sec_data)?;
+        let expires_at = data.expires_at.or(self
+            .settings
+            .default_ttl_secs
+            .map(|ttl| Utc::now() + chrono::Duration::seconds(ttl)));
+        // New secrets start at version 1.
+        let id = app.gen_id().await;
+        let aad = self.aad(&aad::instance_id(app).await?, &data.project, &id, 1);
+        let store_return = self.encrypt(kek_provider, &aad, sec_data).await;
 
-        let store_return = KvStoreReturn {
-            dek: KvStoreReturnDek {
-                nonce,
-                tag,
-                data: wrapped_dek,
-            },
-            gcm_tag: encrypted.gcm_tag,
-            kmac_tag: encrypted.kmac_tag,
-            nonce: encrypted.nonce,
-            data: encrypted.data,
+        let stored_value = self
+            .store_secret(
+                &app,
+                &id,
+                &data.name,
+                &store_return,
+                &data.project,
+                &data.environment,
+                &creator,
+                expires_at,
+                &limits,
+            )
+            .await?;
+        return Ok(KvStored {
+            id: stored_value.id,
+            name: stored_value.key,
+            environment: stored_value.environment,
+            version: stored_value.version,
+            generated: data.generate.is_some(),
+        });
+    }
+
+    async fn update(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: KvStoreUpdateData,
+        requester: &str,
+    ) -> Result<KvStored, StoreAccessError> {
+        require_project_action(app, &data.project, requester, AccessAction::UpdateSecret).await?;
+        let Some(existing) = self
+            .find_by_key(app, &data.project, &data.environment, &data.name)
+            .await
+        else {
+            return Err(self.not_found(&data.project, &data.environment, &data.name));
         };
+        let mut version = existing.version;
+        if !data.value.is_empty() || data.generate.is_some() {
+            let sec_data = value_buffer(&data.value, &data.generate)?;
+            version = self
+                .replace_value(
+                    app,
+                    kek_provider,
+                    &existing,
+                    sec_data,
+                    None,
+                    data.expires_at,
+                    requester,
+                )
+                .await?;
+        } else if let Some(expires_at) = data.expires_at {
+            let mut conn = app.database.inner.acquire().await?;
+            set_expiry(&mut conn, &existing.id, expires_at, Utc::now()).await?;
+        }
+        return Ok(KvStored {
+            id: existing.id,
+            name: existing.key,
+            environment: existing.environment,
+            version,
+            generated: data.generate.is_some(),
+        });
+    }
 
-        self.store_secret(&app, &data.name, &store_return, &project, &creator)
+    async fn delete(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, &target.project, requester, AccessAction::DeleteSecret).await?;
+        let Some(existing) = self
+            .find_by_key(app, &target.project, &target.environment, &target.name)
             .await
-            .unwrap();
-        return store_return;
+        else {
+            return Err(self.not_found(&target.project, &target.environment, &target.name));
+        };
+        let mut tx = app.database.inner.begin().await?;
+        let retained = sqlx::query_as::<_, (String,)>(
+            r#"DELETE FROM tokaysec.kv_store_previous WHERE secret_id = ($1) RETURNING dek_used"#,
+        )
+        .bind(&existing.id)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.kv_store WHERE id = ($1)"#)
+            .bind(&existing.id)
+            .execute(&mut *tx)
+            .await?;
+        let mut deks = retained.into_iter().map(|(e,)| e).collect::<Vec<String>>();
+        deks.push(existing.dek_used.to_owned());
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ANY($1)"#)
+            .bind(&deks)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        app.dek_cache.invalidate(&deks);
+        remove_resource_assignments(app, &format!("{}:{}", &self.name, &existing.id)).await
     }
 }
@@ -1,15 +1,54 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sqlx::FromRow;
 
-use crate::{app::App, kek_provider::KekProvider, stores::kv::KvStoreReturn};
+use crate::{
+    app::{App, ResourceTypes},
+    config::StoreConfig,
+    kek_provider::KekProvider,
+    policies::{AccessAction, check_allowed},
+    stores::{
+        blob::{BLOB_STORE, BlobStore},
+        config::ConfigStore,
+        dynamic_postgres::{DYNAMIC_POSTGRES_STORE, DynamicPostgresStore},
+        kv::{DEFAULT_ENVIRONMENT, KV_STORE, KvStore, KvStoreSettings},
+        pki::PkiStore,
+        plugin::{PluginSettings, PluginStore},
+        ssh::SshStore,
+        structured::{STRUCTURED_STORE, StructuredStore},
+        template::{TEMPLATE_STORE, TemplateStore},
+    },
+};
 
+pub mod blob;
+pub mod config;
+pub mod dynamic_postgres;
+pub mod generate;
 pub mod kv;
+pub mod pki;
+pub mod plugin;
+pub mod sealed;
+pub mod ssh;
+pub mod structured;
+pub mod template;
 
 #[derive(Serialize, Deserialize)]
 pub struct RetrievedSecretData {
     pub id: String,
     pub name: String,
+    // None for stores without environments.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    // When the secret last changed, or was added for stores that
+    // don't update in place.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_when: Option<DateTime<Utc>>,
+    // Only set by stores holding non-sensitive data (config) where
+    // handing the value out in listings is fine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,28 +59,596 @@ pub struct StoreUiRequirements {
     pub secret_type: bool,
 }
 
+#[derive(Debug)]
+pub enum StoreAccessError {
+    NotFound(String),
+    Expired(String),
+    Conflict(String),
+    Forbidden(String),
+    Invalid(String),
+    // Bigger than a limit allows, see limits.rs.
+    TooLarge(String),
+    // A quota (e.g. secrets per project) is used up.
+    OverQuota(String),
+    // Something the store depends on (e.g. a plugin process) is down.
+    Unavailable(String),
+    // Anything else going wrong on our side, e.g. the database.
+    Internal(String),
+}
+
+impl ToString for StoreAccessError {
+    fn to_string(&self) -> String {
+        match self {
+            StoreAccessError::NotFound(e) => e.to_owned(),
+            StoreAccessError::Expired(e) => e.to_owned(),
+            StoreAccessError::Conflict(e) => e.to_owned(),
+            StoreAccessError::Forbidden(e) => e.to_owned(),
+            StoreAccessError::Invalid(e) => e.to_owned(),
+            StoreAccessError::TooLarge(e) => e.to_owned(),
+            StoreAccessError::OverQuota(e) => e.to_owned(),
+            StoreAccessError::Unavailable(e) => e.to_owned(),
+            StoreAccessError::Internal(e) => e.to_owned(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for StoreAccessError {
+    fn from(value: sqlx::Error) -> Self {
+        match value {
+            sqlx::Error::RowNotFound => StoreAccessError::NotFound("Not found".to_string()),
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                StoreAccessError::Conflict(e.message().to_string())
+            }
+            e => StoreAccessError::Internal(e.to_string()),
+        }
+    }
+}
+
+// Request bodies that don't parse are the caller's fault.
+impl From<serde_json::Error> for StoreAccessError {
+    fn from(value: serde_json::Error) -> Self {
+        StoreAccessError::Invalid(value.to_string())
+    }
+}
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+// One secret in a project. `key` is accepted as well since that is
+// what the kv style stores have always called it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecretRef {
+    pub project: String,
+    #[serde(alias = "key")]
+    pub name: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 100;
+pub const MAX_PAGE_LIMIT: i64 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRequest {
+    pub project: String,
+    // All environments when not set. Ignored by stores that don't
+    // have environments.
+    #[serde(default)]
+    pub environment: Option<String>,
+    // `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+// Where list_rows and get_rows find a store's secrets.
+pub struct ListTable<'a> {
+    pub table: &'a str,
+    pub name_column: &'a str,
+    pub has_environment: bool,
+    pub updated_column: &'a str,
+    // For tables shared by several instances (kv, plugin).
+    pub store: Option<&'a str>,
+}
+
+#[derive(FromRow)]
+struct ListedRow {
+    id: String,
+    name: String,
+    environment: Option<String>,
+    updated_when: DateTime<Utc>,
+}
+
+impl From<ListedRow> for RetrievedSecretData {
+    fn from(value: ListedRow) -> Self {
+        RetrievedSecretData {
+            id: value.id,
+            name: value.name,
+            environment: value.environment,
+            updated_when: Some(value.updated_when),
+            value: None,
+        }
+    }
+}
+
+fn listed_columns(source: &ListTable<'_>) -> String {
+    format!(
+        "id, {} AS name, {} AS environment, {} AS updated_when",
+        source.name_column,
+        if source.has_environment {
+            "environment"
+        } else {
+            "NULL::TEXT"
+        },
+        source.updated_column
+    )
+}
+
+// Keyset pagination by id. Ids are snowflakes with the same number of
+// digits, so ordering them as text is creation order.
+pub async fn list_rows(
+    app: &App,
+    source: ListTable<'_>,
+    request: &ListRequest,
+) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+    let limit = request.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit < 1 || limit > MAX_PAGE_LIMIT {
+        return Err(StoreAccessError::Invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_LIMIT
+        )));
+    }
+    let mut sql = format!(
+        "SELECT {} FROM tokaysec.{} WHERE project = ($1) AND id > ($2)",
+        listed_columns(&source),
+        source.table
+    );
+    let environment = request
+        .environment
+        .as_ref()
+        .filter(|_| source.has_environment);
+    let mut next_param = 3;
+    if environment.is_some() {
+        sql.push_str(&format!(" AND environment = (${})", next_param));
+        next_param += 1;
+    }
+    if source.store.is_some() {
+        sql.push_str(&format!(" AND store = (${})", next_param));
+        next_param += 1;
+    }
+    sql.push_str(&format!(" ORDER BY id LIMIT (${})", next_param));
+    let mut query = sqlx::query_as::<_, ListedRow>(&sql)
+        .bind(&request.project)
+        .bind(request.cursor.to_owned().unwrap_or_default());
+    if let Some(environment) = environment {
+        query = query.bind(environment);
+    }
+    if let Some(store) = source.store {
+        query = query.bind(store);
+    }
+    // One extra row tells us whether there is another page.
+    let mut rows = query.bind(limit + 1).fetch_all(&app.database.inner).await?;
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|e| e.id.to_owned())
+    } else {
+        None
+    };
+    Ok(Page {
+        items: rows.into_iter().map(RetrievedSecretData::from).collect(),
+        next_cursor,
+    })
+}
+
+// Batch version of `get` for stores keeping one row per secret. Ids
+// that aren't there are left out, the order is the table's.
+pub async fn get_rows(
+    app: &App,
+    source: ListTable<'_>,
+    ids: &[String],
+) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+    let mut sql = format!(
+        "SELECT {} FROM tokaysec.{} WHERE id = ANY($1)",
+        listed_columns(&source),
+        source.table
+    );
+    if source.store.is_some() {
+        sql.push_str(" AND store = ($2)");
+    }
+    let mut query = sqlx::query_as::<_, ListedRow>(&sql).bind(ids);
+    if let Some(store) = source.store {
+        query = query.bind(store);
+    }
+    let rows = query.fetch_all(&app.database.inner).await?;
+    Ok(rows.into_iter().map(RetrievedSecretData::from).collect())
+}
+
+// Drops whatever ties a deleted secret to its project.
+pub async fn remove_resource_assignments(
+    app: &App,
+    resource: &str,
+) -> Result<(), StoreAccessError> {
+    sqlx::query(r#"DELETE FROM tokaysec.resource_assignment WHERE resource = ($1)"#)
+        .bind(&resource)
+        .execute(&app.database.inner)
+        .await?;
+    sqlx::query(r#"DELETE FROM tokaysec.secret_tags WHERE resource = ($1)"#)
+        .bind(&resource)
+        .execute(&app.database.inner)
+        .await?;
+    Ok(())
+}
+
+// Shared by stores that gate access on a project level permission.
+pub async fn require_project_action(
+    app: &App,
+    project: &str,
+    person: &str,
+    action: AccessAction,
+) -> Result<(), StoreAccessError> {
+    let action_name = action.to_string();
+    if !check_allowed(
+        app,
+        None,
+        Some(project.to_string()),
+        format!("{}:{}", ResourceTypes::Project.to_string(), project),
+        person.to_string(),
+        HashSet::from([action]),
+    )
+    .await
+    {
+        return Err(StoreAccessError::Forbidden(format!(
+            "Missing {} on project {}",
+            action_name, project
+        )));
+    }
+    Ok(())
+}
+
+// What every store implements. Requests and responses are typed, the
+// router talks to stores through the JSON based `Store` below which is
+// implemented for every TypedStore.
+#[async_trait::async_trait]
+pub trait TypedStore: Send + Sync {
+    type StoreRequest: DeserializeOwned + Send;
+    type StoreResponse: Serialize + Send;
+    // Parsed from the query string.
+    type RetrieveRequest: DeserializeOwned + Send;
+    type RetrieveResponse: Serialize + Send;
+    type UpdateRequest: DeserializeOwned + Send;
+
+    // The `type` this store is configured with in Config.toml.
+    fn store_type(&self) -> &str;
+    fn ui_reqs(&self) -> StoreUiRequirements;
+    // For what only kv instances do (bulk import and export), with
+    // the instance's own settings.
+    fn as_kv(&self) -> Option<&KvStore> {
+        None
+    }
+    // Same for the config store (promotions).
+    fn as_config(&self) -> Option<&ConfigStore> {
+        None
+    }
+    async fn store(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: Self::StoreRequest,
+        creator: &str,
+    ) -> Result<Self::StoreResponse, StoreAccessError>;
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: Self::RetrieveRequest,
+        requester: &str,
+    ) -> Result<Self::RetrieveResponse, StoreAccessError>;
+    // What a project listing shows for one of this store's resources.
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError>;
+    // `get` for a page of a project listing at once. Missing ids are
+    // left out. Stores with a table of their own use get_rows, this
+    // fallback is one get per id.
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        let mut found = vec![];
+        for id in ids {
+            match self.get(app, id).await {
+                Ok(data) => found.push(data),
+                Err(StoreAccessError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(found)
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError>;
+    async fn update(
+        &self,
+        _app: &App,
+        _kek_provider: &dyn KekProvider,
+        _request: Self::UpdateRequest,
+        _requester: &str,
+    ) -> Result<Self::StoreResponse, StoreAccessError> {
+        Err(StoreAccessError::Invalid(format!(
+            "{} secrets can't be updated",
+            self.store_type()
+        )))
+    }
+    async fn delete(
+        &self,
+        _app: &App,
+        _kek_provider: &dyn KekProvider,
+        _target: SecretRef,
+        _requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        Err(StoreAccessError::Invalid(format!(
+            "{} secrets can't be deleted",
+            self.store_type()
+        )))
+    }
+}
 
 #[async_trait::async_trait]
 pub trait Store: Send + Sync {
+    fn store_type(&self) -> &str;
     fn ui_reqs(&self) -> StoreUiRequirements;
+    fn as_kv(&self) -> Option<&KvStore>;
+    fn as_config(&self) -> Option<&ConfigStore>;
     async fn store(
         &self,
         app: &App,
-        project: String,
         kek_provider: &dyn KekProvider,
         data: serde_json::Value,
         creator: &str,
-    ) -> KvStoreReturn;
-    async fn retrieve(&self, app: &App, data: HashMap<String, String>, kek_provider: &dyn KekProvider) -> ();
-    async fn get(&self, app: &App, id: &str) -> RetrievedSecretData;
+    ) -> Result<serde_json::Value, StoreAccessError>;
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        query: HashMap<String, String>,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError>;
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError>;
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError>;
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError>;
+    async fn update(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: serde_json::Value,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError>;
+    async fn delete(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError>;
+}
+
+// Query strings only carry strings, going through serde_urlencoded
+// lets typed requests have numbers and booleans in them.
+fn from_query<T: DeserializeOwned>(query: HashMap<String, String>) -> Result<T, StoreAccessError> {
+    let encoded = serde_urlencoded::to_string(&query)
+        .map_err(|e| StoreAccessError::Invalid(e.to_string()))?;
+    serde_urlencoded::from_str(&encoded).map_err(|e| StoreAccessError::Invalid(e.to_string()))
+}
+
+#[async_trait::async_trait]
+impl<T: TypedStore> Store for T {
+    fn store_type(&self) -> &str {
+        TypedStore::store_type(self)
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        TypedStore::ui_reqs(self)
+    }
+    fn as_kv(&self) -> Option<&KvStore> {
+        TypedStore::as_kv(self)
+    }
+    fn as_config(&self) -> Option<&ConfigStore> {
+        TypedStore::as_config(self)
+    }
+    async fn store(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: serde_json::Value,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let request = serde_json::from_value(data)?;
+        let stored = TypedStore::store(self, app, kek_provider, request, creator).await?;
+        Ok(serde_json::to_value(stored)?)
+    }
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        query: HashMap<String, String>,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let request = from_query(query)?;
+        let retrieved = TypedStore::retrieve(self, app, kek_provider, request, requester).await?;
+        Ok(serde_json::to_value(retrieved)?)
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        TypedStore::get(self, app, id).await
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        TypedStore::get_many(self, app, ids).await
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        TypedStore::list(self, app, request, requester).await
+    }
+    async fn update(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: serde_json::Value,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let request = serde_json::from_value(data)?;
+        let updated = TypedStore::update(self, app, kek_provider, request, requester).await?;
+        Ok(serde_json::to_value(updated)?)
+    }
+    async fn delete(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        TypedStore::delete(self, app, kek_provider, target, requester).await
+    }
+}
+
+// Every store type along with the name its instance is registered
+// under when Config.toml doesn't say anything about it.
+pub const STORE_TYPES: &[(&str, &str)] = &[
+    ("kv", KV_STORE),
+    ("config", "config"),
+    ("template", TEMPLATE_STORE),
+    ("pki", "pki"),
+    ("ssh", "ssh"),
+    ("structured", STRUCTURED_STORE),
+    ("blob", BLOB_STORE),
+    ("dynamic_postgres", DYNAMIC_POSTGRES_STORE),
+];
+
+pub fn default_store_configs() -> HashMap<String, StoreConfig> {
+    STORE_TYPES
+        .iter()
+        .map(|(store_type, name)| {
+            (
+                name.to_string(),
+                StoreConfig {
+                    r#type: store_type.to_string(),
+                    enabled: true,
+                    settings: serde_json::Map::new(),
+                },
+            )
+        })
+        .collect()
+}
+
+// Stores from Config.toml replace the built in entry with the same name
+// (e.g. to disable it), any other name adds an instance.
+pub async fn build_stores(
+    configured: HashMap<String, StoreConfig>,
+) -> Result<HashMap<String, Box<dyn Store>>, String> {
+    let mut configs = default_store_configs();
+    configs.extend(configured);
+    let mut stores: HashMap<String, Box<dyn Store>> = HashMap::new();
+    for (name, config) in configs {
+        if !config.enabled {
+            continue;
+        }
+        let store = build_store(&name, &config)
+            .await
+            .map_err(|e| format!("stores.{}: {}", name, e))?;
+        stores.insert(name, store);
+    }
+    Ok(stores)
+}
+
+fn settings<T: serde::de::DeserializeOwned>(config: &StoreConfig) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::Object(config.settings.to_owned()))
+        .map_err(|e| e.to_string())
+}
+
+async fn build_store(name: &str, config: &StoreConfig) -> Result<Box<dyn Store>, String> {
+    // Plugins have no built in instance, every one is named in
+    // Config.toml.
+    if config.r#type == "plugin" {
+        return Ok(Box::new(
+            PluginStore::init(name, settings::<PluginSettings>(config)?).await?,
+        ));
+    }
+    let Some((_, default_name)) = STORE_TYPES.iter().find(|(t, _)| *t == config.r#type) else {
+        return Err(format!("Unknown store type '{}'", config.r#type));
+    };
+    if config.r#type == "kv" {
+        return Ok(Box::new(KvStore::configured(
+            name,
+            settings::<KvStoreSettings>(config)?,
+        )));
+    }
+    // Everything but kv keeps its rows and resource names under a fixed
+    // store name, so there can only be the one instance of those.
+    if name != *default_name {
+        return Err(format!(
+            "Only one '{}' store is supported and it has to be named '{}'",
+            config.r#type, default_name
+        ));
+    }
+    if !config.settings.is_empty() {
+        return Err(format!(
+            "'{}' stores don't take any settings",
+            config.r#type
+        ));
+    }
+    Ok(match config.r#type.as_str() {
+        "config" => Box::new(ConfigStore::init().await),
+        "template" => Box::new(TemplateStore::init().await),
+        "pki" => Box::new(PkiStore::init().await),
+        "ssh" => Box::new(SshStore::init().await),
+        "structured" => Box::new(StructuredStore::init().await),
+        "blob" => Box::new(BlobStore::init().await),
+        "dynamic_postgres" => Box::new(DynamicPostgresStore::init().await),
+        _ => unreachable!(),
+    })
 }
 
 /*
 
 * invokes the store function of {store_name}
-POST /stores/{store_name}
+POST /store/{store_name}
+
+* invokes the retrieve function of {store_name}. Passes
+the query arguments along to the retrieve function. For
+the kv store that is ?project=<id>&key=<name>[&environment=<env>]
+GET /store/{store_name}?{key=value}
+
+* update, with the same kind of body as store
+PUT /store/{store_name}
+
+* delete ?project=<id>&name=<name>[&environment=<env>]
+DELETE /store/{store_name}
 
-* invokes the get function of {store_name}. Passes
-the query arguments along to the get functions
-GET /stores/{store_name}?{key=value}
+* one page of a project's secrets in {store_name}
+?project=<id>[&environment=<env>][&cursor=<id>][&limit=<n>]
+GET /store/{store_name}/list
 */
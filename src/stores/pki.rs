@@ -0,0 +1,1035 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Private, Public},
+    rsa::Rsa,
+    x509::{
+        X509, X509Builder, X509Name, X509NameBuilder, X509Req,
+        extension::{
+            AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage,
+            SubjectAlternativeName, SubjectKeyIdentifier,
+        },
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    app::{App, EasyResource, ResourceTypes},
+    kek_provider::KekProvider,
+    models::{Namespace, PkiCa, PkiCertificate, PkiRole},
+    policies::AccessAction,
+    secure_buf::SecureBuffer,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, StoreAccessError, StoreUiRequirements,
+        TypedStore, get_rows, list_rows, require_project_action,
+        sealed::{seal, unseal},
+    },
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum KeyType {
+    #[serde(rename = "ec-p256")]
+    EcP256,
+    #[serde(rename = "ec-p384")]
+    EcP384,
+    #[serde(rename = "rsa-2048")]
+    Rsa2048,
+    #[serde(rename = "rsa-3072")]
+    Rsa3072,
+    #[serde(rename = "rsa-4096")]
+    Rsa4096,
+    #[serde(rename = "ed25519")]
+    Ed25519,
+}
+
+impl ToString for KeyType {
+    fn to_string(&self) -> String {
+        String::from(match self {
+            KeyType::EcP256 => "ec-p256",
+            KeyType::EcP384 => "ec-p384",
+            KeyType::Rsa2048 => "rsa-2048",
+            KeyType::Rsa3072 => "rsa-3072",
+            KeyType::Rsa4096 => "rsa-4096",
+            KeyType::Ed25519 => "ed25519",
+        })
+    }
+}
+
+impl KeyType {
+    pub fn generate(&self) -> PKey<Private> {
+        match self {
+            KeyType::EcP256 | KeyType::EcP384 => {
+                let nid = if *self == KeyType::EcP256 {
+                    Nid::X9_62_PRIME256V1
+                } else {
+                    Nid::SECP384R1
+                };
+                let group = EcGroup::from_curve_name(nid).unwrap();
+                PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+            }
+            KeyType::Rsa2048 => PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            KeyType::Rsa3072 => PKey::from_rsa(Rsa::generate(3072).unwrap()).unwrap(),
+            KeyType::Rsa4096 => PKey::from_rsa(Rsa::generate(4096).unwrap()).unwrap(),
+            KeyType::Ed25519 => PKey::generate_ed25519().unwrap(),
+        }
+    }
+    // Works out the key type of a key handed to us in a CSR.
+    pub fn of(key: &PKey<Public>) -> Option<Self> {
+        match key.id() {
+            Id::RSA => match key.bits() {
+                2048 => Some(KeyType::Rsa2048),
+                3072 => Some(KeyType::Rsa3072),
+                4096 => Some(KeyType::Rsa4096),
+                _ => None,
+            },
+            Id::EC => match key.ec_key().ok()?.group().curve_name()? {
+                Nid::X9_62_PRIME256V1 => Some(KeyType::EcP256),
+                Nid::SECP384R1 => Some(KeyType::EcP384),
+                _ => None,
+            },
+            Id::ED25519 => Some(KeyType::Ed25519),
+            _ => None,
+        }
+    }
+    // ed25519 signs the message directly, everything else signs a
+    // SHA-256 digest.
+    pub fn digest(&self) -> MessageDigest {
+        match self {
+            KeyType::Ed25519 => MessageDigest::null(),
+            _ => MessageDigest::sha256(),
+        }
+    }
+}
+
+fn default_key_type() -> KeyType {
+    KeyType::EcP256
+}
+fn default_root_ttl_days() -> u32 {
+    3650
+}
+fn default_intermediate_ttl_days() -> u32 {
+    1825
+}
+fn default_true() -> bool {
+    true
+}
+
+// Everything sent to POST /store/pki is one of these, picked by "action".
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PkiStoreRequest {
+    InitCa {
+        project: String,
+        common_name: String,
+        #[serde(default = "default_key_type")]
+        key_type: KeyType,
+        #[serde(default = "default_root_ttl_days")]
+        root_ttl_days: u32,
+        #[serde(default = "default_intermediate_ttl_days")]
+        intermediate_ttl_days: u32,
+    },
+    // A CA shared by every project in the namespace that doesn't init
+    // its own. Instance admin only.
+    InitNamespaceCa {
+        namespace: String,
+        common_name: String,
+        #[serde(default = "default_key_type")]
+        key_type: KeyType,
+        #[serde(default = "default_root_ttl_days")]
+        root_ttl_days: u32,
+        #[serde(default = "default_intermediate_ttl_days")]
+        intermediate_ttl_days: u32,
+    },
+    Role {
+        project: String,
+        name: String,
+        #[serde(default)]
+        allowed_domains: Vec<String>,
+        #[serde(default = "default_true")]
+        allow_bare_domains: bool,
+        #[serde(default)]
+        allow_subdomains: bool,
+        // *.<domain>, needs allow_subdomains as well.
+        #[serde(default)]
+        allow_wildcards: bool,
+        #[serde(default)]
+        allow_ip_sans: bool,
+        key_types: Vec<KeyType>,
+        default_ttl_secs: i64,
+        max_ttl_secs: i64,
+    },
+    Issue {
+        project: String,
+        role: String,
+        common_name: String,
+        #[serde(default)]
+        alt_names: Vec<String>,
+        #[serde(default)]
+        ip_sans: Vec<String>,
+        #[serde(default)]
+        ttl_secs: Option<i64>,
+        // When given only the public key of the CSR is used. Names
+        // always come from the request so the role can check them.
+        #[serde(default)]
+        csr: Option<String>,
+        #[serde(default)]
+        key_type: Option<KeyType>,
+    },
+}
+
+// Letters, digits and inner hyphens, 63 characters a label and 253 in
+// all.
+fn valid_hostname(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|e| e.is_ascii_alphanumeric() || e == '-')
+        })
+}
+
+// A wildcard is allowed where any single label in its place would be,
+// and only by roles that opted into them.
+fn name_allowed(role: &PkiRole, name: &str) -> bool {
+    let name = name.to_lowercase();
+    let (wildcard, host) = match name.strip_prefix("*.") {
+        Some(host) => (true, host),
+        None => (false, name.as_str()),
+    };
+    if (wildcard && !role.allow_wildcards) || !valid_hostname(host) {
+        return false;
+    }
+    role.allowed_domains.iter().any(|domain| {
+        let domain = domain.to_lowercase();
+        let subdomain = host.ends_with(&format!(".{}", domain));
+        match wildcard {
+            true => role.allow_subdomains && (host == domain || subdomain),
+            false => {
+                (role.allow_bare_domains && host == domain) || (role.allow_subdomains && subdomain)
+            }
+        }
+    })
+}
+
+// OpenSSL turns down common names over 64 characters.
+fn subject_name(common_name: &str) -> Result<X509Name, StoreAccessError> {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .map_err(|_| {
+            StoreAccessError::Invalid(format!(
+                "'{}' can't be used as a common name, they're at most 64 characters",
+                common_name
+            ))
+        })?;
+    Ok(name.build())
+}
+
+fn random_serial() -> BigNum {
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(159, MsbOption::MAYBE_ZERO, false).unwrap();
+    serial
+}
+
+fn to_datetime(not_after: &openssl::asn1::Asn1TimeRef) -> DateTime<Utc> {
+    let diff = Asn1Time::from_unix(0).unwrap().diff(not_after).unwrap();
+    DateTime::from_timestamp(diff.days as i64 * 86400 + diff.secs as i64, 0).unwrap()
+}
+
+// Builds a CA certificate. With no issuer it is self signed (root).
+fn build_ca(
+    common_name: &str,
+    key: &PKey<Private>,
+    key_type: KeyType,
+    ttl_days: u32,
+    issuer: Option<(&X509, &PKey<Private>, KeyType)>,
+) -> Result<X509, StoreAccessError> {
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&random_serial().to_asn1_integer().unwrap())
+        .unwrap();
+    let subject = subject_name(common_name)?;
+    builder.set_subject_name(&subject).unwrap();
+    match issuer {
+        Some((issuer_cert, _, _)) => builder.set_issuer_name(issuer_cert.subject_name()),
+        None => builder.set_issuer_name(&subject),
+    }
+    .unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(ttl_days).unwrap())
+        .unwrap();
+    let mut constraints = BasicConstraints::new();
+    constraints.critical().ca();
+    // Intermediates only ever sign leaves.
+    if issuer.is_some() {
+        constraints.pathlen(0);
+    }
+    builder
+        .append_extension(constraints.build().unwrap())
+        .unwrap();
+    builder
+        .append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    let ski = SubjectKeyIdentifier::new()
+        .build(&builder.x509v3_context(issuer.map(|e| e.0.as_ref()), None))
+        .unwrap();
+    builder.append_extension(ski).unwrap();
+    match issuer {
+        Some((issuer_cert, issuer_key, issuer_type)) => {
+            let aki = AuthorityKeyIdentifier::new()
+                .keyid(false)
+                .build(&builder.x509v3_context(Some(issuer_cert), None))
+                .unwrap();
+            builder.append_extension(aki).unwrap();
+            builder.sign(issuer_key, issuer_type.digest()).unwrap();
+        }
+        None => builder.sign(key, key_type.digest()).unwrap(),
+    }
+    Ok(builder.build())
+}
+
+// Who a CA belongs to.
+#[derive(Clone, Copy)]
+enum CaOwner<'a> {
+    Project(&'a str),
+    Namespace(&'a str),
+}
+
+impl CaOwner<'_> {
+    fn project(&self) -> Option<&str> {
+        match self {
+            CaOwner::Project(project) => Some(project),
+            CaOwner::Namespace(_) => None,
+        }
+    }
+    fn namespace(&self) -> Option<&str> {
+        match self {
+            CaOwner::Project(_) => None,
+            CaOwner::Namespace(namespace) => Some(namespace),
+        }
+    }
+    fn resource(&self) -> EasyResource<'_> {
+        match self {
+            CaOwner::Project(project) => EasyResource(ResourceTypes::Project, project),
+            CaOwner::Namespace(namespace) => EasyResource(ResourceTypes::Namespace, namespace),
+        }
+    }
+}
+
+async fn require_admin(app: &App, requester: &str) -> Result<(), StoreAccessError> {
+    let admin_id = app
+        .get_config_value::<String>("admin_account_id")
+        .await
+        .map_err(StoreAccessError::Internal)?;
+    if admin_id != requester {
+        return Err(StoreAccessError::Forbidden(
+            "Only the instance admin can manage namespace CAs".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub struct PkiStore {}
+
+impl PkiStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "pki_cas",
+            name_column: "common_name",
+            has_environment: false,
+            updated_column: "added_when",
+            store: None,
+        }
+    }
+    pub async fn init() -> Self {
+        Self {}
+    }
+    async fn owned_ca(&self, app: &App, owner: CaOwner<'_>, kind: &str) -> Option<PkiCa> {
+        sqlx::query_as::<_, PkiCa>(
+            r#"SELECT * FROM tokaysec.pki_cas WHERE (project = ($1) OR namespace = ($2)) AND kind = ($3)"#,
+        )
+        .bind(owner.project())
+        .bind(owner.namespace())
+        .bind(&kind)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    // The project's own CA, otherwise the one of its namespace.
+    pub async fn get_ca(&self, app: &App, project: &str, kind: &str) -> Option<PkiCa> {
+        sqlx::query_as::<_, PkiCa>(
+            r#"SELECT c.* FROM tokaysec.pki_cas c JOIN tokaysec.projects p ON c.project = p.id OR c.namespace = p.namespace
+            WHERE p.id = ($1) AND c.kind = ($2) ORDER BY c.project IS NULL LIMIT 1"#,
+        )
+        .bind(&project)
+        .bind(&kind)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    async fn save_ca(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        owner: CaOwner<'_>,
+        kind: &str,
+        parent: Option<&str>,
+        key_type: KeyType,
+        certificate: &X509,
+        key: &PKey<Private>,
+        creator: &str,
+    ) -> PkiCa {
+        let id = app.gen_id().await;
+        let key_pem = key.private_key_to_pem_pkcs8().unwrap();
+        let sealed = seal(
+            app,
+            kek_provider,
+            &format!("pki_ca:{}", &id),
+            SecureBuffer::from_slice(&key_pem).unwrap(),
+            creator,
+        )
+        .await;
+        drop(key_pem);
+        let certificate_pem = String::from_utf8(certificate.to_pem().unwrap()).unwrap();
+        let ca = sqlx::query_as::<_, PkiCa>(
+            r#"INSERT INTO tokaysec.pki_cas(id,project,namespace,kind,parent,common_name,key_type,certificate,value,gcm_tag,kmac_tag,nonce,dek_used,not_after,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16) RETURNING *"#,
+        )
+        .bind(&id).bind(owner.project()).bind(owner.namespace()).bind(&kind).bind(parent)
+        .bind(subject_cn(certificate)).bind(key_type.to_string()).bind(&certificate_pem)
+        .bind(&sealed.value).bind(&sealed.gcm_tag).bind(&sealed.kmac_tag).bind(&sealed.nonce).bind(&sealed.dek_used)
+        .bind(to_datetime(certificate.not_after())).bind(Utc::now()).bind(&creator)
+        .fetch_one(&app.database.inner)
+        .await
+        .unwrap();
+        app.create_resource_assignment(
+            owner.resource(),
+            EasyResource(ResourceTypes::Secret, &format!("pki:{}", &ca.id)),
+            creator,
+        )
+        .await
+        .unwrap();
+        ca
+    }
+    async fn ca_key(&self, app: &App, kek_provider: &dyn KekProvider, ca: PkiCa) -> PKey<Private> {
+        let name = format!("pki_ca:{}", &ca.id);
+        let key_pem = unseal(app, kek_provider, &name, ca.private_key).await;
+        PKey::private_key_from_pem(key_pem.expose()).unwrap()
+    }
+    async fn init_ca(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        owner: CaOwner<'_>,
+        common_name: &str,
+        key_type: KeyType,
+        root_ttl_days: u32,
+        intermediate_ttl_days: u32,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        match owner {
+            CaOwner::Project(project) => {
+                require_project_action(app, project, creator, AccessAction::ManagePki).await?;
+                if self.owned_ca(app, owner, "root").await.is_some() {
+                    return Err(StoreAccessError::Conflict(format!(
+                        "Project {} already has a CA",
+                        project
+                    )));
+                }
+            }
+            CaOwner::Namespace(namespace) => {
+                require_admin(app, creator).await?;
+                sqlx::query_as::<_, Namespace>(
+                    r#"SELECT * FROM tokaysec.namespaces WHERE id = ($1)"#,
+                )
+                .bind(&namespace)
+                .fetch_optional(&app.database.inner)
+                .await?
+                .ok_or(StoreAccessError::NotFound(format!(
+                    "No namespace {}",
+                    namespace
+                )))?;
+                if self.owned_ca(app, owner, "root").await.is_some() {
+                    return Err(StoreAccessError::Conflict(format!(
+                        "Namespace {} already has a CA",
+                        namespace
+                    )));
+                }
+            }
+        }
+        let root_key = key_type.generate();
+        let root_cert = build_ca(
+            &format!("{} Root CA", common_name),
+            &root_key,
+            key_type,
+            root_ttl_days,
+            None,
+        )?;
+        let intermediate_key = key_type.generate();
+        let intermediate_cert = build_ca(
+            &format!("{} Intermediate CA", common_name),
+            &intermediate_key,
+            key_type,
+            intermediate_ttl_days.min(root_ttl_days),
+            Some((&root_cert, &root_key, key_type)),
+        )?;
+        let root = self
+            .save_ca(
+                app,
+                kek_provider,
+                owner,
+                "root",
+                None,
+                key_type,
+                &root_cert,
+                &root_key,
+                creator,
+            )
+            .await;
+        let intermediate = self
+            .save_ca(
+                app,
+                kek_provider,
+                owner,
+                "intermediate",
+                Some(&root.id),
+                key_type,
+                &intermediate_cert,
+                &intermediate_key,
+                creator,
+            )
+            .await;
+        Ok(json!({
+            "root": { "id": root.id, "certificate": root.certificate },
+            "intermediate": { "id": intermediate.id, "certificate": intermediate.certificate },
+        }))
+    }
+    async fn define_role(
+        &self,
+        app: &App,
+        request: PkiStoreRequest,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let PkiStoreRequest::Role {
+            project,
+            name,
+            allowed_domains,
+            allow_bare_domains,
+            allow_subdomains,
+            allow_wildcards,
+            allow_ip_sans,
+            key_types,
+            default_ttl_secs,
+            max_ttl_secs,
+        } = request
+        else {
+            unreachable!()
+        };
+        require_project_action(app, &project, creator, AccessAction::ManagePki).await?;
+        if default_ttl_secs <= 0 || default_ttl_secs > max_ttl_secs {
+            return Err(StoreAccessError::Invalid(
+                "default_ttl_secs must be positive and at most max_ttl_secs".to_string(),
+            ));
+        }
+        let key_types = key_types
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        let id = app.gen_id().await;
+        let role = sqlx::query_as::<_, PkiRole>(
+            r#"INSERT INTO tokaysec.pki_roles(id,project,name,allowed_domains,allow_bare_domains,allow_subdomains,allow_ip_sans,key_types,default_ttl_secs,max_ttl_secs,last_updated,added_by,allow_wildcards) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13)
+            ON CONFLICT (project, name) DO UPDATE SET allowed_domains = ($4), allow_bare_domains = ($5), allow_subdomains = ($6), allow_ip_sans = ($7), key_types = ($8), default_ttl_secs = ($9), max_ttl_secs = ($10), last_updated = ($11), allow_wildcards = ($13) RETURNING *"#,
+        )
+        .bind(&id).bind(&project).bind(&name).bind(sqlx::types::Json(&allowed_domains))
+        .bind(allow_bare_domains).bind(allow_subdomains).bind(allow_ip_sans).bind(sqlx::types::Json(&key_types))
+        .bind(default_ttl_secs).bind(max_ttl_secs).bind(Utc::now()).bind(&creator).bind(allow_wildcards)
+        .fetch_one(&app.database.inner)
+        .await
+        .unwrap();
+        Ok(serde_json::to_value(&role).unwrap())
+    }
+    async fn issue(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: PkiStoreRequest,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let PkiStoreRequest::Issue {
+            project,
+            role,
+            common_name,
+            alt_names,
+            ip_sans,
+            ttl_secs,
+            csr,
+            key_type,
+        } = request
+        else {
+            unreachable!()
+        };
+        require_project_action(app, &project, creator, AccessAction::IssueCertificate).await?;
+        let Some(role) = sqlx::query_as::<_, PkiRole>(
+            r#"SELECT * FROM tokaysec.pki_roles WHERE project = ($1) AND name = ($2)"#,
+        )
+        .bind(&project)
+        .bind(&role)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap() else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No PKI role named '{}' in project {}",
+                role, project
+            )));
+        };
+        // Every name on the certificate has to be allowed by the role.
+        for name in std::iter::once(&common_name).chain(alt_names.iter()) {
+            if !name_allowed(&role, name) {
+                return Err(StoreAccessError::Forbidden(format!(
+                    "'{}' is not allowed by role '{}'",
+                    name, role.name
+                )));
+            }
+        }
+        let subject = subject_name(&common_name)?;
+        if !ip_sans.is_empty() && !role.allow_ip_sans {
+            return Err(StoreAccessError::Forbidden(format!(
+                "Role '{}' does not allow IP SANs",
+                role.name
+            )));
+        }
+        let ips = ip_sans
+            .iter()
+            .map(|e| e.parse::<IpAddr>().map(|_| e.to_owned()))
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| StoreAccessError::Invalid(format!("Bad IP SAN: {}", e)))?;
+        let ttl = ttl_secs.unwrap_or(role.default_ttl_secs);
+        if ttl <= 0 || ttl > role.max_ttl_secs {
+            return Err(StoreAccessError::Invalid(format!(
+                "ttl_secs must be between 1 and {}",
+                role.max_ttl_secs
+            )));
+        }
+        // Either the caller brings their own key via a CSR or we make
+        // one and hand it back once.
+        let (public_key, private_key) = match csr {
+            Some(csr) => {
+                let csr = X509Req::from_pem(csr.as_bytes())
+                    .map_err(|e| StoreAccessError::Invalid(format!("Bad CSR: {}", e)))?;
+                let public_key = csr.public_key().unwrap();
+                if !csr.verify(&public_key).unwrap_or(false) {
+                    return Err(StoreAccessError::Invalid(
+                        "CSR signature does not verify".to_string(),
+                    ));
+                }
+                (public_key, None)
+            }
+            None => {
+                let key_type = key_type.unwrap_or(KeyType::EcP256);
+                let private_key = key_type.generate();
+                let public_key =
+                    PKey::public_key_from_der(&private_key.public_key_to_der().unwrap()).unwrap();
+                (public_key, Some(private_key))
+            }
+        };
+        let Some(key_type) = KeyType::of(&public_key) else {
+            return Err(StoreAccessError::Invalid(
+                "Unsupported public key type".to_string(),
+            ));
+        };
+        if !role.key_types.contains(&key_type.to_string()) {
+            return Err(StoreAccessError::Forbidden(format!(
+                "Role '{}' does not allow {} keys",
+                role.name,
+                key_type.to_string()
+            )));
+        }
+        let Some(intermediate) = self.get_ca(app, &project, "intermediate").await else {
+            return Err(StoreAccessError::NotFound(format!(
+                "Project {} has no CA, init one first",
+                project
+            )));
+        };
+        let root = self.get_ca(app, &project, "root").await.unwrap();
+        let issuer_id = intermediate.id.to_owned();
+        let issuer_not_after = intermediate.not_after;
+        if issuer_not_after <= Utc::now() {
+            return Err(StoreAccessError::Invalid(format!(
+                "The issuing CA expired at {}",
+                issuer_not_after.to_rfc3339()
+            )));
+        }
+        let issuer_cert = X509::from_pem(intermediate.certificate.as_bytes()).unwrap();
+        let issuer_type: KeyType = serde_json::from_value(json!(intermediate.key_type)).unwrap();
+        let issuer_chain = vec![
+            intermediate.certificate.to_owned(),
+            root.certificate.to_owned(),
+        ];
+        let issuer_key = self.ca_key(app, kek_provider, intermediate).await;
+
+        let serial = random_serial();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+        builder.set_pubkey(&public_key).unwrap();
+        let now = Utc::now();
+        // Nothing outlives the CA that issued it.
+        let not_after = Duration::try_seconds(ttl)
+            .and_then(|e| now.checked_add_signed(e))
+            .map_or(issuer_not_after, |e| e.min(issuer_not_after));
+        builder
+            .set_not_before(&Asn1Time::from_unix(now.timestamp()).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(not_after.timestamp()).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(
+                KeyUsage::new()
+                    .critical()
+                    .digital_signature()
+                    .key_encipherment()
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        builder
+            .append_extension(
+                ExtendedKeyUsage::new()
+                    .server_auth()
+                    .client_auth()
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        let mut san = SubjectAlternativeName::new();
+        san.dns(&common_name);
+        for name in &alt_names {
+            san.dns(name);
+        }
+        for ip in &ips {
+            san.ip(ip);
+        }
+        let san = san
+            .build(&builder.x509v3_context(Some(&issuer_cert), None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        let ski = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(Some(&issuer_cert), None))
+            .unwrap();
+        builder.append_extension(ski).unwrap();
+        let aki = AuthorityKeyIdentifier::new()
+            .keyid(false)
+            .build(&builder.x509v3_context(Some(&issuer_cert), None))
+            .unwrap();
+        builder.append_extension(aki).unwrap();
+        builder.sign(&issuer_key, issuer_type.digest()).unwrap();
+        drop(issuer_key);
+        let certificate = builder.build();
+        let certificate_pem = String::from_utf8(certificate.to_pem().unwrap()).unwrap();
+        let serial = serial.to_hex_str().unwrap().to_lowercase();
+        let issued = sqlx::query_as::<_, PkiCertificate>(
+            r#"INSERT INTO tokaysec.pki_certificates(serial,project,issuer,role,common_name,certificate,not_after,issued_when,issued_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9) RETURNING *"#,
+        )
+        .bind(&serial).bind(&project).bind(&issuer_id).bind(&role.id).bind(&common_name)
+        .bind(&certificate_pem).bind(to_datetime(certificate.not_after())).bind(now).bind(&creator)
+        .fetch_one(&app.database.inner)
+        .await
+        .unwrap();
+        let mut issued_json = json!({
+            "serial": issued.serial,
+            "certificate": issued.certificate,
+            "issuing_ca": issuer_chain[0],
+            "ca_chain": issuer_chain,
+            "expiration": issued.not_after,
+        });
+        // Only returned here, a generated private key is never stored.
+        if let Some(private_key) = private_key {
+            let key_pem = private_key.private_key_to_pem_pkcs8().unwrap();
+            issued_json["private_key"] = json!(String::from_utf8(key_pem).unwrap());
+            issued_json["private_key_type"] = json!(key_type.to_string());
+        }
+        Ok(issued_json)
+    }
+}
+
+fn subject_cn(certificate: &X509) -> String {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .map(|e| e.data().as_utf8().unwrap().to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PkiRetrieveRequest {
+    pub project: String,
+    // An issued certificate, otherwise the CA certificates.
+    #[serde(default)]
+    pub serial: Option<String>,
+    // root, intermediate or chain (the default).
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl TypedStore for PkiStore {
+    type StoreRequest = PkiStoreRequest;
+    type StoreResponse = serde_json::Value;
+    type RetrieveRequest = PkiRetrieveRequest;
+    type RetrieveResponse = serde_json::Value;
+    type UpdateRequest = serde_json::Value;
+
+    fn store_type(&self) -> &str {
+        "pki"
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: true,
+            description: false,
+            secret_type: false,
+        }
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let ca = sqlx::query_as::<_, PkiCa>(r#"SELECT * FROM tokaysec.pki_cas WHERE id = $1"#)
+            .bind(&id)
+            .fetch_one(&app.database.inner)
+            .await?;
+        return Ok(RetrievedSecretData {
+            id: ca.id,
+            name: ca.common_name,
+            environment: None,
+            updated_when: Some(ca.added_when),
+            value: None,
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    // The CAs of a project, issued certificates are looked up by serial.
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    // Only hands out certificates, which are public. CA private keys
+    // never leave the store.
+    async fn retrieve(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        request: PkiRetrieveRequest,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let project = &request.project;
+        require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+        if let Some(serial) = &request.serial {
+            let Some(issued) = sqlx::query_as::<_, PkiCertificate>(
+                r#"SELECT * FROM tokaysec.pki_certificates WHERE project = ($1) AND serial = ($2)"#,
+            )
+            .bind(&project)
+            .bind(serial.to_lowercase())
+            .fetch_optional(&app.database.inner)
+            .await?
+            else {
+                return Err(StoreAccessError::NotFound(format!(
+                    "No certificate with serial {}",
+                    serial
+                )));
+            };
+            return Ok(serde_json::to_value(&issued).unwrap());
+        }
+        let kind = request.ca.as_deref().unwrap_or("chain");
+        let (Some(root), Some(intermediate)) = (
+            self.get_ca(app, project, "root").await,
+            self.get_ca(app, project, "intermediate").await,
+        ) else {
+            return Err(StoreAccessError::NotFound(format!(
+                "Project {} has no CA",
+                project
+            )));
+        };
+        return match kind {
+            "root" => Ok(json!({ "certificate": root.certificate })),
+            "intermediate" => Ok(json!({ "certificate": intermediate.certificate })),
+            "chain" => Ok(json!({
+                "ca_chain": [intermediate.certificate, root.certificate]
+            })),
+            other => Err(StoreAccessError::Invalid(format!(
+                "Unknown CA '{}', expected root, intermediate or chain",
+                other
+            ))),
+        };
+    }
+
+    async fn store(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: PkiStoreRequest,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        match request {
+            PkiStoreRequest::InitCa {
+                project,
+                common_name,
+                key_type,
+                root_ttl_days,
+                intermediate_ttl_days,
+            } => {
+                self.init_ca(
+                    app,
+                    kek_provider,
+                    CaOwner::Project(&project),
+                    &common_name,
+                    key_type,
+                    root_ttl_days,
+                    intermediate_ttl_days,
+                    creator,
+                )
+                .await
+            }
+            PkiStoreRequest::InitNamespaceCa {
+                namespace,
+                common_name,
+                key_type,
+                root_ttl_days,
+                intermediate_ttl_days,
+            } => {
+                self.init_ca(
+                    app,
+                    kek_provider,
+                    CaOwner::Namespace(&namespace),
+                    &common_name,
+                    key_type,
+                    root_ttl_days,
+                    intermediate_ttl_days,
+                    creator,
+                )
+                .await
+            }
+            request @ PkiStoreRequest::Role { .. } => self.define_role(app, request, creator).await,
+            request @ PkiStoreRequest::Issue { .. } => {
+                self.issue(app, kek_provider, request, creator).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(bare: bool, subdomains: bool, wildcards: bool) -> PkiRole {
+        PkiRole {
+            id: "r1".to_string(),
+            project: "pr1".to_string(),
+            name: "web".to_string(),
+            allowed_domains: sqlx::types::Json(vec!["Example.com".to_string()]),
+            allow_bare_domains: bare,
+            allow_subdomains: subdomains,
+            allow_ip_sans: false,
+            key_types: sqlx::types::Json(vec![KeyType::EcP256.to_string()]),
+            default_ttl_secs: 3600,
+            max_ttl_secs: 86400,
+            last_updated: Utc::now(),
+            added_by: "admin".to_string(),
+            allow_wildcards: wildcards,
+        }
+    }
+
+    #[test]
+    fn bare_domains_and_subdomains_are_separate() {
+        let bare = role(true, false, false);
+        assert!(name_allowed(&bare, "example.com"));
+        assert!(name_allowed(&bare, "EXAMPLE.com"));
+        assert!(!name_allowed(&bare, "www.example.com"));
+        let subdomains = role(false, true, false);
+        assert!(!name_allowed(&subdomains, "example.com"));
+        assert!(name_allowed(&subdomains, "www.example.com"));
+        assert!(name_allowed(&subdomains, "a.b.example.com"));
+        assert!(!name_allowed(&subdomains, "notexample.com"));
+        assert!(!name_allowed(&subdomains, "example.com.evil.org"));
+    }
+
+    #[test]
+    fn wildcards_need_opting_in() {
+        assert!(!name_allowed(&role(true, true, false), "*.example.com"));
+        assert!(!name_allowed(&role(true, true, false), "*.www.example.com"));
+        let wildcards = role(false, true, true);
+        assert!(name_allowed(&wildcards, "*.example.com"));
+        assert!(name_allowed(&wildcards, "*.www.example.com"));
+        // Stands in for a subdomain, so it takes allow_subdomains too.
+        assert!(!name_allowed(&role(true, false, true), "*.example.com"));
+    }
+
+    #[test]
+    fn malformed_labels_are_turned_down() {
+        let role = role(true, true, true);
+        for name in [
+            "www.*.example.com",
+            "*example.com",
+            "w*w.example.com",
+            "**.example.com",
+            ".example.com",
+            "www..example.com",
+            "-www.example.com",
+            "www-.example.com",
+            "ww_w.example.com",
+            "www example.com",
+            &format!("{}.example.com", "a".repeat(64)),
+        ] {
+            assert!(!name_allowed(&role, name), "{} was allowed", name);
+        }
+        assert!(name_allowed(&role, "x-1.example.com"));
+        assert!(name_allowed(
+            &role,
+            &format!("{}.example.com", "a".repeat(63))
+        ));
+    }
+
+    #[test]
+    fn long_common_names_are_invalid() {
+        assert!(subject_name(&"a".repeat(64)).is_ok());
+        assert!(matches!(
+            subject_name(&"a".repeat(65)),
+            Err(StoreAccessError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,779 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::{
+    app::{App, EasyResource, ResourceTypes},
+    kek_provider::KekProvider,
+    models::PluginStoredValue,
+    policies::AccessAction,
+    secure_buf::SecureBuffer,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, get_rows,
+        kv::DEFAULT_ENVIRONMENT,
+        list_rows, remove_resource_assignments, require_project_action,
+        sealed::{seal, unseal},
+    },
+};
+
+/*
+
+Plugins are separate binaries speaking JSON-RPC 2.0 over stdin/stdout,
+one message per line. Anything a plugin wants to log goes to stderr,
+which is passed through to ours. TokaySec does the authorization and
+keeps whatever the plugin calls `state` encrypted, the plugin never
+sees a KEK or DEK.
+
+initialize  {protocol, store, config}                  -> {}
+store       {project, environment, data}               -> {name, state, metadata?}
+retrieve    {project, environment, name, state,
+             metadata, query}                          -> {value, state?, metadata?}
+get         {id, name, metadata}                       -> {name?, value?}
+list        {project, environment, secrets: [{id,
+             name, metadata}]}                         -> {secrets}
+delete      {project, environment, name, state,
+             metadata}                                 -> {}
+
+Errors use the JSON-RPC error object. The codes below map onto
+StoreAccessError, anything else is reported as an invalid request.
+*/
+
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+pub const PLUGIN_NOT_FOUND: i64 = -32001;
+pub const PLUGIN_EXPIRED: i64 = -32002;
+pub const PLUGIN_CONFLICT: i64 = -32003;
+pub const PLUGIN_FORBIDDEN: i64 = -32004;
+
+// A plugin that keeps dying isn't restarted more often than this.
+const RESTART_BACKOFF: Duration = Duration::from_secs(2);
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+fn name_taken(name: &str, environment: &str) -> StoreAccessError {
+    StoreAccessError::Conflict(format!(
+        "A secret named '{}' already exists in this project ({})",
+        name, environment
+    ))
+}
+
+// `[stores.<name>]` settings for `type = "plugin"`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PluginSettings {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // How long a single call may take before the plugin is restarted.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    // Handed to the plugin's initialize call as is.
+    #[serde(default)]
+    pub config: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct PluginStoreData {
+    pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    // Whatever the plugin expects, passed through untouched.
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    id: Option<u64>,
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct StoredByPlugin {
+    name: String,
+    state: serde_json::Value,
+    #[serde(default)]
+    metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct RetrievedByPlugin {
+    value: serde_json::Value,
+    #[serde(default)]
+    state: Option<serde_json::Value>,
+    #[serde(default)]
+    metadata: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct DescribedByPlugin {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    value: Option<serde_json::Value>,
+}
+
+struct PluginProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    next_id: u64,
+}
+
+impl PluginProcess {
+    async fn send(
+        &mut self,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<RpcResponse, String> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut line = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        })
+        .to_string();
+        line.push('\n');
+        self.stdin
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        self.stdin.flush().await.map_err(|e| e.to_string())?;
+        loop {
+            let Some(line) = self.stdout.next_line().await.map_err(|e| e.to_string())? else {
+                return Err("the plugin closed its stdout".to_string());
+            };
+            let response: RpcResponse =
+                serde_json::from_str(&line).map_err(|e| format!("bad response: {}", e))?;
+            // Notifications and stale responses to timed out calls
+            // can't be matched to this call, skip them.
+            if response.id == Some(id) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+pub struct PluginStore {
+    pub name: String,
+    pub settings: PluginSettings,
+    // One call at a time, the protocol has no multiplexing.
+    process: Mutex<Option<PluginProcess>>,
+    last_spawn: Mutex<Option<Instant>>,
+}
+
+impl PluginStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "plugin_store",
+            name_column: "key",
+            has_environment: true,
+            updated_column: "last_updated",
+            store: Some(&self.name),
+        }
+    }
+    // Starts the plugin straight away so a broken command shows up at
+    // startup rather than on the first request.
+    pub async fn init(name: &str, settings: PluginSettings) -> Result<Self, String> {
+        let store = Self {
+            name: name.to_string(),
+            settings,
+            process: Mutex::new(None),
+            last_spawn: Mutex::new(None),
+        };
+        let process = store.spawn().await?;
+        *store.process.lock().await = Some(process);
+        Ok(store)
+    }
+    async fn spawn(&self) -> Result<PluginProcess, String> {
+        *self.last_spawn.lock().await = Some(Instant::now());
+        let mut child = Command::new(&self.settings.command)
+            .args(&self.settings.args)
+            .envs(&self.settings.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Couldn't start {}: {}", &self.settings.command, e))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut process = PluginProcess {
+            child,
+            stdin,
+            stdout,
+            next_id: 0,
+        };
+        let params = json!({
+            "protocol": PLUGIN_PROTOCOL_VERSION,
+            "store": &self.name,
+            "config": &self.settings.config,
+        });
+        let response = tokio::time::timeout(
+            Duration::from_secs(self.settings.timeout_secs),
+            process.send("initialize", &params),
+        )
+        .await
+        .map_err(|_| "initialize timed out".to_string())??;
+        if let Some(error) = response.error {
+            return Err(format!("initialize failed: {}", error.message));
+        }
+        info!(
+            "Plugin store {} started ({})",
+            &self.name, &self.settings.command
+        );
+        Ok(process)
+    }
+    fn unavailable(&self, reason: &str) -> StoreAccessError {
+        StoreAccessError::Unavailable(format!(
+            "Plugin store {} is unavailable: {}",
+            &self.name, reason
+        ))
+    }
+    // Restarts the plugin if it died since the last call. A call that
+    // fails at the transport level kills the process so the next one
+    // starts from a clean slate.
+    async fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T, StoreAccessError> {
+        let mut process = self.process.lock().await;
+        if let Some(running) = process.as_mut()
+            && let Ok(Some(status)) = running.child.try_wait()
+        {
+            warn!("Plugin store {} exited ({})", &self.name, status);
+            *process = None;
+        }
+        if process.is_none() {
+            let recently = self
+                .last_spawn
+                .lock()
+                .await
+                .is_some_and(|at| at.elapsed() < RESTART_BACKOFF);
+            if recently {
+                return Err(self.unavailable("restarting too often"));
+            }
+            match self.spawn().await {
+                Ok(spawned) => *process = Some(spawned),
+                Err(e) => {
+                    warn!("Plugin store {} failed to restart: {}", &self.name, e);
+                    return Err(self.unavailable(&e));
+                }
+            }
+        }
+        let running = process.as_mut().unwrap();
+        let response = match tokio::time::timeout(
+            Duration::from_secs(self.settings.timeout_secs),
+            running.send(method, &params),
+        )
+        .await
+        {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                warn!("Plugin store {} failed on {}: {}", &self.name, method, e);
+                *process = None;
+                return Err(self.unavailable(&e));
+            }
+            Err(_) => {
+                warn!("Plugin store {} timed out on {}", &self.name, method);
+                *process = None;
+                return Err(self.unavailable("timed out"));
+            }
+        };
+        if let Some(error) = response.error {
+            return Err(match error.code {
+                PLUGIN_NOT_FOUND => StoreAccessError::NotFound(error.message),
+                PLUGIN_EXPIRED => StoreAccessError::Expired(error.message),
+                PLUGIN_CONFLICT => StoreAccessError::Conflict(error.message),
+                PLUGIN_FORBIDDEN => StoreAccessError::Forbidden(error.message),
+                _ => StoreAccessError::Invalid(error.message),
+            });
+        }
+        serde_json::from_value(response.result.unwrap_or_default())
+            .map_err(|e| self.unavailable(&format!("bad {} result: {}", method, e)))
+    }
+    fn sealed_name(&self, id: &str) -> String {
+        format!("{}:{}", &self.name, id)
+    }
+    pub async fn find_by_key(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Option<PluginStoredValue> {
+        sqlx::query_as::<_, PluginStoredValue>(
+            r#"SELECT * FROM tokaysec.plugin_store WHERE store = ($1) AND project = ($2) AND environment = ($3) AND key = ($4)"#,
+        )
+        .bind(&self.name)
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    async fn find_or_not_found(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Result<PluginStoredValue, StoreAccessError> {
+        self.find_by_key(app, project, environment, key)
+            .await
+            .ok_or(StoreAccessError::NotFound(format!(
+                "No secret named '{}' in project {} ({})",
+                key, project, environment
+            )))
+    }
+    async fn open_state(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        mut stored: PluginStoredValue,
+    ) -> (PluginStoredValue, serde_json::Value) {
+        let name = self.sealed_name(&stored.id);
+        let sealed = std::mem::take(&mut stored.state);
+        let plaintext = unseal(app, kek_provider, &name, sealed).await;
+        let state = serde_json::from_slice(plaintext.expose()).unwrap();
+        (stored, state)
+    }
+    async fn seal_state(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        id: &str,
+        state: &serde_json::Value,
+        creator: &str,
+    ) -> crate::models::SealedValue {
+        let plaintext = SecureBuffer::from_slice(&serde_json::to_vec(state).unwrap()).unwrap();
+        seal(app, kek_provider, &self.sealed_name(id), plaintext, creator).await
+    }
+    async fn store_secret(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        data: serde_json::Value,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        require_project_action(app, project, creator, AccessAction::CreateSecret).await?;
+        // The plugin picks the final name, but most take it from `data`.
+        // Catch the obvious conflict before the plugin creates anything.
+        if let Some(name) = data.get("name").and_then(|e| e.as_str())
+            && self
+                .find_by_key(app, project, environment, name)
+                .await
+                .is_some()
+        {
+            return Err(name_taken(name, environment));
+        }
+        let stored: StoredByPlugin = self
+            .call(
+                "store",
+                json!({ "project": project, "environment": environment, "data": data }),
+            )
+            .await?;
+        let row = match self
+            .persist_stored(app, kek_provider, project, environment, &stored, creator)
+            .await
+        {
+            Ok(row) => row,
+            Err(e) => {
+                // Nothing points at whatever the plugin just created,
+                // give it the chance to clean up.
+                if let Err(cleanup) = self
+                    .call::<serde_json::Value>(
+                        "delete",
+                        json!({
+                            "project": project,
+                            "environment": environment,
+                            "name": &stored.name,
+                            "state": &stored.state,
+                            "metadata": stored.metadata.as_ref().unwrap_or(&json!({})),
+                        }),
+                    )
+                    .await
+                {
+                    warn!(
+                        "Plugin store {} couldn't clean up '{}' after a failed store: {}",
+                        &self.name,
+                        &stored.name,
+                        cleanup.to_string()
+                    );
+                }
+                return Err(e);
+            }
+        };
+        Ok(json!({
+            "id": row.id,
+            "name": row.key,
+            "environment": row.environment,
+            "metadata": row.metadata.0,
+        }))
+    }
+    // Records what the plugin's store call handed back.
+    async fn persist_stored(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        stored: &StoredByPlugin,
+        creator: &str,
+    ) -> Result<PluginStoredValue, StoreAccessError> {
+        if self
+            .find_by_key(app, project, environment, &stored.name)
+            .await
+            .is_some()
+        {
+            return Err(name_taken(&stored.name, environment));
+        }
+        let id = app.gen_id().await;
+        let sealed = self
+            .seal_state(app, kek_provider, &id, &stored.state, creator)
+            .await;
+        let now = Utc::now();
+        let row = sqlx::query_as::<_, PluginStoredValue>(
+            r#"INSERT INTO tokaysec.plugin_store(id,store,project,environment,key,metadata,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by,last_updated) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$12) RETURNING *"#,
+        )
+        .bind(&id)
+        .bind(&self.name)
+        .bind(&project)
+        .bind(&environment)
+        .bind(&stored.name)
+        .bind(sqlx::types::Json(
+            stored.metadata.to_owned().unwrap_or(json!({})),
+        ))
+        .bind(&sealed.value)
+        .bind(&sealed.gcm_tag)
+        .bind(&sealed.kmac_tag)
+        .bind(&sealed.nonce)
+        .bind(&sealed.dek_used)
+        .bind(now)
+        .bind(&creator)
+        .fetch_one(&app.database.inner)
+        .await;
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+                    .bind(&sealed.dek_used)
+                    .execute(&app.database.inner)
+                    .await
+                    .ok();
+                return Err(e.into());
+            }
+        };
+        app.create_resource_assignment(
+            EasyResource(ResourceTypes::Project, project),
+            EasyResource(ResourceTypes::Secret, &self.sealed_name(&row.id)),
+            creator,
+        )
+        .await
+        .map_err(StoreAccessError::Internal)?;
+        Ok(row)
+    }
+    async fn delete_secret(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        environment: &str,
+        name: &str,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::DeleteSecret).await?;
+        let stored = self
+            .find_or_not_found(app, project, environment, name)
+            .await?;
+        let dek_used = stored.state.dek_used.to_owned();
+        let (stored, state) = self.open_state(app, kek_provider, stored).await;
+        // The plugin gets to clean up (revoke credentials etc.) first,
+        // if that fails the secret is kept so it can be retried.
+        let _: serde_json::Value = self
+            .call(
+                "delete",
+                json!({
+                    "project": project,
+                    "environment": environment,
+                    "name": &stored.key,
+                    "state": state,
+                    "metadata": &stored.metadata.0,
+                }),
+            )
+            .await?;
+        let mut tx = app.database.inner.begin().await?;
+        sqlx::query(r#"DELETE FROM tokaysec.plugin_store WHERE id = ($1)"#)
+            .bind(&stored.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&dek_used)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        remove_resource_assignments(app, &self.sealed_name(&stored.id)).await
+    }
+    async fn list_secrets(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+        let secrets = sqlx::query_as::<_, PluginStoredValue>(
+            r#"SELECT * FROM tokaysec.plugin_store WHERE store = ($1) AND project = ($2) AND environment = ($3) ORDER BY key"#,
+        )
+        .bind(&self.name)
+        .bind(&project)
+        .bind(&environment)
+        .fetch_all(&app.database.inner)
+        .await?
+        .into_iter()
+        .map(|e| json!({ "id": e.id, "name": e.key, "metadata": e.metadata.0 }))
+        .collect::<Vec<serde_json::Value>>();
+        self.call(
+            "list",
+            json!({ "project": project, "environment": environment, "secrets": secrets }),
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl TypedStore for PluginStore {
+    type StoreRequest = PluginStoreData;
+    type StoreResponse = serde_json::Value;
+    // Kept loose, query arguments the core doesn't know about are
+    // handed to the plugin.
+    type RetrieveRequest = HashMap<String, String>;
+    type RetrieveResponse = serde_json::Value;
+    type UpdateRequest = serde_json::Value;
+
+    fn store_type(&self) -> &str {
+        "plugin"
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: false,
+            description: false,
+            secret_type: true,
+        }
+    }
+    // Falls back to what we have on record if the plugin can't answer,
+    // a down plugin shouldn't break listing a project.
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let stored = sqlx::query_as::<_, PluginStoredValue>(
+            r#"SELECT * FROM tokaysec.plugin_store WHERE id = $1"#,
+        )
+        .bind(&id)
+        .fetch_one(&app.database.inner)
+        .await?;
+        let described: Option<DescribedByPlugin> = self
+            .call(
+                "get",
+                json!({ "id": &stored.id, "name": &stored.key, "metadata": &stored.metadata.0 }),
+            )
+            .await
+            .ok();
+        let described = described.unwrap_or(DescribedByPlugin {
+            name: None,
+            value: None,
+        });
+        return Ok(RetrievedSecretData {
+            id: stored.id,
+            name: described.name.unwrap_or(stored.key),
+            environment: Some(stored.environment),
+            updated_when: Some(stored.last_updated),
+            value: described.value,
+        });
+    }
+    // What we have on record like list, asking the plugin about every
+    // secret of a page would be one call each.
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    // What we have on record, the plugin isn't asked. Its own view of
+    // the secrets is still available through retrieve with list=true.
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    // ?project=<id>&name=<name>[&environment=<env>][&<anything else>]
+    // or ?project=<id>&list=true. Extra query arguments are passed on to
+    // the plugin.
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: HashMap<String, String>,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let Some(project) = data.get("project") else {
+            return Err(StoreAccessError::Invalid("project is required".to_string()));
+        };
+        let environment = data
+            .get("environment")
+            .map(|e| e.as_str())
+            .unwrap_or(DEFAULT_ENVIRONMENT);
+        if data.get("list").is_some_and(|e| e.as_str() == "true") {
+            return self
+                .list_secrets(app, project, environment, requester)
+                .await;
+        }
+        let Some(name) = data.get("name") else {
+            return Err(StoreAccessError::Invalid("name is required".to_string()));
+        };
+        require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+        let stored = self
+            .find_or_not_found(app, project, environment, name)
+            .await?;
+        let (stored, state) = self.open_state(app, kek_provider, stored).await;
+        let retrieved: RetrievedByPlugin = self
+            .call(
+                "retrieve",
+                json!({
+                    "project": project,
+                    "environment": environment,
+                    "name": &stored.key,
+                    "state": state,
+                    "metadata": &stored.metadata.0,
+                    "query": &data,
+                }),
+            )
+            .await?;
+        // Plugins can move their state on (e.g. a counter or a rotated
+        // upstream credential), it is re-sealed under a new DEK.
+        if retrieved.state.is_some() || retrieved.metadata.is_some() {
+            let mut tx = app.database.inner.begin().await?;
+            if let Some(state) = &retrieved.state {
+                // Locked so a concurrent read can't re-seal the same row
+                // and leave one of the two new DEKs behind.
+                let (old_dek,) = sqlx::query_as::<_, (String,)>(
+                    r#"SELECT dek_used FROM tokaysec.plugin_store WHERE id = ($1) FOR UPDATE"#,
+                )
+                .bind(&stored.id)
+                .fetch_one(&mut *tx)
+                .await?;
+                let sealed = self
+                    .seal_state(app, kek_provider, &stored.id, state, requester)
+                    .await;
+                sqlx::query(
+                    r#"UPDATE tokaysec.plugin_store SET value = ($1), gcm_tag = ($2), kmac_tag = ($3), nonce = ($4), dek_used = ($5), last_updated = ($6) WHERE id = ($7)"#,
+                )
+                .bind(&sealed.value)
+                .bind(&sealed.gcm_tag)
+                .bind(&sealed.kmac_tag)
+                .bind(&sealed.nonce)
+                .bind(&sealed.dek_used)
+                .bind(Utc::now())
+                .bind(&stored.id)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+                    .bind(&old_dek)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            if let Some(metadata) = &retrieved.metadata {
+                sqlx::query(
+                    r#"UPDATE tokaysec.plugin_store SET metadata = ($1), last_updated = ($2) WHERE id = ($3)"#,
+                )
+                .bind(sqlx::types::Json(metadata))
+                .bind(Utc::now())
+                .bind(&stored.id)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+        }
+        return Ok(json!({
+            "id": stored.id,
+            "name": stored.key,
+            "environment": stored.environment,
+            "value": retrieved.value,
+        }));
+    }
+
+    async fn store(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: PluginStoreData,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        self.store_secret(
+            app,
+            kek_provider,
+            &request.project,
+            &request.environment,
+            request.data,
+            creator,
+        )
+        .await
+    }
+
+    async fn delete(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        self.delete_secret(
+            app,
+            kek_provider,
+            &target.project,
+            &target.environment,
+            &target.name,
+            requester,
+        )
+        .await
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::Utc;
+
+use crate::{
+    app::App,
+    dek::Dek,
+    kek_provider::KekProvider,
+    models::{SealedValue, WrappedDek},
+    secure_buf::SecureBuffer,
+};
+
+// A value encrypted under its own DEK, with the DEK wrapped by the
+// KEK provider and saved to wrapped_deks. Same construction the kv
+// store uses, pulled out so stores holding private keys or
+// credentials don't each re-implement it.
+pub async fn seal(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    name: &str,
+    data: SecureBuffer,
+    creator: &str,
+) -> SealedValue {
+    let dek = Dek::init();
+    let encrypted = dek.wrap_data(data, name.to_string());
+    let (wrapped_dek, nonce, tag) = kek_provider.wrap_dek(dek, name).await.unwrap();
+    let dek_id = app.gen_id().await;
+    sqlx::query(
+        r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+    )
+    .bind(&dek_id)
+    .bind(&wrapped_dek)
+    .bind(nonce)
+    .bind(tag)
+    .bind(Utc::now())
+    .bind(&creator)
+    .execute(&app.database.inner)
+    .await
+    .unwrap();
+    SealedValue {
+        value: encrypted.data,
+        gcm_tag: encrypted.gcm_tag,
+        kmac_tag: encrypted.kmac_tag,
+        nonce: encrypted.nonce,
+        dek_used: dek_id,
+    }
+}
+
+pub async fn unseal(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    name: &str,
+    sealed: SealedValue,
+) -> SecureBuffer {
+    let dek_data =
+        sqlx::query_as::<_, WrappedDek>(r#"SELECT * FROM tokaysec.wrapped_deks WHERE id = $1"#)
+            .bind(&sealed.dek_used)
+            .fetch_one(&app.database.inner)
+            .await
+            .unwrap();
+    let unwrapped_dek: Dek = kek_provider
+        .unwrap_dek(
+            &dek_data.wrapped,
+            dek_data.nonce.try_into().unwrap(),
+            dek_data.tag.try_into().unwrap(),
+            name,
+        )
+        .await
+        .into();
+    unwrapped_dek.unwrap_data(
+        sealed.value,
+        sealed.kmac_tag,
+        name,
+        sealed.nonce,
+        sealed.gcm_tag,
+    )
+}
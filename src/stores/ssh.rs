@@ -0,0 +1,571 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ssh_key::{
+    Algorithm, HashAlg, LineEnding, PrivateKey, PublicKey,
+    certificate::{Builder, CertType},
+    private::{Ed25519Keypair, RsaKeypair},
+};
+
+use crate::{
+    app::{App, EasyResource, PolicyRuleTargetAction, ResourceTypes},
+    kek_provider::KekProvider,
+    models::{ResourceAssignment, Role, SshCa, SshCertificate, SshKey},
+    policies::AccessAction,
+    secure_buf::SecureBuffer,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, get_rows, list_rows, remove_resource_assignments,
+        require_project_action,
+        sealed::{seal, unseal},
+    },
+};
+
+// Longest a signed user certificate can be valid for.
+pub const MAX_CERTIFICATE_TTL_SECS: u64 = 86400;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SshKeyType {
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    #[serde(rename = "rsa-2048")]
+    Rsa2048,
+    #[serde(rename = "rsa-3072")]
+    Rsa3072,
+    #[serde(rename = "rsa-4096")]
+    Rsa4096,
+}
+
+impl SshKeyType {
+    pub fn generate(&self, comment: &str) -> PrivateKey {
+        let mut key: PrivateKey = match self {
+            SshKeyType::Ed25519 => Ed25519Keypair::random(&mut OsRng).into(),
+            SshKeyType::Rsa2048 => RsaKeypair::random(&mut OsRng, 2048).unwrap().into(),
+            SshKeyType::Rsa3072 => RsaKeypair::random(&mut OsRng, 3072).unwrap().into(),
+            SshKeyType::Rsa4096 => RsaKeypair::random(&mut OsRng, 4096).unwrap().into(),
+        };
+        key.set_comment(comment);
+        key
+    }
+}
+
+fn default_key_type() -> SshKeyType {
+    SshKeyType::Ed25519
+}
+fn default_certificate_ttl() -> u64 {
+    3600
+}
+
+// Everything sent to POST /store/ssh is one of these, picked by "action".
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SshStoreRequest {
+    Generate {
+        project: String,
+        name: String,
+        #[serde(default = "default_key_type")]
+        key_type: SshKeyType,
+        #[serde(default)]
+        comment: Option<String>,
+    },
+    Import {
+        project: String,
+        name: String,
+        // OpenSSH format, must not be passphrase protected.
+        private_key: String,
+    },
+    InitCa {
+        project: String,
+        #[serde(default = "default_key_type")]
+        key_type: SshKeyType,
+    },
+    Sign {
+        project: String,
+        public_key: String,
+        #[serde(default = "default_certificate_ttl")]
+        ttl_secs: u64,
+        #[serde(default)]
+        key_id: Option<String>,
+    },
+}
+
+// Only ed25519 and RSA keys are accepted, on import as well.
+fn algorithm_name(key: &PrivateKey) -> Option<String> {
+    match key.algorithm() {
+        Algorithm::Ed25519 => Some("ed25519".to_string()),
+        Algorithm::Rsa { .. } => {
+            let bits = key.key_data().rsa()?.public.n.as_positive_bytes()?.len() * 8;
+            Some(format!("rsa-{}", bits))
+        }
+        _ => None,
+    }
+}
+
+pub struct SshStore {}
+
+impl SshStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "ssh_keys",
+            name_column: "key",
+            has_environment: false,
+            updated_column: "added_when",
+            store: None,
+        }
+    }
+    pub async fn init() -> Self {
+        Self {}
+    }
+    pub async fn find_by_key(&self, app: &App, project: &str, key: &str) -> Option<SshKey> {
+        sqlx::query_as::<_, SshKey>(
+            r#"SELECT * FROM tokaysec.ssh_keys WHERE project = ($1) AND key = ($2)"#,
+        )
+        .bind(&project)
+        .bind(&key)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    pub async fn get_ca(&self, app: &App, project: &str) -> Option<SshCa> {
+        sqlx::query_as::<_, SshCa>(r#"SELECT * FROM tokaysec.ssh_cas WHERE project = ($1)"#)
+            .bind(&project)
+            .fetch_optional(&app.database.inner)
+            .await
+            .unwrap()
+    }
+    // Principals a signed certificate is valid for are the names of
+    // the roles the person holds that the project grants access to.
+    // Roles that only matter elsewhere don't become principals here.
+    async fn principals(&self, app: &App, project: &str, person: &str) -> Vec<String> {
+        let assignments = sqlx::query_as::<_, ResourceAssignment>(
+            r#"SELECT * FROM tokaysec.resource_assignment WHERE assigned_to = ($1) AND assigned_to_type = ($2) AND resource_type = ($3)"#,
+        )
+        .bind(&person)
+        .bind(ResourceTypes::Person.to_string())
+        .bind(ResourceTypes::Role.to_string())
+        .fetch_all(&app.database.inner)
+        .await
+        .unwrap();
+        let role_ids = assignments
+            .into_iter()
+            .map(|e| e.resource)
+            .collect::<Vec<String>>();
+        let allow: i32 = PolicyRuleTargetAction::Allow.into();
+        sqlx::query_as::<_, Role>(
+            r#"SELECT * FROM tokaysec.roles WHERE id = ANY($1) AND id IN (SELECT resource FROM tokaysec.policy_rule_target WHERE target = ($2) AND target_type = ($3) AND action = ($4) AND resource_type = ($5))"#,
+        )
+        .bind(&role_ids)
+        .bind(&project)
+        .bind(ResourceTypes::Project.to_string())
+        .bind(allow)
+        .bind(ResourceTypes::Role.to_string())
+        .fetch_all(&app.database.inner)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect()
+    }
+    async fn save_key(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        name: &str,
+        key: PrivateKey,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        require_project_action(app, project, creator, AccessAction::CreateSecret).await?;
+        if self.find_by_key(app, project, name).await.is_some() {
+            return Err(StoreAccessError::Conflict(format!(
+                "An SSH key named '{}' already exists in this project",
+                name
+            )));
+        }
+        let Some(algorithm) = algorithm_name(&key) else {
+            return Err(StoreAccessError::Invalid(
+                "Only ed25519 and RSA keys are supported".to_string(),
+            ));
+        };
+        let public_key = key.public_key().to_openssh().unwrap();
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        let id = app.gen_id().await;
+        let private_pem = key.to_openssh(LineEnding::LF).unwrap();
+        drop(key);
+        let sealed = seal(
+            app,
+            kek_provider,
+            &format!("ssh_key:{}", &id),
+            SecureBuffer::from_slice(private_pem.as_bytes()).unwrap(),
+            creator,
+        )
+        .await;
+        drop(private_pem);
+        let ssh_key = sqlx::query_as::<_, SshKey>(
+            r#"INSERT INTO tokaysec.ssh_keys(id,project,key,algorithm,public_key,fingerprint,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13) RETURNING *"#,
+        )
+        .bind(&id).bind(&project).bind(&name).bind(&algorithm).bind(&public_key).bind(&fingerprint)
+        .bind(&sealed.value).bind(&sealed.gcm_tag).bind(&sealed.kmac_tag).bind(&sealed.nonce).bind(&sealed.dek_used)
+        .bind(Utc::now()).bind(&creator)
+        .fetch_one(&app.database.inner)
+        .await
+        .unwrap();
+        app.create_resource_assignment(
+            EasyResource(ResourceTypes::Project, &project),
+            EasyResource(ResourceTypes::Secret, &format!("ssh:{}", &ssh_key.id)),
+            creator,
+        )
+        .await
+        .unwrap();
+        Ok(json!({
+            "id": ssh_key.id,
+            "name": ssh_key.key,
+            "algorithm": ssh_key.algorithm,
+            "public_key": ssh_key.public_key,
+            "fingerprint": ssh_key.fingerprint,
+        }))
+    }
+    async fn init_ca(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        key_type: SshKeyType,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        require_project_action(app, project, creator, AccessAction::ManageSshCa).await?;
+        if self.get_ca(app, project).await.is_some() {
+            return Err(StoreAccessError::Conflict(format!(
+                "Project {} already has an SSH CA",
+                project
+            )));
+        }
+        let key = key_type.generate(&format!("tokaysec-user-ca-{}", project));
+        let algorithm = algorithm_name(&key).unwrap();
+        let public_key = key.public_key().to_openssh().unwrap();
+        let id = app.gen_id().await;
+        let private_pem = key.to_openssh(LineEnding::LF).unwrap();
+        drop(key);
+        let sealed = seal(
+            app,
+            kek_provider,
+            &format!("ssh_ca:{}", &id),
+            SecureBuffer::from_slice(private_pem.as_bytes()).unwrap(),
+            creator,
+        )
+        .await;
+        drop(private_pem);
+        let ca = sqlx::query_as::<_, SshCa>(
+            r#"INSERT INTO tokaysec.ssh_cas(id,project,algorithm,public_key,value,gcm_tag,kmac_tag,nonce,dek_used,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11) RETURNING *"#,
+        )
+        .bind(&id).bind(&project).bind(&algorithm).bind(&public_key)
+        .bind(&sealed.value).bind(&sealed.gcm_tag).bind(&sealed.kmac_tag).bind(&sealed.nonce).bind(&sealed.dek_used)
+        .bind(Utc::now()).bind(&creator)
+        .fetch_one(&app.database.inner)
+        .await
+        .unwrap();
+        // Goes into sshd's TrustedUserCAKeys.
+        Ok(json!({ "id": ca.id, "public_key": ca.public_key }))
+    }
+    async fn sign(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        project: &str,
+        public_key: &str,
+        ttl_secs: u64,
+        key_id: Option<String>,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        require_project_action(app, project, requester, AccessAction::SignSshCertificate).await?;
+        if ttl_secs == 0 || ttl_secs > MAX_CERTIFICATE_TTL_SECS {
+            return Err(StoreAccessError::Invalid(format!(
+                "ttl_secs must be between 1 and {}",
+                MAX_CERTIFICATE_TTL_SECS
+            )));
+        }
+        let public_key = PublicKey::from_openssh(public_key)
+            .map_err(|e| StoreAccessError::Invalid(format!("Bad public key: {}", e)))?;
+        let principals = self.principals(app, project, requester).await;
+        if principals.is_empty() {
+            return Err(StoreAccessError::Forbidden(
+                "No roles to use as certificate principals".to_string(),
+            ));
+        }
+        let Some(ca) = self.get_ca(app, project).await else {
+            return Err(StoreAccessError::NotFound(format!(
+                "Project {} has no SSH CA, init one first",
+                project
+            )));
+        };
+        let ca_id = ca.id.to_owned();
+        let ca_pem = unseal(
+            app,
+            kek_provider,
+            &format!("ssh_ca:{}", &ca.id),
+            ca.private_key,
+        )
+        .await;
+        let ca_key = PrivateKey::from_openssh(ca_pem.expose()).unwrap();
+        drop(ca_pem);
+
+        // Backdate a little so small clock skew doesn't reject it.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let valid_after = (now - Duration::from_secs(60)).as_secs();
+        let valid_before = (now + Duration::from_secs(ttl_secs)).as_secs();
+        let key_id = key_id.unwrap_or(format!("{}@{}", requester, project));
+        let serial = OsRng.next_u64() >> 1;
+        let mut builder =
+            Builder::new_with_random_nonce(&mut OsRng, public_key, valid_after, valid_before)
+                .unwrap();
+        builder.serial(serial).unwrap();
+        builder.key_id(key_id.to_owned()).unwrap();
+        builder.cert_type(CertType::User).unwrap();
+        for principal in &principals {
+            builder.valid_principal(principal.to_owned()).unwrap();
+        }
+        for extension in [
+            "permit-pty",
+            "permit-agent-forwarding",
+            "permit-port-forwarding",
+            "permit-user-rc",
+        ] {
+            builder.extension(extension, "").unwrap();
+        }
+        let certificate = builder.sign(&ca_key).unwrap();
+        drop(ca_key);
+        let certificate = certificate.to_openssh().unwrap();
+        let valid_before = DateTime::from_timestamp(valid_before as i64, 0).unwrap();
+        let issued = sqlx::query_as::<_, SshCertificate>(
+            r#"INSERT INTO tokaysec.ssh_certificates(id,project,ca,serial,key_id,principals,certificate,valid_before,issued_when,issued_by) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10) RETURNING *"#,
+        )
+        .bind(app.gen_id().await).bind(&project).bind(&ca_id).bind(serial as i64).bind(&key_id)
+        .bind(sqlx::types::Json(&principals)).bind(&certificate).bind(valid_before).bind(Utc::now()).bind(&requester)
+        .fetch_one(&app.database.inner)
+        .await
+        .unwrap();
+        Ok(serde_json::to_value(&issued).unwrap())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SshRetrieveRequest {
+    pub project: String,
+    #[serde(default, alias = "key")]
+    pub name: Option<String>,
+    // public or private
+    #[serde(default)]
+    pub part: Option<String>,
+    #[serde(default)]
+    pub ca: Option<String>,
+    #[serde(default)]
+    pub certificate: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl TypedStore for SshStore {
+    type StoreRequest = SshStoreRequest;
+    type StoreResponse = serde_json::Value;
+    type RetrieveRequest = SshRetrieveRequest;
+    type RetrieveResponse = serde_json::Value;
+    type UpdateRequest = serde_json::Value;
+
+    fn store_type(&self) -> &str {
+        "ssh"
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: true,
+            description: false,
+            secret_type: true,
+        }
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let ssh_key =
+            sqlx::query_as::<_, SshKey>(r#"SELECT * FROM tokaysec.ssh_keys WHERE id = $1"#)
+                .bind(&id)
+                .fetch_one(&app.database.inner)
+                .await?;
+        return Ok(RetrievedSecretData {
+            id: ssh_key.id,
+            name: ssh_key.key,
+            environment: None,
+            updated_when: Some(ssh_key.added_when),
+            value: None,
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    // ?project=<id>&name=<key>[&part=public|private]
+    // ?project=<id>&ca=public
+    // ?project=<id>&certificate=<id>
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: SshRetrieveRequest,
+        requester: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        let project = &request.project;
+        if request.ca.is_some() {
+            let Some(ca) = self.get_ca(app, project).await else {
+                return Err(StoreAccessError::NotFound(format!(
+                    "Project {} has no SSH CA",
+                    project
+                )));
+            };
+            return Ok(json!({ "public_key": ca.public_key }));
+        }
+        if let Some(certificate) = &request.certificate {
+            let Some(issued) = sqlx::query_as::<_, SshCertificate>(
+                r#"SELECT * FROM tokaysec.ssh_certificates WHERE project = ($1) AND id = ($2)"#,
+            )
+            .bind(&project)
+            .bind(&certificate)
+            .fetch_optional(&app.database.inner)
+            .await?
+            else {
+                return Err(StoreAccessError::NotFound(format!(
+                    "No SSH certificate {}",
+                    certificate
+                )));
+            };
+            return Ok(serde_json::to_value(&issued).unwrap());
+        }
+        let Some(name) = &request.name else {
+            return Err(StoreAccessError::Invalid(
+                "One of name, ca or certificate is required".to_string(),
+            ));
+        };
+        let Some(ssh_key) = self.find_by_key(app, project, name).await else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No SSH key named '{}' in project {}",
+                name, project
+            )));
+        };
+        let mut retrieved = json!({
+            "id": ssh_key.id,
+            "name": ssh_key.key,
+            "algorithm": ssh_key.algorithm,
+            "public_key": ssh_key.public_key,
+            "fingerprint": ssh_key.fingerprint,
+        });
+        if request.part.as_deref() == Some("private") {
+            require_project_action(app, project, requester, AccessAction::ReadSecret).await?;
+            let private_pem = unseal(
+                app,
+                kek_provider,
+                &format!("ssh_key:{}", &ssh_key.id),
+                ssh_key.private_key,
+            )
+            .await;
+            retrieved["private_key"] =
+                json!(String::from_utf8(private_pem.expose().to_vec()).unwrap());
+        }
+        return Ok(retrieved);
+    }
+
+    async fn store(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: SshStoreRequest,
+        creator: &str,
+    ) -> Result<serde_json::Value, StoreAccessError> {
+        match request {
+            SshStoreRequest::Generate {
+                project,
+                name,
+                key_type,
+                comment,
+            } => {
+                let key = key_type.generate(&comment.unwrap_or(format!("{}@tokaysec", name)));
+                self.save_key(app, kek_provider, &project, &name, key, creator)
+                    .await
+            }
+            SshStoreRequest::Import {
+                project,
+                name,
+                private_key,
+            } => {
+                let key = PrivateKey::from_openssh(private_key.as_bytes())
+                    .map_err(|e| StoreAccessError::Invalid(format!("Bad private key: {}", e)))?;
+                drop(private_key);
+                if key.is_encrypted() {
+                    return Err(StoreAccessError::Invalid(
+                        "Passphrase protected keys can't be imported".to_string(),
+                    ));
+                }
+                self.save_key(app, kek_provider, &project, &name, key, creator)
+                    .await
+            }
+            SshStoreRequest::InitCa { project, key_type } => {
+                self.init_ca(app, kek_provider, &project, key_type, creator)
+                    .await
+            }
+            SshStoreRequest::Sign {
+                project,
+                public_key,
+                ttl_secs,
+                key_id,
+            } => {
+                self.sign(
+                    app,
+                    kek_provider,
+                    &project,
+                    &public_key,
+                    ttl_secs,
+                    key_id,
+                    creator,
+                )
+                .await
+            }
+        }
+    }
+
+    // Keys only, the CA stays for as long as the project does.
+    async fn delete(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, &target.project, requester, AccessAction::DeleteSecret).await?;
+        let Some(ssh_key) = self.find_by_key(app, &target.project, &target.name).await else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No SSH key named '{}' in project {}",
+                target.name, target.project
+            )));
+        };
+        let mut tx = app.database.inner.begin().await?;
+        sqlx::query(r#"DELETE FROM tokaysec.ssh_keys WHERE id = ($1)"#)
+            .bind(&ssh_key.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&ssh_key.private_key.dek_used)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        remove_resource_assignments(app, &format!("ssh:{}", &ssh_key.id)).await
+    }
+}
@@ -0,0 +1,772 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    app::{App, EasyResource, PolicyRuleTargetAction, ResourceTypes},
+    dek::{Dek, DekWrapDataResult},
+    kek_provider::KekProvider,
+    models::{
+        PolicyRuleTarget, ResourceAssignment, StructuredField, StructuredStoredValue, WrappedDek,
+    },
+    policies::AccessAction,
+    secure_buf::SecureBuffer,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, get_rows, kv::DEFAULT_ENVIRONMENT, list_rows,
+        remove_resource_assignments, require_project_action,
+    },
+};
+
+pub const STRUCTURED_STORE: &str = "structured";
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StructuredStoreStoreData {
+    pub name: String,
+    pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    pub value: serde_json::Value,
+    // JSON pointer -> who may read it ("role:<id>" or "prsn:<id>").
+    // Fields without an entry are readable by anyone who can read
+    // the secret.
+    #[serde(default)]
+    pub restrict: HashMap<String, Vec<String>>,
+}
+
+fn escape_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape_token(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+// Splits an object into (pointer, leaf) pairs. Arrays, scalars and
+// empty objects are leaves and get encrypted whole.
+fn flatten(pointer: String, value: &serde_json::Value, out: &mut Vec<(String, serde_json::Value)>) {
+    match value {
+        serde_json::Value::Object(fields) if !fields.is_empty() => {
+            for (name, field) in fields {
+                flatten(format!("{}/{}", pointer, escape_token(name)), field, out);
+            }
+        }
+        _ => out.push((pointer, value.to_owned())),
+    }
+}
+
+// Inverse of flatten, puts a leaf back at its pointer creating any
+// objects on the way.
+fn insert_at(root: &mut serde_json::Value, pointer: &str, value: serde_json::Value) {
+    if pointer.is_empty() {
+        *root = value;
+        return;
+    }
+    let tokens = pointer
+        .split('/')
+        .skip(1)
+        .map(unescape_token)
+        .collect::<Vec<String>>();
+    let mut current = root;
+    for token in &tokens[..tokens.len() - 1] {
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(token.to_owned())
+            .or_insert(json!({}));
+    }
+    current
+        .as_object_mut()
+        .unwrap()
+        .insert(tokens[tokens.len() - 1].to_owned(), value);
+}
+
+// Whether `pointer` is `parent` or somewhere underneath it.
+fn covers(parent: &str, pointer: &str) -> bool {
+    parent.is_empty() || pointer == parent || pointer.starts_with(&format!("{}/", parent))
+}
+
+fn field_name(secret_id: &str, pointer: &str) -> String {
+    format!("{}:{}#{}", STRUCTURED_STORE, secret_id, pointer)
+}
+
+// A field is readable unless a rule on it (or on an object above it)
+// says otherwise. Deny rules win wherever they sit, and once a field
+// has any allow rules only the roles/people listed can read it.
+fn field_readable(
+    pointer: &str,
+    rules: &[(String, PolicyRuleTarget)],
+    person: &str,
+    roles: &[String],
+) -> bool {
+    let applicable = rules
+        .iter()
+        .filter(|(rule_pointer, _)| covers(rule_pointer, pointer))
+        .map(|(_, rule)| rule)
+        .collect::<Vec<&PolicyRuleTarget>>();
+    let applies =
+        |rule: &PolicyRuleTarget| match ResourceTypes::try_from(rule.resource_type.as_str()) {
+            Ok(ResourceTypes::Person) => rule.resource == person,
+            Ok(ResourceTypes::Role) => roles.contains(&rule.resource),
+            _ => false,
+        };
+    let (denies, allows): (Vec<&PolicyRuleTarget>, Vec<&PolicyRuleTarget>) = applicable
+        .into_iter()
+        .filter(|rule| {
+            !matches!(
+                PolicyRuleTargetAction::from(rule.action),
+                PolicyRuleTargetAction::FallThrough
+            )
+        })
+        .partition(|rule| {
+            matches!(
+                PolicyRuleTargetAction::from(rule.action),
+                PolicyRuleTargetAction::Deny
+            )
+        });
+    if denies.into_iter().any(applies) {
+        return false;
+    }
+    allows.is_empty() || allows.into_iter().any(applies)
+}
+
+pub struct StructuredStore {}
+
+impl StructuredStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "structured_store",
+            name_column: "key",
+            has_environment: true,
+            updated_column: "last_updated",
+            store: None,
+        }
+    }
+    pub async fn init() -> Self {
+        Self {}
+    }
+    pub async fn find_by_key(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Option<StructuredStoredValue> {
+        sqlx::query_as::<_, StructuredStoredValue>(
+            r#"SELECT * FROM tokaysec.structured_store WHERE project = ($1) AND environment = ($2) AND key = ($3)"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    // Field rules are policy rule targets on scrt:structured:<id>#<pointer>.
+    async fn field_rules(&self, app: &App, secret_id: &str) -> Vec<(String, PolicyRuleTarget)> {
+        let prefix = format!("{}:{}#", STRUCTURED_STORE, secret_id);
+        sqlx::query_as::<_, PolicyRuleTarget>(
+            r#"SELECT * FROM tokaysec.policy_rule_target WHERE target_type = ($1) AND target LIKE ($2)"#,
+        )
+        .bind(ResourceTypes::Secret.to_string())
+        .bind(format!("{}%", &prefix))
+        .fetch_all(&app.database.inner)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rule| (rule.target[prefix.len()..].to_string(), rule))
+        .collect()
+    }
+    async fn person_roles(&self, app: &App, person: &str) -> Vec<String> {
+        sqlx::query_as::<_, ResourceAssignment>(
+            r#"SELECT * FROM tokaysec.resource_assignment WHERE assigned_to = ($1) AND assigned_to_type = ($2) AND resource_type = ($3)"#,
+        )
+        .bind(&person)
+        .bind(ResourceTypes::Person.to_string())
+        .bind(ResourceTypes::Role.to_string())
+        .fetch_all(&app.database.inner)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|e| e.resource)
+        .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StructuredRetrieveRequest {
+    pub project: String,
+    #[serde(alias = "name")]
+    pub key: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    #[serde(default)]
+    pub pointer: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StructuredRetrieved {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub pointer: String,
+    pub value: serde_json::Value,
+    pub redacted: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StructuredStored {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub fields: Vec<String>,
+}
+
+// Replaces every field of an existing secret. Field rules are kept,
+// they're keyed by pointer and apply again if the field comes back.
+#[derive(Serialize, Deserialize)]
+pub struct StructuredStoreUpdateData {
+    pub name: String,
+    pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    pub value: serde_json::Value,
+}
+
+fn check_object(value: &serde_json::Value) -> Result<(), StoreAccessError> {
+    if !value.is_object() {
+        return Err(StoreAccessError::Invalid(
+            "Structured secrets have to be JSON objects".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn insert_fields(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: &str,
+    encrypted: Vec<(String, DekWrapDataResult)>,
+) -> Result<Vec<String>, StoreAccessError> {
+    let mut pointers = Vec::with_capacity(encrypted.len());
+    for (pointer, field) in encrypted {
+        sqlx::query(
+            r#"INSERT INTO tokaysec.structured_store_fields(secret_id,pointer,value,gcm_tag,kmac_tag,nonce) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&id)
+        .bind(&pointer)
+        .bind(&field.data)
+        .bind(&field.gcm_tag)
+        .bind(&field.kmac_tag)
+        .bind(&field.nonce)
+        .execute(&mut **tx)
+        .await?;
+        pointers.push(pointer);
+    }
+    Ok(pointers)
+}
+
+impl StructuredStore {
+    // Every field is encrypted under one fresh DEK, which is stored
+    // right away. The fields are inserted once the secret row exists.
+    async fn encrypt_fields(
+        &self,
+        app: &App,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        kek_provider: &dyn KekProvider,
+        id: &str,
+        value: &serde_json::Value,
+        creator: &str,
+    ) -> Result<(String, Vec<(String, DekWrapDataResult)>), StoreAccessError> {
+        let mut fields = vec![];
+        flatten(String::new(), value, &mut fields);
+        let dek = Dek::init();
+        let encrypted = fields
+            .into_iter()
+            .map(|(pointer, value)| {
+                let plaintext =
+                    SecureBuffer::from_slice(&serde_json::to_vec(&value).unwrap()).unwrap();
+                let encrypted = dek.wrap_data(plaintext, field_name(id, &pointer));
+                (pointer, encrypted)
+            })
+            .collect::<Vec<_>>();
+        let (wrapped_dek, nonce, tag) = kek_provider
+            .wrap_dek(dek, &format!("{}:{}", STRUCTURED_STORE, id))
+            .await
+            .unwrap();
+        let dek_id = app.gen_id().await;
+        sqlx::query(
+            r#"INSERT INTO tokaysec.wrapped_deks(id,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6)"#,
+        )
+        .bind(&dek_id)
+        .bind(&wrapped_dek)
+        .bind(nonce)
+        .bind(tag)
+        .bind(Utc::now())
+        .bind(&creator)
+        .execute(&mut **tx)
+        .await?;
+        Ok((dek_id, encrypted))
+    }
+    fn not_found(&self, project: &str, environment: &str, key: &str) -> StoreAccessError {
+        StoreAccessError::NotFound(format!(
+            "No secret named '{}' in project {} ({})",
+            key, project, environment
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl TypedStore for StructuredStore {
+    type StoreRequest = StructuredStoreStoreData;
+    type StoreResponse = StructuredStored;
+    type RetrieveRequest = StructuredRetrieveRequest;
+    type RetrieveResponse = StructuredRetrieved;
+    type UpdateRequest = StructuredStoreUpdateData;
+
+    fn store_type(&self) -> &str {
+        "structured"
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: true,
+            description: false,
+            secret_type: true,
+        }
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let structured = sqlx::query_as::<_, StructuredStoredValue>(
+            r#"SELECT * FROM tokaysec.structured_store WHERE id = $1"#,
+        )
+        .bind(&id)
+        .fetch_one(&app.database.inner)
+        .await?;
+        return Ok(RetrievedSecretData {
+            id: structured.id,
+            name: structured.key,
+            environment: Some(structured.environment),
+            updated_when: Some(structured.last_updated),
+            value: None,
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    // ?project=<id>&key=<name>[&environment=<env>][&pointer=/db/password]
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: StructuredRetrieveRequest,
+        requester: &str,
+    ) -> Result<StructuredRetrieved, StoreAccessError> {
+        let pointer = request.pointer.as_str();
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(StoreAccessError::Invalid(format!(
+                "'{}' is not a JSON pointer",
+                pointer
+            )));
+        }
+        require_project_action(app, &request.project, requester, AccessAction::ReadSecret).await?;
+        let Some(structured) = self
+            .find_by_key(app, &request.project, &request.environment, &request.key)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No secret named '{}' in project {} ({})",
+                request.key, request.project, request.environment
+            )));
+        };
+        let fields = sqlx::query_as::<_, StructuredField>(
+            r#"SELECT * FROM tokaysec.structured_store_fields WHERE secret_id = ($1) ORDER BY pointer"#,
+        )
+        .bind(&structured.id)
+        .fetch_all(&app.database.inner)
+        .await?;
+        // Either the pointer names a field or an object above some
+        // fields, or it points inside a field (e.g. into an array).
+        let mut matched = fields
+            .into_iter()
+            .filter(|field| covers(pointer, &field.pointer) || covers(&field.pointer, pointer))
+            .collect::<Vec<StructuredField>>();
+        if matched.is_empty() {
+            return Err(StoreAccessError::NotFound(format!(
+                "Secret '{}' has nothing at '{}'",
+                request.key, pointer
+            )));
+        }
+        let rules = self.field_rules(app, &structured.id).await;
+        let roles = self.person_roles(app, requester).await;
+        let (readable, redacted): (Vec<StructuredField>, Vec<StructuredField>) = matched
+            .drain(..)
+            .partition(|field| field_readable(&field.pointer, &rules, requester, &roles));
+        if readable.is_empty() {
+            return Err(StoreAccessError::Forbidden(format!(
+                "Not allowed to read '{}' of secret '{}'",
+                pointer, request.key
+            )));
+        }
+        let dek_data =
+            sqlx::query_as::<_, WrappedDek>(r#"SELECT * FROM tokaysec.wrapped_deks WHERE id = $1"#)
+                .bind(&structured.dek_used)
+                .fetch_one(&app.database.inner)
+                .await?;
+        let dek: Dek = kek_provider
+            .unwrap_dek(
+                &dek_data.wrapped,
+                dek_data.nonce.try_into().unwrap(),
+                dek_data.tag.try_into().unwrap(),
+                &format!("{}:{}", STRUCTURED_STORE, &structured.id),
+            )
+            .await
+            .into();
+        let mut value = json!({});
+        for field in readable {
+            let plaintext = dek.unwrap_data(
+                field.value,
+                field.kmac_tag,
+                &field_name(&structured.id, &field.pointer),
+                field.nonce,
+                field.gcm_tag,
+            );
+            let field_value: serde_json::Value =
+                serde_json::from_slice(plaintext.expose()).unwrap();
+            drop(plaintext);
+            if covers(pointer, &field.pointer) {
+                insert_at(&mut value, &field.pointer[pointer.len()..], field_value);
+            } else {
+                let Some(inner) = field_value.pointer(&pointer[field.pointer.len()..]) else {
+                    return Err(StoreAccessError::NotFound(format!(
+                        "Secret '{}' has nothing at '{}'",
+                        request.key, pointer
+                    )));
+                };
+                value = inner.to_owned();
+            }
+        }
+        return Ok(StructuredRetrieved {
+            id: structured.id,
+            name: structured.key,
+            environment: structured.environment,
+            pointer: pointer.to_string(),
+            value,
+            redacted: redacted.into_iter().map(|e| e.pointer).collect(),
+        });
+    }
+
+    async fn store(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: StructuredStoreStoreData,
+        creator: &str,
+    ) -> Result<StructuredStored, StoreAccessError> {
+        check_object(&data.value)?;
+        for (pointer, allowed) in &data.restrict {
+            if !pointer.starts_with('/') {
+                return Err(StoreAccessError::Invalid(format!(
+                    "'{}' is not a JSON pointer",
+                    pointer
+                )));
+            }
+            for resource in allowed {
+                let valid = resource.split_once(':').is_some_and(|(ty, _)| {
+                    matches!(
+                        ResourceTypes::try_from(ty),
+                        Ok(ResourceTypes::Role) | Ok(ResourceTypes::Person)
+                    )
+                });
+                if !valid {
+                    return Err(StoreAccessError::Invalid(format!(
+                        "'{}' should be role:<id> or prsn:<id>",
+                        resource
+                    )));
+                }
+            }
+        }
+        require_project_action(app, &data.project, creator, AccessAction::CreateSecret).await?;
+        if self
+            .find_by_key(app, &data.project, &data.environment, &data.name)
+            .await
+            .is_some()
+        {
+            return Err(StoreAccessError::Conflict(format!(
+                "A secret named '{}' already exists in this project ({})",
+                data.name, data.environment
+            )));
+        }
+        let id = app.gen_id().await;
+        let now = Utc::now();
+        let mut tx = app.database.inner.begin().await?;
+        let (dek_id, encrypted) = self
+            .encrypt_fields(app, &mut tx, kek_provider, &id, &data.value, creator)
+            .await?;
+        let structured = sqlx::query_as::<_, StructuredStoredValue>(
+            r#"INSERT INTO tokaysec.structured_store(id,project,environment,key,dek_used,added_when,added_by,last_updated) VALUES($1,$2,$3,$4,$5,$6,$7,$6) RETURNING *"#,
+        )
+        .bind(&id)
+        .bind(&data.project)
+        .bind(&data.environment)
+        .bind(&data.name)
+        .bind(&dek_id)
+        .bind(now)
+        .bind(&creator)
+        .fetch_one(&mut *tx)
+        .await?;
+        let pointers = insert_fields(&mut tx, &id, encrypted).await?;
+        tx.commit().await?;
+        for (pointer, allowed) in &data.restrict {
+            for resource in allowed {
+                app.create_policy_rule_target(
+                    &format!(
+                        "{}:{}",
+                        ResourceTypes::Secret.to_string(),
+                        field_name(&id, pointer)
+                    ),
+                    PolicyRuleTargetAction::Allow,
+                    resource,
+                )
+                .await
+                .unwrap();
+            }
+        }
+        app.create_resource_assignment(
+            EasyResource(ResourceTypes::Project, &data.project),
+            EasyResource(
+                ResourceTypes::Secret,
+                &format!("{}:{}", STRUCTURED_STORE, &structured.id),
+            ),
+            creator,
+        )
+        .await
+        .map_err(StoreAccessError::Internal)?;
+        return Ok(StructuredStored {
+            id: structured.id,
+            name: structured.key,
+            environment: structured.environment,
+            fields: pointers,
+        });
+    }
+
+    async fn update(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        data: StructuredStoreUpdateData,
+        requester: &str,
+    ) -> Result<StructuredStored, StoreAccessError> {
+        check_object(&data.value)?;
+        require_project_action(app, &data.project, requester, AccessAction::UpdateSecret).await?;
+        let Some(existing) = self
+            .find_by_key(app, &data.project, &data.environment, &data.name)
+            .await
+        else {
+            return Err(self.not_found(&data.project, &data.environment, &data.name));
+        };
+        let mut tx = app.database.inner.begin().await?;
+        let (dek_id, encrypted) = self
+            .encrypt_fields(
+                app,
+                &mut tx,
+                kek_provider,
+                &existing.id,
+                &data.value,
+                requester,
+            )
+            .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.structured_store_fields WHERE secret_id = ($1)"#)
+            .bind(&existing.id)
+            .execute(&mut *tx)
+            .await?;
+        let pointers = insert_fields(&mut tx, &existing.id, encrypted).await?;
+        sqlx::query(
+            r#"UPDATE tokaysec.structured_store SET dek_used = ($1), last_updated = ($2) WHERE id = ($3)"#,
+        )
+        .bind(&dek_id)
+        .bind(Utc::now())
+        .bind(&existing.id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&existing.dek_used)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        return Ok(StructuredStored {
+            id: existing.id,
+            name: existing.key,
+            environment: existing.environment,
+            fields: pointers,
+        });
+    }
+
+    async fn delete(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, &target.project, requester, AccessAction::DeleteSecret).await?;
+        let Some(existing) = self
+            .find_by_key(app, &target.project, &target.environment, &target.name)
+            .await
+        else {
+            return Err(self.not_found(&target.project, &target.environment, &target.name));
+        };
+        let mut tx = app.database.inner.begin().await?;
+        // Fields go with it (ON DELETE CASCADE).
+        sqlx::query(r#"DELETE FROM tokaysec.structured_store WHERE id = ($1)"#)
+            .bind(&existing.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"DELETE FROM tokaysec.wrapped_deks WHERE id = ($1)"#)
+            .bind(&existing.dek_used)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"DELETE FROM tokaysec.policy_rule_target WHERE target_type = ($1) AND target LIKE ($2)"#,
+        )
+        .bind(ResourceTypes::Secret.to_string())
+        .bind(format!("{}:{}#%", STRUCTURED_STORE, &existing.id))
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        remove_resource_assignments(app, &format!("{}:{}", STRUCTURED_STORE, &existing.id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        pointer: &str,
+        action: PolicyRuleTargetAction,
+        who: &str,
+    ) -> (String, PolicyRuleTarget) {
+        let (resource_type, resource) = who.split_once(':').unwrap();
+        (
+            pointer.to_string(),
+            PolicyRuleTarget {
+                id: format!("{}{}", pointer, who),
+                target: field_name("s1", pointer),
+                target_type: ResourceTypes::Secret.to_string(),
+                action: action.into(),
+                resource: resource.to_string(),
+                resource_type: resource_type.to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn unrestricted_fields_are_readable() {
+        assert!(field_readable("/db/password", &[], "bob", &[]));
+        let rules = [rule("/api", PolicyRuleTargetAction::Deny, "prsn:bob")];
+        assert!(field_readable("/db/password", &rules, "bob", &[]));
+    }
+
+    #[test]
+    fn allow_rules_restrict_to_who_they_name() {
+        let rules = [rule("/db", PolicyRuleTargetAction::Allow, "role:devs")];
+        assert!(field_readable(
+            "/db/password",
+            &rules,
+            "bob",
+            &["devs".to_string()]
+        ));
+        assert!(!field_readable("/db/password", &rules, "eve", &[]));
+        assert!(field_readable("/dbname", &rules, "eve", &[]));
+    }
+
+    // The same rules in either order, bob is in devs but denied the
+    // password itself.
+    #[test]
+    fn deny_wins_regardless_of_order() {
+        let allow = || rule("/db", PolicyRuleTargetAction::Allow, "role:devs");
+        let deny = || rule("/db/password", PolicyRuleTargetAction::Deny, "prsn:bob");
+        let roles = ["devs".to_string()];
+        for rules in [[allow(), deny()], [deny(), allow()]] {
+            assert!(!field_readable("/db/password", &rules, "bob", &roles));
+            assert!(field_readable("/db/host", &rules, "bob", &roles));
+            assert!(field_readable("/db/password", &rules, "alice", &roles));
+        }
+    }
+
+    #[test]
+    fn rules_on_the_root_cover_everything() {
+        let rules = [rule("", PolicyRuleTargetAction::Deny, "prsn:bob")];
+        assert!(!field_readable("/db/password", &rules, "bob", &[]));
+        assert!(field_readable("/db/password", &rules, "alice", &[]));
+    }
+
+    #[test]
+    fn flatten_splits_objects_into_leaves() {
+        let value = json!({
+            "db": { "host": "localhost", "ports": [5432, 5433] },
+            "a/b": { "c~d": true },
+            "empty": {},
+        });
+        let mut leaves = vec![];
+        flatten(String::new(), &value, &mut leaves);
+        leaves.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            leaves,
+            vec![
+                ("/a~1b/c~0d".to_string(), json!(true)),
+                ("/db/host".to_string(), json!("localhost")),
+                ("/db/ports".to_string(), json!([5432, 5433])),
+                ("/empty".to_string(), json!({})),
+            ]
+        );
+    }
+
+    #[test]
+    fn flatten_keeps_scalars_whole() {
+        let mut leaves = vec![];
+        flatten(String::new(), &json!("hunter2"), &mut leaves);
+        assert_eq!(leaves, vec![(String::new(), json!("hunter2"))]);
+    }
+
+    #[test]
+    fn insert_at_undoes_flatten() {
+        let value = json!({
+            "db": { "host": "localhost", "ports": [5432] },
+            "a/b": { "c~d": true },
+            "empty": {},
+        });
+        let mut leaves = vec![];
+        flatten(String::new(), &value, &mut leaves);
+        let mut rebuilt = json!({});
+        for (pointer, leaf) in leaves {
+            insert_at(&mut rebuilt, &pointer, leaf);
+        }
+        assert_eq!(rebuilt, value);
+    }
+
+    #[test]
+    fn insert_at_the_root_replaces_it() {
+        let mut root = json!({ "old": 1 });
+        insert_at(&mut root, "", json!("new"));
+        assert_eq!(root, json!("new"));
+    }
+}
@@ -0,0 +1,677 @@
+use std::{collections::HashMap, ops::Range};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{App, EasyResource, ResourceTypes},
+    kek_provider::KekProvider,
+    models::TemplateStoredValue,
+    policies::AccessAction,
+    stores::{
+        ListRequest, ListTable, Page, RetrievedSecretData, SecretRef, StoreAccessError,
+        StoreUiRequirements, TypedStore, get_rows, kv::DEFAULT_ENVIRONMENT, list_rows,
+        remove_resource_assignments, require_project_action,
+    },
+};
+
+// Name the template store is registered under. References back into
+// it are refused so templates can't include each other.
+pub const TEMPLATE_STORE: &str = "template";
+
+fn default_environment() -> String {
+    DEFAULT_ENVIRONMENT.to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateFormat {
+    Toml,
+    Json,
+    Yaml,
+    Dotenv,
+}
+
+impl ToString for TemplateFormat {
+    fn to_string(&self) -> String {
+        String::from(match self {
+            Self::Toml => "toml",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Dotenv => "dotenv",
+        })
+    }
+}
+
+impl TryFrom<&str> for TemplateFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        return Ok(match value {
+            "toml" => Self::Toml,
+            "json" => Self::Json,
+            "yaml" => Self::Yaml,
+            "dotenv" => Self::Dotenv,
+            _ => return Err(format!("Unknown template format '{}'", value)),
+        });
+    }
+}
+
+impl TemplateFormat {
+    // Resolved values are escaped for use inside a double quoted string
+    // of the document's format, parse_references turns down references
+    // anywhere else. JSON escapes are valid in TOML basic strings and
+    // YAML double quoted scalars as well.
+    pub fn escape(&self, value: &str) -> String {
+        match self {
+            Self::Toml | Self::Json | Self::Yaml => {
+                let quoted = serde_json::to_string(value).unwrap();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Self::Dotenv => value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n"),
+        }
+    }
+    pub fn validate(&self, document: &str) -> Result<(), String> {
+        match self {
+            Self::Toml => toml::from_str::<toml::Value>(document)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Self::Json => serde_json::from_str::<serde_json::Value>(document)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::from_str::<serde_yaml::Value>(document)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Self::Dotenv => {
+                for (number, line) in document.lines().enumerate() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let line = line.strip_prefix("export ").unwrap_or(line);
+                    let Some((key, _)) = line.split_once('=') else {
+                        return Err(format!("line {}: expected KEY=value", number + 1));
+                    };
+                    let key = key.trim();
+                    if key.is_empty()
+                        || key.starts_with(|c: char| c.is_ascii_digit())
+                        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return Err(format!("line {}: invalid key '{}'", number + 1, key));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// {{ secret "<store>" "<key>" }} or {{ secret "<store>" "<key>" "<environment>" }}
+#[derive(Debug)]
+pub struct SecretReference {
+    pub store: String,
+    pub key: String,
+    pub environment: Option<String>,
+    pub span: Range<usize>,
+}
+
+// Whether `at` is inside a double quoted string opened earlier on its
+// line. References before it on the line are skipped, their quotes are
+// part of the directive rather than the document.
+fn in_double_quotes(template: &str, references: &[SecretReference], at: usize) -> bool {
+    let line_start = template[..at].rfind('\n').map_or(0, |e| e + 1);
+    let mut quoted = false;
+    let mut escaped = false;
+    for (offset, c) in template[line_start..at].char_indices() {
+        let position = line_start + offset;
+        if references.iter().any(|e| e.span.contains(&position)) {
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = !escaped,
+            '"' if !escaped => quoted = !quoted,
+            _ => escaped = false,
+        }
+    }
+    quoted
+}
+
+pub fn parse_references(template: &str) -> Result<Vec<SecretReference>, String> {
+    let mut references = vec![];
+    let mut position = 0;
+    while let Some(start) = template[position..].find("{{") {
+        let start = position + start;
+        let Some(end) = template[start..].find("}}") else {
+            return Err(format!("Unclosed '{{{{' at byte {}", start));
+        };
+        let end = start + end + 2;
+        let inner = template[start + 2..end - 2].trim();
+        let Some(arguments) = inner.strip_prefix("secret") else {
+            return Err(format!("Unknown template directive '{}'", inner));
+        };
+        let mut values = vec![];
+        let mut rest = arguments.trim_start();
+        while !rest.is_empty() {
+            let Some(quoted) = rest.strip_prefix('"') else {
+                return Err(format!("Expected a quoted argument in '{}'", inner));
+            };
+            let Some(close) = quoted.find('"') else {
+                return Err(format!("Unterminated string in '{}'", inner));
+            };
+            values.push(quoted[..close].to_string());
+            rest = quoted[close + 1..].trim_start();
+        }
+        let mut values = values.into_iter();
+        let (Some(store), Some(key), environment, None) =
+            (values.next(), values.next(), values.next(), values.next())
+        else {
+            return Err(format!(
+                "'{}' takes a store, a key and optionally an environment",
+                inner
+            ));
+        };
+        if !in_double_quotes(template, &references, start) {
+            return Err(format!(
+                "'{}' at byte {} has to be inside a double quoted string",
+                &template[start..end],
+                start
+            ));
+        }
+        references.push(SecretReference {
+            store,
+            key,
+            environment,
+            span: start..end,
+        });
+        position = end;
+    }
+    Ok(references)
+}
+
+pub fn render(template: &str, references: &[SecretReference], values: &[String]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut position = 0;
+    for (reference, value) in references.iter().zip(values) {
+        rendered.push_str(&template[position..reference.span.start]);
+        rendered.push_str(value);
+        position = reference.span.end;
+    }
+    rendered.push_str(&template[position..]);
+    rendered
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateStoreStoreData {
+    pub name: String,
+    pub project: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+    pub format: TemplateFormat,
+    pub template: String,
+}
+
+pub struct TemplateStore {}
+
+impl TemplateStore {
+    // Where list_rows and get_rows find this store's secrets.
+    fn list_table(&self) -> ListTable<'_> {
+        ListTable {
+            table: "template_store",
+            name_column: "key",
+            has_environment: true,
+            updated_column: "last_updated",
+            store: None,
+        }
+    }
+    pub async fn init() -> Self {
+        Self {}
+    }
+    pub async fn find_by_key(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        key: &str,
+    ) -> Option<TemplateStoredValue> {
+        sqlx::query_as::<_, TemplateStoredValue>(
+            r#"SELECT * FROM tokaysec.template_store WHERE project = ($1) AND environment = ($2) AND key = ($3)"#,
+        )
+        .bind(&project)
+        .bind(&environment)
+        .bind(&key)
+        .fetch_optional(&app.database.inner)
+        .await
+        .unwrap()
+    }
+    // Resolves one reference through the store it points at. Any
+    // failure is returned as is so the whole render fails closed.
+    async fn resolve(
+        &self,
+        app: &App,
+        project: &str,
+        environment: &str,
+        reference: &SecretReference,
+        kek_provider: &dyn KekProvider,
+        requester: &str,
+    ) -> Result<String, StoreAccessError> {
+        if reference.store == TEMPLATE_STORE {
+            return Err(StoreAccessError::Invalid(
+                "Templates can't reference other templates".to_string(),
+            ));
+        }
+        let stores = app.stores.clone();
+        let stores_read = stores.read().await;
+        let Some(store) = stores_read.get(&reference.store) else {
+            return Err(StoreAccessError::NotFound(format!(
+                "Unknown store '{}' referenced",
+                reference.store
+            )));
+        };
+        let query = HashMap::from([
+            ("project".to_string(), project.to_string()),
+            ("key".to_string(), reference.key.to_owned()),
+            (
+                "environment".to_string(),
+                reference
+                    .environment
+                    .to_owned()
+                    .unwrap_or(environment.to_string()),
+            ),
+        ]);
+        let retrieved = store.retrieve(app, kek_provider, query, requester).await?;
+        return match &retrieved["value"] {
+            serde_json::Value::String(value) => Ok(value.to_owned()),
+            serde_json::Value::Array(bytes) => {
+                let bytes = bytes
+                    .iter()
+                    .map(|e| e.as_u64().and_then(|e| u8::try_from(e).ok()))
+                    .collect::<Option<Vec<u8>>>();
+                bytes
+                    .and_then(|e| String::from_utf8(e).ok())
+                    .ok_or_else(|| {
+                        StoreAccessError::Invalid(format!(
+                            "'{}' is not valid UTF-8 and can't be templated",
+                            reference.key
+                        ))
+                    })
+            }
+            other => Ok(other.to_string()),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateRetrieveRequest {
+    pub project: String,
+    #[serde(alias = "name")]
+    pub key: String,
+    #[serde(default = "default_environment")]
+    pub environment: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateRendered {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub format: String,
+    pub rendered: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateStored {
+    pub id: String,
+    pub name: String,
+    pub environment: String,
+    pub format: TemplateFormat,
+}
+
+impl TemplateStore {
+    // Same as config, writing to an existing key replaces it and
+    // updates (must_exist) refuse to create one.
+    async fn write(
+        &self,
+        app: &App,
+        data: TemplateStoreStoreData,
+        creator: &str,
+        must_exist: bool,
+    ) -> Result<TemplateStored, StoreAccessError> {
+        // Check the document is well formed up front by rendering it
+        // with a dummy value in place of every reference.
+        let references = parse_references(&data.template).map_err(StoreAccessError::Invalid)?;
+        let placeholders = vec![String::from("placeholder"); references.len()];
+        if let Err(e) = data
+            .format
+            .validate(&render(&data.template, &references, &placeholders))
+        {
+            return Err(StoreAccessError::Invalid(format!(
+                "Template is not valid {}: {}",
+                data.format.to_string(),
+                e
+            )));
+        }
+        let now = Utc::now();
+        let existing = self
+            .find_by_key(app, &data.project, &data.environment, &data.name)
+            .await;
+        let required = match (&existing, must_exist) {
+            (None, true) => {
+                return Err(StoreAccessError::NotFound(format!(
+                    "No template named '{}' in project {} ({})",
+                    data.name, data.project, data.environment
+                )));
+            }
+            (Some(_), _) => AccessAction::UpdateConfig,
+            (None, false) => AccessAction::CreateConfig,
+        };
+        require_project_action(app, &data.project, creator, required).await?;
+        let id = match existing {
+            Some(existing) => {
+                sqlx::query(
+                    r#"UPDATE tokaysec.template_store SET format = ($1), template = ($2), last_updated = ($3) WHERE id = ($4)"#,
+                )
+                .bind(data.format.to_string())
+                .bind(&data.template)
+                .bind(now)
+                .bind(&existing.id)
+                .execute(&app.database.inner)
+                .await?;
+                existing.id
+            }
+            None => {
+                let id = app.gen_id().await;
+                sqlx::query(
+                    r#"INSERT INTO tokaysec.template_store(id,project,environment,key,format,template,added_when,added_by,last_updated) VALUES($1,$2,$3,$4,$5,$6,$7,$8,$7)"#,
+                )
+                .bind(&id)
+                .bind(&data.project)
+                .bind(&data.environment)
+                .bind(&data.name)
+                .bind(data.format.to_string())
+                .bind(&data.template)
+                .bind(now)
+                .bind(&creator)
+                .execute(&app.database.inner)
+                .await?;
+                app.create_resource_assignment(
+                    EasyResource(ResourceTypes::Project, &data.project),
+                    EasyResource(
+                        ResourceTypes::Config,
+                        &format!("{}:{}", TEMPLATE_STORE, &id),
+                    ),
+                    creator,
+                )
+                .await
+                .map_err(StoreAccessError::Internal)?;
+                id
+            }
+        };
+        return Ok(TemplateStored {
+            id,
+            name: data.name,
+            environment: data.environment,
+            format: data.format,
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl TypedStore for TemplateStore {
+    type StoreRequest = TemplateStoreStoreData;
+    type StoreResponse = TemplateStored;
+    type RetrieveRequest = TemplateRetrieveRequest;
+    type RetrieveResponse = TemplateRendered;
+    type UpdateRequest = TemplateStoreStoreData;
+
+    fn store_type(&self) -> &str {
+        "template"
+    }
+    fn ui_reqs(&self) -> StoreUiRequirements {
+        StoreUiRequirements {
+            name: true,
+            description: false,
+            secret_type: false,
+        }
+    }
+    async fn get(&self, app: &App, id: &str) -> Result<RetrievedSecretData, StoreAccessError> {
+        let template_data = sqlx::query_as::<_, TemplateStoredValue>(
+            r#"SELECT * FROM tokaysec.template_store WHERE id = $1"#,
+        )
+        .bind(&id)
+        .fetch_one(&app.database.inner)
+        .await?;
+        // Never rendered in listings, that would decrypt every
+        // referenced secret.
+        return Ok(RetrievedSecretData {
+            id: template_data.id,
+            name: template_data.key,
+            environment: Some(template_data.environment),
+            updated_when: Some(template_data.last_updated),
+            value: None,
+        });
+    }
+    async fn get_many(
+        &self,
+        app: &App,
+        ids: &[String],
+    ) -> Result<Vec<RetrievedSecretData>, StoreAccessError> {
+        get_rows(app, self.list_table(), ids).await
+    }
+    async fn list(
+        &self,
+        app: &App,
+        request: ListRequest,
+        requester: &str,
+    ) -> Result<Page<RetrievedSecretData>, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadConfig).await?;
+        list_rows(app, self.list_table(), &request).await
+    }
+    async fn retrieve(
+        &self,
+        app: &App,
+        kek_provider: &dyn KekProvider,
+        request: TemplateRetrieveRequest,
+        requester: &str,
+    ) -> Result<TemplateRendered, StoreAccessError> {
+        require_project_action(app, &request.project, requester, AccessAction::ReadConfig).await?;
+        let Some(template_data) = self
+            .find_by_key(app, &request.project, &request.environment, &request.key)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No template named '{}' in project {} ({})",
+                request.key, request.project, request.environment
+            )));
+        };
+        let format = TemplateFormat::try_from(template_data.format.as_str()).unwrap();
+        let references =
+            parse_references(&template_data.template).map_err(StoreAccessError::Invalid)?;
+        let mut values = Vec::with_capacity(references.len());
+        for reference in &references {
+            let value = self
+                .resolve(
+                    app,
+                    &request.project,
+                    &request.environment,
+                    reference,
+                    kek_provider,
+                    requester,
+                )
+                .await?;
+            values.push(format.escape(&value));
+        }
+        let rendered = render(&template_data.template, &references, &values);
+        if let Err(e) = format.validate(&rendered) {
+            return Err(StoreAccessError::Invalid(format!(
+                "Rendered '{}' is not valid {}: {}",
+                request.key,
+                format.to_string(),
+                e
+            )));
+        }
+        return Ok(TemplateRendered {
+            id: template_data.id,
+            name: template_data.key,
+            environment: template_data.environment,
+            format: template_data.format,
+            rendered,
+        });
+    }
+
+    async fn store(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        data: TemplateStoreStoreData,
+        creator: &str,
+    ) -> Result<TemplateStored, StoreAccessError> {
+        self.write(app, data, creator, false).await
+    }
+
+    async fn update(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        data: TemplateStoreStoreData,
+        requester: &str,
+    ) -> Result<TemplateStored, StoreAccessError> {
+        self.write(app, data, requester, true).await
+    }
+
+    async fn delete(
+        &self,
+        app: &App,
+        _kek_provider: &dyn KekProvider,
+        target: SecretRef,
+        requester: &str,
+    ) -> Result<(), StoreAccessError> {
+        require_project_action(app, &target.project, requester, AccessAction::DeleteConfig).await?;
+        let Some(existing) = self
+            .find_by_key(app, &target.project, &target.environment, &target.name)
+            .await
+        else {
+            return Err(StoreAccessError::NotFound(format!(
+                "No template named '{}' in project {} ({})",
+                target.name, target.project, target.environment
+            )));
+        };
+        sqlx::query(r#"DELETE FROM tokaysec.template_store WHERE id = ($1)"#)
+            .bind(&existing.id)
+            .execute(&app.database.inner)
+            .await?;
+        remove_resource_assignments(app, &format!("{}:{}", TEMPLATE_STORE, &existing.id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_references() {
+        let template = "a = \"{{ secret \"kv_store\" \"DB\" }}\"\nb = \"{{secret \"config\" \"HOST\" \"prod\"}}\"\n";
+        let references = parse_references(template).unwrap();
+        assert_eq!(references.len(), 2);
+        assert_eq!(
+            (
+                references[0].store.as_str(),
+                references[0].key.as_str(),
+                references[0].environment.as_deref()
+            ),
+            ("kv_store", "DB", None)
+        );
+        assert_eq!(
+            (
+                references[1].store.as_str(),
+                references[1].key.as_str(),
+                references[1].environment.as_deref()
+            ),
+            ("config", "HOST", Some("prod"))
+        );
+        assert_eq!(
+            &template[references[0].span.clone()],
+            "{{ secret \"kv_store\" \"DB\" }}"
+        );
+    }
+
+    #[test]
+    fn renders_values_in_place() {
+        let template = "x=\"{{ secret \"kv_store\" \"A\" }}\";y=\"{{ secret \"kv_store\" \"B\" }}:{{ secret \"kv_store\" \"C\" }}\"!";
+        let references = parse_references(template).unwrap();
+        let rendered = render(
+            template,
+            &references,
+            &["1".to_string(), "2".to_string(), "3".to_string()],
+        );
+        assert_eq!(rendered, "x=\"1\";y=\"2:3\"!");
+    }
+
+    #[test]
+    fn no_references_renders_unchanged() {
+        let template = "plain = \"text\" { not: a reference }";
+        let references = parse_references(template).unwrap();
+        assert!(references.is_empty());
+        assert_eq!(render(template, &references, &[]), template);
+    }
+
+    #[test]
+    fn rejects_malformed_references() {
+        for template in [
+            "{{ secret \"kv_store\" \"A\"",
+            "{{ env \"HOME\" }}",
+            "{{ secret kv_store \"A\" }}",
+            "{{ secret \"kv_store }}",
+            "{{ secret \"kv_store\" }}",
+            "{{ secret \"kv_store\" \"A\" \"prod\" \"extra\" }}",
+        ] {
+            assert!(
+                parse_references(template).is_err(),
+                "{} was accepted",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unquoted_references() {
+        for template in [
+            "KEY={{ secret \"kv_store\" \"A\" }}",
+            "key: {{ secret \"kv_store\" \"A\" }}",
+            "key: '{{ secret \"kv_store\" \"A\" }}'",
+            "a = \"x\" # {{ secret \"kv_store\" \"A\" }}",
+            "a = \"x\\\"\" {{ secret \"kv_store\" \"A\" }}",
+            "a = \"\n{{ secret \"kv_store\" \"A\" }}\"",
+        ] {
+            assert!(
+                parse_references(template).is_err(),
+                "{} was accepted",
+                template
+            );
+        }
+        for template in [
+            "KEY=\"{{ secret \"kv_store\" \"A\" }}\"",
+            "key: \"prefix {{ secret \"kv_store\" \"A\" }}\"",
+            "{\"a\": \"\\\" {{ secret \"kv_store\" \"A\" }}\"}",
+            "a = \"\"\"{{ secret \"kv_store\" \"A\" }}\"\"\"",
+        ] {
+            assert!(
+                parse_references(template).is_ok(),
+                "{} was turned down",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn escapes_for_the_format() {
+        let value = "a \"b\"\nc\\d";
+        assert_eq!(TemplateFormat::Json.escape(value), "a \\\"b\\\"\\nc\\\\d");
+        assert_eq!(TemplateFormat::Dotenv.escape(value), "a \\\"b\\\"\\nc\\\\d");
+        let document = format!("k = \"{}\"", TemplateFormat::Toml.escape(value));
+        let parsed = toml::from_str::<toml::Value>(&document).unwrap();
+        assert_eq!(parsed["k"].as_str(), Some(value));
+    }
+}
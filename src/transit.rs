@@ -0,0 +1,330 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::Utc;
+
+use crate::{
+    app::App,
+    dek::{Dek, DekWrapDataResult},
+    kek_provider::KekProvider,
+    models::{TransitKey, TransitKeyVersion},
+    secure_buf::SecureBuffer,
+    stores::StoreAccessError,
+};
+
+// tokay:v<version>:<base64(nonce | gcm tag | kmac tag | ciphertext)>
+// The version says which key version to decrypt with, so a key can be
+// rotated without breaking anything encrypted before it.
+pub const CIPHERTEXT_PREFIX: &str = "tokay";
+const NONCE_LEN: usize = 12;
+const GCM_TAG_LEN: usize = 16;
+const KMAC_TAG_LEN: usize = 32;
+
+// Size of the keys handed out by /datakey.
+pub const DATA_KEY_LEN: usize = 32;
+
+fn wrapping_name(key_id: &str, version: i32) -> String {
+    format!("transit_key:{}:v{}", key_id, version)
+}
+
+// AAD for data encrypted under a transit key. Binds ciphertext to the
+// key it was made with so it can't be replayed against another key.
+fn data_name(key_id: &str) -> String {
+    format!("transit:{}", key_id)
+}
+
+fn pack(version: i32, encrypted: &DekWrapDataResult) -> String {
+    let mut packed =
+        Vec::with_capacity(NONCE_LEN + GCM_TAG_LEN + KMAC_TAG_LEN + encrypted.data.len());
+    packed.extend_from_slice(&encrypted.nonce);
+    packed.extend_from_slice(&encrypted.gcm_tag);
+    packed.extend_from_slice(&encrypted.kmac_tag);
+    packed.extend_from_slice(&encrypted.data);
+    format!(
+        "{}:v{}:{}",
+        CIPHERTEXT_PREFIX,
+        version,
+        BASE64_STANDARD.encode(packed)
+    )
+}
+
+// Only checks the shape, whether it decrypts is up to the key.
+fn unpack(ciphertext: &str) -> Result<(i32, DekWrapDataResult), StoreAccessError> {
+    let invalid = || StoreAccessError::Invalid("Malformed transit ciphertext".to_string());
+    let mut parts = ciphertext.splitn(3, ':');
+    if parts.next() != Some(CIPHERTEXT_PREFIX) {
+        return Err(invalid());
+    }
+    let version = parts
+        .next()
+        .and_then(|e| e.strip_prefix('v'))
+        .and_then(|e| e.parse::<i32>().ok())
+        .ok_or_else(invalid)?;
+    let packed = parts
+        .next()
+        .and_then(|e| BASE64_STANDARD.decode(e).ok())
+        .ok_or_else(invalid)?;
+    if packed.len() < NONCE_LEN + GCM_TAG_LEN + KMAC_TAG_LEN {
+        return Err(invalid());
+    }
+    let (nonce, rest) = packed.split_at(NONCE_LEN);
+    let (gcm_tag, rest) = rest.split_at(GCM_TAG_LEN);
+    let (kmac_tag, data) = rest.split_at(KMAC_TAG_LEN);
+    Ok((
+        version,
+        DekWrapDataResult {
+            data: data.to_vec(),
+            gcm_tag: gcm_tag.to_vec(),
+            kmac_tag: kmac_tag.to_vec(),
+            nonce: nonce.to_vec(),
+        },
+    ))
+}
+
+pub fn generate_data_key() -> SecureBuffer {
+    let mut data_key = SecureBuffer::new(DATA_KEY_LEN).unwrap();
+    OsRng.fill_bytes(data_key.expose_mut());
+    data_key
+}
+
+pub async fn find_key(app: &App, project: &str, name: &str) -> Option<TransitKey> {
+    sqlx::query_as::<_, TransitKey>(
+        r#"SELECT * FROM tokaysec.transit_keys WHERE project = ($1) AND name = ($2)"#,
+    )
+    .bind(&project)
+    .bind(&name)
+    .fetch_optional(&app.database.inner)
+    .await
+    .unwrap()
+}
+
+async fn add_version(
+    kek_provider: &dyn KekProvider,
+    key_id: &str,
+    version: i32,
+    creator: &str,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) {
+    let (wrapped, nonce, tag) = kek_provider
+        .wrap_dek(Dek::init(), &wrapping_name(key_id, version))
+        .await
+        .unwrap();
+    sqlx::query(
+        r#"INSERT INTO tokaysec.transit_key_versions(key_id,version,wrapped,nonce,tag,added_when,added_by) VALUES($1,$2,$3,$4,$5,$6,$7)"#,
+    )
+    .bind(&key_id)
+    .bind(version)
+    .bind(&wrapped)
+    .bind(nonce)
+    .bind(tag)
+    .bind(Utc::now())
+    .bind(&creator)
+    .execute(&mut **tx)
+    .await
+    .unwrap();
+}
+
+pub async fn create_key(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    project: &str,
+    name: &str,
+    creator: &str,
+) -> Result<TransitKey, StoreAccessError> {
+    if find_key(app, project, name).await.is_some() {
+        return Err(StoreAccessError::Conflict(format!(
+            "A transit key named '{}' already exists in this project",
+            name
+        )));
+    }
+    let id = app.gen_id().await;
+    let now = Utc::now();
+    let mut tx = app.database.inner.begin().await.unwrap();
+    let key = sqlx::query_as::<_, TransitKey>(
+        r#"INSERT INTO tokaysec.transit_keys(id,project,name,latest_version,added_when,added_by,last_updated) VALUES($1,$2,$3,1,$4,$5,$4) RETURNING *"#,
+    )
+    .bind(&id)
+    .bind(&project)
+    .bind(&name)
+    .bind(now)
+    .bind(&creator)
+    .fetch_one(&mut *tx)
+    .await
+    .unwrap();
+    add_version(kek_provider, &id, 1, creator, &mut tx).await;
+    tx.commit().await.unwrap();
+    Ok(key)
+}
+
+// New data is encrypted with the new version, old versions stay for
+// decryption until everything has been rewrapped.
+pub async fn rotate_key(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    key: &TransitKey,
+    creator: &str,
+) -> TransitKey {
+    let version = key.latest_version + 1;
+    let mut tx = app.database.inner.begin().await.unwrap();
+    add_version(kek_provider, &key.id, version, creator, &mut tx).await;
+    let key = sqlx::query_as::<_, TransitKey>(
+        r#"UPDATE tokaysec.transit_keys SET latest_version = ($1), last_updated = ($2) WHERE id = ($3) RETURNING *"#,
+    )
+    .bind(version)
+    .bind(Utc::now())
+    .bind(&key.id)
+    .fetch_one(&mut *tx)
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+    key
+}
+
+// The given version of the key, or the newest one there is. Returns
+// which version it was along with it.
+async fn key_version(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    key: &TransitKey,
+    version: Option<i32>,
+) -> Result<(i32, Dek), StoreAccessError> {
+    let Some(key_version) = sqlx::query_as::<_, TransitKeyVersion>(
+        r#"SELECT * FROM tokaysec.transit_key_versions WHERE key_id = ($1) AND ($2::INTEGER IS NULL OR version = ($2)) ORDER BY version DESC LIMIT 1"#,
+    )
+    .bind(&key.id)
+    .bind(version)
+    .fetch_optional(&app.database.inner)
+    .await?
+    else {
+        return Err(StoreAccessError::NotFound(format!(
+            "Transit key '{}' has no version {}",
+            key.name,
+            version.map_or("at all".to_string(), |e| e.to_string())
+        )));
+    };
+    let (Ok(nonce), Ok(tag)) = (key_version.nonce.try_into(), key_version.tag.try_into()) else {
+        return Err(StoreAccessError::Internal(format!(
+            "Version {} of transit key '{}' is malformed",
+            key_version.version, key.name
+        )));
+    };
+    let dek = kek_provider
+        .try_unwrap_dek(
+            &key_version.wrapped,
+            nonce,
+            tag,
+            &wrapping_name(&key.id, key_version.version),
+        )
+        .await
+        .map_err(|e| {
+            StoreAccessError::Internal(format!(
+                "Version {} of transit key '{}' failed to unwrap: {}",
+                key_version.version, key.name, e
+            ))
+        })?;
+    Ok((key_version.version, dek.into()))
+}
+
+// Encrypts under the newest version and returns that version with the
+// ciphertext.
+pub async fn encrypt(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    key: &TransitKey,
+    plaintext: SecureBuffer,
+) -> Result<(i32, String), StoreAccessError> {
+    let (version, dek) = key_version(app, kek_provider, key, None).await?;
+    let encrypted = dek.wrap_data(plaintext, data_name(&key.id));
+    Ok((version, pack(version, &encrypted)))
+}
+
+pub async fn decrypt(
+    app: &App,
+    kek_provider: &dyn KekProvider,
+    key: &TransitKey,
+    ciphertext: &str,
+) -> Result<SecureBuffer, StoreAccessError> {
+    let (version, packed) = unpack(ciphertext)?;
+    let (_, dek) = key_version(app, kek_provider, key, Some(version)).await?;
+    dek.try_unwrap_data(
+        packed.data,
+        packed.kmac_tag,
+        &data_name(&key.id),
+        packed.nonce,
+        packed.gcm_tag,
+    )
+    .map_err(|_| StoreAccessError::Invalid("Ciphertext failed to decrypt".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dek: &Dek, key_id: &str, packed: DekWrapDataResult) -> Result<Vec<u8>, String> {
+        dek.try_unwrap_data(
+            packed.data,
+            packed.kmac_tag,
+            &data_name(key_id),
+            packed.nonce,
+            packed.gcm_tag,
+        )
+        .map(|e| e.expose().to_vec())
+    }
+
+    fn sealed(dek: &Dek, version: i32, plaintext: &[u8]) -> String {
+        let encrypted = dek.wrap_data(
+            SecureBuffer::from_slice(plaintext).unwrap(),
+            data_name("k1"),
+        );
+        pack(version, &encrypted)
+    }
+
+    #[test]
+    fn round_trips() {
+        let dek = Dek::init();
+        let ciphertext = sealed(&dek, 3, b"hello transit");
+        assert!(ciphertext.starts_with("tokay:v3:"));
+        let (version, packed) = unpack(&ciphertext).unwrap();
+        assert_eq!(version, 3);
+        assert_eq!(open(&dek, "k1", packed).unwrap(), b"hello transit");
+    }
+
+    #[test]
+    fn is_bound_to_the_key() {
+        let dek = Dek::init();
+        let (_, packed) = unpack(&sealed(&dek, 1, b"secret")).unwrap();
+        assert!(open(&dek, "k2", packed).is_err());
+    }
+
+    #[test]
+    fn tampering_fails_to_decrypt() {
+        let dek = Dek::init();
+        let (_, mut packed) = unpack(&sealed(&dek, 1, b"secret")).unwrap();
+        packed.data[0] ^= 1;
+        assert!(open(&dek, "k1", packed).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_ciphertext() {
+        let too_short = BASE64_STANDARD.encode([0u8; NONCE_LEN + GCM_TAG_LEN + KMAC_TAG_LEN - 1]);
+        for ciphertext in [
+            "".to_string(),
+            "tokay".to_string(),
+            "vault:v1:AAAA".to_string(),
+            "tokay:1:AAAA".to_string(),
+            "tokay:vx:AAAA".to_string(),
+            "tokay:v1".to_string(),
+            "tokay:v1:not base64!".to_string(),
+            format!("tokay:v1:{}", too_short),
+        ] {
+            assert!(unpack(&ciphertext).is_err(), "{} was accepted", ciphertext);
+        }
+    }
+
+    #[test]
+    fn accepts_an_empty_payload_after_the_tags() {
+        let just_tags = BASE64_STANDARD.encode([0u8; NONCE_LEN + GCM_TAG_LEN + KMAC_TAG_LEN]);
+        let (version, packed) = unpack(&format!("tokay:v7:{}", just_tags)).unwrap();
+        assert_eq!(version, 7);
+        assert!(packed.data.is_empty());
+    }
+}
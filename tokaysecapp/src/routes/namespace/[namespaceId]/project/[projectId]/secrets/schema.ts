@@ -3,7 +3,7 @@ import { z } from "zod";
 export const createSecretFormSchema = z.object({
     name: z.string().min(2).max(50),
     description: z.string().min(2).optional(),
-    secret_type: z.enum(["key-value"]),
+    secret_type: z.string().min(1),
     secret: z.string().min(1),
 });
 